@@ -1,5 +1,6 @@
 use std::mem::MaybeUninit;
-use std::ptr::addr_of_mut;
+use std::ptr::{self, addr_of_mut};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Mutex};
 use std::{mem, process, slice, thread};
 
@@ -17,33 +18,68 @@ use self::scheme::AudioScheme;
 
 mod scheme;
 
+/// Set by SIGTERM to drain the queued audio and exit.
+static SHUTDOWN: AtomicBool = AtomicBool::new(false);
+
 extern "C" fn sigusr_handler(_sig: usize) {}
 
+extern "C" fn sigterm_handler(_sig: usize) {
+    SHUTDOWN.store(true, Ordering::SeqCst);
+}
+
+fn sigaction(sig: u32, handler: extern "C" fn(usize)) -> Result<()> {
+    let new_sigaction = unsafe {
+        let mut sigaction = MaybeUninit::<libc::sigaction>::uninit();
+        addr_of_mut!((*sigaction.as_mut_ptr()).sa_flags).write(0);
+        libc::sigemptyset(addr_of_mut!((*sigaction.as_mut_ptr()).sa_mask));
+        addr_of_mut!((*sigaction.as_mut_ptr()).sa_sigaction).write(handler as *const () as usize);
+        sigaction.assume_init()
+    };
+    libredox::call::sigaction(sig, Some(&new_sigaction), None)
+}
+
 fn thread(scheme: Arc<Mutex<AudioScheme>>, pid: usize, hw_file: Fd) -> Result<()> {
+    // Leave SIGTERM to the scheme thread, so that it gets interrupted
+    unsafe {
+        let mut set = MaybeUninit::<libc::sigset_t>::uninit();
+        libc::sigemptyset(set.as_mut_ptr());
+        libc::sigaddset(set.as_mut_ptr(), libc::SIGTERM);
+        libc::pthread_sigmask(libc::SIG_BLOCK, set.as_ptr(), ptr::null_mut());
+    }
+
     loop {
-        let buffer = scheme.lock().unwrap().buffer();
+        let shutdown = SHUTDOWN.load(Ordering::SeqCst);
+        let buffer = {
+            let mut scheme = scheme.lock().unwrap();
+            // Keep playing until everything queued before the shutdown was mixed
+            if shutdown && !scheme.has_pending_audio() {
+                return Ok(());
+            }
+            scheme.buffer()
+        };
         let buffer_u8 = unsafe {
             slice::from_raw_parts(buffer.as_ptr() as *const u8, mem::size_of_val(&buffer))
         };
 
-        // Wake up the scheme thread
-        libredox::call::kill(pid, libredox::flag::SIGUSR1 as u32)?;
+        // Wake up the scheme thread, unless it stopped handling requests
+        if !shutdown {
+            libredox::call::kill(pid, libredox::flag::SIGUSR1 as u32)?;
+        }
 
-        hw_file.write(&buffer_u8)?;
+        // Retry writes interrupted by a signal, so that no mixed buffer is lost
+        while let Err(err) = hw_file.write(&buffer_u8) {
+            if !err.is_interrupt() {
+                return Err(err);
+            }
+        }
     }
 }
 
 fn daemon(daemon: Daemon) -> anyhow::Result<()> {
     // Handle signals from the hw thread
 
-    let new_sigaction = unsafe {
-        let mut sigaction = MaybeUninit::<libc::sigaction>::uninit();
-        addr_of_mut!((*sigaction.as_mut_ptr()).sa_flags).write(0);
-        libc::sigemptyset(addr_of_mut!((*sigaction.as_mut_ptr()).sa_mask));
-        addr_of_mut!((*sigaction.as_mut_ptr()).sa_sigaction).write(sigusr_handler as *const () as usize);
-        sigaction.assume_init()
-    };
-    libredox::call::sigaction(flag::SIGUSR1, Some(&new_sigaction), None)?;
+    sigaction(flag::SIGUSR1, sigusr_handler)?;
+    sigaction(flag::SIGTERM, sigterm_handler)?;
 
     let pid = libredox::call::getpid()?;
 
@@ -66,14 +102,14 @@ fn daemon(daemon: Daemon) -> anyhow::Result<()> {
 
     // Spawn a thread to mix and send audio data
     let scheme_thread = scheme.clone();
-    let _thread = thread::spawn(move || {
+    let mixer = thread::spawn(move || {
         libredox::call::setrens(ns, ns).unwrap();
         thread(scheme_thread, pid, hw_file)
     });
 
     let mut readiness = ReadinessBased::new(&socket, 16);
 
-    loop {
+    while !SHUTDOWN.load(Ordering::SeqCst) {
         if !readiness.read_requests()? {
             break;
         }
@@ -84,6 +120,12 @@ fn daemon(daemon: Daemon) -> anyhow::Result<()> {
         };
     }
 
+    // Stop the mixing thread once it played the remaining audio, and send the responses
+    // which were already computed.
+    SHUTDOWN.store(true, Ordering::SeqCst);
+    mixer.join().unwrap()?;
+    readiness.write_responses()?;
+
     Ok(())
 }
 
@@ -11,13 +11,28 @@ const HW_BUFFER_SIZE: usize = 512;
 // The desired buffer size of each handle
 const HANDLE_BUFFER_SIZE: usize = 4096;
 
+/// `call` opcode returning the volume, from 0 to 100.
+///
+/// On an audio handle this is the volume of that stream, on the volume handle the master volume.
+pub const CALL_GET_VOLUME: u64 = 1;
+/// `call` opcode setting the volume to the value in the second metadata word, clamped to 100.
+pub const CALL_SET_VOLUME: u64 = 2;
+
 enum Handle {
-    Audio { buffer: VecDeque<(i16, i16)> },
+    Audio {
+        buffer: VecDeque<(i16, i16)>,
+        volume: i32,
+    },
     // TODO: move volume to audiohw:?
-    // TODO: Use SYS_CALL to handle this better?
     Volume,
 }
 
+/// Multiply each sample by the cube of volume divided by 100
+/// This mimics natural perception of loudness
+fn volume_factor(volume: i32) -> f32 {
+    ((volume as f32) / 100.0).powi(3)
+}
+
 pub struct AudioScheme {
     next_id: usize,
     handles: BTreeMap<usize, Handle>,
@@ -33,15 +48,31 @@ impl AudioScheme {
         }
     }
 
+    /// Whether any handle has audio which wasn't mixed yet.
+    pub fn has_pending_audio(&self) -> bool {
+        self.handles.values().any(|handle| match handle {
+            Handle::Audio { buffer, .. } => !buffer.is_empty(),
+            _ => false,
+        })
+    }
+
     pub fn buffer(&mut self) -> [(i16, i16); HW_BUFFER_SIZE] {
         let mut mix_buffer = [(0i16, 0i16); HW_BUFFER_SIZE];
 
-        // Multiply each sample by the cube of volume divided by 100
-        // This mimics natural perception of loudness
-        let volume_factor = ((self.volume as f32) / 100.0).powi(3);
         for (_id, handle) in self.handles.iter_mut() {
             match handle {
-                Handle::Audio { ref mut buffer } => {
+                Handle::Audio {
+                    ref mut buffer,
+                    volume,
+                } => {
+                    if self.volume == 0 || *volume == 0 {
+                        // Muted, drop the samples which would have been played
+                        let len = buffer.len().min(mix_buffer.len());
+                        buffer.drain(..len);
+                        continue;
+                    }
+
+                    let volume_factor = volume_factor(self.volume) * volume_factor(*volume);
                     let mut i = 0;
                     while i < mix_buffer.len() {
                         if let Some(sample) = buffer.pop_front() {
@@ -69,6 +100,7 @@ impl SchemeSync for AudioScheme {
             "" => (
                 Handle::Audio {
                     buffer: VecDeque::new(),
+                    volume: 100,
                 },
                 NewFdFlags::empty(),
             ),
@@ -93,7 +125,7 @@ impl SchemeSync for AudioScheme {
     ) -> Result<usize> {
         //TODO: check flags for readable
         match self.handles.get_mut(&id).ok_or(Error::new(EBADF))? {
-            Handle::Audio { buffer: _ } => {
+            Handle::Audio { .. } => {
                 //TODO: audio input?
                 Err(Error::new(EBADF))
             }
@@ -122,7 +154,7 @@ impl SchemeSync for AudioScheme {
     ) -> Result<usize> {
         //TODO: check flags for writable
         match self.handles.get_mut(&id).ok_or(Error::new(EBADF))? {
-            Handle::Audio { ref mut buffer } => {
+            Handle::Audio { ref mut buffer, .. } => {
                 if buffer.len() >= HANDLE_BUFFER_SIZE {
                     Err(Error::new(EWOULDBLOCK))
                 } else {
@@ -160,4 +192,27 @@ impl SchemeSync for AudioScheme {
             }
         }
     }
+
+    fn call(
+        &mut self,
+        id: usize,
+        _payload: &mut [u8],
+        metadata: &[u64],
+        _ctx: &CallerCtx,
+    ) -> Result<usize> {
+        let volume = match self.handles.get_mut(&id).ok_or(Error::new(EBADF))? {
+            Handle::Audio { volume, .. } => volume,
+            Handle::Volume => &mut self.volume,
+        };
+
+        match metadata.first() {
+            Some(&CALL_GET_VOLUME) => Ok(*volume as usize),
+            Some(&CALL_SET_VOLUME) => {
+                let value = metadata.get(1).ok_or(Error::new(EINVAL))?;
+                *volume = (*value).min(100) as i32;
+                Ok(0)
+            }
+            _ => Err(Error::new(EINVAL)),
+        }
+    }
 }
@@ -1,24 +1,39 @@
 // Simple HTTP/HTTPS client for Redox using std::net + rustls-rustcrypto
 // Supports wget-like file download with -o FILE option
 use std::env;
-use std::fs::File;
-use std::io::{self, Read, Write, BufRead, BufReader};
-use std::net::TcpStream;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write, BufRead, BufReader};
+use std::net::{TcpStream, ToSocketAddrs};
 use std::process;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-use rustls::pki_types::ServerName;
-use rustls::{ClientConfig, ClientConnection, StreamOwned, RootCertStore};
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::CryptoProvider;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use rustls::{ClientConfig, ClientConnection, DigitallySignedStruct, SignatureScheme, StreamOwned, RootCertStore, SupportedProtocolVersion};
 
 fn print_usage() {
     eprintln!("Usage: curl [options] <url>");
     eprintln!("Options:");
     eprintln!("  -o FILE      Write output to FILE (wget-style download)");
     eprintln!("  -O           Write to file named from URL");
+    eprintln!("  -C -         Resume the download into an existing output file");
     eprintln!("  -L           Follow redirects");
     eprintln!("  -v           Verbose mode");
     eprintln!("  -I           Show headers only");
     eprintln!("  -s           Silent mode (no progress)");
+    eprintln!("  -d DATA      POST DATA (@FILE reads it from FILE)");
+    eprintln!("  -H 'NAME: VALUE'  Add a request header, replacing a default one ('NAME:' removes it)");
+    eprintln!("  --data-binary DATA  POST DATA without stripping newlines from @FILE");
+    eprintln!("  -k, --insecure  Skip TLS certificate verification");
+    eprintln!("  --cacert FILE   Trust the PEM certificate(s) in FILE");
+    eprintln!("  --http1.0       Use HTTP/1.0 instead of HTTP/1.1");
+    eprintln!("  --tlsv1.2       Only negotiate TLS 1.2");
+    eprintln!("  --tlsv1.3       Only negotiate TLS 1.3");
+    eprintln!("  --connect-timeout SECS  Give up connecting (and the TLS handshake) after SECS");
+    eprintln!("  --max-time SECS         Give up the whole transfer after SECS");
     eprintln!();
     eprintln!("Supports HTTP and HTTPS (pure-Rust TLS via rustls-rustcrypto).");
 }
@@ -57,54 +72,438 @@ fn parse_url(url: &str) -> Option<UrlParts> {
     Some(UrlParts { scheme: scheme.to_string(), host, port, path })
 }
 
-fn create_tls_config() -> Arc<ClientConfig> {
+#[derive(Clone, Default)]
+struct TlsOptions {
+    insecure: bool,
+    cacert: Option<String>,
+    // None negotiates the safe default versions (TLS 1.2 and 1.3)
+    version: Option<&'static SupportedProtocolVersion>,
+    alpn: Vec<Vec<u8>>,
+}
+
+// Certificate verifier used by -k: accepts any certificate the server presents
+#[derive(Debug)]
+struct NoVerification(Arc<CryptoProvider>);
+
+impl ServerCertVerifier for NoVerification {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        _message: &[u8],
+        _cert: &CertificateDer<'_>,
+        _dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        Ok(HandshakeSignatureValid::assertion())
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        _message: &[u8],
+        _cert: &CertificateDer<'_>,
+        _dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        Ok(HandshakeSignatureValid::assertion())
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+fn create_tls_config(options: &TlsOptions) -> io::Result<Arc<ClientConfig>> {
     let crypto = Arc::new(rustls_rustcrypto::provider());
-    let root_store = RootCertStore::from_iter(
-        webpki_roots::TLS_SERVER_ROOTS.iter().cloned()
-    );
 
-    let config = ClientConfig::builder_with_provider(crypto)
-        .with_safe_default_protocol_versions()
-        .expect("TLS protocol versions")
-        .with_root_certificates(root_store)
-        .with_no_client_auth();
+    let builder = ClientConfig::builder_with_provider(crypto.clone());
+    let builder = match options.version {
+        Some(version) => builder.with_protocol_versions(&[version]),
+        None => builder.with_safe_default_protocol_versions(),
+    }
+    .map_err(|e| io::Error::new(io::ErrorKind::Unsupported, format!("TLS protocol version: {}", e)))?;
+
+    let mut config = if options.insecure {
+        builder
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(NoVerification(crypto)))
+            .with_no_client_auth()
+    } else {
+        let mut root_store = RootCertStore::from_iter(
+            webpki_roots::TLS_SERVER_ROOTS.iter().cloned()
+        );
+
+        if let Some(ref path) = options.cacert {
+            let certs = CertificateDer::pem_file_iter(path)
+                .and_then(|iter| iter.collect::<Result<Vec<_>, _>>())
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{}: cannot load CA certificates: {}", path, e)))?;
+            if certs.is_empty() {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, format!("{}: no certificates found", path)));
+            }
+            for cert in certs {
+                root_store.add(cert)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{}: invalid CA certificate: {}", path, e)))?;
+            }
+        }
+
+        builder
+            .with_root_certificates(root_store)
+            .with_no_client_auth()
+    };
+
+    config.alpn_protocols = options.alpn.clone();
+
+    Ok(Arc::new(config))
+}
 
-    Arc::new(config)
+#[derive(Clone, Default)]
+struct RequestOptions {
+    http10: bool,
+    // Ask the server to keep the connection open for further requests (HTTP/1.1 only)
+    keep_alive: bool,
+    // Extra request headers, as given to -H
+    headers: Vec<(String, String)>,
+    // POST body, as given to -d/--data-binary
+    data: Option<Vec<u8>>,
+    // Size of the partial output file to continue from, with -C -
+    resume_from: Option<u64>,
+    // Limit for connecting and the TLS handshake, from --connect-timeout
+    connect_timeout: Option<Duration>,
+    // End of the whole transfer, from --max-time
+    deadline: Option<Instant>,
+}
+
+impl RequestOptions {
+    fn http_version(&self) -> &'static str {
+        if self.http10 { "HTTP/1.0" } else { "HTTP/1.1" }
+    }
+
+    fn alpn_protocol(&self) -> &'static [u8] {
+        if self.http10 { b"http/1.0" } else { b"http/1.1" }
+    }
+
+    fn keep_alive(&self) -> bool {
+        self.keep_alive && !self.http10
+    }
+
+    fn connect_deadline(&self) -> Option<Instant> {
+        let connect_deadline = self.connect_timeout.map(|timeout| Instant::now() + timeout);
+        match (connect_deadline, self.deadline) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        }
+    }
+
+    fn has_header(&self, name: &str) -> bool {
+        self.headers.iter().any(|(key, _)| key.eq_ignore_ascii_case(name))
+    }
+}
+
+// Parses the "Name: Value" argument of -H
+fn parse_header(arg: &str) -> Option<(String, String)> {
+    let (name, value) = arg.split_once(':')?;
+    let name = name.trim();
+    if name.is_empty() || name.contains(char::is_whitespace) {
+        return None;
+    }
+    Some((name.to_string(), value.trim().to_string()))
+}
+
+// Reads the argument of -d/--data-binary, from a file if it starts with '@'
+fn read_data(arg: &str, binary: bool) -> io::Result<Vec<u8>> {
+    let Some(path) = arg.strip_prefix('@') else {
+        return Ok(arg.as_bytes().to_vec());
+    };
+
+    let mut data = Vec::new();
+    if path == "-" {
+        io::stdin().read_to_end(&mut data)?;
+    } else {
+        File::open(path)?.read_to_end(&mut data)?;
+    }
+    if !binary {
+        data.retain(|&b| b != b'\r' && b != b'\n');
+    }
+    Ok(data)
 }
 
 trait HttpStream: Read + Write {}
 impl<T: Read + Write> HttpStream for T {}
 
+fn timed_out() -> io::Error {
+    io::Error::new(io::ErrorKind::TimedOut, "Operation timed out")
+}
+
+// TCP stream whose reads and writes fail with TimedOut once the deadline has passed
+struct TimeoutStream {
+    stream: TcpStream,
+    deadline: Option<Instant>,
+}
+
+impl TimeoutStream {
+    fn remaining(&self) -> io::Result<Option<Duration>> {
+        match self.deadline {
+            Some(deadline) => {
+                let remaining = deadline.saturating_duration_since(Instant::now());
+                if remaining.is_zero() {
+                    return Err(timed_out());
+                }
+                Ok(Some(remaining))
+            }
+            None => Ok(None),
+        }
+    }
+}
+
+// A socket timeout is reported as WouldBlock on Unix and TimedOut elsewhere
+fn map_timeout(e: io::Error) -> io::Error {
+    match e.kind() {
+        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => timed_out(),
+        _ => e,
+    }
+}
+
+impl Read for TimeoutStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.stream.set_read_timeout(self.remaining()?)?;
+        self.stream.read(buf).map_err(map_timeout)
+    }
+}
+
+impl Write for TimeoutStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.stream.set_write_timeout(self.remaining()?)?;
+        self.stream.write(buf).map_err(map_timeout)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+fn connect_tcp(host: &str, port: u16, deadline: Option<Instant>) -> io::Result<TcpStream> {
+    let Some(deadline) = deadline else {
+        return TcpStream::connect((host, port));
+    };
+
+    let mut last_error = io::Error::new(io::ErrorKind::NotFound, "no address found");
+    for addr in (host, port).to_socket_addrs()? {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return Err(timed_out());
+        }
+        match TcpStream::connect_timeout(&addr, remaining) {
+            Ok(stream) => return Ok(stream),
+            Err(e) => last_error = e,
+        }
+    }
+    Err(last_error)
+}
+
+// Destination of the response body
+trait Output: Write {
+    // Discards the partial download when the server sends the whole body instead of a range
+    fn restart(&mut self) -> io::Result<()>;
+}
+
+impl Output for File {
+    fn restart(&mut self) -> io::Result<()> {
+        self.set_len(0)?;
+        self.seek(SeekFrom::Start(0))?;
+        Ok(())
+    }
+}
+
+impl Output for io::StdoutLock<'_> {
+    fn restart(&mut self) -> io::Result<()> {
+        // Ranges are only requested for output files
+        Ok(())
+    }
+}
+
 struct Response {
     status_code: u16,
     headers: Vec<(String, String)>,
     content_length: Option<usize>,
     location: Option<String>,
+    // Whether the connection can be used for another request
+    keep_alive: bool,
+}
+
+// An open connection, reused for following requests to the same scheme, host and port
+struct Connection {
+    scheme: String,
+    host: String,
+    port: u16,
+    stream: Box<dyn HttpStream>,
+}
+
+impl Connection {
+    fn serves(&self, url: &UrlParts) -> bool {
+        self.scheme == url.scheme && self.host == url.host && self.port == url.port
+    }
+}
+
+fn show_body_progress(total: usize, content_length: Option<usize>) {
+    if let Some(len) = content_length {
+        let pct = (total * 100) / len.max(1);
+        eprint!("\r  {} / {} bytes ({}%)", total, len, pct);
+    } else {
+        eprint!("\r  {} bytes", total);
+    }
+}
+
+// Copy a body delimited by Content-Length or the end of the connection
+fn read_body<R: BufRead>(
+    reader: &mut R,
+    output: &mut dyn Write,
+    content_length: Option<usize>,
+    show_progress: bool,
+) -> io::Result<usize> {
+    let mut buffer = [0u8; 8192];
+    let mut total = 0usize;
+    // Don't read past the body, the connection may be reused
+    let mut reader = reader.by_ref().take(content_length.map_or(u64::MAX, |len| len as u64));
+
+    loop {
+        match reader.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(n) => {
+                output.write_all(&buffer[..n])?;
+                total += n;
+
+                if show_progress {
+                    show_body_progress(total, content_length);
+                }
+            }
+            // Treat UnexpectedEof as normal EOF (server didn't send TLS close_notify)
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok(total)
+}
+
+// Decode a Transfer-Encoding: chunked body, made of <hexlen>\r\n<data>\r\n frames ending
+// with a zero-length chunk and optional trailers
+fn read_chunked_body<R: BufRead>(
+    reader: &mut R,
+    output: &mut dyn Write,
+    show_progress: bool,
+) -> io::Result<usize> {
+    let truncated = || io::Error::new(io::ErrorKind::UnexpectedEof, "truncated chunked body");
+    let mut total = 0usize;
+    let mut line = String::new();
+
+    loop {
+        line.clear();
+        if reader.read_line(&mut line)? == 0 {
+            return Err(truncated());
+        }
+
+        // Chunk extensions after ';' are ignored
+        let size_str = line.trim_end().split(';').next().unwrap_or("").trim();
+        let size = usize::from_str_radix(size_str, 16).map_err(|_| {
+            io::Error::new(io::ErrorKind::InvalidData, format!("invalid chunk size: {:?}", size_str))
+        })?;
+        if size == 0 {
+            break;
+        }
+
+        let copied = io::copy(&mut reader.by_ref().take(size as u64), output)? as usize;
+        if copied < size {
+            return Err(truncated());
+        }
+        total += copied;
+
+        if show_progress {
+            show_body_progress(total, None);
+        }
+
+        // Each chunk's data is followed by CRLF
+        line.clear();
+        if reader.read_line(&mut line)? == 0 {
+            return Err(truncated());
+        }
+        if !line.trim_end().is_empty() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "missing CRLF after chunk"));
+        }
+    }
+
+    // Skip the trailers up to the final empty line
+    loop {
+        line.clear();
+        if reader.read_line(&mut line)? == 0 || line.trim_end().is_empty() {
+            break;
+        }
+    }
+
+    Ok(total)
 }
 
+#[allow(clippy::too_many_arguments)]
 fn do_request(
     stream: &mut dyn HttpStream,
     url: &UrlParts,
+    options: &RequestOptions,
+    body: Option<&[u8]>,
     headers_only: bool,
     verbose: bool,
-    output: &mut dyn Write,
+    output: &mut dyn Output,
     show_progress: bool,
 ) -> io::Result<Response> {
-    let method = if headers_only { "HEAD" } else { "GET" };
-    let request = format!(
-        "{} {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\nUser-Agent: curl/redox\r\n\r\n",
-        method, url.path, url.host
-    );
+    let method = if headers_only {
+        "HEAD"
+    } else if body.is_some() {
+        "POST"
+    } else {
+        "GET"
+    };
+    let version = options.http_version();
+    let connection = if options.keep_alive() { "keep-alive" } else { "close" };
+
+    let mut request_headers = vec![
+        ("Host".to_string(), url.host.clone()),
+        ("Connection".to_string(), connection.to_string()),
+        ("User-Agent".to_string(), "curl/redox".to_string()),
+    ];
+    if let Some(body) = body {
+        request_headers.push(("Content-Type".to_string(), "application/x-www-form-urlencoded".to_string()));
+        request_headers.push(("Content-Length".to_string(), body.len().to_string()));
+    }
+    if let Some(offset) = options.resume_from {
+        request_headers.push(("Range".to_string(), format!("bytes={}-", offset)));
+    }
+    // User headers replace the defaults of the same name, an empty value only removes them
+    request_headers.retain(|(name, _)| !options.has_header(name));
+    request_headers.extend(options.headers.iter().filter(|(_, value)| !value.is_empty()).cloned());
+
+    let mut request = format!("{} {} {}\r\n", method, url.path, version);
+    for (name, value) in &request_headers {
+        request.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    request.push_str("\r\n");
 
     if verbose {
-        eprintln!("> {} {} HTTP/1.1", method, url.path);
-        eprintln!("> Host: {}", url.host);
-        eprintln!("> Connection: close");
-        eprintln!("> User-Agent: curl/redox");
+        eprintln!("> {} {} {}", method, url.path, version);
+        for (name, value) in &request_headers {
+            eprintln!("> {}: {}", name, value);
+        }
         eprintln!(">");
     }
 
     stream.write_all(request.as_bytes())?;
+    if let Some(body) = body {
+        stream.write_all(body)?;
+    }
     stream.flush()?;
 
     let mut reader = BufReader::new(stream);
@@ -129,6 +528,8 @@ fn do_request(
     let mut headers = Vec::new();
     let mut content_length = None;
     let mut location = None;
+    let mut chunked = false;
+    let mut server_closes = false;
 
     loop {
         line.clear();
@@ -151,6 +552,15 @@ fn do_request(
                         content_length = value.parse().ok();
                     } else if key == "location" {
                         location = Some(value.clone());
+                    } else if key == "connection" {
+                        server_closes = value
+                            .split(',')
+                            .any(|option| option.trim().eq_ignore_ascii_case("close"));
+                    } else if key == "transfer-encoding" {
+                        chunked = value
+                            .rsplit(',')
+                            .next()
+                            .is_some_and(|coding| coding.trim().eq_ignore_ascii_case("chunked"));
                     }
 
                     headers.push((key, value));
@@ -166,35 +576,41 @@ fn do_request(
         }
     }
 
-    if !headers_only && (status_code == 200 || status_code >= 400) {
-        let mut buffer = [0u8; 8192];
-        let mut total = 0usize;
+    // Without a length the body ends when the server closes the connection
+    let delimited = chunked || content_length.is_some();
+    let mut keep_alive = options.keep_alive() && !server_closes && status_code != 0;
 
-        loop {
-            match reader.read(&mut buffer) {
-                Ok(0) => break,
-                Ok(n) => {
-                    output.write_all(&buffer[..n])?;
-                    total += n;
+    // The partial file is already complete when the range starts at its end
+    let complete = options.resume_from.is_some() && status_code == 416;
 
-                    if show_progress {
-                        if let Some(len) = content_length {
-                            let pct = (total * 100) / len;
-                            eprint!("\r  {} / {} bytes ({}%)", total, len, pct);
-                        } else {
-                            eprint!("\r  {} bytes", total);
-                        }
-                    }
-                }
-                // Treat UnexpectedEof as normal EOF (server didn't send TLS close_notify)
-                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
-                Err(e) => return Err(e),
+    if !headers_only && (status_code == 200 || status_code == 206 || (status_code >= 400 && !complete)) {
+        if status_code == 200 && options.resume_from.is_some() {
+            if verbose {
+                eprintln!("* Server ignored the range, restarting the download");
             }
+            output.restart()?;
         }
 
+        // Transfer-Encoding takes precedence over Content-Length
+        let total = if chunked {
+            read_chunked_body(&mut reader, output, show_progress)?
+        } else {
+            read_body(&mut reader, output, content_length, show_progress)?
+        };
+        keep_alive &= delimited;
+
         if show_progress && total > 0 {
             eprintln!();
         }
+    } else if keep_alive && !headers_only && status_code != 204 && status_code != 304 {
+        // Skip the body of e.g. a redirect so the next request can be sent
+        if chunked {
+            read_chunked_body(&mut reader, &mut io::sink(), false)?;
+        } else if content_length.is_some() {
+            read_body(&mut reader, &mut io::sink(), content_length, false)?;
+        } else {
+            keep_alive = false;
+        }
     }
 
     Ok(Response {
@@ -202,23 +618,24 @@ fn do_request(
         headers,
         content_length,
         location,
+        keep_alive,
     })
 }
 
-fn fetch_url(
+fn connect(
     url: &UrlParts,
-    headers_only: bool,
+    tls_options: &TlsOptions,
+    options: &RequestOptions,
     verbose: bool,
-    output: &mut dyn Write,
-    show_progress: bool,
-) -> io::Result<Response> {
+) -> io::Result<Connection> {
     let addr = format!("{}:{}", url.host, url.port);
 
     if verbose {
         eprintln!("* Connecting to {}...", addr);
     }
 
-    let tcp_stream = TcpStream::connect(&addr).map_err(|e| {
+    let connect_deadline = options.connect_deadline();
+    let tcp_stream = connect_tcp(&url.host, url.port, connect_deadline).map_err(|e| {
         io::Error::new(e.kind(), format!("{}: Connection failed: {}", addr, e))
     })?;
 
@@ -226,29 +643,92 @@ fn fetch_url(
         eprintln!("* Connected to {} port {}", url.host, url.port);
     }
 
-    if url.scheme == "https" {
+    let stream: Box<dyn HttpStream> = if url.scheme == "https" {
         if verbose {
             eprintln!("* TLS handshake with {}...", url.host);
+            if tls_options.insecure {
+                eprintln!("* WARNING: the certificate of {} is not verified", url.host);
+            }
         }
 
-        let tls_config = create_tls_config();
+        let tls_options = TlsOptions {
+            alpn: vec![options.alpn_protocol().to_vec()],
+            ..tls_options.clone()
+        };
+        let tls_config = create_tls_config(&tls_options)?;
         let server_name = ServerName::try_from(url.host.clone())
             .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, format!("invalid server name: {}", e)))?;
 
-        let tls_conn = ClientConnection::new(tls_config, server_name)
+        let mut tls_conn = ClientConnection::new(tls_config, server_name)
             .map_err(|e| io::Error::new(io::ErrorKind::Other, format!("TLS error: {}", e)))?;
 
-        let mut tls_stream = StreamOwned::new(tls_conn, tcp_stream);
+        // The handshake is part of connecting and is bounded by the same timeout
+        let mut socket = TimeoutStream { stream: tcp_stream, deadline: connect_deadline };
+        while tls_conn.is_handshaking() {
+            tls_conn.complete_io(&mut socket)?;
+        }
+        socket.deadline = options.deadline;
+
+        let tls_stream = StreamOwned::new(tls_conn, socket);
 
         if verbose {
             eprintln!("* TLS handshake complete");
         }
 
-        do_request(&mut tls_stream, url, headers_only, verbose, output, show_progress)
+        Box::new(tls_stream)
+    } else {
+        Box::new(TimeoutStream { stream: tcp_stream, deadline: options.deadline })
+    };
+
+    Ok(Connection {
+        scheme: url.scheme.clone(),
+        host: url.host.clone(),
+        port: url.port,
+        stream,
+    })
+}
+
+#[allow(clippy::too_many_arguments)]
+fn fetch_url(
+    url: &UrlParts,
+    connection: &mut Option<Connection>,
+    tls_options: &TlsOptions,
+    options: &RequestOptions,
+    headers_only: bool,
+    verbose: bool,
+    output: &mut dyn Output,
+    show_progress: bool,
+) -> io::Result<Response> {
+    let reused = connection.as_ref().is_some_and(|conn| conn.serves(url));
+    if reused {
+        if verbose {
+            eprintln!("* Re-using existing connection to {} port {}", url.host, url.port);
+        }
     } else {
-        let mut tcp = tcp_stream;
-        do_request(&mut tcp, url, headers_only, verbose, output, show_progress)
+        *connection = Some(connect(url, tls_options, options, verbose)?);
+    }
+
+    let conn = connection.as_mut().unwrap();
+    let mut result = do_request(&mut *conn.stream, url, options, options.data.as_deref(), headers_only, verbose, output, show_progress);
+
+    // The server may have closed the idle connection before receiving the request
+    let closed = match result {
+        Ok(ref response) => response.status_code == 0,
+        Err(ref e) => e.kind() == io::ErrorKind::BrokenPipe,
+    };
+    if reused && closed {
+        if verbose {
+            eprintln!("* Connection closed by server, reconnecting");
+        }
+        let conn = connection.insert(connect(url, tls_options, options, verbose)?);
+        result = do_request(&mut *conn.stream, url, options, options.data.as_deref(), headers_only, verbose, output, show_progress);
+    }
+
+    if !matches!(result, Ok(ref response) if response.keep_alive) {
+        *connection = None;
     }
+
+    result
 }
 
 fn resolve_redirect(base_url: &UrlParts, location: &str) -> Option<UrlParts> {
@@ -294,6 +774,10 @@ fn main() {
     let mut output_file: Option<String> = None;
     let mut remote_name = wget_mode;       // wget saves to file by default
     let mut silent = false;
+    let mut resume = false;
+    let mut max_time = None;
+    let mut tls_options = TlsOptions::default();
+    let mut options = RequestOptions::default();
 
     let mut i = 0;
     while i < args.len() {
@@ -303,6 +787,78 @@ fn main() {
             "-L" => follow_redirects = true,
             "-s" => silent = true,
             "-O" => remote_name = true,
+            "-k" | "--insecure" => tls_options.insecure = true,
+            "--http1.0" => options.http10 = true,
+            "--tlsv1.2" => tls_options.version = Some(&rustls::version::TLS12),
+            "--tlsv1.3" => tls_options.version = Some(&rustls::version::TLS13),
+            "--cacert" => {
+                i += 1;
+                if i >= args.len() {
+                    eprintln!("curl: --cacert requires a filename");
+                    process::exit(1);
+                }
+                tls_options.cacert = Some(args[i].clone());
+            }
+            "-d" | "--data" | "--data-binary" => {
+                let binary = args[i] == "--data-binary";
+                i += 1;
+                if i >= args.len() {
+                    eprintln!("curl: {} requires data", args[i - 1]);
+                    process::exit(1);
+                }
+                let data = match read_data(&args[i], binary) {
+                    Ok(data) => data,
+                    Err(e) => {
+                        eprintln!("curl: cannot read '{}': {}", &args[i][1..], e);
+                        process::exit(26);
+                    }
+                };
+                // Several -d options are joined like form fields
+                match options.data {
+                    Some(ref mut body) => {
+                        body.push(b'&');
+                        body.extend_from_slice(&data);
+                    }
+                    None => options.data = Some(data),
+                }
+            }
+            "-H" => {
+                i += 1;
+                if i >= args.len() {
+                    eprintln!("curl: -H requires a header");
+                    process::exit(1);
+                }
+                match parse_header(&args[i]) {
+                    Some(header) => options.headers.push(header),
+                    None => {
+                        eprintln!("curl: invalid header '{}', expected 'Name: Value'", args[i]);
+                        process::exit(2);
+                    }
+                }
+            }
+            "-C" => {
+                i += 1;
+                if i >= args.len() || args[i] != "-" {
+                    eprintln!("curl: -C only supports '-' (continue from the size of the output file)");
+                    process::exit(1);
+                }
+                resume = true;
+            }
+            "--connect-timeout" | "--max-time" => {
+                let option = args[i].clone();
+                i += 1;
+                let timeout = args.get(i)
+                    .and_then(|secs| secs.parse::<f64>().ok())
+                    .and_then(|secs| Duration::try_from_secs_f64(secs).ok());
+                match timeout {
+                    Some(timeout) if option == "--connect-timeout" => options.connect_timeout = Some(timeout),
+                    Some(timeout) => max_time = Some(timeout),
+                    None => {
+                        eprintln!("curl: {} requires a number of seconds", option);
+                        process::exit(1);
+                    }
+                }
+            }
             "-o" => {
                 i += 1;
                 if i >= args.len() {
@@ -348,6 +904,11 @@ fn main() {
         }
     }
 
+    if resume && output_file.is_none() {
+        eprintln!("curl: -C - requires an output file (-o or -O)");
+        process::exit(1);
+    }
+
     let mut url = match parse_url(&url_str) {
         Some(parts) => parts,
         None => {
@@ -356,9 +917,18 @@ fn main() {
         }
     };
 
+    if tls_options.insecure {
+        eprintln!("curl: warning: -k/--insecure disables TLS certificate verification");
+    }
+
+    // Redirects to the same host are fetched over the same connection
+    options.keep_alive = follow_redirects;
+    options.deadline = max_time.map(|timeout| Instant::now() + timeout);
+
     let show_progress = output_file.is_some() && !silent && !verbose;
     let max_redirects = 10;
     let mut redirects = 0;
+    let mut connection = None;
 
     loop {
         if let Some(ref filename) = output_file {
@@ -369,14 +939,21 @@ fn main() {
 
         // Create output writer
         let result = if let Some(ref filename) = output_file {
-            let mut file = match File::create(filename) {
+            let file = if resume {
+                // Continue after what is already there, a missing file starts from zero
+                options.resume_from = std::fs::metadata(filename).map(|m| m.len()).ok().filter(|&len| len > 0);
+                OpenOptions::new().create(true).append(true).open(filename)
+            } else {
+                File::create(filename)
+            };
+            let mut file = match file {
                 Ok(f) => f,
                 Err(e) => {
                     eprintln!("curl: cannot create '{}': {}", filename, e);
                     process::exit(23);
                 }
             };
-            let res = fetch_url(&url, headers_only, verbose, &mut file, show_progress);
+            let res = fetch_url(&url, &mut connection, &tls_options, &options, headers_only, verbose, &mut file, show_progress);
             if let Err(ref e) = res {
                 eprintln!("curl: {}", e);
             }
@@ -385,13 +962,13 @@ fn main() {
         } else {
             let stdout = io::stdout();
             let mut handle = stdout.lock();
-            fetch_url(&url, headers_only, verbose, &mut handle, false)
+            fetch_url(&url, &mut connection, &tls_options, &options, headers_only, verbose, &mut handle, false)
         };
 
         match result {
             Ok(response) => {
                 // Check for redirects
-                if follow_redirects && (response.status_code == 301 || response.status_code == 302 || response.status_code == 307 || response.status_code == 308) {
+                if follow_redirects && matches!(response.status_code, 301 | 302 | 303 | 307 | 308) {
                     if let Some(location) = response.location {
                         redirects += 1;
                         if redirects > max_redirects {
@@ -403,6 +980,12 @@ fn main() {
                             eprintln!("* Redirecting to: {}", location);
                         }
 
+                        // Like browsers, a POST is turned into a GET by 301 and 302, and 303
+                        // always asks for a GET
+                        if matches!(response.status_code, 301 | 302 | 303) {
+                            options.data = None;
+                        }
+
                         url = match resolve_redirect(&url, &location) {
                             Some(new_url) => new_url,
                             None => {
@@ -424,7 +1007,7 @@ fn main() {
             }
             Err(e) => {
                 eprintln!("curl: {}", e);
-                process::exit(56);
+                process::exit(if e.kind() == io::ErrorKind::TimedOut { 28 } else { 56 });
             }
         }
     }
@@ -2,6 +2,7 @@
 
 use std::io::{self, PipeWriter, Read, Write};
 
+/// Handle to signal readiness of a daemon to the process that started it.
 #[must_use = "Daemon::ready must be called"]
 pub struct Daemon {
     write_pipe: PipeWriter,
@@ -12,6 +13,11 @@ fn errno() -> io::Error {
 }
 
 impl Daemon {
+    /// Fork and run `f` in the child process.
+    ///
+    /// The parent process blocks until the child calls [`Daemon::ready`] and only then exits, so
+    /// that whoever spawned the daemon can rely on it being ready once the spawned process has
+    /// exited. If the child exits without calling `ready`, the parent exits with status 101.
     pub fn new<F: FnOnce(Daemon) -> !>(f: F) -> ! {
         let (mut read_pipe, write_pipe) = std::io::pipe().unwrap();
 
@@ -40,7 +46,16 @@ impl Daemon {
         }
     }
 
-    pub fn ready(mut self) {
-        self.write_pipe.write_all(&[0]).unwrap();
+    /// Signal that the daemon is ready, which lets the parent process exit successfully.
+    pub fn ready(self) {
+        self.ready_with_status(0);
+    }
+
+    /// Report the result of the daemon initialization to the parent process.
+    ///
+    /// The parent process exits with `status`, so a nonzero value tells whoever spawned the
+    /// daemon that the initialization failed, for example because the device isn't present.
+    pub fn ready_with_status(mut self, status: u8) {
+        self.write_pipe.write_all(&[status]).unwrap();
     }
 }
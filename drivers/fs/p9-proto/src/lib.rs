@@ -1,10 +1,12 @@
 //! 9P2000.L protocol implementation
 //!
 //! This is a minimal implementation supporting the operations needed for
-//! a read-only or read-write filesystem mount via virtio-9p.
+//! a read-only or read-write filesystem mount. It only deals with encoding
+//! and decoding messages, so it can be used with any transport.
 
 #![allow(dead_code)]
 
+use std::fmt;
 
 // 9P2000.L message types
 #[repr(u8)]
@@ -21,6 +23,9 @@ pub enum MsgType {
     Rattach = 105,
     // Error
     Rerror = 107,
+    // Error (9P2000.L)
+    Tlerror = 6,
+    Rlerror = 7,
     // Flush
     Tflush = 108,
     Rflush = 109,
@@ -92,6 +97,165 @@ pub enum MsgType {
     Runlinkat = 77,
 }
 
+impl MsgType {
+    /// Every message type, in declaration order
+    pub const ALL: [MsgType; 67] = [
+        MsgType::Tversion,
+        MsgType::Rversion,
+        MsgType::Tauth,
+        MsgType::Rauth,
+        MsgType::Tattach,
+        MsgType::Rattach,
+        MsgType::Rerror,
+        MsgType::Tlerror,
+        MsgType::Rlerror,
+        MsgType::Tflush,
+        MsgType::Rflush,
+        MsgType::Twalk,
+        MsgType::Rwalk,
+        MsgType::Topen,
+        MsgType::Ropen,
+        MsgType::Tcreate,
+        MsgType::Rcreate,
+        MsgType::Tread,
+        MsgType::Rread,
+        MsgType::Twrite,
+        MsgType::Rwrite,
+        MsgType::Tclunk,
+        MsgType::Rclunk,
+        MsgType::Tremove,
+        MsgType::Rremove,
+        MsgType::Tstat,
+        MsgType::Rstat,
+        MsgType::Twstat,
+        MsgType::Rwstat,
+        MsgType::Tstatfs,
+        MsgType::Rstatfs,
+        MsgType::Tlopen,
+        MsgType::Rlopen,
+        MsgType::Tlcreate,
+        MsgType::Rlcreate,
+        MsgType::Tsymlink,
+        MsgType::Rsymlink,
+        MsgType::Tmknod,
+        MsgType::Rmknod,
+        MsgType::Trename,
+        MsgType::Rrename,
+        MsgType::Treadlink,
+        MsgType::Rreadlink,
+        MsgType::Tgetattr,
+        MsgType::Rgetattr,
+        MsgType::Tsetattr,
+        MsgType::Rsetattr,
+        MsgType::Txattrwalk,
+        MsgType::Rxattrwalk,
+        MsgType::Txattrcreate,
+        MsgType::Rxattrcreate,
+        MsgType::Treaddir,
+        MsgType::Rreaddir,
+        MsgType::Tfsync,
+        MsgType::Rfsync,
+        MsgType::Tlock,
+        MsgType::Rlock,
+        MsgType::Tgetlock,
+        MsgType::Rgetlock,
+        MsgType::Tlink,
+        MsgType::Rlink,
+        MsgType::Tmkdir,
+        MsgType::Rmkdir,
+        MsgType::Trenameat,
+        MsgType::Rrenameat,
+        MsgType::Tunlinkat,
+        MsgType::Runlinkat,
+    ];
+
+    /// Look up the message type of a raw header type byte
+    pub fn from_u8(typ: u8) -> Option<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|&msg_type| msg_type as u8 == typ)
+    }
+
+    /// The name used by the 9P specification, e.g. `"Twalk"`
+    pub fn name(self) -> &'static str {
+        match self {
+            MsgType::Tversion => "Tversion",
+            MsgType::Rversion => "Rversion",
+            MsgType::Tauth => "Tauth",
+            MsgType::Rauth => "Rauth",
+            MsgType::Tattach => "Tattach",
+            MsgType::Rattach => "Rattach",
+            MsgType::Rerror => "Rerror",
+            MsgType::Tlerror => "Tlerror",
+            MsgType::Rlerror => "Rlerror",
+            MsgType::Tflush => "Tflush",
+            MsgType::Rflush => "Rflush",
+            MsgType::Twalk => "Twalk",
+            MsgType::Rwalk => "Rwalk",
+            MsgType::Topen => "Topen",
+            MsgType::Ropen => "Ropen",
+            MsgType::Tcreate => "Tcreate",
+            MsgType::Rcreate => "Rcreate",
+            MsgType::Tread => "Tread",
+            MsgType::Rread => "Rread",
+            MsgType::Twrite => "Twrite",
+            MsgType::Rwrite => "Rwrite",
+            MsgType::Tclunk => "Tclunk",
+            MsgType::Rclunk => "Rclunk",
+            MsgType::Tremove => "Tremove",
+            MsgType::Rremove => "Rremove",
+            MsgType::Tstat => "Tstat",
+            MsgType::Rstat => "Rstat",
+            MsgType::Twstat => "Twstat",
+            MsgType::Rwstat => "Rwstat",
+            MsgType::Tstatfs => "Tstatfs",
+            MsgType::Rstatfs => "Rstatfs",
+            MsgType::Tlopen => "Tlopen",
+            MsgType::Rlopen => "Rlopen",
+            MsgType::Tlcreate => "Tlcreate",
+            MsgType::Rlcreate => "Rlcreate",
+            MsgType::Tsymlink => "Tsymlink",
+            MsgType::Rsymlink => "Rsymlink",
+            MsgType::Tmknod => "Tmknod",
+            MsgType::Rmknod => "Rmknod",
+            MsgType::Trename => "Trename",
+            MsgType::Rrename => "Rrename",
+            MsgType::Treadlink => "Treadlink",
+            MsgType::Rreadlink => "Rreadlink",
+            MsgType::Tgetattr => "Tgetattr",
+            MsgType::Rgetattr => "Rgetattr",
+            MsgType::Tsetattr => "Tsetattr",
+            MsgType::Rsetattr => "Rsetattr",
+            MsgType::Txattrwalk => "Txattrwalk",
+            MsgType::Rxattrwalk => "Rxattrwalk",
+            MsgType::Txattrcreate => "Txattrcreate",
+            MsgType::Rxattrcreate => "Rxattrcreate",
+            MsgType::Treaddir => "Treaddir",
+            MsgType::Rreaddir => "Rreaddir",
+            MsgType::Tfsync => "Tfsync",
+            MsgType::Rfsync => "Rfsync",
+            MsgType::Tlock => "Tlock",
+            MsgType::Rlock => "Rlock",
+            MsgType::Tgetlock => "Tgetlock",
+            MsgType::Rgetlock => "Rgetlock",
+            MsgType::Tlink => "Tlink",
+            MsgType::Rlink => "Rlink",
+            MsgType::Tmkdir => "Tmkdir",
+            MsgType::Rmkdir => "Rmkdir",
+            MsgType::Trenameat => "Trenameat",
+            MsgType::Rrenameat => "Rrenameat",
+            MsgType::Tunlinkat => "Tunlinkat",
+            MsgType::Runlinkat => "Runlinkat",
+        }
+    }
+}
+
+impl fmt::Display for MsgType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
 // QID type flags
 pub const QID_DIR: u8 = 0x80;
 pub const QID_APPEND: u8 = 0x40;
@@ -150,9 +314,73 @@ pub const P9_NOATIME: u32 = 0x40000;
 pub const P9_CLOEXEC: u32 = 0x80000;
 pub const P9_SYNC: u32 = 0x101000;
 
+// Lock types (Tlock/Tgetlock)
+pub const P9_LOCK_TYPE_RDLCK: u8 = 0;
+pub const P9_LOCK_TYPE_WRLCK: u8 = 1;
+pub const P9_LOCK_TYPE_UNLCK: u8 = 2;
+
+// Tlock flags
+pub const P9_LOCK_FLAGS_BLOCK: u32 = 1;
+pub const P9_LOCK_FLAGS_RECLAIM: u32 = 2;
+
+// Rlock status
+pub const P9_LOCK_SUCCESS: u8 = 0;
+pub const P9_LOCK_BLOCKED: u8 = 1;
+pub const P9_LOCK_ERROR: u8 = 2;
+pub const P9_LOCK_GRACE: u8 = 3;
+
 // Special FIDs
 pub const NOFID: u32 = u32::MAX;
 
+// Tag of Tversion, which is never used for other requests
+pub const NOTAG: u16 = u16::MAX;
+
+/// Convert an errno sent by the server to a Redox error.
+///
+/// 9P2000.L servers report Linux errno values. Unknown values become `EIO`.
+pub fn p9_errno_to_redox(errno: u32) -> syscall::Error {
+    use syscall::error::*;
+
+    let redox = match errno {
+        1 => EPERM,
+        2 => ENOENT,
+        4 => EINTR,
+        5 => EIO,
+        6 => ENXIO,
+        7 => E2BIG,
+        9 => EBADF,
+        11 => EAGAIN,
+        12 => ENOMEM,
+        13 => EACCES,
+        16 => EBUSY,
+        17 => EEXIST,
+        18 => EXDEV,
+        20 => ENOTDIR,
+        21 => EISDIR,
+        22 => EINVAL,
+        23 => ENFILE,
+        24 => EMFILE,
+        26 => ETXTBSY,
+        27 => EFBIG,
+        28 => ENOSPC,
+        29 => ESPIPE,
+        30 => EROFS,
+        31 => EMLINK,
+        34 => ERANGE,
+        36 => ENAMETOOLONG,
+        38 => ENOSYS,
+        39 => ENOTEMPTY,
+        40 => ELOOP,
+        61 => ENODATA,
+        75 => EOVERFLOW,
+        95 => EOPNOTSUPP,
+        116 => ESTALE,
+        122 => EDQUOT,
+        _ => EIO,
+    };
+    syscall::Error::new(redox)
+}
+
 /// QID - unique file identifier
 #[repr(C, packed)]
 #[derive(Debug, Clone, Copy, Default)]
@@ -300,6 +528,14 @@ impl<'a> MessageParser<'a> {
         Self { data, pos: 0 }
     }
 
+    /// Parser for a message of `declared_size` bytes at the start of a larger buffer
+    ///
+    /// Nothing past `declared_size` is ever read, so garbage after the message can't be mistaken
+    /// for part of it.
+    pub fn new_bounded(data: &'a [u8], declared_size: usize) -> Self {
+        Self::new(&data[..declared_size.min(data.len())])
+    }
+
     pub fn skip(&mut self, n: usize) -> Option<()> {
         if self.pos + n > self.data.len() {
             return None;
@@ -483,6 +719,28 @@ impl StatFs {
     }
 }
 
+/// Lock description from Rgetlock
+#[derive(Debug, Clone)]
+pub struct LockDesc {
+    pub typ: u8,
+    pub start: u64,
+    pub length: u64,
+    pub proc_id: u32,
+    pub client_id: String,
+}
+
+impl LockDesc {
+    pub fn decode(parser: &mut MessageParser) -> Option<Self> {
+        Some(Self {
+            typ: parser.get_u8()?,
+            start: parser.get_u64()?,
+            length: parser.get_u64()?,
+            proc_id: parser.get_u32()?,
+            client_id: parser.get_str()?.to_string(),
+        })
+    }
+}
+
 /// Directory entry from Rreaddir
 #[derive(Debug, Clone)]
 pub struct DirEntry {
@@ -502,3 +760,93 @@ impl DirEntry {
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn msg_type_names_roundtrip() {
+        for msg_type in MsgType::ALL {
+            assert_eq!(MsgType::from_u8(msg_type as u8), Some(msg_type));
+            assert_eq!(format!("{msg_type:?}"), msg_type.name());
+            assert_eq!(msg_type.to_string(), msg_type.name());
+        }
+        // Every variant is listed exactly once
+        let mut types: Vec<u8> = MsgType::ALL
+            .iter()
+            .map(|&msg_type| msg_type as u8)
+            .collect();
+        types.sort_unstable();
+        types.dedup();
+        assert_eq!(types.len(), MsgType::ALL.len());
+        assert_eq!(MsgType::from_u8(0), None);
+    }
+
+    #[test]
+    fn roundtrip_readdir_entry() {
+        let qid = Qid {
+            typ: QID_DIR,
+            version: 3,
+            path: 0x1234_5678_9abc,
+        };
+        let msg = MessageBuilder::new(MsgType::Rreaddir, 7)
+            .put_qid(&qid)
+            .put_u64(42)
+            .put_u8(4)
+            .put_str("subdir")
+            .finish();
+
+        let mut parser = MessageParser::new(&msg);
+        let header = parser.get_header().unwrap();
+        assert_eq!({ header.size } as usize, msg.len());
+        assert_eq!(header.typ, MsgType::Rreaddir as u8);
+        assert_eq!({ header.tag }, 7);
+
+        let entry = DirEntry::decode(&mut parser).unwrap();
+        assert!(entry.qid.is_dir());
+        assert_eq!({ entry.qid.path }, 0x1234_5678_9abc);
+        assert_eq!(entry.offset, 42);
+        assert_eq!(entry.name, "subdir");
+        assert!(parser.remaining().is_empty());
+    }
+
+    #[test]
+    fn truncated_message() {
+        let msg = MessageBuilder::new(MsgType::Rversion, 0)
+            .put_u32(8192)
+            .put_str("9P2000.L")
+            .finish();
+
+        let mut parser = MessageParser::new(&msg[..msg.len() - 1]);
+        parser.get_header().unwrap();
+        assert_eq!(parser.get_u32(), Some(8192));
+        assert_eq!(parser.get_str(), None);
+    }
+
+    #[test]
+    fn bounded_by_declared_size() {
+        let msg = MessageBuilder::new(MsgType::Rreadlink, 1)
+            .put_str("target")
+            .finish();
+        let size = msg.len();
+
+        // The buffer the reply was written to is larger than the reply itself
+        let mut buffer = msg;
+        buffer.extend_from_slice(b"garbage");
+        // A length field which is only valid if the trailing bytes are counted
+        buffer[Header::SIZE] = 13;
+
+        let mut parser = MessageParser::new(&buffer);
+        parser.get_header().unwrap();
+        assert_eq!(parser.get_str(), Some("targetgarbage"));
+
+        let mut parser = MessageParser::new_bounded(&buffer, size);
+        parser.get_header().unwrap();
+        assert_eq!(parser.get_str(), None);
+
+        let mut parser = MessageParser::new_bounded(&buffer[..size], size + 100);
+        parser.get_header().unwrap();
+        assert_eq!(parser.remaining().len(), size - Header::SIZE);
+    }
+}
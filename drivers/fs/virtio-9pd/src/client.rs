@@ -1,7 +1,8 @@
-//! 9P client over virtio transport
+//! 9P client over virtio transport, or any other [`Transport`]
 
-use std::sync::Arc;
-use std::sync::atomic::{AtomicU16, AtomicU32, Ordering};
+use std::collections::BTreeSet;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicU16, AtomicU32, Ordering};
 
 use anyhow::{anyhow, Result};
 
@@ -9,12 +10,18 @@ use common::dma::Dma;
 use virtio_core::spec::{Buffer, ChainBuilder, DescriptorFlags};
 use virtio_core::transport::Queue;
 
-use crate::protocol::*;
+use p9_proto::*;
 
 const MSIZE: u32 = 131072; // Maximum message size (128KB for good 9p performance)
+const IOHDRSZ: u32 = 24; // Overhead of Twrite/Rread messages on top of their data
+
+/// Number of times a request is polled before giving up on the device (several seconds)
+const TRANSACT_SPIN_LIMIT: u64 = 5_000_000;
 
 /// Simple spin-polling for futures without an async runtime
-fn spin_poll<F: std::future::Future>(mut future: F) -> F::Output {
+///
+/// Returns `None` if the future didn't complete within `max_polls` polls.
+fn spin_poll<F: std::future::Future>(mut future: F, max_polls: u64) -> Option<F::Output> {
     use std::pin::Pin;
     use std::task::{Context, Poll, RawWaker, RawWakerVTable, Waker};
 
@@ -31,9 +38,9 @@ fn spin_poll<F: std::future::Future>(mut future: F) -> F::Output {
     // SAFETY: We never move the future after pinning
     let mut future = unsafe { Pin::new_unchecked(&mut future) };
 
-    loop {
+    for _ in 0..max_polls {
         match future.as_mut().poll(&mut cx) {
-            Poll::Ready(result) => return result,
+            Poll::Ready(result) => return Some(result),
             Poll::Pending => {
                 // Spin and yield to let the device process
                 for _ in 0..100 {
@@ -42,53 +49,109 @@ fn spin_poll<F: std::future::Future>(mut future: F) -> F::Output {
             }
         }
     }
+    None
+}
+
+/// Name of a received message type for error messages, e.g. `Rerror`
+fn type_name(typ: u8) -> String {
+    match MsgType::from_u8(typ) {
+        Some(msg_type) => msg_type.to_string(),
+        None => format!("unknown type {typ}"),
+    }
 }
+
 const VERSION: &str = "9P2000.L";
+/// Identifies this client to the server in lock requests, together with the process id
+const LOCK_CLIENT_ID: &str = "redox";
+
+/// An `Rlerror` or `Rerror` reply from the server, carrying its Linux errno.
+#[derive(Debug, thiserror::Error)]
+#[error("9P error: errno={errno}")]
+pub struct RemoteError {
+    pub errno: u32,
+}
 
-/// 9P client over virtio-9p
-pub struct Client9p<'a> {
-    queue: Arc<Queue<'a>>,
-    tag_counter: AtomicU16,
-    fid_counter: AtomicU32,
-    #[allow(dead_code)]
+impl RemoteError {
+    /// The Redox equivalent of the errno
+    pub fn to_redox(&self) -> syscall::Error {
+        p9_errno_to_redox(self.errno)
+    }
+}
+
+/// The device didn't complete a request in time, e.g. because the host is paused.
+#[derive(Debug, thiserror::Error)]
+#[error("9P request timed out")]
+pub struct Timeout;
+
+/// Fid allocator which reuses clunked fids before handing out new ones
+struct FidPool {
     root_fid: u32,
-    msize: u32,
+    next: AtomicU32,
+    free: Mutex<Vec<u32>>,
 }
 
-impl<'a> Client9p<'a> {
-    pub fn new(queue: Arc<Queue<'a>>) -> Result<Self> {
-        Ok(Self {
-            queue,
-            tag_counter: AtomicU16::new(1),
-            fid_counter: AtomicU32::new(1),
-            root_fid: 0,
-            msize: MSIZE,
-        })
+impl FidPool {
+    fn new(root_fid: u32) -> Self {
+        Self {
+            root_fid,
+            next: AtomicU32::new(root_fid + 1),
+            free: Mutex::new(Vec::new()),
+        }
     }
 
-    fn next_tag(&self) -> u16 {
-        self.tag_counter.fetch_add(1, Ordering::Relaxed)
+    fn alloc(&self) -> u32 {
+        if let Some(fid) = self.free.lock().unwrap().pop() {
+            return fid;
+        }
+        self.next.fetch_add(1, Ordering::Relaxed)
     }
 
-    pub fn alloc_fid(&self) -> u32 {
-        self.fid_counter.fetch_add(1, Ordering::Relaxed)
+    fn release(&self, fid: u32) {
+        // The root fid stays attached for the lifetime of the client
+        if fid == self.root_fid || fid == NOFID {
+            return;
+        }
+        self.free.lock().unwrap().push(fid);
     }
 
-    /// Send a 9P message and receive response
-    fn transact(&self, request: Vec<u8>) -> Result<Vec<u8>> {
-        log::trace!("transact: sending {} bytes", request.len());
+    /// Forget about all fids, for use when the server dropped them
+    fn reset(&mut self) {
+        *self = Self::new(self.root_fid);
+    }
+}
+
+/// Carries 9P messages to the server and its replies back
+pub trait Transport {
+    /// Send a request and wait for the reply, which is at most `max_len` bytes
+    ///
+    /// Returns a [`Timeout`] error if the reply didn't arrive in time.
+    fn send(&self, request: &[u8], max_len: usize) -> Result<Vec<u8>>;
+}
+
+/// Transport over the request queue of a virtio-9p device
+pub struct VirtioTransport<'a> {
+    queue: Arc<Queue<'a>>,
+}
+
+impl<'a> VirtioTransport<'a> {
+    pub fn new(queue: Arc<Queue<'a>>) -> Self {
+        Self { queue }
+    }
+}
 
+impl Transport for VirtioTransport<'_> {
+    fn send(&self, request: &[u8], max_len: usize) -> Result<Vec<u8>> {
         // Allocate request buffer and copy data
         let mut req_dma = unsafe {
             Dma::<[u8]>::zeroed_slice(request.len())
                 .map_err(|_| anyhow!("DMA alloc failed"))?
                 .assume_init()
         };
-        req_dma.copy_from_slice(&request);
+        req_dma.copy_from_slice(request);
 
         // Allocate response buffer
         let resp_dma = unsafe {
-            Dma::<[u8]>::zeroed_slice(self.msize as usize)
+            Dma::<[u8]>::zeroed_slice(max_len)
                 .map_err(|_| anyhow!("DMA alloc failed"))?
                 .assume_init()
         };
@@ -102,39 +165,140 @@ impl<'a> Client9p<'a> {
 
         log::trace!("transact: calling queue.send()");
         // Use spin-polling instead of futures executor since we don't have an event loop
-        let pending = self.queue.send(chain)
-            .ok_or_else(|| anyhow!("no descriptors available"))?;
-        let written = spin_poll(pending) as usize;
+        let Some(pending) = self.queue.send(chain) else {
+            return Err(anyhow!("no descriptors available"));
+        };
+        let Some(written) = spin_poll(pending, TRANSACT_SPIN_LIMIT) else {
+            // The device may still write the response later, so the buffers can't be freed
+            std::mem::forget(req_dma);
+            std::mem::forget(resp_dma);
+            return Err(Timeout.into());
+        };
         log::trace!("transact: queue.send() returned {} bytes", written);
 
+        let written = (written as usize).min(resp_dma.len());
+        Ok(resp_dma[..written].to_vec())
+    }
+}
+
+/// 9P client over a [`Transport`], usually virtio-9p
+pub struct Client9p<T> {
+    transport: T,
+    tag_counter: AtomicU16,
+    fids: FidPool,
+    root_fid: u32,
+    /// Message size negotiated with the server, at most [`MSIZE`]
+    msize: AtomicU32,
+    /// Name of the attached filesystem, to attach again on `reset`
+    aname: String,
+    /// Set when a request timed out and the connection needs a `reset`
+    timed_out: AtomicBool,
+    /// Tags of requests sent to the server whose reply didn't arrive yet
+    in_flight: Mutex<BTreeSet<u16>>,
+}
+
+impl<T: Transport> Client9p<T> {
+    pub fn new(transport: T) -> Result<Self> {
+        Ok(Self {
+            transport,
+            tag_counter: AtomicU16::new(1),
+            fids: FidPool::new(0),
+            root_fid: 0,
+            msize: AtomicU32::new(MSIZE),
+            aname: String::new(),
+            timed_out: AtomicBool::new(false),
+            in_flight: Mutex::new(BTreeSet::new()),
+        })
+    }
+
+    fn msize(&self) -> u32 {
+        self.msize.load(Ordering::Relaxed)
+    }
+
+    fn next_tag(&self) -> u16 {
+        let in_flight = self.in_flight.lock().unwrap();
+        loop {
+            // Skip NOTAG and tags still in use after the counter wrapped around
+            let tag = self.tag_counter.fetch_add(1, Ordering::Relaxed);
+            if tag != NOTAG && !in_flight.contains(&tag) {
+                return tag;
+            }
+        }
+    }
+
+    /// Tags of requests which were abandoned before their reply arrived
+    pub fn in_flight_tags(&self) -> Vec<u16> {
+        self.in_flight.lock().unwrap().iter().copied().collect()
+    }
+
+    pub fn alloc_fid(&self) -> u32 {
+        self.fids.alloc()
+    }
+
+    /// Return a fid which is no longer known to the server to the pool
+    ///
+    /// This is done by `clunk`, but also needs to be done for fids of failed walks.
+    pub fn release_fid(&self, fid: u32) {
+        self.fids.release(fid);
+    }
+
+    /// Send a 9P message and receive response
+    fn transact(&self, request: Vec<u8>) -> Result<Vec<u8>> {
+        log::trace!("transact: sending {} bytes", request.len());
+
+        let tag = u16::from_le_bytes([request[5], request[6]]);
+        self.in_flight.lock().unwrap().insert(tag);
+
+        let mut response = match self.transport.send(&request, self.msize() as usize) {
+            Ok(response) => response,
+            Err(err) => {
+                if err.is::<Timeout>() {
+                    // The server may still reply, so the tag stays in use
+                    self.timed_out.store(true, Ordering::Relaxed);
+                } else {
+                    self.in_flight.lock().unwrap().remove(&tag);
+                }
+                return Err(err);
+            }
+        };
+        let written = response.len();
+        self.in_flight.lock().unwrap().remove(&tag);
+
         // Parse response
         if written < Header::SIZE {
             return Err(anyhow!("response too short"));
         }
 
-        let header = Header::decode(&resp_dma[..Header::SIZE])
+        let header = Header::decode(&response[..Header::SIZE])
             .ok_or_else(|| anyhow!("invalid response header"))?;
 
         let size = header.size as usize;
-        if size > written || size > self.msize as usize {
+        if size > written || size > self.msize() as usize {
             return Err(anyhow!("invalid response size"));
         }
 
         // Check for error response
-        if header.typ == MsgType::Rerror as u8 {
-            let mut parser = MessageParser::new(&resp_dma[Header::SIZE..size]);
+        if header.typ == MsgType::Rlerror as u8 || header.typ == MsgType::Rerror as u8 {
+            let mut parser = MessageParser::new_bounded(&response, size);
+            parser.skip(Header::SIZE);
+            // Rerror carries an error string in front of the errno
+            if header.typ == MsgType::Rerror as u8 {
+                parser.get_str();
+            }
             let errno = parser.get_u32().unwrap_or(0);
-            return Err(anyhow!("9P error: errno={}", errno));
+            return Err(RemoteError { errno }.into());
         }
 
-        Ok(resp_dma[..size].to_vec())
+        // Anything after the declared size isn't part of the reply
+        response.truncate(size);
+        Ok(response)
     }
 
     /// Negotiate protocol version
     pub fn version(&self) -> Result<()> {
         let tag = self.next_tag();
         let msg = MessageBuilder::new(MsgType::Tversion, tag)
-            .put_u32(self.msize)
+            .put_u32(MSIZE)
             .put_str(VERSION)
             .finish();
 
@@ -143,21 +307,46 @@ impl<'a> Client9p<'a> {
         let header = parser.get_header().ok_or_else(|| anyhow!("no header"))?;
 
         if header.typ != MsgType::Rversion as u8 {
-            return Err(anyhow!("unexpected response type: {}", header.typ));
+            return Err(anyhow!("unexpected response type: {}", type_name(header.typ)));
         }
 
-        let _msize = parser.get_u32().ok_or_else(|| anyhow!("no msize"))?;
+        let msize = parser.get_u32().ok_or_else(|| anyhow!("no msize"))?;
         let version = parser.get_str().ok_or_else(|| anyhow!("no version"))?;
 
         if version != VERSION {
             return Err(anyhow!("version mismatch: got {}", version));
         }
+        if msize <= IOHDRSZ {
+            return Err(anyhow!("msize too small: {}", msize));
+        }
+        // The server may only lower the size we asked for
+        self.msize.store(msize.min(MSIZE), Ordering::Relaxed);
 
         Ok(())
     }
 
+    /// Whether a request timed out since the last `reset`
+    pub fn timed_out(&self) -> bool {
+        self.timed_out.load(Ordering::Relaxed)
+    }
+
+    /// Start a new session after a request timed out
+    ///
+    /// Negotiating the version again makes the server drop all fids, so every fid handed out
+    /// before is invalid afterwards and must not be used anymore.
+    pub fn reset(&mut self) -> Result<()> {
+        self.fids.reset();
+        self.version()?;
+        // The server aborted all outstanding requests
+        self.in_flight.lock().unwrap().clear();
+        let aname = self.aname.clone();
+        self.attach(&aname)?;
+        self.timed_out.store(false, Ordering::Relaxed);
+        Ok(())
+    }
+
     /// Attach to the filesystem root
-    pub fn attach(&self, aname: &str) -> Result<Qid> {
+    pub fn attach(&mut self, aname: &str) -> Result<Qid> {
         let tag = self.next_tag();
         let root_fid = 0u32; // Use fid 0 for root
 
@@ -174,10 +363,11 @@ impl<'a> Client9p<'a> {
         let header = parser.get_header().ok_or_else(|| anyhow!("no header"))?;
 
         if header.typ != MsgType::Rattach as u8 {
-            return Err(anyhow!("attach failed: type={}", header.typ));
+            return Err(anyhow!("attach failed: got {}", type_name(header.typ)));
         }
 
         let qid = parser.get_qid().ok_or_else(|| anyhow!("no qid"))?;
+        self.aname = aname.to_string();
         Ok(qid)
     }
 
@@ -198,7 +388,7 @@ impl<'a> Client9p<'a> {
         let header = parser.get_header().ok_or_else(|| anyhow!("no header"))?;
 
         if header.typ != MsgType::Rwalk as u8 {
-            return Err(anyhow!("walk failed: type={}", header.typ));
+            return Err(anyhow!("walk failed: got {}", type_name(header.typ)));
         }
 
         let nwqid = parser.get_u16().ok_or_else(|| anyhow!("no nwqid"))? as usize;
@@ -223,7 +413,7 @@ impl<'a> Client9p<'a> {
         let header = parser.get_header().ok_or_else(|| anyhow!("no header"))?;
 
         if header.typ != MsgType::Rlopen as u8 {
-            return Err(anyhow!("lopen failed: type={}", header.typ));
+            return Err(anyhow!("lopen failed: got {}", type_name(header.typ)));
         }
 
         let qid = parser.get_qid().ok_or_else(|| anyhow!("no qid"))?;
@@ -248,7 +438,7 @@ impl<'a> Client9p<'a> {
         let header = parser.get_header().ok_or_else(|| anyhow!("no header"))?;
 
         if header.typ != MsgType::Rlcreate as u8 {
-            return Err(anyhow!("lcreate failed: type={}", header.typ));
+            return Err(anyhow!("lcreate failed: got {}", type_name(header.typ)));
         }
 
         let qid = parser.get_qid().ok_or_else(|| anyhow!("no qid"))?;
@@ -261,7 +451,7 @@ impl<'a> Client9p<'a> {
     pub fn read(&self, fid: u32, offset: u64, count: u32) -> Result<Vec<u8>> {
         // Limit count to fit response in msize buffer
         // Response: header (7) + data_len (4) + data
-        let max_data = self.msize.saturating_sub(7 + 4);
+        let max_data = self.msize().saturating_sub(7 + 4);
         let count = count.min(max_data);
 
         let tag = self.next_tag();
@@ -276,7 +466,7 @@ impl<'a> Client9p<'a> {
         let header = parser.get_header().ok_or_else(|| anyhow!("no header"))?;
 
         if header.typ != MsgType::Rread as u8 {
-            return Err(anyhow!("read failed: type={}", header.typ));
+            return Err(anyhow!("read failed: got {}", type_name(header.typ)));
         }
 
         let data = parser.get_data().ok_or_else(|| anyhow!("no data"))?;
@@ -297,7 +487,7 @@ impl<'a> Client9p<'a> {
         let header = parser.get_header().ok_or_else(|| anyhow!("no header"))?;
 
         if header.typ != MsgType::Rwrite as u8 {
-            return Err(anyhow!("write failed: type={}", header.typ));
+            return Err(anyhow!("write failed: got {}", type_name(header.typ)));
         }
 
         let count = parser.get_u32().ok_or_else(|| anyhow!("no count"))?;
@@ -317,7 +507,7 @@ impl<'a> Client9p<'a> {
         let header = parser.get_header().ok_or_else(|| anyhow!("no header"))?;
 
         if header.typ != MsgType::Rgetattr as u8 {
-            return Err(anyhow!("getattr failed: type={}", header.typ));
+            return Err(anyhow!("getattr failed: got {}", type_name(header.typ)));
         }
 
         FileAttr::decode(&mut parser).ok_or_else(|| anyhow!("invalid attr"))
@@ -356,7 +546,7 @@ impl<'a> Client9p<'a> {
         let header = parser.get_header().ok_or_else(|| anyhow!("no header"))?;
 
         if header.typ != MsgType::Rsetattr as u8 {
-            return Err(anyhow!("setattr failed: type={}", header.typ));
+            return Err(anyhow!("setattr failed: got {}", type_name(header.typ)));
         }
 
         Ok(())
@@ -376,7 +566,7 @@ impl<'a> Client9p<'a> {
         let header = parser.get_header().ok_or_else(|| anyhow!("no header"))?;
 
         if header.typ != MsgType::Rreaddir as u8 {
-            return Err(anyhow!("readdir failed: type={}", header.typ));
+            return Err(anyhow!("readdir failed: got {}", type_name(header.typ)));
         }
 
         let data = parser.get_data().ok_or_else(|| anyhow!("no data"))?;
@@ -406,7 +596,7 @@ impl<'a> Client9p<'a> {
         let header = parser.get_header().ok_or_else(|| anyhow!("no header"))?;
 
         if header.typ != MsgType::Rstatfs as u8 {
-            return Err(anyhow!("statfs failed: type={}", header.typ));
+            return Err(anyhow!("statfs failed: got {}", type_name(header.typ)));
         }
 
         StatFs::decode(&mut parser).ok_or_else(|| anyhow!("invalid statfs"))
@@ -424,9 +614,10 @@ impl<'a> Client9p<'a> {
         let header = parser.get_header().ok_or_else(|| anyhow!("no header"))?;
 
         if header.typ != MsgType::Rclunk as u8 {
-            return Err(anyhow!("clunk failed: type={}", header.typ));
+            return Err(anyhow!("clunk failed: got {}", type_name(header.typ)));
         }
 
+        self.release_fid(fid);
         Ok(())
     }
 
@@ -444,7 +635,7 @@ impl<'a> Client9p<'a> {
         let header = parser.get_header().ok_or_else(|| anyhow!("no header"))?;
 
         if header.typ != MsgType::Runlinkat as u8 {
-            return Err(anyhow!("unlinkat failed: type={}", header.typ));
+            return Err(anyhow!("unlinkat failed: got {}", type_name(header.typ)));
         }
 
         Ok(())
@@ -465,7 +656,7 @@ impl<'a> Client9p<'a> {
         let header = parser.get_header().ok_or_else(|| anyhow!("no header"))?;
 
         if header.typ != MsgType::Rmkdir as u8 {
-            return Err(anyhow!("mkdir failed: type={}", header.typ));
+            return Err(anyhow!("mkdir failed: got {}", type_name(header.typ)));
         }
 
         parser.get_qid().ok_or_else(|| anyhow!("no qid"))
@@ -484,7 +675,7 @@ impl<'a> Client9p<'a> {
         let header = parser.get_header().ok_or_else(|| anyhow!("no header"))?;
 
         if header.typ != MsgType::Rfsync as u8 {
-            return Err(anyhow!("fsync failed: type={}", header.typ));
+            return Err(anyhow!("fsync failed: got {}", type_name(header.typ)));
         }
 
         Ok(())
@@ -505,7 +696,7 @@ impl<'a> Client9p<'a> {
         let header = parser.get_header().ok_or_else(|| anyhow!("no header"))?;
 
         if header.typ != MsgType::Rsymlink as u8 {
-            return Err(anyhow!("symlink failed: type={}", header.typ));
+            return Err(anyhow!("symlink failed: got {}", type_name(header.typ)));
         }
 
         parser.get_qid().ok_or_else(|| anyhow!("no qid"))
@@ -523,7 +714,7 @@ impl<'a> Client9p<'a> {
         let header = parser.get_header().ok_or_else(|| anyhow!("no header"))?;
 
         if header.typ != MsgType::Rreadlink as u8 {
-            return Err(anyhow!("readlink failed: type={}", header.typ));
+            return Err(anyhow!("readlink failed: got {}", type_name(header.typ)));
         }
 
         parser.get_str().map(|s| s.to_string()).ok_or_else(|| anyhow!("no target"))
@@ -544,14 +735,360 @@ impl<'a> Client9p<'a> {
         let header = parser.get_header().ok_or_else(|| anyhow!("no header"))?;
 
         if header.typ != MsgType::Rrenameat as u8 {
-            return Err(anyhow!("renameat failed: type={}", header.typ));
+            return Err(anyhow!("renameat failed: got {}", type_name(header.typ)));
+        }
+
+        Ok(())
+    }
+
+    /// Create a hard link named `name` in directory `dfid` to the file `fid`
+    pub fn link(&self, dfid: u32, fid: u32, name: &str) -> Result<()> {
+        let tag = self.next_tag();
+        let msg = MessageBuilder::new(MsgType::Tlink, tag)
+            .put_u32(dfid)
+            .put_u32(fid)
+            .put_str(name)
+            .finish();
+
+        let resp = self.transact(msg)?;
+        let mut parser = MessageParser::new(&resp);
+        let header = parser.get_header().ok_or_else(|| anyhow!("no header"))?;
+
+        if header.typ != MsgType::Rlink as u8 {
+            return Err(anyhow!("link failed: got {}", type_name(header.typ)));
         }
 
         Ok(())
     }
 
+    /// Abort the request with tag `oldtag`, so that the tag can be reused
+    ///
+    /// Does nothing if the reply to the request already arrived.
+    pub fn flush(&self, oldtag: u16) -> Result<()> {
+        if !self.in_flight.lock().unwrap().contains(&oldtag) {
+            return Ok(());
+        }
+
+        let tag = self.next_tag();
+        let msg = MessageBuilder::new(MsgType::Tflush, tag)
+            .put_u16(oldtag)
+            .finish();
+
+        let resp = self.transact(msg)?;
+        let mut parser = MessageParser::new(&resp);
+        let header = parser.get_header().ok_or_else(|| anyhow!("no header"))?;
+
+        if header.typ != MsgType::Rflush as u8 {
+            return Err(anyhow!("flush failed: got {}", type_name(header.typ)));
+        }
+
+        // After Rflush the server won't reply to oldtag anymore
+        self.in_flight.lock().unwrap().remove(&oldtag);
+        Ok(())
+    }
+
+    /// Look up the extended attribute `name` of `fid`
+    ///
+    /// `newfid` can then be read to get the value of the attribute. Returns the size of the value.
+    pub fn xattrwalk(&self, fid: u32, newfid: u32, name: &str) -> Result<u64> {
+        let tag = self.next_tag();
+        let msg = MessageBuilder::new(MsgType::Txattrwalk, tag)
+            .put_u32(fid)
+            .put_u32(newfid)
+            .put_str(name)
+            .finish();
+
+        let resp = self.transact(msg)?;
+        let mut parser = MessageParser::new(&resp);
+        let header = parser.get_header().ok_or_else(|| anyhow!("no header"))?;
+
+        if header.typ != MsgType::Rxattrwalk as u8 {
+            return Err(anyhow!("xattrwalk failed: got {}", type_name(header.typ)));
+        }
+
+        parser.get_u64().ok_or_else(|| anyhow!("no size"))
+    }
+
+    /// Acquire or release (with `P9_LOCK_TYPE_UNLCK`) a POSIX record lock for process `proc_id`
+    ///
+    /// Returns the `P9_LOCK_*` status reported by the server.
+    pub fn lock(
+        &self,
+        fid: u32,
+        typ: u8,
+        flags: u32,
+        start: u64,
+        length: u64,
+        proc_id: u32,
+    ) -> Result<u8> {
+        let tag = self.next_tag();
+        let msg = MessageBuilder::new(MsgType::Tlock, tag)
+            .put_u32(fid)
+            .put_u8(typ)
+            .put_u32(flags)
+            .put_u64(start)
+            .put_u64(length)
+            .put_u32(proc_id)
+            .put_str(LOCK_CLIENT_ID)
+            .finish();
+
+        let resp = self.transact(msg)?;
+        let mut parser = MessageParser::new(&resp);
+        let header = parser.get_header().ok_or_else(|| anyhow!("no header"))?;
+
+        if header.typ != MsgType::Rlock as u8 {
+            return Err(anyhow!("lock failed: got {}", type_name(header.typ)));
+        }
+
+        parser.get_u8().ok_or_else(|| anyhow!("no status"))
+    }
+
+    /// Find a lock which would conflict with the described one
+    ///
+    /// The type of the returned lock is `P9_LOCK_TYPE_UNLCK` if there is none.
+    pub fn getlock(
+        &self,
+        fid: u32,
+        typ: u8,
+        start: u64,
+        length: u64,
+        proc_id: u32,
+    ) -> Result<LockDesc> {
+        let tag = self.next_tag();
+        let msg = MessageBuilder::new(MsgType::Tgetlock, tag)
+            .put_u32(fid)
+            .put_u8(typ)
+            .put_u64(start)
+            .put_u64(length)
+            .put_u32(proc_id)
+            .put_str(LOCK_CLIENT_ID)
+            .finish();
+
+        let resp = self.transact(msg)?;
+        let mut parser = MessageParser::new(&resp);
+        let header = parser.get_header().ok_or_else(|| anyhow!("no header"))?;
+
+        if header.typ != MsgType::Rgetlock as u8 {
+            return Err(anyhow!("getlock failed: got {}", type_name(header.typ)));
+        }
+
+        LockDesc::decode(&mut parser).ok_or_else(|| anyhow!("invalid lock"))
+    }
+
+    /// Largest amount of data that fits in a single read or write message
+    pub fn max_io_size(&self) -> u32 {
+        self.msize() - IOHDRSZ
+    }
+
     /// Get the root fid (always 0 after attach)
     pub fn root_fid(&self) -> u32 {
-        0
+        self.root_fid
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{mock_client, reply_to};
+
+    /// Expected bytes of a message: the header followed by `body`
+    fn message(typ: MsgType, tag: u16, body: &[u8]) -> Vec<u8> {
+        let size = (Header::SIZE + body.len()) as u32;
+        let mut msg = size.to_le_bytes().to_vec();
+        msg.push(typ as u8);
+        msg.extend_from_slice(&tag.to_le_bytes());
+        msg.extend_from_slice(body);
+        msg
+    }
+
+    #[test]
+    fn version_roundtrip() {
+        let client = mock_client(|request| {
+            let mut body = MSIZE.to_le_bytes().to_vec();
+            body.extend_from_slice(&[8, 0]);
+            body.extend_from_slice(b"9P2000.L");
+            assert_eq!(request, message(MsgType::Tversion, 1, &body));
+
+            reply_to(request, MsgType::Rversion)
+                .put_u32(8192)
+                .put_str(VERSION)
+                .finish()
+        });
+        client.version().unwrap();
+        assert_eq!(client.max_io_size(), 8192 - IOHDRSZ);
+    }
+
+    #[test]
+    fn version_keeps_own_msize_if_server_offers_more() {
+        let client = mock_client(|request| {
+            reply_to(request, MsgType::Rversion)
+                .put_u32(MSIZE * 2)
+                .put_str(VERSION)
+                .finish()
+        });
+        client.version().unwrap();
+        assert_eq!(client.max_io_size(), MSIZE - IOHDRSZ);
+    }
+
+    #[test]
+    fn version_mismatch() {
+        let client = mock_client(|request| {
+            reply_to(request, MsgType::Rversion)
+                .put_u32(8192)
+                .put_str("9P2000")
+                .finish()
+        });
+        assert!(client.version().is_err());
+    }
+
+    #[test]
+    fn attach_roundtrip() {
+        let root = Qid {
+            typ: QID_DIR,
+            version: 0,
+            path: 2,
+        };
+        let mut client = mock_client(|request| {
+            let mut body = Vec::new();
+            body.extend_from_slice(&0u32.to_le_bytes());
+            body.extend_from_slice(&NOFID.to_le_bytes());
+            body.extend_from_slice(&[0, 0]);
+            body.extend_from_slice(&[4, 0]);
+            body.extend_from_slice(b"host");
+            body.extend_from_slice(&0u32.to_le_bytes());
+            assert_eq!(request, message(MsgType::Tattach, 1, &body));
+
+            reply_to(request, MsgType::Rattach).put_qid(&root).finish()
+        });
+        let qid = client.attach("host").unwrap();
+        assert!(qid.is_dir());
+        assert_eq!({ qid.path }, 2);
+        assert_eq!(client.aname, "host");
+    }
+
+    #[test]
+    fn walk_roundtrip() {
+        let client = mock_client(|request| {
+            let mut body = Vec::new();
+            body.extend_from_slice(&0u32.to_le_bytes());
+            body.extend_from_slice(&5u32.to_le_bytes());
+            body.extend_from_slice(&[2, 0]);
+            body.extend_from_slice(&[3, 0]);
+            body.extend_from_slice(b"usr");
+            body.extend_from_slice(&[3, 0]);
+            body.extend_from_slice(b"bin");
+            assert_eq!(request, message(MsgType::Twalk, 1, &body));
+
+            reply_to(request, MsgType::Rwalk)
+                .put_u16(2)
+                .put_qid(&Qid {
+                    typ: QID_DIR,
+                    version: 0,
+                    path: 10,
+                })
+                .put_qid(&Qid {
+                    typ: QID_DIR,
+                    version: 0,
+                    path: 11,
+                })
+                .finish()
+        });
+        let qids = client.walk(0, 5, &["usr", "bin"]).unwrap();
+        assert_eq!(qids.len(), 2);
+        assert_eq!({ qids[0].path }, 10);
+        assert_eq!({ qids[1].path }, 11);
+    }
+
+    #[test]
+    fn read_roundtrip() {
+        let client = mock_client(|request| {
+            let mut body = Vec::new();
+            body.extend_from_slice(&3u32.to_le_bytes());
+            body.extend_from_slice(&4096u64.to_le_bytes());
+            body.extend_from_slice(&512u32.to_le_bytes());
+            assert_eq!(request, message(MsgType::Tread, 1, &body));
+
+            reply_to(request, MsgType::Rread)
+                .put_data(b"hello")
+                .finish()
+        });
+        assert_eq!(client.read(3, 4096, 512).unwrap(), b"hello");
+        assert!(client.in_flight_tags().is_empty());
+    }
+
+    #[test]
+    fn lock_roundtrip() {
+        let client = mock_client(|request| {
+            let mut body = Vec::new();
+            body.extend_from_slice(&3u32.to_le_bytes());
+            body.push(P9_LOCK_TYPE_WRLCK);
+            body.extend_from_slice(&0u32.to_le_bytes());
+            body.extend_from_slice(&100u64.to_le_bytes());
+            body.extend_from_slice(&0u64.to_le_bytes());
+            body.extend_from_slice(&42u32.to_le_bytes());
+            body.extend_from_slice(&[5, 0]);
+            body.extend_from_slice(LOCK_CLIENT_ID.as_bytes());
+            assert_eq!(request, message(MsgType::Tlock, 1, &body));
+
+            reply_to(request, MsgType::Rlock)
+                .put_u8(P9_LOCK_BLOCKED)
+                .finish()
+        });
+        let status = client.lock(3, P9_LOCK_TYPE_WRLCK, 0, 100, 0, 42).unwrap();
+        assert_eq!(status, P9_LOCK_BLOCKED);
+    }
+
+    #[test]
+    fn reply_bounded_by_declared_size() {
+        let client = mock_client(|request| {
+            let mut reply = reply_to(request, MsgType::Rreadlink)
+                .put_str("target")
+                .finish();
+            reply.extend_from_slice(b"garbage");
+            reply[Header::SIZE] = 13;
+            reply
+        });
+        assert!(client.readlink(3).is_err());
+    }
+
+    #[test]
+    fn remote_error() {
+        let client = mock_client(|request| reply_to(request, MsgType::Rlerror).put_u32(2).finish());
+        let err = client.read(3, 0, 512).unwrap_err();
+        assert_eq!(err.downcast_ref::<RemoteError>().unwrap().errno, 2);
+    }
+
+    #[test]
+    fn fid_pool_reuses_released_fids() {
+        let pool = FidPool::new(0);
+
+        // Each open walks to a fid which is clunked again on close
+        let mut max_fid = 0;
+        for _ in 0..10_000 {
+            let fid = pool.alloc();
+            assert_ne!(fid, 0);
+            max_fid = max_fid.max(fid);
+            pool.release(fid);
+        }
+        assert_eq!(max_fid, 1);
+
+        // Interleaved opens only grow the range up to the amount open at once
+        let open: Vec<u32> = (0..16).map(|_| pool.alloc()).collect();
+        for &fid in &open {
+            pool.release(fid);
+        }
+        for _ in 0..10_000 {
+            let fid = pool.alloc();
+            assert!(fid <= 16);
+            pool.release(fid);
+        }
+    }
+
+    #[test]
+    fn fid_pool_never_recycles_root() {
+        let pool = FidPool::new(0);
+        pool.release(0);
+        pool.release(NOFID);
+        assert_eq!(pool.alloc(), 1);
     }
 }
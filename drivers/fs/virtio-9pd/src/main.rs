@@ -3,18 +3,19 @@
 
 use std::sync::Arc;
 
-use anyhow::{anyhow, Context, Result};
+use anyhow::{anyhow, Result};
 use thiserror::Error;
 
 use pcid_interface::*;
 use virtio_core::spec::*;
 use virtio_core::transport::Transport;
 
-mod protocol;
 mod scheme;
 mod client;
+#[cfg(test)]
+mod testing;
 
-use client::Client9p;
+use client::{Client9p, VirtioTransport};
 use scheme::Scheme9p;
 
 #[derive(Debug, Error)]
@@ -78,7 +79,7 @@ fn daemon(daemon: daemon::Daemon, mut pcid_handle: PciFunctionHandle) -> Result<
     log::info!("virtio-9pd: device initialized");
 
     // Create 9P client
-    let client = Client9p::new(queue)?;
+    let mut client = Client9p::new(VirtioTransport::new(queue))?;
 
     // Negotiate version
     client.version()?;
@@ -98,40 +99,19 @@ fn daemon(daemon: daemon::Daemon, mut pcid_handle: PciFunctionHandle) -> Result<
 
     log::info!("virtio-9pd: creating scheme '{}'", scheme_name);
 
-    let socket = redox_scheme::Socket::create(&scheme_name)
-        .context("failed to create scheme socket")?;
+    log::info!("virtio-9pd: serving requests");
 
-    let mut scheme = Scheme9p::new(scheme_name, client, root_qid);
+    redox_scheme::wrappers::run_scheme(
+        &scheme_name,
+        || {
+            let scheme = Scheme9p::new(scheme_name.clone(), client, root_qid);
 
-    libredox::call::setrens(0, 0).expect("virtio-9pd: failed to enter null namespace");
+            libredox::call::setrens(0, 0).expect("virtio-9pd: failed to enter null namespace");
 
-    daemon.ready();
-
-    log::info!("virtio-9pd: ready, serving requests");
-
-    loop {
-        let Some(request) = socket
-            .next_request(redox_scheme::SignalBehavior::Restart)
-            .context("failed to get next request")?
-        else {
-            break;
-        };
-
-        match request.kind() {
-            redox_scheme::RequestKind::Call(call) => {
-                let response = call.handle_sync(&mut scheme);
-                socket
-                    .write_response(response, redox_scheme::SignalBehavior::Restart)
-                    .context("failed to write response")?;
-            }
-            redox_scheme::RequestKind::OnClose { id } => {
-                scheme.on_close(id);
-            }
-            _ => (),
-        }
-    }
-
-    Ok(())
+            scheme
+        },
+        daemon,
+    )
 }
 
 /// Read the mount tag from virtio-9p device config space
@@ -3,16 +3,16 @@
 use std::collections::BTreeMap;
 
 use syscall::dirent::{DirEntry, DirentBuf, DirentKind};
-use syscall::error::{EBADF, EBADFD, EIO, EISDIR, ENOENT, ENOSYS, ENOTDIR, EXDEV};
-use syscall::flag::{O_ACCMODE, O_CREAT, O_DIRECTORY, O_RDONLY, O_RDWR, O_STAT, O_SYMLINK, O_TRUNC, O_WRONLY};
+use syscall::error::{EBADF, EBADFD, EEXIST, EINVAL, EIO, EISDIR, ENAMETOOLONG, ENODATA, ENOENT, ENOSYS, ENOTDIR, ERANGE, EXDEV};
+use syscall::flag::{O_ACCMODE, O_CREAT, O_DIRECTORY, O_EXCL, O_NONBLOCK, O_RDONLY, O_RDWR, O_STAT, O_SYMLINK, O_TRUNC, O_WRONLY};
 use syscall::schemev2::NewFdFlags;
 use syscall::{Error, EventFlags, Result, Stat, StatVfs, TimeSpec};
 
-use redox_scheme::scheme::SchemeSync;
-use redox_scheme::{CallerCtx, OpenResult};
+use redox_scheme::scheme::{decode_fcntl, FcntlCmd, SchemeSync};
+use redox_scheme::{CallerCtx, Id, OpenResult};
 
-use crate::client::Client9p;
-use crate::protocol::{self, FileAttr, P9_GETATTR_BASIC, P9_SETATTR_MODE, P9_SETATTR_UID, P9_SETATTR_GID, P9_SETATTR_SIZE, P9_SETATTR_ATIME_SET, P9_SETATTR_MTIME_SET, Qid, QID_SYMLINK};
+use crate::client::{Client9p, RemoteError, Timeout, Transport};
+use p9_proto::{FileAttr, P9_GETATTR_BASIC, P9_SETATTR_MODE, P9_SETATTR_UID, P9_SETATTR_GID, P9_SETATTR_SIZE, P9_SETATTR_ATIME_SET, P9_SETATTR_MTIME_SET, Qid, QID_SYMLINK};
 
 /// State for an open file handle
 struct Handle {
@@ -24,43 +24,158 @@ struct Handle {
     qid: Qid,
     /// Open flags
     flags: usize,
-    /// Current directory read offset (for readdir)
-    dir_offset: u64,
+    /// Preferred maximum size of reads and writes reported by the server, 0 if unknown
+    iounit: u32,
+    /// Whether the fid was opened with lopen or lcreate rather than just walked to
+    opened: bool,
+    /// Connection session the fid belongs to, see `Scheme9p::revive`
+    session: u32,
+    /// Created with `O_CREAT | O_SYMLINK` and not written to yet. The link is only made once
+    /// its target is written, until then `fid` is the one of the parent directory.
+    pending_symlink: bool,
+}
+
+/// Split a path into its parent directory and its last component
+fn split_parent(path: &str) -> (&str, &str) {
+    match path.rfind('/') {
+        Some(i) => (&path[..i], &path[i + 1..]),
+        None => ("", path),
+    }
+}
+
+/// Runs `op(pos, len)` on consecutive chunks of at most `chunk_size` bytes out of `total`,
+/// stopping at the first short transfer. Returns the number of bytes transferred.
+///
+/// An error is only returned if nothing was transferred yet, like a short read or write would.
+fn transfer_chunked(
+    total: usize,
+    chunk_size: usize,
+    mut op: impl FnMut(usize, usize) -> Result<usize>,
+) -> Result<usize> {
+    let mut done = 0;
+    while done < total {
+        let len = (total - done).min(chunk_size);
+        let count = match op(done, len) {
+            Ok(count) => count.min(len),
+            Err(_) if done > 0 => break,
+            Err(err) => return Err(err),
+        };
+        done += count;
+        if count < len {
+            break;
+        }
+    }
+    Ok(done)
+}
+
+/// `call` opcode (`metadata[0]`) reading an extended attribute, e.g. `security.selinux`.
+///
+/// The payload starts with the attribute name, whose length is passed in `metadata[1]`. On
+/// success the value is written to the payload and its length is returned. Fails with `ENODATA`
+/// if the file doesn't have the attribute and with `ERANGE` if the value doesn't fit the payload.
+pub const CALL_GETXATTR: u64 = 1;
+
+/// Number of `getattr` results kept by `AttrCache`
+const ATTR_CACHE_SIZE: usize = 64;
+
+/// Key of an `AttrCache` entry: fid and qid path
+///
+/// The qid version isn't part of it, as the one of a handle is only fetched on open and never
+/// changes afterwards.
+type AttrKey = (u32, u64);
+
+/// Least recently used cache of `getattr` results.
+///
+/// Entries are only valid as long as the file isn't changed, so every mutation done through
+/// our client must evict the entries of the file it touches.
+struct AttrCache {
+    /// Least recently used entry first
+    entries: Vec<(AttrKey, FileAttr)>,
+}
+
+impl AttrCache {
+    fn new() -> Self {
+        Self {
+            entries: Vec::with_capacity(ATTR_CACHE_SIZE),
+        }
+    }
+
+    fn get(&mut self, key: AttrKey) -> Option<FileAttr> {
+        let i = self.entries.iter().position(|(k, _)| *k == key)?;
+        let entry = self.entries.remove(i);
+        let attr = entry.1.clone();
+        self.entries.push(entry);
+        Some(attr)
+    }
+
+    fn insert(&mut self, key: AttrKey, attr: FileAttr) {
+        self.entries.retain(|(k, _)| *k != key);
+        if self.entries.len() >= ATTR_CACHE_SIZE {
+            self.entries.remove(0);
+        }
+        self.entries.push((key, attr));
+    }
+
+    /// Evict all entries of the file with the given qid path
+    fn invalidate(&mut self, qid_path: u64) {
+        self.entries.retain(|((_, path), _)| *path != qid_path);
+    }
+
+    /// Evict all entries of a fid which is about to be clunked and possibly reused
+    fn invalidate_fid(&mut self, fid: u32) {
+        self.entries.retain(|((f, _), _)| *f != fid);
+    }
+
+    fn clear(&mut self) {
+        self.entries.clear();
+    }
 }
 
 /// Redox scheme for 9P filesystem
-pub struct Scheme9p<'a> {
+pub struct Scheme9p<T> {
     scheme_name: String,
-    client: Client9p<'a>,
+    client: Client9p<T>,
     root_qid: Qid,
     /// Map from Redox fd number to Handle
     handles: BTreeMap<usize, Handle>,
     /// Next handle ID
     next_handle: usize,
+    /// Cached `getattr` results for `fstat`
+    attr_cache: AttrCache,
+    /// Incremented each time the connection is reset, invalidating all fids
+    session: u32,
+    /// Scheme request currently being served, if known
+    request: Option<Id>,
+    /// Scheme requests which abandoned 9P requests that may still be in flight, by 9P tag
+    abandoned: BTreeMap<u16, Id>,
 }
 
-impl<'a> Scheme9p<'a> {
-    pub fn new(scheme_name: String, client: Client9p<'a>, root_qid: Qid) -> Self {
+impl<T: Transport> Scheme9p<T> {
+    pub fn new(scheme_name: String, client: Client9p<T>, root_qid: Qid) -> Self {
         Self {
             scheme_name,
             client,
             root_qid,
             handles: BTreeMap::new(),
             next_handle: 1,
+            attr_cache: AttrCache::new(),
+            session: 0,
+            request: None,
+            abandoned: BTreeMap::new(),
         }
     }
 
     /// Convert Redox open flags to 9P lopen flags (excludes O_CREAT - that's for lcreate only)
     fn to_9p_lopen_flags(&self, flags: usize) -> u32 {
         let mut p9_flags = match flags & O_ACCMODE {
-            O_RDONLY => protocol::P9_RDONLY,
-            O_WRONLY => protocol::P9_WRONLY,
-            O_RDWR => protocol::P9_RDWR,
-            _ => protocol::P9_RDONLY,
+            O_RDONLY => p9_proto::P9_RDONLY,
+            O_WRONLY => p9_proto::P9_WRONLY,
+            O_RDWR => p9_proto::P9_RDWR,
+            _ => p9_proto::P9_RDONLY,
         };
 
         if flags & O_TRUNC != 0 {
-            p9_flags |= protocol::P9_TRUNC;
+            p9_flags |= p9_proto::P9_TRUNC;
         }
         // Note: O_CREAT is NOT passed to lopen - lopen doesn't create files
         p9_flags
@@ -80,12 +195,14 @@ impl<'a> Scheme9p<'a> {
             .walk(self.client.root_fid(), new_fid, &components)
             .map_err(|e| {
                 log::debug!("walk failed for '{}': {}", path, e);
-                Error::new(ENOENT)
+                self.client.release_fid(new_fid);
+                Self::to_redox_error(&e)
             })?;
 
-        // If we got fewer QIDs than path components, the walk failed partway
+        // If we got fewer QIDs than path components, the walk failed partway and new_fid
+        // wasn't created
         if components.len() > 0 && qids.len() != components.len() {
-            let _ = self.client.clunk(new_fid);
+            self.client.release_fid(new_fid);
             return Err(Error::new(ENOENT));
         }
 
@@ -94,6 +211,16 @@ impl<'a> Scheme9p<'a> {
         Ok((new_fid, qid))
     }
 
+    /// Size of the chunks reads and writes through a handle with the given `iounit` are split in
+    fn chunk_size(&self, iounit: u32) -> usize {
+        let max = self.client.max_io_size();
+        if iounit == 0 || iounit > max {
+            max as usize
+        } else {
+            iounit as usize
+        }
+    }
+
     /// Convert 9P FileAttr to Redox Stat
     fn attr_to_stat(&self, attr: &FileAttr) -> Stat {
         Stat {
@@ -115,73 +242,289 @@ impl<'a> Scheme9p<'a> {
         }
     }
 
+    /// Convert a client error to a Redox error, keeping the errno sent by the server if any
+    fn to_redox_error(err: &anyhow::Error) -> Error {
+        if err.is::<Timeout>() {
+            log::error!("virtio-9pd: request timed out, the connection will be reset");
+        }
+        match err.downcast_ref::<RemoteError>() {
+            Some(remote) => remote.to_redox(),
+            None => Error::new(EIO),
+        }
+    }
+
+    /// Read the value of the extended attribute `name` of `fid`, which may be at most
+    /// `max_len` bytes
+    fn get_xattr(&self, fid: u32, name: &str, max_len: usize) -> Result<Vec<u8>> {
+        let xattr_fid = self.client.alloc_fid();
+        let size = match self.client.xattrwalk(fid, xattr_fid, name) {
+            Ok(size) => size,
+            Err(e) => {
+                self.client.release_fid(xattr_fid);
+                let err = Self::to_redox_error(&e);
+                if err.errno == ENODATA {
+                    log::trace!("xattrwalk: no attribute '{}'", name);
+                } else {
+                    log::debug!("xattrwalk failed: {}", e);
+                }
+                return Err(err);
+            }
+        };
+
+        if size > max_len as u64 {
+            let _ = self.client.clunk(xattr_fid);
+            return Err(Error::new(ERANGE));
+        }
+
+        // Attributes can be empty, in which case there is nothing to read
+        let mut value = Vec::with_capacity(size as usize);
+        while (value.len() as u64) < size {
+            let remaining = (size - value.len() as u64) as u32;
+            match self.client.read(xattr_fid, value.len() as u64, remaining) {
+                Ok(data) if data.is_empty() => break,
+                Ok(data) => value.extend_from_slice(&data),
+                Err(e) => {
+                    let _ = self.client.clunk(xattr_fid);
+                    log::debug!("xattr read failed: {}", e);
+                    return Err(Self::to_redox_error(&e));
+                }
+            }
+        }
+
+        let _ = self.client.clunk(xattr_fid);
+        Ok(value)
+    }
+
+    /// Reconnect after a request timed out.
+    ///
+    /// All fids are lost, so handles opened before get a new one by `revive` on their next use.
+    fn recover(&mut self) -> Result<()> {
+        if !self.client.timed_out() {
+            return Ok(());
+        }
+
+        log::warn!("virtio-9pd: resetting connection, reopening {} handles", self.handles.len());
+        self.session = self.session.wrapping_add(1);
+        self.attr_cache.clear();
+        self.client.reset().map_err(|e| {
+            log::error!("virtio-9pd: failed to reset connection: {}", e);
+            Error::new(EIO)
+        })
+    }
+
+    /// Remember the 9P requests still in flight as abandoned by the scheme request served last,
+    /// so that they can be flushed when it gets cancelled.
+    fn note_abandoned(&mut self) {
+        let in_flight = self.client.in_flight_tags();
+        self.abandoned.retain(|tag, _| in_flight.contains(tag));
+        if let Some(request) = self.request {
+            for tag in in_flight {
+                self.abandoned.entry(tag).or_insert(request);
+            }
+        }
+    }
+
+    /// Start serving the scheme request `request`, reconnecting if needed.
+    fn begin(&mut self, request: Option<Id>) -> Result<()> {
+        self.note_abandoned();
+        self.request = request;
+        self.recover()
+    }
+
+    /// Start serving `request` on handle `id`, walking to and opening its file again if its fid
+    /// was lost to a reset since.
+    fn revive(&mut self, id: usize, request: Option<Id>) -> Result<()> {
+        self.begin(request)?;
+
+        let handle = self.handles.get(&id).ok_or(Error::new(EBADFD))?;
+        if handle.session == self.session {
+            return Ok(());
+        }
+        let (path, flags, opened) = (handle.path.clone(), handle.flags, handle.opened);
+        log::debug!("virtio-9pd: reopening '{}' after a reset", path);
+
+        // The link doesn't exist yet, but its parent directory does
+        let walk_to = if handle.pending_symlink {
+            split_parent(&path).0
+        } else {
+            &path
+        };
+        let (fid, qid) = self.walk_path(walk_to)?;
+        let mut iounit = 0;
+        if opened {
+            // The file must not be truncated again
+            let p9_flags = self.to_9p_lopen_flags(flags & !O_TRUNC);
+            match self.client.lopen(fid, p9_flags) {
+                Ok((_, lopen_iounit)) => iounit = lopen_iounit,
+                Err(e) => {
+                    log::debug!("lopen failed: {}", e);
+                    let _ = self.client.clunk(fid);
+                    return Err(Self::to_redox_error(&e));
+                }
+            }
+        }
+
+        let handle = self.handles.get_mut(&id).unwrap();
+        handle.fid = fid;
+        handle.qid = qid;
+        handle.iounit = iounit;
+        handle.session = self.session;
+        Ok(())
+    }
+
     /// Convert Redox open flags to 9P open flags
     fn to_9p_flags(&self, flags: usize) -> u32 {
         let mut p9_flags = match flags & O_ACCMODE {
-            O_RDONLY => protocol::P9_RDONLY,
-            O_WRONLY => protocol::P9_WRONLY,
-            O_RDWR => protocol::P9_RDWR,
-            _ => protocol::P9_RDONLY,
+            O_RDONLY => p9_proto::P9_RDONLY,
+            O_WRONLY => p9_proto::P9_WRONLY,
+            O_RDWR => p9_proto::P9_RDWR,
+            _ => p9_proto::P9_RDONLY,
         };
 
         if flags & O_TRUNC != 0 {
-            p9_flags |= protocol::P9_TRUNC;
+            p9_flags |= p9_proto::P9_TRUNC;
         }
         if flags & O_CREAT != 0 {
-            p9_flags |= protocol::P9_CREATE;
+            p9_flags |= p9_proto::P9_CREATE;
+        }
+        if flags & O_EXCL != 0 {
+            p9_flags |= p9_proto::P9_EXCL;
         }
 
         p9_flags
     }
 
-    pub fn on_close(&mut self, id: usize) {
-        if let Some(handle) = self.handles.remove(&id) {
-            let _ = self.client.clunk(handle.fid);
+    /// Open a symlink which doesn't exist yet, to be created by writing its target.
+    fn open_pending_symlink(&mut self, path: &str, flags: usize) -> Result<OpenResult> {
+        let (parent, name) = split_parent(path);
+        if name.is_empty() {
+            return Err(Error::new(EINVAL));
+        }
+
+        let (dir_fid, dir_qid) = self.walk_path(parent)?;
+        if !dir_qid.is_dir() {
+            let _ = self.client.clunk(dir_fid);
+            return Err(Error::new(ENOTDIR));
         }
+
+        let handle_id = self.next_handle;
+        self.next_handle += 1;
+
+        self.handles.insert(handle_id, Handle {
+            fid: dir_fid,
+            path: path.to_string(),
+            qid: dir_qid,
+            flags,
+            iounit: 0,
+            opened: false,
+            session: self.session,
+            pending_symlink: true,
+        });
+
+        Ok(OpenResult::ThisScheme {
+            number: handle_id,
+            flags: NewFdFlags::POSITIONED,
+        })
+    }
+
+    /// Create the link of the pending symlink handle `id`, pointing to `target`, and move the
+    /// handle over to it.
+    fn create_symlink(&mut self, id: usize, target: &str, gid: u32) -> Result<()> {
+        let handle = self.handles.get(&id).ok_or(Error::new(EBADFD))?;
+        let dir_fid = handle.fid;
+        let name = split_parent(&handle.path).1.to_string();
+
+        // The request must fit in a single message
+        if name.len() + target.len() > self.client.max_io_size() as usize {
+            return Err(Error::new(ENAMETOOLONG));
+        }
+
+        // The parent directory changes
+        self.attr_cache.clear();
+
+        self.client
+            .symlink(dir_fid, &name, target, gid)
+            .map_err(|e| {
+                log::debug!("symlink failed: {}", e);
+                Self::to_redox_error(&e)
+            })?;
+
+        let fid = self.client.alloc_fid();
+        let qids = self.client.walk(dir_fid, fid, &[&name]).map_err(|e| {
+            log::debug!("walk to new symlink failed: {}", e);
+            self.client.release_fid(fid);
+            Self::to_redox_error(&e)
+        })?;
+        let Some(&qid) = qids.last() else {
+            self.client.release_fid(fid);
+            return Err(Error::new(ENOENT));
+        };
+        let _ = self.client.clunk(dir_fid);
+
+        let handle = self.handles.get_mut(&id).unwrap();
+        handle.fid = fid;
+        handle.qid = qid;
+        handle.pending_symlink = false;
+        Ok(())
     }
 }
 
-impl SchemeSync for Scheme9p<'_> {
+impl<T: Transport> SchemeSync for Scheme9p<T> {
     fn open(&mut self, path: &str, flags: usize, ctx: &CallerCtx) -> Result<OpenResult> {
         log::trace!("OPEN CALLED: path='{}' flags={:#x}", path, flags);
 
+        self.begin(Some(ctx.id))?;
+
+        let mut iounit = 0;
+
         // Walk to the path - track whether we created the file (lcreate opens it)
         let (fid, qid, already_opened) = match self.walk_path(path) {
+            Ok((fid, _)) if flags & O_CREAT != 0 && flags & O_EXCL != 0 => {
+                let _ = self.client.clunk(fid);
+                return Err(Error::new(EEXIST));
+            }
             Ok((fid, qid)) => {
                 log::trace!("walk_path OK: path='{}' qid.typ={:#x}", path, qid.typ);
                 (fid, qid, false)
             }
-            Err(e) if flags & O_CREAT != 0 => {
+            Err(e) if e.errno == ENOENT && flags & O_CREAT != 0 && flags & O_SYMLINK != 0 => {
+                return self.open_pending_symlink(path, flags);
+            }
+            Err(e) if e.errno == ENOENT && flags & O_CREAT != 0 => {
                 // File doesn't exist but O_CREAT is set - try to create it
                 // First walk to parent directory
-                let (parent_path, name) = match path.rfind('/') {
-                    Some(i) => (&path[..i], &path[i + 1..]),
-                    None => ("", path),
-                };
+                let (parent_path, name) = split_parent(path);
 
                 let (parent_fid, _parent_qid) = if parent_path.is_empty() {
                     // Clone root fid
                     let new_fid = self.client.alloc_fid();
                     self.client
                         .walk(self.client.root_fid(), new_fid, &[])
-                        .map_err(|_| Error::new(EIO))?;
+                        .map_err(|_| {
+                            self.client.release_fid(new_fid);
+                            Error::new(EIO)
+                        })?;
                     (new_fid, self.root_qid)
                 } else {
                     self.walk_path(parent_path)?
                 };
 
+                // The parent directory changes
+                self.attr_cache.clear();
+
                 // Create the file - lcreate also opens it, so don't call lopen after
                 let mode = (flags & 0o7777) as u32 | 0o100000; // S_IFREG
                 let p9_flags = self.to_9p_flags(flags);
 
-                let (qid, _iounit) = self.client
+                let (qid, lcreate_iounit) = self.client
                     .lcreate(parent_fid, name, p9_flags, mode, ctx.gid)
                     .map_err(|e| {
                         log::debug!("lcreate failed: {}", e);
-                        Error::new(EIO)
+                        Self::to_redox_error(&e)
                     })?;
 
                 // lcreate repurposes parent_fid to point to new file AND opens it
+                iounit = lcreate_iounit;
                 (parent_fid, qid, true)
             }
             Err(e) => {
@@ -208,14 +551,19 @@ impl SchemeSync for Scheme9p<'_> {
 
         // Open the file (unless O_STAT, symlink with O_SYMLINK, or already opened by lcreate)
         // Symlinks opened with O_SYMLINK don't need lopen - we just read the target
-        if flags & O_STAT == 0 && !already_opened && !(is_symlink && flags & O_SYMLINK != 0) {
+        let opened = flags & O_STAT == 0 && !(is_symlink && flags & O_SYMLINK != 0);
+        if opened && !already_opened {
+            if flags & O_TRUNC != 0 {
+                self.attr_cache.invalidate(qid.path);
+            }
             // Use to_9p_lopen_flags which excludes O_CREAT (lopen doesn't create files)
             let p9_flags = self.to_9p_lopen_flags(flags);
-            let _ = self.client.lopen(fid, p9_flags).map_err(|e| {
+            let (_, lopen_iounit) = self.client.lopen(fid, p9_flags).map_err(|e| {
                 log::debug!("lopen failed: {}", e);
                 let _ = self.client.clunk(fid);
-                Error::new(EIO)
+                Self::to_redox_error(&e)
             })?;
+            iounit = lopen_iounit;
         }
 
         // Allocate handle
@@ -227,7 +575,10 @@ impl SchemeSync for Scheme9p<'_> {
             path: path.to_string(),
             qid,
             flags,
-            dir_offset: 0,
+            iounit,
+            opened: opened || already_opened,
+            session: self.session,
+            pending_symlink: false,
         });
 
         Ok(OpenResult::ThisScheme {
@@ -242,8 +593,9 @@ impl SchemeSync for Scheme9p<'_> {
         buf: &mut [u8],
         offset: u64,
         fcntl_flags: u32,
-        _ctx: &CallerCtx,
+        ctx: &CallerCtx,
     ) -> Result<usize> {
+        self.revive(id, Some(ctx.id))?;
         let handle = self.handles.get(&id).ok_or(Error::new(EBADFD))?;
 
         if handle.qid.is_dir() {
@@ -255,7 +607,7 @@ impl SchemeSync for Scheme9p<'_> {
         if is_symlink && handle.flags & O_SYMLINK != 0 {
             let target = self.client.readlink(handle.fid).map_err(|e| {
                 log::debug!("readlink failed: {}", e);
-                Error::new(EIO)
+                Self::to_redox_error(&e)
             })?;
             let target_bytes = target.as_bytes();
             let offset = offset as usize;
@@ -272,16 +624,21 @@ impl SchemeSync for Scheme9p<'_> {
             return Err(Error::new(EBADF));
         }
 
-        let data = self.client
-            .read(handle.fid, offset, buf.len() as u32)
-            .map_err(|e| {
-                log::debug!("read failed: {}", e);
-                Error::new(EIO)
-            })?;
+        let fid = handle.fid;
+        let chunk_size = self.chunk_size(handle.iounit);
 
-        let len = data.len().min(buf.len());
-        buf[..len].copy_from_slice(&data[..len]);
-        Ok(len)
+        transfer_chunked(buf.len(), chunk_size, |pos, len| {
+            let data = self.client
+                .read(fid, offset + pos as u64, len as u32)
+                .map_err(|e| {
+                    log::debug!("read failed: {}", e);
+                    Self::to_redox_error(&e)
+                })?;
+
+            let count = data.len().min(len);
+            buf[pos..pos + count].copy_from_slice(&data[..count]);
+            Ok(count)
+        })
     }
 
     fn write(
@@ -290,22 +647,37 @@ impl SchemeSync for Scheme9p<'_> {
         buf: &[u8],
         offset: u64,
         _fcntl_flags: u32,
-        _ctx: &CallerCtx,
+        ctx: &CallerCtx,
     ) -> Result<usize> {
+        self.revive(id, Some(ctx.id))?;
         let handle = self.handles.get(&id).ok_or(Error::new(EBADFD))?;
 
+        if handle.pending_symlink {
+            if offset != 0 {
+                return Err(Error::new(EINVAL));
+            }
+            let target = std::str::from_utf8(buf).map_err(|_| Error::new(EINVAL))?;
+            self.create_symlink(id, target, ctx.gid)?;
+            return Ok(buf.len());
+        }
+
         if handle.qid.is_dir() {
             return Err(Error::new(EISDIR));
         }
 
-        let count = self.client
-            .write(handle.fid, offset, buf)
-            .map_err(|e| {
-                log::debug!("write failed: {}", e);
-                Error::new(EIO)
-            })?;
-
-        Ok(count as usize)
+        self.attr_cache.invalidate(handle.qid.path);
+        let fid = handle.fid;
+        let chunk_size = self.chunk_size(handle.iounit);
+
+        transfer_chunked(buf.len(), chunk_size, |pos, len| {
+            let count = self.client
+                .write(fid, offset + pos as u64, &buf[pos..pos + len])
+                .map_err(|e| {
+                    log::debug!("write failed: {}", e);
+                    Self::to_redox_error(&e)
+                })?;
+            Ok(count as usize)
+        })
     }
 
     fn getdents<'buf>(
@@ -314,60 +686,92 @@ impl SchemeSync for Scheme9p<'_> {
         mut buf: DirentBuf<&'buf mut [u8]>,
         opaque_offset: u64,
     ) -> Result<DirentBuf<&'buf mut [u8]>> {
-        let handle = self.handles.get_mut(&id).ok_or(Error::new(EBADFD))?;
+        self.revive(id, None)?;
+        let handle = self.handles.get(&id).ok_or(Error::new(EBADFD))?;
 
         if !handle.qid.is_dir() {
             return Err(Error::new(ENOTDIR));
         }
 
-        // Read directory entries from 9P
-        let entries = self.client
-            .readdir(handle.fid, opaque_offset, 4096)
-            .map_err(|e| {
-                log::debug!("readdir failed: {}", e);
-                Error::new(EIO)
-            })?;
+        // A single readdir only returns up to `count` bytes of entries, so keep reading at the
+        // offset of the last entry until the directory is exhausted or `buf` is full.
+        let mut offset = opaque_offset;
+        let mut written_any = false;
+        'read: loop {
+            let entries = self.client
+                .readdir(handle.fid, offset, 4096)
+                .map_err(|e| {
+                    log::debug!("readdir failed: {}", e);
+                    Self::to_redox_error(&e)
+                })?;
+            if entries.is_empty() {
+                break;
+            }
 
-        for entry in entries {
-            let kind = if entry.qid.is_dir() {
-                DirentKind::Directory
-            } else {
-                DirentKind::Regular
-            };
-
-            buf.entry(DirEntry {
-                inode: entry.qid.path,
-                name: &entry.name,
-                kind,
-                next_opaque_id: entry.offset,
-            })?;
+            for entry in entries.iter() {
+                // Not every host sends these, so never list them for consistency
+                if entry.name == "." || entry.name == ".." {
+                    offset = entry.offset;
+                    continue;
+                }
+
+                let kind = if entry.qid.is_dir() {
+                    DirentKind::Directory
+                } else {
+                    DirentKind::Regular
+                };
+
+                match buf.entry(DirEntry {
+                    inode: entry.qid.path,
+                    name: &entry.name,
+                    kind,
+                    next_opaque_id: entry.offset,
+                }) {
+                    Ok(()) => {}
+                    // Buffer full - the client resumes from the last written entry's offset
+                    Err(Error { errno: EINVAL }) if written_any => break 'read,
+                    Err(err) => return Err(err),
+                }
+                written_any = true;
+                offset = entry.offset;
+            }
         }
 
         Ok(buf)
     }
 
-    fn fstat(&mut self, id: usize, stat: &mut Stat, _ctx: &CallerCtx) -> Result<()> {
+    fn fstat(&mut self, id: usize, stat: &mut Stat, ctx: &CallerCtx) -> Result<()> {
+        self.revive(id, Some(ctx.id))?;
         let handle = self.handles.get(&id).ok_or(Error::new(EBADFD))?;
+        let key = (handle.fid, handle.qid.path);
 
-        let attr = self.client
-            .getattr(handle.fid, P9_GETATTR_BASIC)
-            .map_err(|e| {
-                log::debug!("getattr failed: {}", e);
-                Error::new(EIO)
-            })?;
+        let attr = match self.attr_cache.get(key) {
+            Some(attr) => attr,
+            None => {
+                let attr = self.client
+                    .getattr(handle.fid, P9_GETATTR_BASIC)
+                    .map_err(|e| {
+                        log::debug!("getattr failed: {}", e);
+                        Self::to_redox_error(&e)
+                    })?;
+                self.attr_cache.insert(key, attr.clone());
+                attr
+            }
+        };
 
         *stat = self.attr_to_stat(&attr);
         Ok(())
     }
 
-    fn fstatvfs(&mut self, id: usize, stat: &mut StatVfs, _ctx: &CallerCtx) -> Result<()> {
+    fn fstatvfs(&mut self, id: usize, stat: &mut StatVfs, ctx: &CallerCtx) -> Result<()> {
+        self.revive(id, Some(ctx.id))?;
         let handle = self.handles.get(&id).ok_or(Error::new(EBADFD))?;
 
         let fs = self.client
             .statfs(handle.fid)
             .map_err(|e| {
                 log::debug!("statfs failed: {}", e);
-                Error::new(EIO)
+                Self::to_redox_error(&e)
             })?;
 
         *stat = StatVfs {
@@ -390,71 +794,92 @@ impl SchemeSync for Scheme9p<'_> {
         Ok(len)
     }
 
-    fn fsync(&mut self, id: usize, _ctx: &CallerCtx) -> Result<()> {
+    fn fsync(&mut self, id: usize, ctx: &CallerCtx) -> Result<()> {
+        self.revive(id, Some(ctx.id))?;
         let handle = self.handles.get(&id).ok_or(Error::new(EBADFD))?;
 
         self.client.fsync(handle.fid).map_err(|e| {
             log::debug!("fsync failed: {}", e);
-            Error::new(EIO)
+            Self::to_redox_error(&e)
         })
     }
 
-    fn unlinkat(&mut self, id: usize, path: &str, flags: usize, _ctx: &CallerCtx) -> Result<()> {
+    fn unlinkat(&mut self, id: usize, path: &str, flags: usize, ctx: &CallerCtx) -> Result<()> {
+        self.revive(id, Some(ctx.id))?;
         let handle = self.handles.get(&id).ok_or(Error::new(EBADFD))?;
 
         // AT_REMOVEDIR flag
         let rmdir = flags & syscall::AT_REMOVEDIR != 0;
         let p9_flags = if rmdir { 0x200 } else { 0 }; // AT_REMOVEDIR in 9P
 
+        // Both the directory and the link count of the removed file change
+        self.attr_cache.clear();
         self.client
             .unlinkat(handle.fid, path, p9_flags)
             .map_err(|e| {
                 log::debug!("unlinkat failed: {}", e);
-                Error::new(EIO)
+                Self::to_redox_error(&e)
             })
     }
 
-    fn fcntl(&mut self, _id: usize, _cmd: usize, _arg: usize, _ctx: &CallerCtx) -> Result<usize> {
-        Ok(0)
+    fn fcntl(&mut self, id: usize, cmd: usize, arg: usize, _ctx: &CallerCtx) -> Result<usize> {
+        let handle = self.handles.get_mut(&id).ok_or(Error::new(EBADFD))?;
+        match decode_fcntl(cmd, arg) {
+            FcntlCmd::GetFl => Ok(handle.flags),
+            FcntlCmd::SetFl(flags) => {
+                // O_NONBLOCK is the only status flag which can be changed after open
+                handle.flags = (handle.flags & !O_NONBLOCK) | (flags & O_NONBLOCK);
+                Ok(0)
+            }
+            _ => Ok(0),
+        }
     }
 
     fn fevent(&mut self, _id: usize, _flags: EventFlags, _ctx: &CallerCtx) -> Result<EventFlags> {
         Err(Error::new(ENOSYS))
     }
 
-    fn fchmod(&mut self, id: usize, mode: u16, _ctx: &CallerCtx) -> Result<()> {
+    fn fchmod(&mut self, id: usize, mode: u16, ctx: &CallerCtx) -> Result<()> {
+        self.revive(id, Some(ctx.id))?;
         let handle = self.handles.get(&id).ok_or(Error::new(EBADFD))?;
+        self.attr_cache.invalidate(handle.qid.path);
         self.client
             .setattr(handle.fid, P9_SETATTR_MODE, mode as u32, 0, 0, 0, 0, 0, 0, 0)
             .map_err(|e| {
                 log::debug!("setattr (chmod) failed: {}", e);
-                Error::new(EIO)
+                Self::to_redox_error(&e)
             })
     }
 
-    fn fchown(&mut self, id: usize, uid: u32, gid: u32, _ctx: &CallerCtx) -> Result<()> {
+    fn fchown(&mut self, id: usize, uid: u32, gid: u32, ctx: &CallerCtx) -> Result<()> {
+        self.revive(id, Some(ctx.id))?;
         let handle = self.handles.get(&id).ok_or(Error::new(EBADFD))?;
+        self.attr_cache.invalidate(handle.qid.path);
         let valid = P9_SETATTR_UID | P9_SETATTR_GID;
         self.client
             .setattr(handle.fid, valid, 0, uid, gid, 0, 0, 0, 0, 0)
             .map_err(|e| {
                 log::debug!("setattr (chown) failed: {}", e);
-                Error::new(EIO)
+                Self::to_redox_error(&e)
             })
     }
 
-    fn ftruncate(&mut self, id: usize, len: u64, _ctx: &CallerCtx) -> Result<()> {
+    fn ftruncate(&mut self, id: usize, len: u64, ctx: &CallerCtx) -> Result<()> {
+        self.revive(id, Some(ctx.id))?;
         let handle = self.handles.get(&id).ok_or(Error::new(EBADFD))?;
+        self.attr_cache.invalidate(handle.qid.path);
         self.client
             .setattr(handle.fid, P9_SETATTR_SIZE, 0, 0, 0, len, 0, 0, 0, 0)
             .map_err(|e| {
                 log::debug!("setattr (truncate) failed: {}", e);
-                Error::new(EIO)
+                Self::to_redox_error(&e)
             })
     }
 
-    fn futimens(&mut self, id: usize, times: &[TimeSpec], _ctx: &CallerCtx) -> Result<()> {
+    fn futimens(&mut self, id: usize, times: &[TimeSpec], ctx: &CallerCtx) -> Result<()> {
+        self.revive(id, Some(ctx.id))?;
         let handle = self.handles.get(&id).ok_or(Error::new(EBADFD))?;
+        self.attr_cache.invalidate(handle.qid.path);
 
         let (atime_sec, atime_nsec, mtime_sec, mtime_nsec, valid) = if times.len() >= 2 {
             (
@@ -476,12 +901,15 @@ impl SchemeSync for Scheme9p<'_> {
             .setattr(handle.fid, valid, 0, 0, 0, 0, atime_sec, atime_nsec, mtime_sec, mtime_nsec)
             .map_err(|e| {
                 log::debug!("setattr (utimens) failed: {}", e);
-                Error::new(EIO)
+                Self::to_redox_error(&e)
             })
     }
 
-    fn frename(&mut self, id: usize, new_path: &str, _ctx: &CallerCtx) -> Result<usize> {
+    fn frename(&mut self, id: usize, new_path: &str, ctx: &CallerCtx) -> Result<usize> {
+        self.revive(id, Some(ctx.id))?;
         let handle = self.handles.get(&id).ok_or(Error::new(EBADFD))?;
+        // The parent directories change as well
+        self.attr_cache.clear();
         let old_path = handle.path.clone();
 
         // Split old path into parent + name
@@ -503,16 +931,18 @@ impl SchemeSync for Scheme9p<'_> {
             .walk(self.client.root_fid(), old_dir_fid, &old_components)
             .map_err(|e| {
                 log::debug!("frename: walk to old parent failed: {}", e);
-                Error::new(ENOENT)
+                self.client.release_fid(old_dir_fid);
+                Self::to_redox_error(&e)
             })?;
 
         // Walk to new parent directory
         let new_dir_fid = self.client.alloc_fid();
         let new_components: Vec<&str> = new_parent.split('/').filter(|s| !s.is_empty()).collect();
         if let Err(e) = self.client.walk(self.client.root_fid(), new_dir_fid, &new_components) {
+            self.client.release_fid(new_dir_fid);
             let _ = self.client.clunk(old_dir_fid);
             log::debug!("frename: walk to new parent failed: {}", e);
-            return Err(Error::new(ENOENT));
+            return Err(Self::to_redox_error(&e));
         }
 
         // Perform the rename
@@ -524,7 +954,7 @@ impl SchemeSync for Scheme9p<'_> {
 
         result.map_err(|e| {
             log::debug!("frename failed: {}", e);
-            Error::new(EIO)
+            Self::to_redox_error(&e)
         })?;
 
         // Update handle path
@@ -535,6 +965,84 @@ impl SchemeSync for Scheme9p<'_> {
         Ok(0)
     }
 
+    fn flink(&mut self, id: usize, new_path: &str, ctx: &CallerCtx) -> Result<usize> {
+        self.revive(id, Some(ctx.id))?;
+        let handle = self.handles.get(&id).ok_or(Error::new(EBADFD))?;
+        // The parent directories change as well
+        self.attr_cache.clear();
+        let old_path = handle.path.clone();
+
+        // Links can't point outside of this filesystem
+        if new_path.contains(':') || new_path.starts_with("/scheme/") {
+            return Err(Error::new(EXDEV));
+        }
+
+        // Split new path into parent + name
+        let (new_parent, new_name) = match new_path.rfind('/') {
+            Some(i) => (&new_path[..i], &new_path[i + 1..]),
+            None => ("", new_path),
+        };
+        if new_name.is_empty() {
+            return Err(Error::new(EINVAL));
+        }
+
+        // Walk to new parent directory
+        let (dir_fid, dir_qid) = self.walk_path(new_parent)?;
+        if !dir_qid.is_dir() {
+            let _ = self.client.clunk(dir_fid);
+            return Err(Error::new(ENOTDIR));
+        }
+
+        // The handle's own fid may be open, so link through a fresh fid for the same file
+        let (file_fid, _) = match self.walk_path(&old_path) {
+            Ok(walked) => walked,
+            Err(e) => {
+                let _ = self.client.clunk(dir_fid);
+                return Err(e);
+            }
+        };
+
+        let result = self.client.link(dir_fid, file_fid, new_name);
+
+        // Clean up fids
+        let _ = self.client.clunk(file_fid);
+        let _ = self.client.clunk(dir_fid);
+
+        result.map_err(|e| {
+            log::debug!("flink failed: {}", e);
+            Self::to_redox_error(&e)
+        })?;
+
+        Ok(0)
+    }
+
+    fn call(
+        &mut self,
+        id: usize,
+        payload: &mut [u8],
+        metadata: &[u64],
+        ctx: &CallerCtx,
+    ) -> Result<usize> {
+        self.revive(id, Some(ctx.id))?;
+        let handle = self.handles.get(&id).ok_or(Error::new(EBADFD))?;
+
+        match metadata.first().copied() {
+            Some(CALL_GETXATTR) => {
+                let name_len = *metadata.get(1).ok_or(Error::new(EINVAL))? as usize;
+                let name = payload
+                    .get(..name_len)
+                    .and_then(|name| std::str::from_utf8(name).ok())
+                    .ok_or(Error::new(EINVAL))?
+                    .to_string();
+
+                let value = self.get_xattr(handle.fid, &name, payload.len())?;
+                payload[..value.len()].copy_from_slice(&value);
+                Ok(value.len())
+            }
+            _ => Err(Error::new(EINVAL)),
+        }
+    }
+
     fn mmap_prep(
         &mut self,
         _id: usize,
@@ -545,4 +1053,213 @@ impl SchemeSync for Scheme9p<'_> {
     ) -> Result<usize> {
         Err(Error::new(ENOSYS))
     }
+
+    fn on_cancel(&mut self, id: Id) {
+        // Requests are served one at a time, so by the time a cancellation arrives the request
+        // was answered already. Its tags can only still be in flight if it timed out, so flush
+        // those to free them.
+        self.note_abandoned();
+        let tags: Vec<u16> = self
+            .abandoned
+            .iter()
+            .filter(|&(_, &owner)| owner == id)
+            .map(|(&tag, _)| tag)
+            .collect();
+        for tag in tags {
+            self.abandoned.remove(&tag);
+            if let Err(e) = self.client.flush(tag) {
+                log::debug!("flush of tag {} failed: {}", tag, e);
+            }
+        }
+    }
+
+    fn on_close(&mut self, id: usize) {
+        if let Some(handle) = self.handles.remove(&id) {
+            // The fid of an earlier session may have been handed out again since
+            if handle.session != self.session {
+                return;
+            }
+            self.attr_cache.invalidate_fid(handle.fid);
+            let _ = self.client.clunk(handle.fid);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{caller, caller_of, mock_client, FakeHost, MockTransport, ROOT_QID};
+    use p9_proto::MsgType;
+
+    fn mount(host: &FakeHost) -> Scheme9p<MockTransport<impl Fn(&[u8]) -> Vec<u8> + '_>> {
+        let client = mock_client(move |request| host.serve(request));
+        Scheme9p::new("9p.test".to_string(), client, ROOT_QID)
+    }
+
+    fn open(scheme: &mut Scheme9p<impl Transport>, path: &str, flags: usize) -> usize {
+        match scheme.open(path, flags, &caller()).unwrap() {
+            OpenResult::ThisScheme { number, .. } => number,
+            _ => panic!("open didn't return a handle"),
+        }
+    }
+
+    #[test]
+    fn chunked_write_with_small_iounit() {
+        const IOUNIT: usize = 4096;
+        let host = FakeHost::new(IOUNIT as u32);
+        let mut scheme = mount(&host);
+        let data: Vec<u8> = (0..1024 * 1024).map(|i| i as u8).collect();
+
+        let fd = open(&mut scheme, "file", O_RDWR);
+        let written = scheme
+            .write(fd, &data, 0, O_RDWR as u32, &caller())
+            .unwrap();
+
+        assert_eq!(written, data.len());
+        let state = host.state.borrow();
+        assert_eq!(state.writes.len(), data.len() / IOUNIT);
+        assert!(state.writes.iter().all(|&len| len == IOUNIT));
+        assert!(state.file == data);
+    }
+
+    #[test]
+    fn chunked_read_stops_at_short_read() {
+        let file_len = 10_000;
+        let read = transfer_chunked(1024 * 1024, 4096, |pos, len| Ok(len.min(file_len - pos)))
+            .unwrap();
+        assert_eq!(read, file_len);
+
+        // Errors after a partial transfer turn into a short transfer
+        let read = transfer_chunked(8192, 4096, |pos, len| {
+            if pos == 0 {
+                Ok(len)
+            } else {
+                Err(Error::new(EIO))
+            }
+        })
+        .unwrap();
+        assert_eq!(read, 4096);
+    }
+
+    fn attr(size: u64) -> FileAttr {
+        FileAttr {
+            size,
+            ..FileAttr::default()
+        }
+    }
+
+    #[test]
+    fn attr_cache_write_invalidates() {
+        let mut cache = AttrCache::new();
+        cache.insert((1, 42), attr(10));
+        cache.insert((2, 43), attr(20));
+        assert_eq!(cache.get((1, 42)).map(|a| a.size), Some(10));
+
+        // A write through any fid of the file evicts it
+        cache.invalidate(42);
+        assert!(cache.get((1, 42)).is_none());
+        assert_eq!(cache.get((2, 43)).map(|a| a.size), Some(20));
+    }
+
+    #[test]
+    fn attr_cache_evicts_least_recently_used() {
+        let mut cache = AttrCache::new();
+        for i in 0..ATTR_CACHE_SIZE as u64 {
+            cache.insert((i as u32, i), attr(i));
+        }
+        // Touch the oldest entry so that the second oldest gets evicted instead
+        assert!(cache.get((0, 0)).is_some());
+        cache.insert((1000, 1000), attr(1000));
+
+        assert!(cache.get((0, 0)).is_some());
+        assert!(cache.get((1, 1)).is_none());
+        assert!(cache.get((1000, 1000)).is_some());
+    }
+
+    #[test]
+    fn handles_are_reopened_after_reset() {
+        let host = FakeHost::new(0);
+        let mut scheme = mount(&host);
+        let fd = open(&mut scheme, "file", O_RDWR);
+        let written = scheme.write(fd, b"hello", 0, O_RDWR as u32, &caller());
+        assert_eq!(written, Ok(5));
+
+        host.state.borrow_mut().timeouts = 1;
+        let mut buf = [0; 5];
+        let read = scheme.read(fd, &mut buf, 0, O_RDWR as u32, &caller());
+        assert_eq!(read, Err(Error::new(EIO)));
+
+        // The next request resets the connection, which drops all fids of the server
+        let read = scheme.read(fd, &mut buf, 0, O_RDWR as u32, &caller());
+        assert_eq!(read, Ok(5));
+        assert_eq!(&buf, b"hello");
+        let state = host.state.borrow();
+        assert_eq!(state.count(MsgType::Tversion), 1);
+        assert_eq!(state.count(MsgType::Tlopen), 2);
+    }
+
+    #[test]
+    fn cancel_flushes_only_tags_of_the_cancelled_request() {
+        let host = FakeHost::new(0);
+        let mut scheme = mount(&host);
+        let fd = open(&mut scheme, "file", O_RDWR);
+
+        host.state.borrow_mut().timeouts = 1;
+        let mut buf = [0; 5];
+        let read = scheme.read(fd, &mut buf, 0, O_RDWR as u32, &caller_of(1));
+        assert_eq!(read, Err(Error::new(EIO)));
+        let tags = scheme.client.in_flight_tags();
+        assert_eq!(tags.len(), 1);
+
+        scheme.on_cancel(caller_of(2).id);
+        assert!(host.state.borrow().flushed.is_empty());
+
+        scheme.on_cancel(caller_of(1).id);
+        assert_eq!(host.state.borrow().flushed, tags);
+        assert!(scheme.client.in_flight_tags().is_empty());
+    }
+
+    #[test]
+    fn symlink_is_created_by_writing_its_target() {
+        let host = FakeHost::new(0);
+        let mut scheme = mount(&host);
+        let flags = O_CREAT | O_EXCL | O_WRONLY | O_SYMLINK;
+        let fd = open(&mut scheme, "link", flags);
+        // Nothing is created until the target is known
+        assert!(host.state.borrow().link.is_none());
+
+        let written = scheme.write(fd, b"file", 0, flags as u32, &caller());
+        assert_eq!(written, Ok(4));
+        assert_eq!(host.state.borrow().link.as_deref(), Some("file"));
+        assert_eq!(host.state.borrow().count(MsgType::Tlcreate), 0);
+        scheme.on_close(fd);
+
+        let fd = open(&mut scheme, "link", O_RDONLY | O_SYMLINK);
+        let mut buf = [0; 16];
+        let read = scheme.read(fd, &mut buf, 0, O_RDONLY as u32, &caller());
+        assert_eq!(read, Ok(4));
+        assert_eq!(&buf[..4], b"file");
+        assert_eq!(host.state.borrow().count(MsgType::Treadlink), 1);
+    }
+
+    #[test]
+    fn write_evicts_cached_attrs() {
+        let host = FakeHost::new(0);
+        let mut scheme = mount(&host);
+        let fd = open(&mut scheme, "file", O_RDWR);
+        let getattrs = |host: &FakeHost| host.state.borrow().count(MsgType::Tgetattr);
+
+        let mut stat = Stat::default();
+        scheme.fstat(fd, &mut stat, &caller()).unwrap();
+        assert_eq!(stat.st_size, 0);
+        // Served from the cache
+        scheme.fstat(fd, &mut stat, &caller()).unwrap();
+        assert_eq!(getattrs(&host), 1);
+
+        let written = scheme.write(fd, b"hello", 0, O_RDWR as u32, &caller());
+        assert_eq!(written, Ok(5));
+        scheme.fstat(fd, &mut stat, &caller()).unwrap();
+        assert_eq!(stat.st_size, 5);
+        assert_eq!(getattrs(&host), 2);
+    }
 }
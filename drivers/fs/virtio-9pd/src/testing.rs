@@ -0,0 +1,248 @@
+//! Fake 9P transports and servers for tests
+
+use std::cell::RefCell;
+use std::collections::BTreeSet;
+
+use anyhow::Result;
+use p9_proto::*;
+use redox_scheme::{CallerCtx, Id};
+
+use crate::client::{Client9p, Timeout, Transport};
+
+/// Transport which hands every request to a closure playing the server
+///
+/// An empty reply stands for a request which timed out.
+pub struct MockTransport<F> {
+    server: F,
+}
+
+impl<F: Fn(&[u8]) -> Vec<u8>> Transport for MockTransport<F> {
+    fn send(&self, request: &[u8], max_len: usize) -> Result<Vec<u8>> {
+        let reply = (self.server)(request);
+        assert!(reply.len() <= max_len);
+        if reply.is_empty() {
+            return Err(Timeout.into());
+        }
+        Ok(reply)
+    }
+}
+
+pub fn mock_client<F: Fn(&[u8]) -> Vec<u8>>(server: F) -> Client9p<MockTransport<F>> {
+    Client9p::new(MockTransport { server }).unwrap()
+}
+
+/// Start a reply of type `typ` to `request`, echoing its tag
+pub fn reply_to(request: &[u8], typ: MsgType) -> MessageBuilder {
+    MessageBuilder::new(typ, u16::from_le_bytes([request[5], request[6]]))
+}
+
+/// A root caller for scheme requests
+pub fn caller() -> CallerCtx {
+    caller_of(0)
+}
+
+/// A root caller for the scheme request with id `request`
+pub fn caller_of(request: u32) -> CallerCtx {
+    CallerCtx {
+        pid: 1,
+        uid: 0,
+        gid: 0,
+        id: Id::from_raw(request),
+    }
+}
+
+/// Linux errno sent for paths which don't exist
+const ENOENT: u32 = 2;
+/// Linux errno sent for unknown fids
+const EBADF: u32 = 9;
+
+pub const ROOT_QID: Qid = Qid {
+    typ: QID_DIR,
+    version: 0,
+    path: 1,
+};
+
+/// In-memory 9P server exporting a root directory with a single regular file called `file`,
+/// and possibly a symlink called `link`
+pub struct FakeHost {
+    pub state: RefCell<HostState>,
+}
+
+#[derive(Default)]
+pub struct HostState {
+    /// Contents of `file`
+    pub file: Vec<u8>,
+    /// Version of `file`, bumped on every write
+    pub version: u32,
+    /// iounit reported when opening `file`
+    pub iounit: u32,
+    /// Types of all requests received, in order
+    pub requests: Vec<MsgType>,
+    /// Sizes of the writes received
+    pub writes: Vec<usize>,
+    /// Fids known to the server
+    pub fids: BTreeSet<u32>,
+    /// Number of upcoming requests which time out instead of being answered
+    pub timeouts: usize,
+    /// Tags of the requests that were flushed
+    pub flushed: Vec<u16>,
+    /// Target of `link`, if it exists
+    pub link: Option<String>,
+}
+
+impl HostState {
+    /// Number of requests of type `typ` received
+    pub fn count(&self, typ: MsgType) -> usize {
+        self.requests.iter().filter(|&&t| t == typ).count()
+    }
+}
+
+impl FakeHost {
+    /// A server the root of which is attached as fid 0
+    pub fn new(iounit: u32) -> Self {
+        Self {
+            state: RefCell::new(HostState {
+                iounit,
+                fids: BTreeSet::from([0]),
+                ..HostState::default()
+            }),
+        }
+    }
+
+    const LINK_QID: Qid = Qid {
+        typ: QID_SYMLINK,
+        version: 0,
+        path: 3,
+    };
+
+    fn file_qid(state: &HostState) -> Qid {
+        Qid {
+            typ: 0,
+            version: state.version,
+            path: 2,
+        }
+    }
+
+    /// Handle `request` and return the reply
+    pub fn serve(&self, request: &[u8]) -> Vec<u8> {
+        let mut state = self.state.borrow_mut();
+        let mut parser = MessageParser::new(request);
+        let header = parser.get_header().unwrap();
+        let typ = MsgType::from_u8(header.typ).unwrap();
+        state.requests.push(typ);
+        if state.timeouts > 0 {
+            state.timeouts -= 1;
+            return Vec::new();
+        }
+
+        // Every request apart from these starts with the fid it operates on
+        if !matches!(typ, MsgType::Tversion | MsgType::Tattach | MsgType::Tflush) {
+            let fid =
+                u32::from_le_bytes(request[Header::SIZE..Header::SIZE + 4].try_into().unwrap());
+            if !state.fids.contains(&fid) {
+                return reply_to(request, MsgType::Rlerror).put_u32(EBADF).finish();
+            }
+        }
+
+        match typ {
+            MsgType::Tversion => {
+                // A new session drops all fids
+                state.fids.clear();
+                reply_to(request, MsgType::Rversion)
+                    .put_u32(parser.get_u32().unwrap())
+                    .put_str("9P2000.L")
+                    .finish()
+            }
+            MsgType::Tattach => {
+                state.fids.insert(parser.get_u32().unwrap());
+                reply_to(request, MsgType::Rattach)
+                    .put_qid(&ROOT_QID)
+                    .finish()
+            }
+            MsgType::Tflush => {
+                state.flushed.push(parser.get_u16().unwrap());
+                reply_to(request, MsgType::Rflush).finish()
+            }
+            MsgType::Twalk => {
+                let (_fid, new_fid) = (parser.get_u32().unwrap(), parser.get_u32().unwrap());
+                let names: Vec<&str> = (0..parser.get_u16().unwrap())
+                    .map(|_| parser.get_str().unwrap())
+                    .collect();
+                let reply = match names[..] {
+                    [] => reply_to(request, MsgType::Rwalk).put_u16(0).finish(),
+                    ["file"] => reply_to(request, MsgType::Rwalk)
+                        .put_u16(1)
+                        .put_qid(&Self::file_qid(&state))
+                        .finish(),
+                    ["link"] if state.link.is_some() => reply_to(request, MsgType::Rwalk)
+                        .put_u16(1)
+                        .put_qid(&Self::LINK_QID)
+                        .finish(),
+                    _ => return reply_to(request, MsgType::Rlerror).put_u32(ENOENT).finish(),
+                };
+                state.fids.insert(new_fid);
+                reply
+            }
+            MsgType::Tlopen => reply_to(request, MsgType::Rlopen)
+                .put_qid(&Self::file_qid(&state))
+                .put_u32(state.iounit)
+                .finish(),
+            MsgType::Tread => {
+                let _fid = parser.get_u32().unwrap();
+                let offset = parser.get_u64().unwrap() as usize;
+                let count = parser.get_u32().unwrap() as usize;
+                let start = offset.min(state.file.len());
+                let end = (offset + count).min(state.file.len());
+                reply_to(request, MsgType::Rread)
+                    .put_data(&state.file[start..end])
+                    .finish()
+            }
+            MsgType::Twrite => {
+                let _fid = parser.get_u32().unwrap();
+                let offset = parser.get_u64().unwrap() as usize;
+                let data = parser.get_data().unwrap();
+                if state.file.len() < offset + data.len() {
+                    state.file.resize(offset + data.len(), 0);
+                }
+                state.file[offset..offset + data.len()].copy_from_slice(data);
+                state.version += 1;
+                state.writes.push(data.len());
+                reply_to(request, MsgType::Rwrite)
+                    .put_u32(data.len() as u32)
+                    .finish()
+            }
+            MsgType::Tgetattr => {
+                let mut reply = reply_to(request, MsgType::Rgetattr)
+                    .put_u64(P9_GETATTR_BASIC)
+                    .put_qid(&Self::file_qid(&state))
+                    .put_u32(0o100644)
+                    .put_u32(0)
+                    .put_u32(0)
+                    .put_u64(1)
+                    .put_u64(0)
+                    .put_u64(state.file.len() as u64);
+                // blksize, blocks, the timestamps, gen and data_version
+                for _ in 0..12 {
+                    reply = reply.put_u64(0);
+                }
+                reply.finish()
+            }
+            MsgType::Tsymlink => {
+                let _dfid = parser.get_u32().unwrap();
+                assert_eq!(parser.get_str().unwrap(), "link");
+                state.link = Some(parser.get_str().unwrap().to_string());
+                reply_to(request, MsgType::Rsymlink)
+                    .put_qid(&Self::LINK_QID)
+                    .finish()
+            }
+            MsgType::Treadlink => reply_to(request, MsgType::Rreadlink)
+                .put_str(state.link.as_deref().unwrap())
+                .finish(),
+            MsgType::Tclunk => {
+                state.fids.remove(&parser.get_u32().unwrap());
+                reply_to(request, MsgType::Rclunk).finish()
+            }
+            _ => panic!("unexpected request {typ}"),
+        }
+    }
+}
@@ -18,15 +18,20 @@ use graphics_ipc::v2::Damage;
 use inputd::{VtEvent, VtEventKind};
 use libredox::Fd;
 use redox_scheme::scheme::SchemeSync;
-use redox_scheme::{CallerCtx, OpenResult, RequestKind, SignalBehavior, Socket};
+use redox_scheme::{CallerCtx, OpenResult, RequestKind, Response, SignalBehavior, Socket};
 use syscall::schemev2::NewFdFlags;
-use syscall::{Error, MapFlags, Result, EAGAIN, EBADF, EINVAL, ENOENT, EOPNOTSUPP};
+use syscall::{
+    Error, EventFlags, MapFlags, Result, EAGAIN, EBADF, EINVAL, ENODEV, ENOENT, EOPNOTSUPP,
+    EVENT_READ,
+};
 
 use crate::objects::{DrmObjectId, DrmObjects};
 use crate::properties::DrmPropertyKind;
 
 pub mod objects;
 pub mod properties;
+#[cfg(test)]
+mod testing;
 
 #[derive(Debug, Copy, Clone)]
 pub struct StandardProperties {
@@ -60,6 +65,12 @@ pub trait GraphicsAdapter: Sized + Debug {
     /// This must be constant for the lifetime of the graphics adapter.
     fn display_count(&self) -> usize;
     fn display_size(&self, display_id: usize) -> (u32, u32);
+    /// Whether a display is currently attached at `display_id`. Disabled displays don't get a
+    /// framebuffer shown.
+    fn display_enabled(&self, display_id: usize) -> bool {
+        let _ = display_id;
+        true
+    }
 
     fn create_dumb_framebuffer(&mut self, width: u32, height: u32) -> Self::Framebuffer;
     fn map_dumb_framebuffer(&mut self, framebuffer: &Self::Framebuffer) -> *mut u8;
@@ -70,6 +81,43 @@ pub trait GraphicsAdapter: Sized + Debug {
     fn create_cursor_framebuffer(&mut self) -> Self::Cursor;
     fn map_cursor_framebuffer(&mut self, cursor: &Self::Cursor) -> *mut u8;
     fn handle_cursor(&mut self, cursor: &CursorPlane<Self::Cursor>, dirty_fb: bool);
+
+    /// Use `framebuffer` as hardware cursor image, for clients of the v2 API.
+    fn set_hw_cursor(
+        &mut self,
+        framebuffer: &Self::Framebuffer,
+        hot_x: i32,
+        hot_y: i32,
+    ) -> Result<()> {
+        let _ = (framebuffer, hot_x, hot_y);
+        Err(Error::new(EOPNOTSUPP))
+    }
+
+    /// Move the hardware cursor set with `set_hw_cursor`.
+    fn move_hw_cursor(&mut self, x: i32, y: i32) -> Result<()> {
+        let _ = (x, y);
+        Err(Error::new(EOPNOTSUPP))
+    }
+
+    /// Handle a driver specific `call` on the v2 handle `handle_id`, for opcodes the generic
+    /// scheme doesn't know. If `metadata[1]` names a framebuffer of the handle, it is passed as
+    /// `framebuffer`.
+    fn driver_call(
+        &mut self,
+        handle_id: usize,
+        framebuffer: Option<&Arc<Self::Framebuffer>>,
+        payload: &mut [u8],
+        metadata: &[u64],
+    ) -> Result<usize> {
+        let _ = (handle_id, framebuffer, payload, metadata);
+        Err(Error::new(EINVAL))
+    }
+
+    /// Release everything `driver_call` created on behalf of the handle `handle_id`, which just
+    /// got closed.
+    fn close_handle(&mut self, handle_id: usize) {
+        let _ = handle_id;
+    }
 }
 
 pub trait Framebuffer {
@@ -97,13 +145,16 @@ pub struct GraphicsScheme<T: GraphicsAdapter> {
     standard_properties: StandardProperties,
     next_id: usize,
     handles: BTreeMap<usize, Handle<T>>,
+    /// Number of times the displays changed since the driver started.
+    hotplug_count: u64,
 
     active_vt: usize,
     vts: HashMap<usize, VtState<T>>,
 }
 
 struct VtState<T: GraphicsAdapter> {
-    display_fbs: Vec<Arc<T::Framebuffer>>,
+    /// The framebuffer shown on each display, `None` while the display is disabled.
+    display_fbs: Vec<Option<Arc<T::Framebuffer>>>,
     cursor_plane: Option<CursorPlane<T::Cursor>>,
 }
 
@@ -112,13 +163,48 @@ enum Handle<T: GraphicsAdapter> {
         vt: usize,
         screen: usize,
     },
+    /// Reading from a v2 handle returns the hotplug counter as a native endian `u64`. It is
+    /// incremented each time the displays change, after which compositors should re-query the
+    /// connectors. The handle becomes readable (`EVENT_READ`) when the counter changed since the
+    /// last read.
     V2 {
         vt: usize,
         next_id: u32,
         fbs: HashMap<u32, Arc<T::Framebuffer>>,
+        events: EventFlags,
+        hotplug_seen: u64,
     },
 }
 
+impl<T: GraphicsAdapter> Handle<T> {
+    /// Subscribes to `flags`, returning the events which are already pending.
+    fn fevent(&mut self, flags: EventFlags, hotplug_count: u64) -> EventFlags {
+        match self {
+            Handle::V1Screen { .. } => EventFlags::empty(),
+            Handle::V2 {
+                events,
+                hotplug_seen,
+                ..
+            } => {
+                *events = flags;
+                if flags.contains(EVENT_READ) && *hotplug_seen != hotplug_count {
+                    EVENT_READ
+                } else {
+                    EventFlags::empty()
+                }
+            }
+        }
+    }
+
+    /// The events to post to this handle when the displays changed.
+    fn hotplug_events(&self) -> EventFlags {
+        match self {
+            Handle::V2 { events, .. } if events.contains(EVENT_READ) => EVENT_READ,
+            _ => EventFlags::empty(),
+        }
+    }
+}
+
 impl<T: GraphicsAdapter> GraphicsScheme<T> {
     pub fn new(mut adapter: T, scheme_name: String) -> Self {
         assert!(scheme_name.starts_with("display"));
@@ -159,6 +245,7 @@ impl<T: GraphicsAdapter> GraphicsScheme<T> {
             standard_properties,
             next_id: 0,
             handles: BTreeMap::new(),
+            hotplug_count: 0,
             active_vt: 0,
             vts: HashMap::new(),
         }
@@ -211,7 +298,9 @@ impl<T: GraphicsAdapter> GraphicsScheme<T> {
                     Self::get_or_create_vt(&mut self.adapter, &mut self.vts, vt_event.vt);
 
                 for (display_id, fb) in vt_state.display_fbs.iter().enumerate() {
-                    Self::update_whole_screen(&mut self.adapter, display_id, fb);
+                    if let Some(fb) = fb {
+                        Self::update_whole_screen(&mut self.adapter, display_id, fb);
+                    }
                 }
 
                 if let Some(cursor_plane) = &vt_state.cursor_plane {
@@ -225,8 +314,57 @@ impl<T: GraphicsAdapter> GraphicsScheme<T> {
         }
     }
 
+    /// Re-probe connectors after the adapter detected a display change, e.g. a hotplug.
     pub fn notify_displays_changed(&mut self) {
-        // FIXME notify clients
+        for connector_id in self.objects.connector_ids().to_vec() {
+            self.adapter.probe_connector(
+                &mut self.objects,
+                &self.standard_properties,
+                connector_id,
+            );
+        }
+
+        // Displays enabled after boot usually come with a different size than the fallback one
+        // their framebuffers were created with. Those that got disabled release theirs.
+        for (&vt, vt_state) in self.vts.iter_mut() {
+            for (display_id, slot) in vt_state.display_fbs.iter_mut().enumerate() {
+                if !self.adapter.display_enabled(display_id) {
+                    *slot = None;
+                    continue;
+                }
+
+                let (width, height) = self.adapter.display_size(display_id);
+                if let Some(fb) = slot {
+                    if fb.width() == width && fb.height() == height {
+                        continue;
+                    }
+                }
+
+                let fb = slot.insert(Arc::new(
+                    self.adapter.create_dumb_framebuffer(width, height),
+                ));
+                if vt == self.active_vt {
+                    Self::update_whole_screen(&mut self.adapter, display_id, fb);
+                }
+            }
+        }
+
+        self.hotplug_count += 1;
+        for (&id, handle) in &self.handles {
+            let events = handle.hotplug_events();
+            if events.is_empty() {
+                continue;
+            }
+            // A handle missing the event is no reason to take down the whole driver
+            match self.socket.write_response(
+                Response::post_fevent(id, events.bits()),
+                SignalBehavior::Restart,
+            ) {
+                Ok(true) => {}
+                Ok(false) => log::error!("driver-graphics: hotplug event for {id} not accepted"),
+                Err(err) => log::error!("driver-graphics: failed to send hotplug event: {err}"),
+            }
+        }
     }
 
     /// Process new scheme requests.
@@ -283,8 +421,14 @@ impl<T: GraphicsAdapter> GraphicsScheme<T> {
         vts.entry(vt).or_insert_with(|| {
             let mut display_fbs = vec![];
             for display_id in 0..adapter.display_count() {
+                if !adapter.display_enabled(display_id) {
+                    display_fbs.push(None);
+                    continue;
+                }
                 let (width, height) = adapter.display_size(display_id);
-                display_fbs.push(Arc::new(adapter.create_dumb_framebuffer(width, height)));
+                display_fbs.push(Some(Arc::new(
+                    adapter.create_dumb_framebuffer(width, height),
+                )));
             }
 
             let cursor_plane = adapter.supports_hw_cursor().then(|| CursorPlane {
@@ -326,6 +470,8 @@ impl<T: GraphicsAdapter> SchemeSync for GraphicsScheme<T> {
                 vt,
                 next_id: 0,
                 fbs: HashMap::new(),
+                events: EventFlags::empty(),
+                hotplug_seen: self.hotplug_count,
             }
         } else {
             let mut parts = path.split('/');
@@ -354,7 +500,9 @@ impl<T: GraphicsAdapter> SchemeSync for GraphicsScheme<T> {
     fn fpath(&mut self, id: usize, buf: &mut [u8], _ctx: &CallerCtx) -> syscall::Result<usize> {
         let path = match self.handles.get(&id).ok_or(Error::new(EBADF))? {
             Handle::V1Screen { vt, screen } => {
-                let framebuffer = &self.vts[vt].display_fbs[*screen];
+                let framebuffer = self.vts[vt].display_fbs[*screen]
+                    .as_ref()
+                    .ok_or(Error::new(ENODEV))?;
                 format!(
                     "{}:{vt}.{screen}/{}/{}",
                     self.scheme_name,
@@ -362,11 +510,7 @@ impl<T: GraphicsAdapter> SchemeSync for GraphicsScheme<T> {
                     framebuffer.height()
                 )
             }
-            Handle::V2 {
-                vt,
-                next_id: _,
-                fbs: _,
-            } => format!("/scheme/{}/v2/{vt}", self.scheme_name),
+            Handle::V2 { vt, .. } => format!("/scheme/{}/v2/{vt}", self.scheme_name),
         };
         buf[..path.len()].copy_from_slice(path.as_bytes());
         Ok(path.len())
@@ -380,11 +524,9 @@ impl<T: GraphicsAdapter> SchemeSync for GraphicsScheme<T> {
                     // flush the framebuffer on the next VT switch anyway
                     return Ok(());
                 }
-                Self::update_whole_screen(
-                    &mut self.adapter,
-                    *screen,
-                    &self.vts[vt].display_fbs[*screen],
-                );
+                if let Some(fb) = &self.vts[vt].display_fbs[*screen] {
+                    Self::update_whole_screen(&mut self.adapter, *screen, fb);
+                }
                 Ok(())
             }
             Handle::V2 { .. } => Err(Error::new(EOPNOTSUPP)),
@@ -399,7 +541,7 @@ impl<T: GraphicsAdapter> SchemeSync for GraphicsScheme<T> {
         _fcntl_flags: u32,
         _ctx: &CallerCtx,
     ) -> Result<usize> {
-        match self.handles.get(&id).ok_or(Error::new(EBADF))? {
+        match self.handles.get_mut(&id).ok_or(Error::new(EBADF))? {
             Handle::V1Screen { .. } => {
                 //Currently read is only used for Orbital to check GPU cursor support
                 //and only expects a buf to pass a 0 or 1 flag
@@ -411,7 +553,13 @@ impl<T: GraphicsAdapter> SchemeSync for GraphicsScheme<T> {
 
                 Ok(1)
             }
-            Handle::V2 { .. } => Err(Error::new(EOPNOTSUPP)),
+            Handle::V2 { hotplug_seen, .. } => {
+                let count = self.hotplug_count.to_ne_bytes();
+                let buf = buf.get_mut(..count.len()).ok_or(Error::new(EINVAL))?;
+                buf.copy_from_slice(&count);
+                *hotplug_seen = self.hotplug_count;
+                Ok(count.len())
+            }
         }
     }
 
@@ -485,8 +633,9 @@ impl<T: GraphicsAdapter> SchemeSync for GraphicsScheme<T> {
                 assert_eq!(buf.len(), std::mem::size_of::<Damage>());
                 let damage = unsafe { *buf.as_ptr().cast::<Damage>() };
 
-                self.adapter
-                    .update_plane(*screen, &vt_state.display_fbs[*screen], damage);
+                if let Some(fb) = &vt_state.display_fbs[*screen] {
+                    self.adapter.update_plane(*screen, fb, damage);
+                }
 
                 Ok(buf.len())
             }
@@ -533,7 +682,9 @@ impl<T: GraphicsAdapter> SchemeSync for GraphicsScheme<T> {
             Handle::V1Screen { .. } => {
                 return Err(Error::new(EOPNOTSUPP));
             }
-            Handle::V2 { vt, next_id, fbs } => match metadata[0] {
+            Handle::V2 {
+                vt, next_id, fbs, ..
+            } => match metadata[0] {
                 ipc::VERSION => ipc::DrmVersion::with(payload, |mut data| {
                     data.set_version_major(1);
                     data.set_version_minor(4);
@@ -629,7 +780,10 @@ impl<T: GraphicsAdapter> SchemeSync for GraphicsScheme<T> {
                     data.set_mm_width(connector.mm_width);
                     data.set_mm_height(connector.mm_width);
                     data.set_subpixel(connector.subpixel as u32);
-                    data.set_encoders_ptr(&[connector.encoder_id.0]);
+                    let encoders = self
+                        .objects
+                        .possible_encoders(DrmObjectId(data.connector_id()))?;
+                    data.set_encoders_ptr(&encoders.iter().map(|id| id.0).collect::<Vec<_>>());
                     let props = self
                         .objects
                         .get_object_properties(DrmObjectId(data.connector_id()))?;
@@ -850,7 +1004,13 @@ impl<T: GraphicsAdapter> SchemeSync for GraphicsScheme<T> {
                         return Err(Error::new(EINVAL));
                     };
 
-                    self.vts.get_mut(vt).unwrap().display_fbs[display_id] = framebuffer.clone();
+                    if !self.adapter.display_enabled(display_id) {
+                        // The plane gets set up again once the display shows up.
+                        return Ok(size_of::<ipc::UpdatePlane>());
+                    }
+
+                    self.vts.get_mut(vt).unwrap().display_fbs[display_id] =
+                        Some(framebuffer.clone());
 
                     if *vt == self.active_vt {
                         self.adapter
@@ -859,11 +1019,59 @@ impl<T: GraphicsAdapter> SchemeSync for GraphicsScheme<T> {
 
                     Ok(size_of::<ipc::UpdatePlane>())
                 }
-                _ => return Err(Error::new(EINVAL)),
+                ipc::SET_CURSOR => {
+                    if payload.len() < size_of::<ipc::SetCursor>() {
+                        return Err(Error::new(EINVAL));
+                    }
+                    let payload = unsafe {
+                        transmute::<&mut [u8; size_of::<ipc::SetCursor>()], &mut ipc::SetCursor>(
+                            payload.as_mut_array().unwrap(),
+                        )
+                    };
+
+                    let Some(framebuffer) = fbs.get(&id_index(payload.fb_id)) else {
+                        return Err(Error::new(EINVAL));
+                    };
+
+                    if *vt == self.active_vt {
+                        self.adapter
+                            .set_hw_cursor(framebuffer, payload.hot_x, payload.hot_y)?;
+                    }
+
+                    Ok(size_of::<ipc::SetCursor>())
+                }
+                ipc::MOVE_CURSOR => {
+                    if payload.len() < size_of::<ipc::MoveCursor>() {
+                        return Err(Error::new(EINVAL));
+                    }
+                    let payload = unsafe {
+                        transmute::<&mut [u8; size_of::<ipc::MoveCursor>()], &mut ipc::MoveCursor>(
+                            payload.as_mut_array().unwrap(),
+                        )
+                    };
+
+                    if *vt == self.active_vt {
+                        self.adapter.move_hw_cursor(payload.x, payload.y)?;
+                    }
+
+                    Ok(size_of::<ipc::MoveCursor>())
+                }
+                _ => {
+                    let framebuffer = metadata
+                        .get(1)
+                        .and_then(|&fb_id| fbs.get(&id_index(fb_id as u32)));
+                    self.adapter.driver_call(id, framebuffer, payload, metadata)
+                }
             },
         }
     }
 
+    fn fevent(&mut self, id: usize, flags: EventFlags, _ctx: &CallerCtx) -> Result<EventFlags> {
+        let hotplug_count = self.hotplug_count;
+        let handle = self.handles.get_mut(&id).ok_or(Error::new(EBADF))?;
+        Ok(handle.fevent(flags, hotplug_count))
+    }
+
     fn mmap_prep(
         &mut self,
         id: usize,
@@ -874,12 +1082,13 @@ impl<T: GraphicsAdapter> SchemeSync for GraphicsScheme<T> {
     ) -> syscall::Result<usize> {
         // log::trace!("KSMSG MMAP {} {:?} {} {}", id, _flags, _offset, _size);
         let (framebuffer, offset) = match self.handles.get(&id).ok_or(Error::new(EINVAL))? {
-            Handle::V1Screen { vt, screen } => (&self.vts[vt].display_fbs[*screen], offset),
-            Handle::V2 {
-                vt: _,
-                next_id: _,
-                fbs,
-            } => (
+            Handle::V1Screen { vt, screen } => (
+                self.vts[vt].display_fbs[*screen]
+                    .as_ref()
+                    .ok_or(Error::new(ENODEV))?,
+                offset,
+            ),
+            Handle::V2 { fbs, .. } => (
                 fbs.get(&((offset as usize / MAP_FAKE_OFFSET_MULTIPLIER) as u32))
                     .ok_or(Error::new(EINVAL))
                     .unwrap(),
@@ -894,6 +1103,7 @@ impl<T: GraphicsAdapter> SchemeSync for GraphicsScheme<T> {
 impl<T: GraphicsAdapter> GraphicsScheme<T> {
     fn on_close(&mut self, id: usize) {
         self.handles.remove(&id);
+        self.adapter.close_handle(id);
     }
 }
 
@@ -930,3 +1140,42 @@ pub fn modeinfo_for_size(width: u32, height: u32) -> drm_mode_modeinfo {
 
     modeinfo
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TestAdapter;
+
+    fn v2_handle() -> Handle<TestAdapter> {
+        Handle::V2 {
+            vt: 1,
+            next_id: 0,
+            fbs: HashMap::new(),
+            events: EventFlags::empty(),
+            hotplug_seen: 0,
+        }
+    }
+
+    #[test]
+    fn display_change_posts_fevent_after_subscribing() {
+        let mut handle = v2_handle();
+        assert_eq!(handle.hotplug_events(), EventFlags::empty());
+
+        // Nothing changed since the handle was opened
+        assert_eq!(handle.fevent(EVENT_READ, 0), EventFlags::empty());
+        assert_eq!(handle.hotplug_events(), EVENT_READ);
+
+        // Subscribing after a change reports it right away
+        assert_eq!(handle.fevent(EVENT_READ, 1), EVENT_READ);
+
+        assert_eq!(handle.fevent(EventFlags::empty(), 1), EventFlags::empty());
+        assert_eq!(handle.hotplug_events(), EventFlags::empty());
+    }
+
+    #[test]
+    fn v1_handles_have_no_hotplug_events() {
+        let mut handle = Handle::<TestAdapter>::V1Screen { vt: 1, screen: 0 };
+        assert_eq!(handle.fevent(EVENT_READ, 1), EventFlags::empty());
+        assert_eq!(handle.hotplug_events(), EventFlags::empty());
+    }
+}
@@ -13,6 +13,8 @@ pub struct DrmObjects<T: GraphicsAdapter> {
     next_id: DrmObjectId,
     connectors: Vec<DrmObjectId>,
     encoders: Vec<DrmObjectId>,
+    /// The encoders which can drive each connector.
+    possible_encoders: HashMap<DrmObjectId, Vec<DrmObjectId>>,
     pub(crate) objects: HashMap<DrmObjectId, DrmObjectData>,
     _marker: PhantomData<T>,
 }
@@ -23,6 +25,7 @@ impl<T: GraphicsAdapter> DrmObjects<T> {
             next_id: DrmObjectId(1),
             connectors: vec![],
             encoders: vec![],
+            possible_encoders: HashMap::new(),
             objects: HashMap::new(),
             _marker: PhantomData,
         }
@@ -87,6 +90,7 @@ impl<T: GraphicsAdapter> DrmObjects<T> {
         self.encoders.push(encoder_id);
 
         self.get_connector_mut(connector_id).unwrap().encoder_id = encoder_id;
+        self.bind_encoder(connector_id, encoder_id).unwrap();
 
         connector_id
     }
@@ -125,6 +129,27 @@ impl<T: GraphicsAdapter> DrmObjects<T> {
     pub fn get_encoder_mut(&mut self, id: DrmObjectId) -> Result<&mut DrmEncoder> {
         self.get_mut(id)
     }
+
+    /// Record that `encoder` can drive `connector`.
+    pub fn bind_encoder(&mut self, connector: DrmObjectId, encoder: DrmObjectId) -> Result<()> {
+        self.get_connector(connector)?;
+        self.get_encoder(encoder)?;
+
+        let encoders = self.possible_encoders.entry(connector).or_default();
+        if !encoders.contains(&encoder) {
+            encoders.push(encoder);
+        }
+        Ok(())
+    }
+
+    /// The encoders which can drive `connector`.
+    pub fn possible_encoders(&self, connector: DrmObjectId) -> Result<&[DrmObjectId]> {
+        self.get_connector(connector)?;
+        Ok(self
+            .possible_encoders
+            .get(&connector)
+            .map_or(&[], |encoders| encoders))
+    }
 }
 
 #[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
@@ -201,3 +226,47 @@ impl DrmObject for DrmEncoder {
         DRM_MODE_OBJECT_ENCODER
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use syscall::EINVAL;
+
+    use super::*;
+    use crate::testing::TestAdapter;
+
+    #[test]
+    fn bind_encoder() {
+        let mut objects = DrmObjects::<TestAdapter>::new();
+        let first = objects.add_connector(());
+        let second = objects.add_connector(());
+        let first_encoder = objects.get_connector(first).unwrap().encoder_id;
+        let second_encoder = objects.get_connector(second).unwrap().encoder_id;
+        assert_eq!(objects.possible_encoders(first).unwrap(), &[first_encoder]);
+
+        objects.bind_encoder(first, second_encoder).unwrap();
+        objects.bind_encoder(first, second_encoder).unwrap();
+        assert_eq!(
+            objects.possible_encoders(first).unwrap(),
+            &[first_encoder, second_encoder]
+        );
+        assert_eq!(
+            objects.possible_encoders(second).unwrap(),
+            &[second_encoder]
+        );
+    }
+
+    #[test]
+    fn bind_encoder_type_mismatch() {
+        let mut objects = DrmObjects::<TestAdapter>::new();
+        let connector = objects.add_connector(());
+        let encoder = objects.get_connector(connector).unwrap().encoder_id;
+
+        let err = objects.bind_encoder(encoder, connector).unwrap_err();
+        assert_eq!(err.errno, EINVAL);
+        let err = objects.bind_encoder(connector, connector).unwrap_err();
+        assert_eq!(err.errno, EINVAL);
+        let err = objects.possible_encoders(encoder).unwrap_err();
+        assert_eq!(err.errno, EINVAL);
+        assert_eq!(objects.possible_encoders(connector).unwrap(), &[encoder]);
+    }
+}
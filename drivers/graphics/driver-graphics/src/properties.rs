@@ -1,8 +1,9 @@
 use std::ffi::c_char;
 use std::fmt::Debug;
+use std::mem;
 
 use drm_sys::{DRM_MODE_OBJECT_BLOB, DRM_MODE_OBJECT_PROPERTY, DRM_PROP_NAME_LEN};
-use syscall::{Error, Result, EINVAL};
+use syscall::{Error, Result, EBUSY, EINVAL};
 
 use crate::objects::{DrmObject, DrmObjectId, DrmObjects};
 use crate::GraphicsAdapter;
@@ -32,10 +33,11 @@ impl<T: GraphicsAdapter> DrmObjects<T> {
             DrmPropertyKind::Blob => {}
             DrmPropertyKind::Bitmask(bitmask_flags) => {
                 // FIXME check overlapping flag numbers
-                for (flag_name, _) in bitmask_flags {
+                for &(flag_name, bit) in bitmask_flags {
                     if flag_name.len() > DRM_PROP_NAME_LEN as usize {
                         panic!("Property bitflag name {flag_name} is too long");
                     }
+                    assert!(bit < 64, "Property bitflag {flag_name} is out of range");
                 }
             }
             DrmPropertyKind::Object => {}
@@ -59,34 +61,129 @@ impl<T: GraphicsAdapter> DrmObjects<T> {
         self.get(id)
     }
 
-    pub fn add_object_property(&mut self, object: DrmObjectId, property: DrmObjectId, value: u64) {
-        let object = self.objects.get_mut(&object).unwrap();
-        // FIXME validate property uniqueness and value
-        object.properties.push((property, value));
+    pub fn add_object_property(
+        &mut self,
+        object: DrmObjectId,
+        property: DrmObjectId,
+        value: u64,
+    ) -> Result<()> {
+        let data = self.objects.get(&object).ok_or(Error::new(EINVAL))?;
+        if data.properties.iter().any(|&(prop, _)| prop == property) {
+            return Err(Error::new(EINVAL));
+        }
+        self.check_property_value(property, value)?;
+
+        let data = self.objects.get_mut(&object).unwrap();
+        data.properties.push((property, value));
+        self.move_blob_ref(property, 0, value);
+        Ok(())
     }
 
-    pub fn set_object_property(&mut self, object: DrmObjectId, property: DrmObjectId, value: u64) {
-        let object = self.objects.get_mut(&object).unwrap();
-        // FIXME validate property existence and value
-        for (prop, val) in object.properties.iter_mut() {
-            if *prop == property {
-                *val = value;
+    pub fn set_object_property(
+        &mut self,
+        object: DrmObjectId,
+        property: DrmObjectId,
+        value: u64,
+    ) -> Result<()> {
+        let data = self.objects.get(&object).ok_or(Error::new(EINVAL))?;
+        let index = data
+            .properties
+            .iter()
+            .position(|&(prop, _)| prop == property)
+            .ok_or(Error::new(EINVAL))?;
+        self.check_property_value(property, value)?;
+
+        let data = self.objects.get_mut(&object).unwrap();
+        let old_value = mem::replace(&mut data.properties[index].1, value);
+        self.move_blob_ref(property, old_value, value);
+        Ok(())
+    }
+
+    /// Checks that `value` is allowed by the kind of `property`.
+    fn check_property_value(&self, property: DrmObjectId, value: u64) -> Result<()> {
+        let valid = match &self.get_property(property)?.kind {
+            &DrmPropertyKind::Range(start, end) => (start..=end).contains(&value),
+            DrmPropertyKind::Enum(variants) => {
+                variants.iter().any(|&(_, variant)| variant == value)
+            }
+            DrmPropertyKind::Blob => {
+                value == 0
+                    || object_id_for_value(value).is_some_and(|id| self.get::<DrmBlob>(id).is_ok())
             }
+            DrmPropertyKind::Bitmask(bitmask_flags) => {
+                let mask = bitmask_flags
+                    .iter()
+                    .fold(0u64, |mask, &(_, bit)| mask | 1 << bit);
+                value & !mask == 0
+            }
+            DrmPropertyKind::Object => {
+                value == 0
+                    || object_id_for_value(value).is_some_and(|id| self.objects.contains_key(&id))
+            }
+            &DrmPropertyKind::SignedRange(start, end) => (start..=end).contains(&(value as i64)),
+        };
+
+        if valid {
+            Ok(())
+        } else {
+            Err(Error::new(EINVAL))
+        }
+    }
+
+    /// Moves a reference held by a blob property from the `old` to the `new` blob.
+    ///
+    /// Blob property values are blob ids, with 0 meaning that no blob is set.
+    fn move_blob_ref(&mut self, property: DrmObjectId, old: u64, new: u64) {
+        let is_blob = matches!(
+            self.get_property(property),
+            Ok(DrmProperty {
+                kind: DrmPropertyKind::Blob,
+                ..
+            })
+        );
+        if !is_blob || old == new {
+            return;
+        }
+
+        if let Some(blob) = self.blob_for_value(old) {
+            blob.refs -= 1;
+        }
+        if let Some(blob) = self.blob_for_value(new) {
+            blob.refs += 1;
         }
     }
 
+    fn blob_for_value(&mut self, value: u64) -> Option<&mut DrmBlob> {
+        self.get_mut::<DrmBlob>(object_id_for_value(value)?).ok()
+    }
+
     pub fn get_object_properties(&self, id: DrmObjectId) -> Result<&[(DrmObjectId, u64)]> {
         let object = self.objects.get(&id).ok_or(Error::new(EINVAL))?;
         Ok(&object.properties)
     }
 
     pub fn add_blob(&mut self, data: Vec<u8>) -> DrmObjectId {
-        self.add(DrmBlob { data })
+        self.add(DrmBlob { data, refs: 0 })
     }
 
     pub fn get_blob(&self, id: DrmObjectId) -> Result<&[u8]> {
         Ok(&self.get::<DrmBlob>(id)?.data)
     }
+
+    /// Destroy a blob which is no longer used.
+    ///
+    /// Fails with `EBUSY` while the blob is still the value of an object property.
+    pub fn destroy_blob(&mut self, id: DrmObjectId) -> Result<()> {
+        if self.get::<DrmBlob>(id)?.refs > 0 {
+            return Err(Error::new(EBUSY));
+        }
+        self.objects.remove(&id);
+        Ok(())
+    }
+}
+
+fn object_id_for_value(value: u64) -> Option<DrmObjectId> {
+    Some(DrmObjectId(u32::try_from(value).ok()?))
 }
 
 #[derive(Debug)]
@@ -102,6 +199,7 @@ pub enum DrmPropertyKind {
     Range(u64, u64),
     Enum(Vec<(&'static str, u64)>),
     Blob,
+    /// Flag names with their bit number.
     Bitmask(Vec<(&'static str, u64)>),
     Object,
     SignedRange(i64, i64),
@@ -116,6 +214,8 @@ impl DrmObject for DrmProperty {
 #[derive(Debug)]
 pub struct DrmBlob {
     data: Vec<u8>,
+    /// Number of object properties which have this blob as value.
+    refs: usize,
 }
 
 impl DrmObject for DrmBlob {
@@ -123,3 +223,178 @@ impl DrmObject for DrmBlob {
         DRM_MODE_OBJECT_BLOB
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use syscall::{EBUSY, EINVAL};
+
+    use super::*;
+    use crate::testing::TestAdapter;
+
+    #[test]
+    fn destroy_blob_reclaims_objects() {
+        let mut objects = DrmObjects::<TestAdapter>::new();
+        let baseline = objects.objects.len();
+
+        let blobs = (0..1000)
+            .map(|i| objects.add_blob(vec![i as u8; 16]))
+            .collect::<Vec<_>>();
+        assert_eq!(objects.objects.len(), baseline + 1000);
+
+        for blob in blobs {
+            objects.destroy_blob(blob).unwrap();
+        }
+        assert_eq!(objects.objects.len(), baseline);
+    }
+
+    #[test]
+    fn destroy_blob_rejects_other_objects() {
+        let mut objects = DrmObjects::<TestAdapter>::new();
+        let property = objects.add_property("EDID", true, false, DrmPropertyKind::Blob);
+
+        let err = objects.destroy_blob(property).unwrap_err();
+        assert_eq!(err.errno, EINVAL);
+        assert!(objects.get_property(property).is_ok());
+    }
+
+    #[test]
+    fn destroy_blob_referenced_by_property() {
+        let mut objects = DrmObjects::<TestAdapter>::new();
+        let property = objects.add_property("EDID", true, false, DrmPropertyKind::Blob);
+        let connector = objects.add_connector(());
+
+        let first = objects.add_blob(vec![1]);
+        objects
+            .add_object_property(connector, property, first.into())
+            .unwrap();
+        assert_eq!(objects.destroy_blob(first).unwrap_err().errno, EBUSY);
+
+        let second = objects.add_blob(vec![2]);
+        objects
+            .set_object_property(connector, property, second.into())
+            .unwrap();
+        objects.destroy_blob(first).unwrap();
+        assert_eq!(objects.destroy_blob(second).unwrap_err().errno, EBUSY);
+
+        objects.set_object_property(connector, property, 0).unwrap();
+        objects.destroy_blob(second).unwrap();
+    }
+
+    fn set_errno(kind: DrmPropertyKind, initial: u64, value: u64) -> Option<i32> {
+        let mut objects = DrmObjects::<TestAdapter>::new();
+        let property = objects.add_property("test", false, false, kind);
+        let connector = objects.add_connector(());
+        objects
+            .add_object_property(connector, property, initial)
+            .unwrap();
+
+        let result = objects.set_object_property(connector, property, value);
+        assert_eq!(
+            objects.get_object_properties(connector).unwrap(),
+            &[(property, if result.is_ok() { value } else { initial })]
+        );
+        result.err().map(|err| err.errno)
+    }
+
+    #[test]
+    fn set_unattached_property() {
+        let mut objects = DrmObjects::<TestAdapter>::new();
+        let property = objects.add_property("test", false, false, DrmPropertyKind::Range(0, 10));
+        let connector = objects.add_connector(());
+
+        let err = objects
+            .set_object_property(connector, property, 1)
+            .unwrap_err();
+        assert_eq!(err.errno, EINVAL);
+    }
+
+    #[test]
+    fn set_range_property() {
+        assert_eq!(set_errno(DrmPropertyKind::Range(1, 10), 1, 10), None);
+        assert_eq!(set_errno(DrmPropertyKind::Range(1, 10), 1, 0), Some(EINVAL));
+        assert_eq!(
+            set_errno(DrmPropertyKind::Range(1, 10), 1, 11),
+            Some(EINVAL)
+        );
+    }
+
+    #[test]
+    fn set_signed_range_property() {
+        let kind = || DrmPropertyKind::SignedRange(-5, 5);
+        assert_eq!(set_errno(kind(), 0, -5i64 as u64), None);
+        assert_eq!(set_errno(kind(), 0, -6i64 as u64), Some(EINVAL));
+        assert_eq!(set_errno(kind(), 0, 6), Some(EINVAL));
+    }
+
+    #[test]
+    fn set_enum_property() {
+        let kind = || DrmPropertyKind::Enum(vec![("On", 0), ("Off", 3)]);
+        assert_eq!(set_errno(kind(), 0, 3), None);
+        assert_eq!(set_errno(kind(), 0, 1), Some(EINVAL));
+    }
+
+    #[test]
+    fn set_bitmask_property() {
+        let kind = || DrmPropertyKind::Bitmask(vec![("A", 0), ("B", 2)]);
+        assert_eq!(set_errno(kind(), 0, 0b101), None);
+        assert_eq!(set_errno(kind(), 0, 0b010), Some(EINVAL));
+        assert_eq!(set_errno(kind(), 0, 1 << 63), Some(EINVAL));
+    }
+
+    #[test]
+    fn set_blob_property() {
+        assert_eq!(set_errno(DrmPropertyKind::Blob, 0, 0), None);
+        // The property object itself is not a blob.
+        assert_eq!(set_errno(DrmPropertyKind::Blob, 0, 1), Some(EINVAL));
+        assert_eq!(set_errno(DrmPropertyKind::Blob, 0, 1000), Some(EINVAL));
+    }
+
+    #[test]
+    fn set_object_property() {
+        assert_eq!(set_errno(DrmPropertyKind::Object, 0, 1), None);
+        assert_eq!(set_errno(DrmPropertyKind::Object, 0, 1000), Some(EINVAL));
+        assert_eq!(
+            set_errno(DrmPropertyKind::Object, 0, u64::MAX),
+            Some(EINVAL)
+        );
+    }
+
+    #[test]
+    fn unallocated_object() {
+        let mut objects = DrmObjects::<TestAdapter>::new();
+        let property = objects.add_property("test", false, false, DrmPropertyKind::Range(0, 10));
+        let bogus = DrmObjectId(1000);
+
+        let err = objects.add_object_property(bogus, property, 1).unwrap_err();
+        assert_eq!(err.errno, EINVAL);
+        let err = objects.set_object_property(bogus, property, 1).unwrap_err();
+        assert_eq!(err.errno, EINVAL);
+
+        let connector = objects.add_connector(());
+        let err = objects
+            .add_object_property(connector, bogus, 1)
+            .unwrap_err();
+        assert_eq!(err.errno, EINVAL);
+        let err = objects
+            .set_object_property(connector, bogus, 1)
+            .unwrap_err();
+        assert_eq!(err.errno, EINVAL);
+    }
+
+    #[test]
+    fn add_object_property_twice() {
+        let mut objects = DrmObjects::<TestAdapter>::new();
+        let property = objects.add_property("test", false, false, DrmPropertyKind::Range(0, 10));
+        let connector = objects.add_connector(());
+
+        objects.add_object_property(connector, property, 1).unwrap();
+        let err = objects
+            .add_object_property(connector, property, 2)
+            .unwrap_err();
+        assert_eq!(err.errno, EINVAL);
+        assert_eq!(
+            objects.get_object_properties(connector).unwrap(),
+            &[(property, 1)]
+        );
+    }
+}
@@ -0,0 +1,98 @@
+//! A graphics adapter without any hardware behind it, for testing the object model.
+
+use graphics_ipc::v2::Damage;
+use syscall::{Error, Result, EOPNOTSUPP};
+
+use crate::objects::{DrmObjectId, DrmObjects};
+use crate::{CursorFramebuffer, CursorPlane, Framebuffer, GraphicsAdapter, StandardProperties};
+
+#[derive(Debug)]
+pub(crate) struct TestAdapter;
+
+pub(crate) struct TestFramebuffer;
+
+impl Framebuffer for TestFramebuffer {
+    fn width(&self) -> u32 {
+        0
+    }
+
+    fn height(&self) -> u32 {
+        0
+    }
+}
+
+pub(crate) struct TestCursor;
+
+impl CursorFramebuffer for TestCursor {}
+
+impl GraphicsAdapter for TestAdapter {
+    type Connector = ();
+
+    type Framebuffer = TestFramebuffer;
+    type Cursor = TestCursor;
+
+    fn name(&self) -> &'static [u8] {
+        b"test"
+    }
+
+    fn desc(&self) -> &'static [u8] {
+        b"Test adapter"
+    }
+
+    fn init(&mut self, _objects: &mut DrmObjects<Self>, _standard_properties: &StandardProperties) {
+    }
+
+    fn get_cap(&self, _cap: u32) -> Result<u64> {
+        Err(Error::new(EOPNOTSUPP))
+    }
+
+    fn set_client_cap(&self, _cap: u32, _value: u64) -> Result<()> {
+        Err(Error::new(EOPNOTSUPP))
+    }
+
+    fn probe_connector(
+        &mut self,
+        _objects: &mut DrmObjects<Self>,
+        _standard_properties: &StandardProperties,
+        _id: DrmObjectId,
+    ) {
+    }
+
+    fn display_count(&self) -> usize {
+        0
+    }
+
+    fn display_size(&self, _display_id: usize) -> (u32, u32) {
+        (0, 0)
+    }
+
+    fn create_dumb_framebuffer(&mut self, _width: u32, _height: u32) -> Self::Framebuffer {
+        TestFramebuffer
+    }
+
+    fn map_dumb_framebuffer(&mut self, _framebuffer: &Self::Framebuffer) -> *mut u8 {
+        std::ptr::null_mut()
+    }
+
+    fn update_plane(
+        &mut self,
+        _display_id: usize,
+        _framebuffer: &Self::Framebuffer,
+        _damage: Damage,
+    ) {
+    }
+
+    fn supports_hw_cursor(&self) -> bool {
+        false
+    }
+
+    fn create_cursor_framebuffer(&mut self) -> Self::Cursor {
+        TestCursor
+    }
+
+    fn map_cursor_framebuffer(&mut self, _cursor: &Self::Cursor) -> *mut u8 {
+        std::ptr::null_mut()
+    }
+
+    fn handle_cursor(&mut self, _cursor: &CursorPlane<Self::Cursor>, _dirty_fb: bool) {}
+}
@@ -84,6 +84,28 @@ impl V2GraphicsHandle {
         }
         Ok(())
     }
+
+    /// Use the framebuffer `fb_id` as hardware cursor image, with its hotspot at `hot_x`/`hot_y`.
+    pub fn set_cursor(&self, fb_id: u32, hot_x: i32, hot_y: i32) -> io::Result<()> {
+        let mut cmd = ipc::SetCursor {
+            fb_id,
+            hot_x,
+            hot_y,
+        };
+        unsafe {
+            sys_call(&self.file, &mut cmd, 0, &[ipc::SET_CURSOR, 0, 0])?;
+        }
+        Ok(())
+    }
+
+    /// Move the hardware cursor hotspot to `x`/`y`.
+    pub fn move_cursor(&self, x: i32, y: i32) -> io::Result<()> {
+        let mut cmd = ipc::MoveCursor { x, y };
+        unsafe {
+            sys_call(&self.file, &mut cmd, 0, &[ipc::MOVE_CURSOR, 0, 0])?;
+        }
+        Ok(())
+    }
 }
 
 pub mod ipc {
@@ -99,4 +121,19 @@ pub mod ipc {
         pub fb_id: u32,
         pub damage: Damage,
     }
+
+    pub const SET_CURSOR: u64 = 7;
+    #[repr(C, packed)]
+    pub struct SetCursor {
+        pub fb_id: u32,
+        pub hot_x: i32,
+        pub hot_y: i32,
+    }
+
+    pub const MOVE_CURSOR: u64 = 8;
+    #[repr(C, packed)]
+    pub struct MoveCursor {
+        pub x: i32,
+        pub y: i32,
+    }
 }
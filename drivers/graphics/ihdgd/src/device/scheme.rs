@@ -44,11 +44,9 @@ impl GraphicsAdapter for Device {
         // FIXME enumerate actual connectors
         for (framebuffer_id, _) in self.framebuffers.iter().enumerate() {
             let connector = objects.add_connector(Connector { framebuffer_id });
-            objects.add_object_property(
-                connector,
-                standard_properties.dpms,
-                DRM_MODE_DPMS_ON.into(),
-            );
+            objects
+                .add_object_property(connector, standard_properties.dpms, DRM_MODE_DPMS_ON.into())
+                .unwrap();
         }
     }
 
@@ -47,11 +47,9 @@ impl GraphicsAdapter for FbAdapter {
                 width: framebuffer.width as u32,
                 height: framebuffer.height as u32,
             });
-            objects.add_object_property(
-                connector,
-                standard_properties.dpms,
-                DRM_MODE_DPMS_ON.into(),
-            );
+            objects
+                .add_object_property(connector, standard_properties.dpms, DRM_MODE_DPMS_ON.into())
+                .unwrap();
         }
     }
 
@@ -0,0 +1,41 @@
+//! The virtio-gpu specific `call` ABI of v2 display handles.
+//!
+//! This gives compositors direct access to 2D host resources, so they can flush partial
+//! rectangles instead of transferring the whole framebuffer on every update. The opcode is passed
+//! in `metadata[0]` and the command struct as payload. The opcodes are above the ones used by
+//! `graphics_ipc::v2::ipc`, so they never clash with the generic DRM interface.
+
+use graphics_ipc::v2::Damage;
+
+/// Create a 2D host resource. The id of the new resource is written back into `resource_id`.
+pub const CREATE_RESOURCE: u64 = 0x1000;
+#[repr(C, packed)]
+pub struct CreateResource {
+    pub width: u32,
+    pub height: u32,
+    pub resource_id: u32,
+}
+
+/// Use the pages of the dumb framebuffer passed in `metadata[1]` as backing storage of the
+/// resource. The framebuffer must have the same size as the resource.
+pub const ATTACH_BACKING: u64 = 0x1001;
+#[repr(C, packed)]
+pub struct AttachBacking {
+    pub resource_id: u32,
+}
+
+/// Transfer `rect` of the backing storage to the host and flush it to every scanout showing
+/// the resource.
+pub const FLUSH: u64 = 0x1002;
+#[repr(C, packed)]
+pub struct Flush {
+    pub resource_id: u32,
+    pub rect: Damage,
+}
+
+/// Destroy the resource. The backing framebuffer is released, but stays valid for the client.
+pub const UNREF: u64 = 0x1003;
+#[repr(C, packed)]
+pub struct Unref {
+    pub resource_id: u32,
+}
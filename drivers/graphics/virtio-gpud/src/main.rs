@@ -28,6 +28,7 @@ use pcid_interface::PciFunctionHandle;
 use virtio_core::utils::VolatileCell;
 use virtio_core::MSIX_PRIMARY_VECTOR;
 
+mod call;
 mod scheme;
 
 //const VIRTIO_GPU_F_VIRGL: u32 = 0;
@@ -499,8 +500,11 @@ fn deamon(deamon: daemon::Daemon, mut pcid_handle: PciFunctionHandle) -> anyhow:
     let device = DEVICE.try_call_once(|| virtio_core::probe_device(&mut pcid_handle))?;
     let config = unsafe { &mut *(device.device_space as *mut GpuConfig) };
 
-    // Negotiate features (EDID disabled for now)
-    let has_edid = false;
+    // Negotiate features
+    let has_edid = device.transport.check_device_feature(VIRTIO_GPU_F_EDID);
+    if has_edid {
+        device.transport.ack_driver_feature(VIRTIO_GPU_F_EDID);
+    }
     device.transport.finalize_features();
 
     // Queue for sending control commands
@@ -538,13 +542,27 @@ fn deamon(deamon: daemon::Daemon, mut pcid_handle: PciFunctionHandle) -> anyhow:
 
     // Process any initial scheme requests
     let _ = scheme.tick();
+    scheme.adapter_mut().flush_damage();
 
     // Use a polling loop for scheme requests
     // This is a workaround for event notification issues on aarch64 where
     // the kernel event queue doesn't reliably deliver scheme socket notifications
     loop {
+        // Displays were (un)plugged or resized
+        if scheme.adapter().config.events_read.get() & VIRTIO_GPU_EVENT_DISPLAY != 0 {
+            scheme
+                .adapter_mut()
+                .config
+                .events_clear
+                .set(VIRTIO_GPU_EVENT_DISPLAY);
+
+            futures::executor::block_on(scheme.adapter_mut().update_displays())?;
+            scheme.notify_displays_changed();
+        }
+
         // Poll scheme for any pending requests
         let _ = scheme.tick();
+        scheme.adapter_mut().flush_damage();
 
         // Sleep to avoid busy-waiting (10ms)
         std::thread::sleep(std::time::Duration::from_millis(10));
@@ -1,3 +1,4 @@
+use std::collections::HashMap;
 use std::fmt;
 use std::sync::Arc;
 
@@ -12,13 +13,22 @@ use graphics_ipc::v1::Damage;
 use graphics_ipc::v2::ipc::{DRM_CAP_DUMB_BUFFER, DRM_CLIENT_CAP_CURSOR_PLANE_HOTSPOT};
 use inputd::DisplayHandle;
 
-use syscall::{EINVAL, PAGE_SIZE};
+use syscall::{EINVAL, EIO, PAGE_SIZE};
 
 use virtio_core::spec::{Buffer, ChainBuilder, DescriptorFlags};
 use virtio_core::transport::{Error, Queue, Transport};
 
 use crate::*;
 
+/// Reinterpret the payload of a `call` as the command struct `T`.
+fn call_payload<T>(payload: &mut [u8]) -> syscall::Result<&mut T> {
+    if payload.len() < size_of::<T>() {
+        return Err(syscall::Error::new(EINVAL));
+    }
+    // SAFETY: The command structs are packed and only hold integers.
+    Ok(unsafe { &mut *payload.as_mut_ptr().cast::<T>() })
+}
+
 impl Into<GpuRect> for Damage {
     fn into(self) -> GpuRect {
         GpuRect {
@@ -33,6 +43,8 @@ impl Into<GpuRect> for Damage {
 #[derive(Debug)]
 pub struct VirtGpuConnector {
     display_id: u32,
+    /// The blob holding the EDID of the display, if any was probed.
+    edid_blob: Option<DrmObjectId>,
 }
 
 pub struct VirtGpuFramebuffer<'a> {
@@ -78,6 +90,27 @@ pub struct VirtGpuCursor {
 
 impl CursorFramebuffer for VirtGpuCursor {}
 
+/// A 2D host resource created through the `call` ABI.
+struct CallResource<'a> {
+    id: ResourceId,
+    /// The scheme handle that created the resource and may use it.
+    owner: usize,
+    width: u32,
+    height: u32,
+    /// The framebuffer whose pages are attached as backing storage, kept alive while attached.
+    backing: Option<Arc<VirtGpuFramebuffer<'a>>>,
+}
+
+/// A framebuffer update of a display that hasn't been sent to the device yet.
+#[derive(Debug, Clone, Copy)]
+struct PendingUpdate {
+    resource: ResourceId,
+    width: u32,
+    height: u32,
+    /// The part of the framebuffer that changed, already clipped to its size.
+    damage: Damage,
+}
+
 #[derive(Debug, Clone)]
 pub struct Display {
     enabled: bool,
@@ -85,6 +118,39 @@ pub struct Display {
     height: u32,
     edid: Vec<u8>,
     active_resource: Option<ResourceId>,
+    /// Damage accumulated by `update_plane` until the next `flush_damage`.
+    pending: Option<PendingUpdate>,
+}
+
+/// Extracts the native resolution (the preferred timing) from a base EDID block.
+///
+/// Returns `None` if the block is truncated, has an invalid header or checksum, or doesn't
+/// start with a detailed timing descriptor.
+fn parse_edid(blob: &[u8]) -> Option<(u32, u32)> {
+    const EDID_HEADER: [u8; 8] = [0x00, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x00];
+
+    let block = blob.get(..128)?;
+    if block[..8] != EDID_HEADER {
+        return None;
+    }
+    if block.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte)) != 0 {
+        return None;
+    }
+
+    // The first detailed timing descriptor holds the preferred timing.
+    let timing = &block[54..72];
+    // A zero pixel clock means this is a display descriptor instead.
+    if timing[0] == 0 && timing[1] == 0 {
+        return None;
+    }
+
+    let width = u32::from(timing[2]) | (u32::from(timing[4] & 0xf0) << 4);
+    let height = u32::from(timing[5]) | (u32::from(timing[7] & 0xf0) << 4);
+    if width == 0 || height == 0 {
+        return None;
+    }
+
+    Some((width, height))
 }
 
 pub struct VirtGpuAdapter<'a> {
@@ -94,6 +160,11 @@ pub struct VirtGpuAdapter<'a> {
     transport: Arc<dyn Transport>,
     has_edid: bool,
     displays: Vec<Display>,
+    /// Last position of the cursor hotspot, which `UpdateCursor` needs to be given again.
+    cursor_pos: (i32, i32),
+    /// Resources created through the `call` ABI, by resource id. They are unreferenced when
+    /// the handle that created them is closed.
+    call_resources: HashMap<u32, CallResource<'a>>,
 }
 
 impl<'a> fmt::Debug for VirtGpuAdapter<'a> {
@@ -117,6 +188,7 @@ impl VirtGpuAdapter<'_> {
                 height: 0,
                 edid: vec![],
                 active_resource: None,
+                pending: None,
             },
         );
         for (i, info) in raw_displays.iter().enumerate() {
@@ -126,8 +198,18 @@ impl VirtGpuAdapter<'_> {
                 info.rect.height
             );
 
+            let was_enabled = self.displays[i].enabled;
             self.displays[i].enabled = info.enabled != 0;
 
+            if was_enabled && !self.displays[i].enabled {
+                log::info!("virtio-gpu: display {i} disabled");
+                self.disable_scanout(i).await?;
+            } else if !was_enabled && self.displays[i].enabled {
+                log::info!("virtio-gpu: display {i} enabled");
+                // Make sure the next update_plane attaches a framebuffer again.
+                self.displays[i].active_resource = None;
+            }
+
             if info.rect.width == 0 || info.rect.height == 0 {
                 // QEMU gives all displays other than the first a zero width and height, but trying
                 // to attach a zero sized framebuffer to the display will result an error, so
@@ -141,13 +223,91 @@ impl VirtGpuAdapter<'_> {
 
             if self.has_edid {
                 let edid = self.get_edid(i as u32).await?;
-                self.displays[i].edid = edid.edid[..edid.size as usize].to_vec();
+                let blob = &edid.edid[..(edid.size as usize).min(edid.edid.len())];
+
+                match parse_edid(blob) {
+                    Some((width, height)) => {
+                        log::info!("virtio-gpu: display {i} native resolution {width}x{height}px");
+                        self.displays[i].width = width;
+                        self.displays[i].height = height;
+                        self.displays[i].edid = blob.to_vec();
+                    }
+                    None => {
+                        log::warn!("virtio-gpu: display {i} has an invalid EDID, ignoring it");
+                        self.displays[i].edid = vec![];
+                    }
+                }
             }
         }
 
         Ok(())
     }
 
+    /// Send the framebuffer updates accumulated by `update_plane` to the device.
+    ///
+    /// Only the damaged part of each framebuffer is transferred to the host and flushed, so a
+    /// few changed lines don't cost a transfer of the whole screen.
+    pub fn flush_damage(&mut self) {
+        for display_id in 0..self.displays.len() {
+            let Some(update) = self.displays[display_id].pending.take() else {
+                continue;
+            };
+            let rect: GpuRect = update.damage.into();
+            let damaged = rect.width != 0 && rect.height != 0;
+
+            futures::executor::block_on(async {
+                if damaged {
+                    // The backing storage is laid out like the resource, so this is where the
+                    // damaged rect starts.
+                    let offset =
+                        (u64::from(rect.y) * u64::from(update.width) + u64::from(rect.x)) * 4;
+                    let req = Dma::new(XferToHost2d::new(update.resource, rect, offset)).unwrap();
+                    let header = self.send_request(req).await.unwrap();
+                    assert_eq!(header.ty, CommandTy::RespOkNodata);
+                }
+
+                // FIXME once we support resizing we also need to check that the current and target size match
+                if self.displays[display_id].active_resource != Some(update.resource) {
+                    let scanout_request = Dma::new(SetScanout::new(
+                        display_id as u32,
+                        update.resource,
+                        GpuRect::new(0, 0, update.width, update.height),
+                    ))
+                    .unwrap();
+                    let header = self.send_request(scanout_request).await.unwrap();
+                    assert_eq!(header.ty, CommandTy::RespOkNodata);
+                    self.displays[display_id].active_resource = Some(update.resource);
+                }
+
+                if damaged {
+                    let flush = ResourceFlush::new(update.resource, rect);
+                    let header = self.send_request(Dma::new(flush).unwrap()).await.unwrap();
+                    assert_eq!(header.ty, CommandTy::RespOkNodata);
+                }
+            });
+        }
+    }
+
+    /// Detach the framebuffer of a scanout that is no longer enabled.
+    ///
+    /// The resource itself is unreferenced once `GraphicsScheme::notify_displays_changed`
+    /// releases the framebuffer of the display.
+    async fn disable_scanout(&mut self, display_id: usize) -> Result<(), Error> {
+        self.displays[display_id].pending = None;
+        if self.displays[display_id].active_resource.take().is_none() {
+            return Ok(());
+        }
+
+        let request = Dma::new(SetScanout::new(
+            display_id as u32,
+            ResourceId(0),
+            GpuRect::new(0, 0, 0, 0),
+        ))?;
+        let header = self.send_request(request).await?;
+        assert_eq!(header.ty, CommandTy::RespOkNodata);
+        Ok(())
+    }
+
     async fn send_request<T>(&self, request: Dma<T>) -> Result<Dma<ControlHeader>, Error> {
         let header = Dma::new(ControlHeader::default())?;
         let command = ChainBuilder::new()
@@ -175,6 +335,59 @@ impl VirtGpuAdapter<'_> {
         Ok(header)
     }
 
+    /// Attach the pages of `sgl` as backing storage to the resource `resource_id`.
+    async fn attach_backing(
+        &self,
+        resource_id: ResourceId,
+        sgl: &sgl::Sgl,
+    ) -> Result<Dma<ControlHeader>, Error> {
+        let mut mem_entries = unsafe { Dma::zeroed_slice(sgl.chunks().len())?.assume_init() };
+        for (entry, chunk) in mem_entries.iter_mut().zip(sgl.chunks().iter()) {
+            *entry = MemEntry {
+                address: chunk.phys as u64,
+                length: chunk.length.next_multiple_of(PAGE_SIZE) as u32,
+                padding: 0,
+            };
+        }
+
+        let attach_request = Dma::new(AttachBacking::new(resource_id, mem_entries.len() as u32))?;
+        let header = Dma::new(ControlHeader::default())?;
+        let command = ChainBuilder::new()
+            .chain(Buffer::new(&attach_request))
+            .chain(Buffer::new_unsized(&mem_entries))
+            .chain(Buffer::new(&header).flags(DescriptorFlags::WRITE_ONLY))
+            .build();
+
+        self.control_queue.send(command)
+            .expect("virtio-gpud: no descriptors for attach_backing")
+            .await;
+        Ok(header)
+    }
+
+    /// Send a request on behalf of a `call`, reporting an error response of the host as `EIO`.
+    fn send_call_request<T>(&self, request: T) -> syscall::Result<()> {
+        futures::executor::block_on(async {
+            let header = self.send_request(Dma::new(request).unwrap()).await.unwrap();
+            if header.ty != CommandTy::RespOkNodata {
+                log::warn!("virtio-gpu: call request failed with {:?}", header.ty);
+                return Err(syscall::Error::new(EIO));
+            }
+            Ok(())
+        })
+    }
+
+    /// Look up a resource created through the `call` ABI by the handle `handle_id`.
+    fn call_resource(
+        &self,
+        handle_id: usize,
+        resource_id: u32,
+    ) -> syscall::Result<&CallResource<'a>> {
+        self.call_resources
+            .get(&resource_id)
+            .filter(|resource| resource.owner == handle_id)
+            .ok_or(syscall::Error::new(EINVAL))
+    }
+
     async fn get_display_info(&self) -> Result<Dma<GetDisplayInfo>, Error> {
         let header = Dma::new(ControlHeader::with_ty(CommandTy::GetDisplayInfo))?;
         let response = Dma::new(GetDisplayInfo::default())?;
@@ -209,6 +422,8 @@ impl VirtGpuAdapter<'_> {
     }
 
     fn update_cursor(&mut self, cursor: &VirtGpuCursor, x: i32, y: i32, hot_x: i32, hot_y: i32) {
+        self.cursor_pos = (x, y);
+
         //Transfering cursor resource to host
         futures::executor::block_on(async {
             let transfer_request = Dma::new(XferToHost2d::new(
@@ -243,7 +458,25 @@ impl VirtGpuAdapter<'_> {
         });
     }
 
+    /// Use the 64x64 2D resource `resource_id` as cursor image.
+    ///
+    /// As per the virtio-gpu spec, `hot_x`/`hot_y` is the hotspot within the image, which is
+    /// the point placed at the position given to `move_cursor`.
+    fn set_cursor(&mut self, resource_id: ResourceId, hot_x: i32, hot_y: i32) {
+        let (x, y) = self.cursor_pos;
+        let request =
+            Dma::new(UpdateCursor::update_cursor(x, y, hot_x, hot_y, resource_id)).unwrap();
+
+        futures::executor::block_on(async {
+            let command = ChainBuilder::new().chain(Buffer::new(&request)).build();
+            self.cursor_queue.send(command)
+                .expect("virtio-gpud: no descriptors for cursor update")
+                .await;
+        });
+    }
+
     fn move_cursor(&mut self, x: i32, y: i32) {
+        self.cursor_pos = (x, y);
         let request = Dma::new(MoveCursor::move_cursor(x, y)).unwrap();
 
         futures::executor::block_on(async {
@@ -276,15 +509,18 @@ impl<'a> GraphicsAdapter for VirtGpuAdapter<'a> {
 
         for display_id in 0..self.config.num_scanouts.get() {
             log::info!("virtio-gpu: init() adding connector for display {}", display_id);
-            let connector = objects.add_connector(VirtGpuConnector { display_id });
+            let connector = objects.add_connector(VirtGpuConnector {
+                display_id,
+                edid_blob: None,
+            });
             if self.has_edid {
-                objects.add_object_property(connector, standard_properties.edid, 0);
+                objects
+                    .add_object_property(connector, standard_properties.edid, 0)
+                    .unwrap();
             }
-            objects.add_object_property(
-                connector,
-                standard_properties.dpms,
-                DRM_MODE_DPMS_ON.into(),
-            );
+            objects
+                .add_object_property(connector, standard_properties.dpms, DRM_MODE_DPMS_ON.into())
+                .unwrap();
         }
         log::info!("virtio-gpu: init() done");
     }
@@ -321,7 +557,7 @@ impl<'a> GraphicsAdapter for VirtGpuAdapter<'a> {
                 DrmConnectorStatus::Disconnected
             };
 
-            if self.has_edid {
+            if self.has_edid && !display.edid.is_empty() {
                 let edid = edid::parse(&display.edid).unwrap().1;
 
                 let first_detailed_timing = edid
@@ -358,7 +594,15 @@ impl<'a> GraphicsAdapter for VirtGpuAdapter<'a> {
                 }
 
                 let blob = objects.add_blob(display.edid.clone());
-                objects.set_object_property(id, standard_properties.edid, blob.into());
+                objects
+                    .set_object_property(id, standard_properties.edid, blob.into())
+                    .unwrap();
+
+                // The previous EDID is no longer referenced by the property.
+                let connector = objects.get_connector_mut(id).unwrap();
+                if let Some(old_blob) = connector.driver_data.edid_blob.replace(blob) {
+                    objects.destroy_blob(old_blob).unwrap();
+                }
             }
         });
     }
@@ -374,6 +618,10 @@ impl<'a> GraphicsAdapter for VirtGpuAdapter<'a> {
         )
     }
 
+    fn display_enabled(&self, display_id: usize) -> bool {
+        self.displays[display_id].enabled
+    }
+
     fn create_dumb_framebuffer(&mut self, width: u32, height: u32) -> Self::Framebuffer {
         futures::executor::block_on(async {
             let bpp = 32;
@@ -400,29 +648,7 @@ impl<'a> GraphicsAdapter for VirtGpuAdapter<'a> {
 
             // Use the allocated framebuffer from the guest ram, and attach it as backing
             // storage to the resource just created, using `VIRTIO_GPU_CMD_RESOURCE_ATTACH_BACKING`.
-
-            let mut mem_entries =
-                unsafe { Dma::zeroed_slice(sgl.chunks().len()).unwrap().assume_init() };
-            for (entry, chunk) in mem_entries.iter_mut().zip(sgl.chunks().iter()) {
-                *entry = MemEntry {
-                    address: chunk.phys as u64,
-                    length: chunk.length.next_multiple_of(PAGE_SIZE) as u32,
-                    padding: 0,
-                };
-            }
-
-            let attach_request =
-                Dma::new(AttachBacking::new(res_id, mem_entries.len() as u32)).unwrap();
-            let header = Dma::new(ControlHeader::default()).unwrap();
-            let command = ChainBuilder::new()
-                .chain(Buffer::new(&attach_request))
-                .chain(Buffer::new_unsized(&mem_entries))
-                .chain(Buffer::new(&header).flags(DescriptorFlags::WRITE_ONLY))
-                .build();
-
-            self.control_queue.send(command)
-                .expect("virtio-gpud: no descriptors for attach_backing")
-                .await;
+            let header = self.attach_backing(res_id, &sgl).await.unwrap();
             assert_eq!(header.ty, CommandTy::RespOkNodata);
 
             VirtGpuFramebuffer {
@@ -440,40 +666,39 @@ impl<'a> GraphicsAdapter for VirtGpuAdapter<'a> {
     }
 
     fn update_plane(&mut self, display_id: usize, framebuffer: &Self::Framebuffer, damage: Damage) {
-        futures::executor::block_on(async {
-            let req = Dma::new(XferToHost2d::new(
-                framebuffer.id,
-                GpuRect {
-                    x: 0,
-                    y: 0,
-                    width: framebuffer.width,
-                    height: framebuffer.height,
-                },
-                0,
-            ))
-            .unwrap();
-            let header = self.send_request(req).await.unwrap();
-            assert_eq!(header.ty, CommandTy::RespOkNodata);
-
-            // FIXME once we support resizing we also need to check that the current and target size match
-            if self.displays[display_id].active_resource != Some(framebuffer.id) {
-                let scanout_request = Dma::new(SetScanout::new(
-                    display_id as u32,
-                    framebuffer.id,
-                    GpuRect::new(0, 0, framebuffer.width, framebuffer.height),
-                ))
-                .unwrap();
-                let header = self.send_request(scanout_request).await.unwrap();
-                assert_eq!(header.ty, CommandTy::RespOkNodata);
-                self.displays[display_id].active_resource = Some(framebuffer.id);
-            }
+        let whole = Damage {
+            x: 0,
+            y: 0,
+            width: framebuffer.width,
+            height: framebuffer.height,
+        };
+        let (x, y, width, height) = (damage.x, damage.y, damage.width, damage.height);
+        // Damage wrapping around the coordinate space can't be clipped, so it invalidates
+        // everything.
+        let damage = if x.checked_add(width).is_none() || y.checked_add(height).is_none() {
+            whole
+        } else {
+            damage.clip(framebuffer.width, framebuffer.height)
+        };
 
-            let flush = ResourceFlush::new(
-                framebuffer.id,
-                damage.clip(framebuffer.width, framebuffer.height).into(),
-            );
-            let header = self.send_request(Dma::new(flush).unwrap()).await.unwrap();
-            assert_eq!(header.ty, CommandTy::RespOkNodata);
+        let display = &mut self.displays[display_id];
+        if !display.enabled {
+            return;
+        }
+        let damage = match display.pending {
+            Some(pending) if pending.resource == framebuffer.id => pending.damage.merge(damage),
+            // A framebuffer that isn't shown yet needs to be transferred as a whole. The same goes
+            // for one that got replaced by another pending update in the meantime, as its
+            // damage is lost.
+            Some(_) => whole,
+            None if display.active_resource != Some(framebuffer.id) => whole,
+            None => damage,
+        };
+        display.pending = Some(PendingUpdate {
+            resource: framebuffer.id,
+            width: framebuffer.width,
+            height: framebuffer.height,
+            damage,
         });
     }
 
@@ -563,6 +788,151 @@ impl<'a> GraphicsAdapter for VirtGpuAdapter<'a> {
             self.move_cursor(cursor.x, cursor.y);
         }
     }
+
+    fn set_hw_cursor(
+        &mut self,
+        framebuffer: &Self::Framebuffer,
+        hot_x: i32,
+        hot_y: i32,
+    ) -> syscall::Result<()> {
+        // The device only supports 64x64 cursor images.
+        if framebuffer.width != 64 || framebuffer.height != 64 {
+            return Err(syscall::Error::new(EINVAL));
+        }
+
+        futures::executor::block_on(async {
+            let transfer_request = Dma::new(XferToHost2d::new(
+                framebuffer.id,
+                GpuRect::new(0, 0, 64, 64),
+                0,
+            ))
+            .unwrap();
+            let header = self.send_request_fenced(transfer_request).await.unwrap();
+            assert_eq!(header.ty, CommandTy::RespOkNodata);
+        });
+
+        self.set_cursor(framebuffer.id, hot_x, hot_y);
+        Ok(())
+    }
+
+    fn move_hw_cursor(&mut self, x: i32, y: i32) -> syscall::Result<()> {
+        self.move_cursor(x, y);
+        Ok(())
+    }
+
+    fn driver_call(
+        &mut self,
+        handle_id: usize,
+        framebuffer: Option<&Arc<Self::Framebuffer>>,
+        payload: &mut [u8],
+        metadata: &[u64],
+    ) -> syscall::Result<usize> {
+        match metadata[0] {
+            call::CREATE_RESOURCE => {
+                let cmd = call_payload::<call::CreateResource>(payload)?;
+                let (width, height) = (cmd.width, cmd.height);
+                if width == 0 || height == 0 {
+                    return Err(syscall::Error::new(EINVAL));
+                }
+
+                let id = ResourceId::alloc();
+                self.send_call_request(ResourceCreate2d::new(
+                    id,
+                    ResourceFormat::Bgrx,
+                    width,
+                    height,
+                ))?;
+                self.call_resources.insert(
+                    id.0,
+                    CallResource {
+                        id,
+                        owner: handle_id,
+                        width,
+                        height,
+                        backing: None,
+                    },
+                );
+
+                cmd.resource_id = id.0;
+                Ok(size_of::<call::CreateResource>())
+            }
+            call::ATTACH_BACKING => {
+                let resource_id = call_payload::<call::AttachBacking>(payload)?.resource_id;
+                let framebuffer = framebuffer.ok_or(syscall::Error::new(EINVAL))?;
+                let resource = self.call_resource(handle_id, resource_id)?;
+                if resource.backing.is_some()
+                    || framebuffer.width != resource.width
+                    || framebuffer.height != resource.height
+                {
+                    return Err(syscall::Error::new(EINVAL));
+                }
+
+                let header =
+                    futures::executor::block_on(self.attach_backing(resource.id, &framebuffer.sgl))
+                        .unwrap();
+                if header.ty != CommandTy::RespOkNodata {
+                    log::warn!("virtio-gpu: attach_backing failed with {:?}", header.ty);
+                    return Err(syscall::Error::new(EIO));
+                }
+                self.call_resources.get_mut(&resource_id).unwrap().backing =
+                    Some(framebuffer.clone());
+
+                Ok(size_of::<call::AttachBacking>())
+            }
+            call::FLUSH => {
+                let cmd = call_payload::<call::Flush>(payload)?;
+                let (resource_id, damage) = (cmd.resource_id, cmd.rect);
+                let resource = self.call_resource(handle_id, resource_id)?;
+                if resource.backing.is_none() {
+                    return Err(syscall::Error::new(EINVAL));
+                }
+                // Unlike in `update_plane` the rect comes straight from the client, so reject
+                // it if it can't be clipped.
+                let (x, y, width, height) = (damage.x, damage.y, damage.width, damage.height);
+                if x.checked_add(width).is_none() || y.checked_add(height).is_none() {
+                    return Err(syscall::Error::new(EINVAL));
+                }
+
+                let rect: GpuRect = damage.clip(resource.width, resource.height).into();
+                if rect.width != 0 && rect.height != 0 {
+                    // The backing storage has the same layout as the resource, so the rect
+                    // starts at this offset.
+                    let offset =
+                        (u64::from(rect.y) * u64::from(resource.width) + u64::from(rect.x)) * 4;
+                    self.send_call_request(XferToHost2d::new(resource.id, rect, offset))?;
+                    self.send_call_request(ResourceFlush::new(resource.id, rect))?;
+                }
+
+                Ok(size_of::<call::Flush>())
+            }
+            call::UNREF => {
+                let resource_id = call_payload::<call::Unref>(payload)?.resource_id;
+                self.call_resource(handle_id, resource_id)?;
+                let resource = self.call_resources.remove(&resource_id).unwrap();
+
+                // Unreferencing the resource also detaches its backing storage, after which
+                // the backing framebuffer can be dropped.
+                self.send_call_request(ResourceUnref::new(resource.id))?;
+
+                Ok(size_of::<call::Unref>())
+            }
+            _ => Err(syscall::Error::new(EINVAL)),
+        }
+    }
+
+    fn close_handle(&mut self, handle_id: usize) {
+        let owned: Vec<u32> = self
+            .call_resources
+            .iter()
+            .filter(|(_, resource)| resource.owner == handle_id)
+            .map(|(&resource_id, _)| resource_id)
+            .collect();
+        for resource_id in owned {
+            let resource = self.call_resources.remove(&resource_id).unwrap();
+            // A failure is already logged and there is nobody left to report it to.
+            let _ = self.send_call_request(ResourceUnref::new(resource.id));
+        }
+    }
 }
 
 pub struct GpuScheme {}
@@ -582,6 +952,8 @@ impl<'a> GpuScheme {
             transport,
             has_edid,
             displays: vec![],
+            cursor_pos: (0, 0),
+            call_resources: HashMap::new(),
         };
 
         let scheme = GraphicsScheme::new(adapter, "display.virtio-gpu".to_owned());
@@ -17,6 +17,12 @@ pub trait NetworkAdapter {
     /// network adapter.
     fn mac_address(&mut self) -> [u8; 6];
 
+    /// The largest packet payload this network adapter can send in a single
+    /// frame, exposed to the network stack as the `mtu` file.
+    fn mtu(&mut self) -> usize {
+        1500
+    }
+
     /// The amount of network packets that can be read without blocking.
     fn available_for_read(&mut self) -> usize;
 
@@ -196,6 +202,7 @@ struct NetworkSchemeInner<T: NetworkAdapter> {
 enum Handle {
     Data,
     Mac,
+    Mtu,
 }
 
 impl<T: NetworkAdapter> NetworkSchemeInner<T> {
@@ -218,6 +225,7 @@ impl<T: NetworkAdapter> SchemeSync for NetworkSchemeInner<T> {
         let (handle, flags) = match path {
             "" => (Handle::Data, NewFdFlags::empty()),
             "mac" => (Handle::Mac, NewFdFlags::POSITIONED),
+            "mtu" => (Handle::Mtu, NewFdFlags::POSITIONED),
             _ => return Err(Error::new(EINVAL)),
         };
 
@@ -247,6 +255,13 @@ impl<T: NetworkAdapter> SchemeSync for NetworkSchemeInner<T> {
                 buf[..i].copy_from_slice(&data[..i]);
                 return Ok(i);
             }
+            Handle::Mtu => {
+                let mtu = self.adapter.mtu().to_string();
+                let data = mtu.as_bytes().get(offset as usize..).unwrap_or(&[]);
+                let i = cmp::min(buf.len(), data.len());
+                buf[..i].copy_from_slice(&data[..i]);
+                return Ok(i);
+            }
         };
 
         match self.adapter.read_packet(buf)? {
@@ -273,7 +288,7 @@ impl<T: NetworkAdapter> SchemeSync for NetworkSchemeInner<T> {
 
         match handle {
             Handle::Data => {}
-            Handle::Mac { .. } => return Err(Error::new(EINVAL)),
+            Handle::Mac { .. } | Handle::Mtu => return Err(Error::new(EINVAL)),
         }
 
         Ok(self.adapter.write_packet(buf)?)
@@ -306,6 +321,7 @@ impl<T: NetworkAdapter> SchemeSync for NetworkSchemeInner<T> {
         let path = match handle {
             Handle::Data { .. } => &b""[..],
             Handle::Mac { .. } => &b"mac"[..],
+            Handle::Mtu => &b"mtu"[..],
         };
 
         j = 0;
@@ -329,6 +345,10 @@ impl<T: NetworkAdapter> SchemeSync for NetworkSchemeInner<T> {
                 stat.st_mode = MODE_FILE | 0o400;
                 stat.st_size = 6;
             }
+            Handle::Mtu => {
+                stat.st_mode = MODE_FILE | 0o400;
+                stat.st_size = self.adapter.mtu().to_string().len() as u64;
+            }
         }
 
         Ok(())
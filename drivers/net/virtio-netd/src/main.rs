@@ -1,15 +1,39 @@
 mod scheme;
 
+use std::future::Future;
 use std::io::{Read, Write};
 use std::os::unix::io::AsRawFd;
+use std::pin::pin;
+use std::task::{Context, Poll, Waker};
+use std::time::{Duration, Instant};
 
 use driver_network::NetworkScheme;
 use event::{user_data, EventFlags, UserData};
 use pcid_interface::PciFunctionHandle;
+use virtio_core::spec::{Buffer, ChainBuilder, DescriptorFlags};
+use virtio_core::transport::Queue;
+
+use common::dma::Dma;
 
 use scheme::VirtioNet;
 
+pub const VIRTIO_NET_F_CSUM: u32 = 0;
 pub const VIRTIO_NET_F_MAC: u32 = 5;
+pub const VIRTIO_NET_F_CTRL_VQ: u32 = 17;
+pub const VIRTIO_NET_F_MQ: u32 = 22;
+
+const VIRTIO_NET_CTRL_MQ: u8 = 4;
+const VIRTIO_NET_CTRL_MQ_VQ_PAIRS_SET: u8 = 0;
+const VIRTIO_NET_OK: u8 = 0;
+
+/// How long to wait for the device to answer a control queue command.
+const CTRL_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Upper bound on the number of queue pairs used, each RX queue pins its own set of buffers.
+const MAX_QUEUE_PAIRS: u16 = 4;
+
+/// `VirtHeader::flags`: the device must compute the checksum from `csum_start`.
+pub const VIRTIO_NET_HDR_F_NEEDS_CSUM: u8 = 1;
 
 #[derive(Debug)]
 #[repr(C)]
@@ -26,6 +50,42 @@ pub struct VirtHeader {
 static_assertions::const_assert_eq!(core::mem::size_of::<VirtHeader>(), 12);
 
 const MAX_BUFFER_LEN: usize = 65535;
+
+/// Tell the device how many queue pairs to use, through the control queue.
+fn set_queue_pairs(ctrl: &Queue, pairs: u16) -> syscall::Result<()> {
+    let mut command = unsafe { Dma::<[u8; 2]>::zeroed()?.assume_init() };
+    *command = [VIRTIO_NET_CTRL_MQ, VIRTIO_NET_CTRL_MQ_VQ_PAIRS_SET];
+    let mut data = unsafe { Dma::<u16>::zeroed()?.assume_init() };
+    *data = pairs.to_le();
+    let ack = unsafe { Dma::<u8>::zeroed()?.assume_init() };
+
+    let chain = ChainBuilder::new()
+        .chain(Buffer::new(&command))
+        .chain(Buffer::new(&data))
+        .chain(Buffer::new(&ack).flags(DescriptorFlags::WRITE_ONLY))
+        .build();
+
+    let mut pending = pin!(ctrl
+        .send(chain)
+        .ok_or(syscall::Error::new(syscall::EBUSY))?);
+
+    // There is no IRQ thread for the control queue, so spin until the device answers.
+    let mut cx = Context::from_waker(Waker::noop());
+    let deadline = Instant::now() + CTRL_TIMEOUT;
+    while let Poll::Pending = pending.as_mut().poll(&mut cx) {
+        if Instant::now() >= deadline {
+            // The device may still write the ack later, so the buffers must outlive us.
+            std::mem::forget((command, data, ack));
+            return Err(syscall::Error::new(syscall::ETIMEDOUT));
+        }
+    }
+
+    if *ack != VIRTIO_NET_OK {
+        return Err(syscall::Error::new(syscall::EINVAL));
+    }
+    Ok(())
+}
+
 fn main() {
     pcid_interface::pci_daemon(daemon_runner);
 }
@@ -97,6 +157,25 @@ fn deamon(
         [0x52, 0x54, 0x00, 0x12, 0x34, 0x56] // Default QEMU MAC
     };
 
+    let csum_offload = device.transport.check_device_feature(VIRTIO_NET_F_CSUM);
+    if csum_offload {
+        device.transport.ack_driver_feature(VIRTIO_NET_F_CSUM);
+        log::info!("virtio-net: using checksum offload for transmitted packets");
+    } else {
+        log::info!("virtio-net: device does not support checksum offload, using software checksums");
+    }
+
+    let multiqueue = device.transport.check_device_feature(VIRTIO_NET_F_CTRL_VQ)
+        && device.transport.check_device_feature(VIRTIO_NET_F_MQ);
+    let max_queue_pairs = if multiqueue {
+        device.transport.ack_driver_feature(VIRTIO_NET_F_CTRL_VQ);
+        device.transport.ack_driver_feature(VIRTIO_NET_F_MQ);
+        // max_virtqueue_pairs follows the MAC address and the status field.
+        (device.transport.load_config(8, 2) as u16).max(1)
+    } else {
+        1
+    };
+
     device.transport.finalize_features();
 
     // Allocate the recieve and transmit queues:
@@ -104,24 +183,73 @@ fn deamon(
     // > Empty buffers are placed in one virtqueue for receiving
     // > packets, and outgoing packets are enqueued into another
     // > for transmission in that order.
+    //
+    // With VIRTIO_NET_F_MQ, the queues are laid out as receiveq1, transmitq1, ...,
+    // receiveqN, transmitqN followed by the control queue, N being max_virtqueue_pairs.
     //
 	// TODO(andypython): Should we use the same IRQ vector for both?
     // Use setup_queue_no_irq to avoid spawning IRQ threads - we handle IRQs
     // in our main event loop instead for more responsive packet handling.
-    let rx_queue = device
-        .transport
-        .setup_queue_no_irq(virtio_core::MSIX_PRIMARY_VECTOR)?;
+    // Only the queue pairs that are used get set up, the rest stay disabled.
+    let mut queue_pairs = max_queue_pairs.min(MAX_QUEUE_PAIRS);
+    let mut rx_queues = Vec::new();
+    let mut tx_queues = Vec::new();
+    for _ in 0..queue_pairs {
+        rx_queues.push(
+            device
+                .transport
+                .setup_queue_no_irq(virtio_core::MSIX_PRIMARY_VECTOR)?,
+        );
+        tx_queues.push(
+            device
+                .transport
+                .setup_queue_no_irq(virtio_core::MSIX_PRIMARY_VECTOR)?,
+        );
+    }
 
-    let tx_queue = device
-        .transport
-        .setup_queue_no_irq(virtio_core::MSIX_PRIMARY_VECTOR)?;
+    let ctrl_queue = if multiqueue {
+        device
+            .transport
+            .skip_queues(2 * (max_queue_pairs - queue_pairs));
+        Some(
+            device
+                .transport
+                .setup_queue_no_irq(virtio_core::MSIX_PRIMARY_VECTOR)?,
+        )
+    } else {
+        None
+    };
 
     device.transport.run_device();
 
+    if let Some(ctrl_queue) = &ctrl_queue {
+        if let Err(e) = set_queue_pairs(ctrl_queue, queue_pairs) {
+            // The device keeps using a single queue pair until told otherwise.
+            log::warn!("virtio-net: failed to enable {queue_pairs} queue pairs: {:?}", e);
+            queue_pairs = 1;
+        }
+    }
+    log::info!("virtio-net: using {queue_pairs} queue pair(s) (device supports {max_queue_pairs})");
+
+    // The queues past `queue_pairs` stay idle, but are owned by the device until it is reset
+    // when `VirtioNet` is dropped. Declared before `scheme`, so that they are dropped after it.
+    let _idle_queues: Vec<_> = rx_queues
+        .split_off(usize::from(queue_pairs))
+        .into_iter()
+        .chain(tx_queues.split_off(usize::from(queue_pairs)))
+        .chain(ctrl_queue)
+        .collect();
+
     let mut name = pci_config.func.name();
     name.push_str("_virtio_net");
 
-    let dev = match VirtioNet::new(mac_address, rx_queue, tx_queue) {
+    let dev = match VirtioNet::new(
+        mac_address,
+        device.transport.clone(),
+        rx_queues,
+        tx_queues,
+        csum_offload,
+    ) {
         Ok(dev) => dev,
         Err(e) => {
             log::error!("virtio-netd: failed to initialize device: {:?}", e);
@@ -181,58 +309,43 @@ fn deamon(
         log::warn!("virtio-netd: failed to enter null namespace: {:?}", e);
     }
 
+    // Drain whatever arrived before we subscribed to the event queue.
     scheme.tick()?;
 
-    log::debug!("virtio-netd: entering polling event loop");
+    log::debug!("virtio-netd: entering event loop");
 
     let mut event_buf = [event::raw::RawEventV1::default()];
-    let mut poll_count: u64 = 0;
 
-    // Simple polling loop: check for events, then sleep briefly
+    // Block until one of the subscribed sources fires, so that the thread sleeps while idle.
+    // The wakeup sources are:
+    // - `Source::Irq`: the device used buffers, i.e. received packets or finished transmitting,
+    // - `Source::Scheme`: clients of the network scheme sent requests.
     loop {
-        // Non-blocking check for events
-        // We can't use timeout on event queue, so we poll in a tight loop
-        // with short sleeps between iterations
-
-        loop {
-            // Try to get an event (this might block if nothing is ready)
-            let count = unsafe {
-                event::raw::redox_event_queue_get_events_v1(
-                    queue_fd,
-                    event_buf.as_mut_ptr(),
-                    1,
-                    0,
-                    core::ptr::null(),
-                    core::ptr::null(),
-                )
-            };
-
-            if count == 0 || count == !0 {
-                // No event, break to poll the device
-                break;
-            }
-
-            let event = &event_buf[0];
-            let user_data = event.user_data;
-
-            if user_data == Source::Irq.into_user_data() {
-                log::debug!("virtio-netd: IRQ event");
-                let mut irq = [0u8; 8];
-                let _ = irq_handle.read(&mut irq);
-                let _ = irq_handle.write(&irq);
-            }
-            // For any event, tick the scheme
-            scheme.tick()?;
+        let count = unsafe {
+            event::raw::redox_event_queue_get_events_v1(
+                queue_fd,
+                event_buf.as_mut_ptr(),
+                1,
+                0,
+                core::ptr::null(),
+                core::ptr::null(),
+            )
+        };
+
+        if count == !0 {
+            return Err("virtio-netd: failed to wait for events".into());
+        }
+        if count == 0 {
+            continue;
         }
 
-        // Poll the device even without events (for packet reception)
-        poll_count += 1;
-        if poll_count % 1000 == 1 {
-            log::debug!("virtio-netd poll #{}", poll_count);
+        if event_buf[0].user_data == Source::Irq.into_user_data() {
+            log::debug!("virtio-netd: IRQ event");
+            let mut irq = [0u8; 8];
+            let _ = irq_handle.read(&mut irq);
+            let _ = irq_handle.write(&irq);
         }
-        scheme.tick()?;
 
-        // Yield to other threads instead of sleeping
-        std::thread::yield_now();
+        scheme.tick()?;
     }
 }
@@ -1,3 +1,4 @@
+use std::hash::{DefaultHasher, Hasher};
 use std::sync::Arc;
 
 use driver_network::NetworkAdapter;
@@ -5,25 +6,151 @@ use driver_network::NetworkAdapter;
 use common::dma::Dma;
 
 use virtio_core::spec::{Buffer, ChainBuilder, DescriptorFlags};
-use virtio_core::transport::Queue;
+use virtio_core::transport::{Queue, Transport};
 
-use crate::{VirtHeader, MAX_BUFFER_LEN};
+use crate::{VirtHeader, MAX_BUFFER_LEN, VIRTIO_NET_HDR_F_NEEDS_CSUM};
+
+/// Reciever queue, along with the buffers posted to it.
+struct RxQueue<'a> {
+    queue: Arc<Queue<'a>>,
+    buffers: Vec<Dma<[u8]>>,
+    recv_head: u16,
+}
 
 pub struct VirtioNet<'a> {
     mac_address: [u8; 6],
+    transport: Arc<dyn Transport>,
 
-    /// Reciever Queue.
-    rx: Arc<Queue<'a>>,
-    rx_buffers: Vec<Dma<[u8]>>,
+    /// Reciever Queues.
+    rx: Vec<RxQueue<'a>>,
+    /// Reciever queue to check first on the next read, so that none of them starves.
+    next_rx: usize,
 
-    /// Transmiter Queue.
-    tx: Arc<Queue<'a>>,
+    /// Transmiter Queues.
+    tx: Vec<Arc<Queue<'a>>>,
+    /// Whether VIRTIO_NET_F_CSUM was negotiated.
+    csum_offload: bool,
+}
 
-    recv_head: u16,
+/// Picks the transmit queue of a packet from its destination, so that a flow always uses the
+/// same queue and isn't reordered.
+fn tx_queue_index(frame: &[u8], queue_count: usize) -> usize {
+    if queue_count <= 1 {
+        return 0;
+    }
+
+    let ethertype = frame.get(12..14).map(|t| u16::from_be_bytes([t[0], t[1]]));
+    let destination = match ethertype {
+        Some(0x0800) => frame.get(30..34),
+        Some(0x86DD) => frame.get(38..54),
+        _ => None,
+    }
+    .or_else(|| frame.get(0..6))
+    .unwrap_or(&[]);
+
+    let mut hasher = DefaultHasher::new();
+    hasher.write(destination);
+    (hasher.finish() % queue_count as u64) as usize
+}
+
+/// Folds a one's complement sum into 16 bits.
+fn fold_csum(mut sum: u32) -> u16 {
+    while sum >> 16 != 0 {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    sum as u16
+}
+
+fn sum_words(bytes: &[u8]) -> u32 {
+    bytes
+        .chunks(2)
+        .map(|w| u32::from(u16::from_be_bytes([w[0], *w.get(1).unwrap_or(&0)])))
+        .sum()
+}
+
+/// Prepares a TCP or UDP packet in an ethernet `frame` for checksum offload.
+///
+/// The checksum field is replaced by the checksum of the pseudo header, as required by the
+/// device, and `(csum_start, csum_offset)` are returned. Returns `None` for packets that the
+/// device can't checksum, which keep their software computed checksum.
+fn prepare_csum_offload(frame: &mut [u8]) -> Option<(u16, u16)> {
+    const ETH_HEADER_LEN: usize = 14;
+    const PROTO_TCP: u8 = 6;
+    const PROTO_UDP: u8 = 17;
+
+    let ethertype = u16::from_be_bytes([*frame.get(12)?, *frame.get(13)?]);
+    let ip = frame.get(ETH_HEADER_LEN..)?;
+
+    let (l4_start, protocol, pseudo_sum) = match ethertype {
+        // IPv4
+        0x0800 => {
+            let ihl = usize::from(*ip.first()? & 0xf) * 4;
+            let total_len = usize::from(u16::from_be_bytes([*ip.get(2)?, *ip.get(3)?]));
+            let frag = u16::from_be_bytes([*ip.get(6)?, *ip.get(7)?]);
+            // Fragments only carry a part of the transport header/payload.
+            if frag & 0x3fff != 0 || ihl < 20 || total_len < ihl {
+                return None;
+            }
+            let protocol = *ip.get(9)?;
+            let l4_len = (total_len - ihl) as u32;
+            let sum = sum_words(ip.get(12..20)?) + u32::from(protocol) + l4_len;
+            (ETH_HEADER_LEN + ihl, protocol, sum)
+        }
+        // IPv6, without extension headers
+        0x86DD => {
+            let payload_len = u16::from_be_bytes([*ip.get(4)?, *ip.get(5)?]);
+            let protocol = *ip.get(6)?;
+            let sum = sum_words(ip.get(8..40)?) + u32::from(protocol) + u32::from(payload_len);
+            (ETH_HEADER_LEN + 40, protocol, sum)
+        }
+        _ => return None,
+    };
+
+    let csum_offset = match protocol {
+        PROTO_TCP => 16,
+        PROTO_UDP => 6,
+        _ => return None,
+    };
+
+    let field = l4_start + csum_offset;
+    let csum = frame.get_mut(field..field + 2)?;
+    // A zero UDP checksum over IPv4 means no checksum, keep it that way.
+    if ethertype == 0x0800 && protocol == PROTO_UDP && csum == [0, 0] {
+        return None;
+    }
+    csum.copy_from_slice(&fold_csum(pseudo_sum).to_be_bytes());
+
+    Some((l4_start as u16, csum_offset as u16))
 }
 
 impl<'a> VirtioNet<'a> {
-    pub fn new(mac_address: [u8; 6], rx: Arc<Queue<'a>>, tx: Arc<Queue<'a>>) -> Result<Self, syscall::Error> {
+    pub fn new(
+        mac_address: [u8; 6],
+        transport: Arc<dyn Transport>,
+        rx: Vec<Arc<Queue<'a>>>,
+        tx: Vec<Arc<Queue<'a>>>,
+        csum_offload: bool,
+    ) -> Result<Self, syscall::Error> {
+        assert!(!rx.is_empty() && !tx.is_empty());
+
+        let rx = rx
+            .into_iter()
+            .map(Self::populate_rx_queue)
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(Self {
+            mac_address,
+            transport,
+
+            rx,
+            next_rx: 0,
+
+            tx,
+            csum_offload,
+        })
+    }
+
+    fn populate_rx_queue(rx: Arc<Queue<'a>>) -> Result<RxQueue<'a>, syscall::Error> {
         // Populate all of the `rx_queue` with buffers to maximize performence.
         let mut rx_buffers = vec![];
         for i in 0..(rx.descriptor_len() as usize) {
@@ -48,35 +175,45 @@ impl<'a> VirtioNet<'a> {
             }
         }
 
-        Ok(Self {
-            mac_address,
-
-            rx,
-            rx_buffers,
-            tx,
-
+        Ok(RxQueue {
+            queue: rx,
+            buffers: rx_buffers,
             recv_head: 0,
         })
     }
 
     /// Returns the number of bytes read. Returns `0` if the operation would block.
     fn try_recv(&mut self, target: &mut [u8]) -> usize {
+        let count = self.rx.len();
+        for i in 0..count {
+            let index = (self.next_rx + i) % count;
+            let bytes = Self::try_recv_from(&mut self.rx[index], target);
+            if bytes != 0 {
+                self.next_rx = (index + 1) % count;
+                return bytes;
+            }
+        }
+
+        0
+    }
+
+    fn try_recv_from(rx: &mut RxQueue<'a>, target: &mut [u8]) -> usize {
         let header_size = core::mem::size_of::<VirtHeader>();
 
-        if self.recv_head == self.rx.used.head_index() {
+        if rx.recv_head == rx.queue.used.head_index() {
             // The read would block.
             return 0;
         }
 
-        let idx = self.rx.used.head_index() as usize;
-        let element = self.rx.used.get_element_at(idx - 1);
+        let idx = rx.queue.used.head_index() as usize;
+        let element = rx.queue.used.get_element_at(idx - 1);
 
         let descriptor_idx = element.table_index.get();
         let payload_size = element.written.get() as usize - header_size;
 
         // XXX: The header and packet are added as one output descriptor to the transmit queue,
         //      and the device is notified of the new entry (see 5.1.5 Device Initialization).
-        let buffer = &self.rx_buffers[descriptor_idx as usize];
+        let buffer = &rx.buffers[descriptor_idx as usize];
         // TODO: Check the header.
         let _header = unsafe { &*(buffer.as_ptr() as *const VirtHeader) };
         let packet = &buffer[header_size..(header_size + payload_size)];
@@ -85,23 +222,33 @@ impl<'a> VirtioNet<'a> {
         let copy_size = core::cmp::min(payload_size, target.len());
         target[..copy_size].copy_from_slice(&packet[..copy_size]);
 
-        self.recv_head = self.rx.used.head_index();
+        rx.recv_head = rx.queue.used.head_index();
 
         // Recycle the RX buffer back to the available ring for future packets
-        log::info!("Recycling RX descriptor {} (recv_head now {})", descriptor_idx, self.recv_head);
-        self.rx.recycle_descriptor(descriptor_idx as u16);
+        log::info!("Recycling RX descriptor {} (recv_head now {})", descriptor_idx, rx.recv_head);
+        rx.queue.recycle_descriptor(descriptor_idx as u16);
 
         copy_size
     }
 }
 
+impl Drop for VirtioNet<'_> {
+    fn drop(&mut self) {
+        // Stop the device from using any of the queues before their memory is freed.
+        self.transport.reset();
+    }
+}
+
 impl<'a> NetworkAdapter for VirtioNet<'a> {
     fn mac_address(&mut self) -> [u8; 6] {
         self.mac_address
     }
 
     fn available_for_read(&mut self) -> usize {
-        (self.rx.used.head_index() - self.recv_head).into()
+        self.rx
+            .iter()
+            .map(|rx| usize::from(rx.queue.used.head_index().wrapping_sub(rx.recv_head)))
+            .sum()
     }
 
     fn read_packet(&mut self, buf: &mut [u8]) -> syscall::Result<Option<usize>> {
@@ -134,13 +281,23 @@ impl<'a> NetworkAdapter for VirtioNet<'a> {
         };
         payload.copy_from_slice(buffer);
 
+        if self.csum_offload {
+            if let Some((csum_start, csum_offset)) = prepare_csum_offload(payload) {
+                header.flags = VIRTIO_NET_HDR_F_NEEDS_CSUM;
+                header.csum_start = csum_start;
+                header.csum_offset = csum_offset;
+            }
+        }
+
         let chain = ChainBuilder::new()
             .chain(Buffer::new(header))
             .chain(Buffer::new_unsized(payload))
             .build();
 
+        let tx = &self.tx[tx_queue_index(buffer, self.tx.len())];
+
         // send() now reclaims completed TX descriptors automatically before checking availability
-        match self.tx.send(chain) {
+        match tx.send(chain) {
             Some(_) => Ok(buffer.len()),
             None => {
                 // No descriptors available even after reclaiming - would block
@@ -1,5 +1,12 @@
-use std::fs;
-use std::process::{Child, Command};
+use std::collections::BTreeMap;
+use std::fs::OpenOptions;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::process::CommandExt;
+use std::path::Path;
+use std::process::{Child, Command, ExitStatus};
+use std::sync::{mpsc, Mutex};
+use std::time::{Duration, Instant};
+use std::{fs, io, thread};
 
 use anyhow::{anyhow, Context, Result};
 
@@ -13,42 +20,246 @@ struct SpawnedDriver {
     channel_fd: i32,
 }
 
-fn busy_wait_ms(ms: u64) {
-    // Use sched_yield syscall to actually wait - spin loops get optimized away
-    // Each yield gives up timeslice to other processes
-    let yields_per_ms = 10; // Approximate
-    for _ in 0..(ms * yields_per_ms) {
-        let _ = syscall::sched_yield();
+/// Number of devices which are probed concurrently.
+const PROBE_WORKERS: usize = 4;
+
+/// How often spawning a driver is attempted before giving up on the device.
+const SPAWN_ATTEMPTS: u32 = 3;
+/// Delay before the first retry to spawn a driver, doubled for each further retry.
+const SPAWN_RETRY_DELAY: Duration = Duration::from_millis(100);
+
+/// How long drivers get to signal readiness before pcid-spawner stops waiting for them.
+const READY_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How long pcid gets to register the pci scheme before pcid-spawner gives up.
+const SCHEME_TIMEOUT: Duration = Duration::from_secs(60);
+
+event::user_data! {
+    enum SchemeWaitEvent {
+        Namespace,
+        Timeout,
     }
 }
 
-fn wait_for_scheme(path: &str, max_retries: u32, _delay_ms: u64) -> Result<fs::ReadDir> {
-    // Wait up to 30 seconds with 100 retries of 300ms each
-    // Also wait for directory to have at least one entry
-    for i in 0..100 {
-        match fs::read_dir(path) {
-            Ok(dir) => {
-                // Peek to see if there are any entries
-                let entries: Vec<_> = dir.collect();
-                let count = entries.len();
-                if count > 0 {
-                    eprintln!("pcid-spawner: found {} with {} devices after {} attempts", path, count, i + 1);
-                    // Return a new iterator since we consumed the original
-                    return fs::read_dir(path).map_err(Into::into);
-                }
-                eprintln!("pcid-spawner: {} exists but empty, retrying (attempt {})", path, i + 1);
-                busy_wait_ms(300);
-            }
-            Err(_e) => {
-                if i % 10 == 0 {
-                    eprintln!("pcid-spawner: waiting for {} (attempt {}/100)", path, i + 1);
-                }
-                busy_wait_ms(300); // 300ms per attempt
+/// Blocks until the scheme at `path` exists, or until `SCHEME_TIMEOUT` elapsed.
+///
+/// Rather than polling, this sleeps on an fevent subscription to the scheme namespace, which is
+/// notified when a scheme is registered, and on a timer for the timeout.
+fn wait_for_scheme(path: &str) -> Result<()> {
+    let start = Instant::now();
+
+    let event_queue = event::EventQueue::new().context("failed to create event queue")?;
+    let namespace = fs::File::open("/scheme").context("failed to open /scheme")?;
+    event_queue
+        .subscribe(
+            namespace.as_raw_fd() as usize,
+            SchemeWaitEvent::Namespace,
+            event::EventFlags::READ,
+        )
+        .context("failed to watch /scheme")?;
+
+    let timer = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(format!("/scheme/time/{}", syscall::CLOCK_MONOTONIC))
+        .context("failed to open timer")?;
+    let mut deadline = syscall::TimeSpec::default();
+    syscall::read(timer.as_raw_fd() as usize, &mut deadline)
+        .map_err(|err| io::Error::from_raw_os_error(err.errno))?;
+    deadline.tv_sec += SCHEME_TIMEOUT.as_secs() as i64;
+    syscall::write(timer.as_raw_fd() as usize, &deadline)
+        .map_err(|err| io::Error::from_raw_os_error(err.errno))?;
+    event_queue
+        .subscribe(
+            timer.as_raw_fd() as usize,
+            SchemeWaitEvent::Timeout,
+            event::EventFlags::READ,
+        )
+        .context("failed to watch timer")?;
+
+    // Checked after subscribing, so that a scheme registered in between isn't missed
+    while !Path::new(path).exists() {
+        let event = event_queue
+            .next_event()
+            .context("failed to wait for events")?;
+        if let SchemeWaitEvent::Timeout = event.user_data {
+            return Err(anyhow!("{path} did not appear after {SCHEME_TIMEOUT:?}"));
+        }
+    }
+
+    eprintln!("pcid-spawner: found {} after {:?}", path, start.elapsed());
+    Ok(())
+}
+
+/// Waits until every driver signaled readiness, or until `READY_TIMEOUT` elapsed.
+///
+/// Drivers fork using `daemon::Daemon`, and the spawned process exits with the status reported
+/// by `Daemon::ready_with_status` as soon as the daemon is ready, or with a nonzero status when
+/// it exited before signaling readiness. The daemon itself keeps running.
+fn wait_ready(spawned_drivers: Vec<SpawnedDriver>) {
+    let deadline = Instant::now() + READY_TIMEOUT;
+    let (sender, receiver) = mpsc::channel();
+
+    let mut pending = BTreeMap::new();
+    for (index, spawned) in spawned_drivers.into_iter().enumerate() {
+        let SpawnedDriver {
+            name,
+            mut child,
+            channel_fd,
+        } = spawned;
+        pending.insert(index, (name, channel_fd));
+
+        // Waiting blocks, so every driver is waited for on its own thread. Threads of drivers
+        // which never become ready are left behind, they end with pcid-spawner.
+        let sender = sender.clone();
+        thread::spawn(move || {
+            let status = child.wait().map_err(|err| format!("failed to wait: {err}"));
+            let _ = sender.send((index, status));
+        });
+    }
+
+    while !pending.is_empty() {
+        let timeout = deadline.saturating_duration_since(Instant::now());
+        let Ok((index, status)) = receiver.recv_timeout(timeout) else {
+            break;
+        };
+        let (name, channel_fd) = pending.remove(&index).unwrap();
+        report_ready(&name, status);
+        syscall::close(channel_fd as usize).unwrap();
+    }
+
+    for (name, channel_fd) in pending.into_values() {
+        report_ready(&name, Err(format!("no readiness after {READY_TIMEOUT:?}")));
+        syscall::close(channel_fd as usize).unwrap();
+    }
+}
+
+fn report_ready(name: &str, status: Result<ExitStatus, String>) {
+    match status {
+        Ok(status) if status.success() => {
+            eprintln!("pcid-spawner: driver {} is ready", name);
+        }
+        Ok(status) => {
+            log::error!("pcid-spawner: driver {} failed to initialize: {}", name, status);
+        }
+        Err(err) => {
+            log::error!("pcid-spawner: driver {}: {}", name, err);
+        }
+    }
+}
+
+/// Spawns `command`, retrying with exponential backoff if it failed for a possibly transient
+/// reason. A missing driver binary isn't retried.
+fn spawn_with_retry(command: &mut Command) -> io::Result<Child> {
+    let mut delay = SPAWN_RETRY_DELAY;
+    let mut attempt = 1;
+    loop {
+        match command.spawn() {
+            Err(err) if err.kind() != io::ErrorKind::NotFound && attempt < SPAWN_ATTEMPTS => {
+                log::warn!(
+                    "pcid-spawner: failed to spawn {:?} (attempt {}/{}), retrying: {err}",
+                    command.get_program(),
+                    attempt,
+                    SPAWN_ATTEMPTS,
+                );
+                thread::sleep(delay);
+                delay *= 2;
+                attempt += 1;
             }
+            result => return result,
+        }
+    }
+}
+
+/// Connects to the PCI function at `device_path` and spawns the driver matching it.
+///
+/// The returned driver owns the channel fd, which is closed here if no driver was spawned.
+fn probe_device(config: &Config, device_path: &Path) -> Result<Option<SpawnedDriver>> {
+    log::trace!("ENTRY: {}", device_path.to_string_lossy());
+
+    eprintln!("pcid-spawner: trying {}", device_path.display());
+    let mut handle = match PciFunctionHandle::connect_by_path(device_path) {
+        Ok(handle) => handle,
+        Err(err) => {
+            // Either the device is gone or it is already in-use by a driver.
+            eprintln!(
+                "pcid-spawner: {} already in use: {err}",
+                device_path.display(),
+            );
+            return Ok(None);
+        }
+    };
+
+    let full_device_id = handle.config().func.full_device_id;
+
+    eprintln!(
+        "pcid-spawner: PCI {} vendor={:04x} device={:04x} class={:02x}",
+        handle.config().func.addr,
+        full_device_id.vendor_id,
+        full_device_id.device_id,
+        full_device_id.class
+    );
+
+    let Some(driver) = config
+        .drivers
+        .iter()
+        .find(|driver| driver.match_function(&full_device_id))
+    else {
+        eprintln!("pcid-spawner: no driver for {:04x}:{:04x}", full_device_id.vendor_id, full_device_id.device_id);
+        return Ok(None);
+    };
+    let driver_name = driver.name.clone();
+    eprintln!("pcid-spawner: MATCHED {:04x} -> {:?}", full_device_id.device_id, driver_name);
+
+    let mut args = driver.command.iter();
+
+    let program = args
+        .next()
+        .ok_or_else(|| anyhow!("driver configuration entry did not have any command!"))?;
+    let program = if program.starts_with('/') {
+        program.to_owned()
+    } else {
+        "/usr/lib/drivers/".to_owned() + program
+    };
+
+    let mut command = Command::new(&program);
+    command.args(args);
+
+    log::debug!("pcid-spawner: spawn {:?}", command);
+
+    handle.enable_device();
+
+    let channel_fd = handle.into_inner_fd();
+    command.env("PCID_CLIENT_CHANNEL", channel_fd.to_string());
+    // Suppress INFO/DEBUG logging for drivers (change to "info" or "debug" for verbose)
+    command.env("RUST_LOG", "warn");
+
+    // Other workers spawn drivers at the same time, so the channel is close-on-exec and only
+    // inherited by the driver it belongs to
+    unsafe {
+        command.pre_exec(move || {
+            syscall::fcntl(channel_fd as usize, syscall::F_SETFD, 0)
+                .map(|_| ())
+                .map_err(|err| io::Error::from_raw_os_error(err.errno))
+        });
+    }
+
+    // Spawn driver in parallel instead of blocking
+    match spawn_with_retry(&mut command) {
+        Ok(child) => {
+            eprintln!("pcid-spawner: spawned {} (pid unknown)", driver_name);
+            Ok(Some(SpawnedDriver {
+                name: driver_name,
+                child,
+                channel_fd,
+            }))
+        }
+        Err(err) => {
+            log::error!("pcid-spawner: failed to spawn {}: {err}", driver_name);
+            syscall::close(channel_fd as usize).unwrap();
+            Ok(None)
         }
     }
-    eprintln!("pcid-spawner: gave up waiting for {} after 100 attempts (30s)", path);
-    Err(anyhow::anyhow!("timeout waiting for {}", path))
 }
 
 fn main() -> Result<()> {
@@ -84,109 +295,38 @@ fn main() -> Result<()> {
     eprintln!("pcid-spawner: parsing config");
     let config: Config = toml::from_str(&config_data)?;
 
-    eprintln!("pcid-spawner: waiting for /scheme/pci");
-    // Wait for pcid to register the pci scheme (workaround for race condition)
-    let dir_iter = wait_for_scheme("/scheme/pci", 50, 100)?;
+    wait_for_scheme("/scheme/pci")?;
+    let dir_iter = fs::read_dir("/scheme/pci").context("failed to read /scheme/pci")?;
     eprintln!("pcid-spawner: starting device enumeration (parallel mode)");
 
-    // Collect spawned drivers for parallel execution
-    let mut spawned_drivers: Vec<SpawnedDriver> = Vec::new();
-
-    for entry in dir_iter {
-        let entry = entry.context("failed to get entry")?;
-        let device_path = entry.path();
-        log::trace!("ENTRY: {}", device_path.to_string_lossy());
-
-        eprintln!("pcid-spawner: trying {}", device_path.display());
-        let mut handle = match PciFunctionHandle::connect_by_path(&device_path) {
-            Ok(handle) => handle,
-            Err(err) => {
-                // Either the device is gone or it is already in-use by a driver.
-                eprintln!(
-                    "pcid-spawner: {} already in use: {err}",
-                    device_path.display(),
-                );
-                continue;
-            }
-        };
-
-        let full_device_id = handle.config().func.full_device_id;
-
-        eprintln!(
-            "pcid-spawner: PCI {} vendor={:04x} device={:04x} class={:02x}",
-            handle.config().func.addr,
-            full_device_id.vendor_id,
-            full_device_id.device_id,
-            full_device_id.class
-        );
-
-        let Some(driver) = config
-            .drivers
-            .iter()
-            .find(|driver| driver.match_function(&full_device_id))
-        else {
-            eprintln!("pcid-spawner: no driver for {:04x}:{:04x}", full_device_id.vendor_id, full_device_id.device_id);
-            continue;
-        };
-        let driver_name = driver.name.clone();
-        eprintln!("pcid-spawner: MATCHED {:04x} -> {:?}", full_device_id.device_id, driver_name);
-
-        let mut args = driver.command.iter();
-
-        let program = args
-            .next()
-            .ok_or_else(|| anyhow!("driver configuration entry did not have any command!"))?;
-        let program = if program.starts_with('/') {
-            program.to_owned()
-        } else {
-            "/usr/lib/drivers/".to_owned() + program
-        };
+    let device_paths = dir_iter
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<io::Result<Vec<_>>>()
+        .context("failed to get entry")?;
 
-        let mut command = Command::new(&program);
-        command.args(args);
-
-        log::debug!("pcid-spawner: spawn {:?}", command);
-
-        handle.enable_device();
-
-        let channel_fd = handle.into_inner_fd();
-        command.env("PCID_CLIENT_CHANNEL", channel_fd.to_string());
-        // Suppress INFO/DEBUG logging for drivers (change to "info" or "debug" for verbose)
-        command.env("RUST_LOG", "warn");
-
-        // Spawn driver in parallel instead of blocking
-        match command.spawn() {
-            Ok(child) => {
-                eprintln!("pcid-spawner: spawned {} (pid unknown)", driver_name);
-                spawned_drivers.push(SpawnedDriver {
-                    name: driver_name,
-                    child,
-                    channel_fd,
-                });
-            }
-            Err(err) => {
-                log::error!("pcid-spawner: failed to spawn {}: {err}", driver_name);
-                syscall::close(channel_fd as usize).unwrap();
-            }
+    // Connecting to a device can block, so probe several devices at once
+    let device_paths = Mutex::new(device_paths.into_iter());
+    let spawned_drivers = Mutex::new(Vec::new());
+    thread::scope(|scope| {
+        for _ in 0..PROBE_WORKERS {
+            scope.spawn(|| loop {
+                let Some(device_path) = device_paths.lock().unwrap().next() else {
+                    break;
+                };
+                match probe_device(&config, &device_path) {
+                    Ok(Some(spawned)) => spawned_drivers.lock().unwrap().push(spawned),
+                    Ok(None) => {}
+                    Err(err) => {
+                        log::error!("pcid-spawner: {}: {err}", device_path.display());
+                    }
+                }
+            });
         }
-    }
+    });
+    let spawned_drivers = spawned_drivers.into_inner().unwrap();
 
-    // Wait for all spawned drivers to complete
     eprintln!("pcid-spawner: waiting for {} drivers to initialize", spawned_drivers.len());
-    for mut spawned in spawned_drivers {
-        match spawned.child.wait() {
-            Ok(status) if !status.success() => {
-                log::error!("pcid-spawner: driver {} failed with {}", spawned.name, status);
-            }
-            Ok(_) => {
-                eprintln!("pcid-spawner: driver {} completed", spawned.name);
-            }
-            Err(err) => {
-                log::error!("pcid-spawner: failed to wait for {}: {err}", spawned.name);
-            }
-        }
-        syscall::close(spawned.channel_fd as usize).unwrap();
-    }
+    wait_ready(spawned_drivers);
 
     eprintln!("pcid-spawner: all drivers initialized");
     Ok(())
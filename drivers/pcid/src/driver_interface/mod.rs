@@ -327,10 +327,14 @@ impl PciFunctionHandle {
         Self::connect_common(channel_fd)
     }
 
+    /// Connects to the PCI function at `device_path`.
+    ///
+    /// The channel is opened close-on-exec, so that it is only inherited by the driver it is
+    /// explicitly handed to using `into_inner_fd`.
     pub fn connect_by_path(device_path: &Path) -> io::Result<Self> {
         let channel_fd = libredox::call::open(
             device_path.join("channel").to_str().unwrap(),
-            libredox::flag::O_RDWR,
+            libredox::flag::O_RDWR | libredox::flag::O_CLOEXEC,
             0,
         )
         .map_err(|err| io::Error::other(format!("failed to open pcid channel: {}", err)))?;
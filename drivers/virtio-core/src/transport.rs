@@ -597,6 +597,10 @@ pub trait Transport: Sync + Send {
     /// Use this when IRQ handling is done in the driver's main event loop.
     fn setup_queue_no_irq(&self, vector: u16) -> Result<Arc<Queue<'_>>, Error>;
 
+    /// Leaves the next `count` queues disabled; the next queue created gets the index
+    /// following them.
+    fn skip_queues(&self, count: u16);
+
     // TODO(andypython): Should this function be unsafe?
     fn reinit_queue(&self, queue: Arc<Queue>);
     fn insert_status(&self, status: DeviceStatusFlags);
@@ -806,6 +810,10 @@ impl Transport for StandardTransport<'_> {
         ))
     }
 
+    fn skip_queues(&self, count: u16) {
+        self.queue_index.fetch_add(count, Ordering::SeqCst);
+    }
+
     fn insert_status(&self, status: DeviceStatusFlags) {
         let mut common = self.common.lock().unwrap();
         let old = common.device_status.get();
@@ -4,73 +4,166 @@ use std::fs::File;
 use std::io::{ErrorKind, Read, Write};
 use std::rc::Rc;
 
+use smoltcp::phy::ChecksumCapabilities;
 use smoltcp::storage::PacketMetadata;
 use smoltcp::time::{Duration, Instant};
 use smoltcp::wire::{
     ArpOperation, ArpPacket, ArpRepr, EthernetAddress, EthernetFrame, EthernetProtocol,
-    EthernetRepr, IpAddress, IpCidr, Ipv4Address, Ipv4Cidr,
+    EthernetRepr, Icmpv6Message, Icmpv6Packet, Icmpv6Repr, IpAddress, IpCidr, IpProtocol,
+    Ipv4Address, Ipv4Cidr, Ipv6Address, Ipv6Cidr, Ipv6Packet, Ipv6Repr, NdiscNeighborFlags,
+    NdiscRepr, RawHardwareAddress, IPV6_LINK_LOCAL_ALL_NODES,
 };
 
-use super::LinkDevice;
+use super::fragmentation::{self, Reassembler};
+use super::{LinkDevice, LinkStats};
 
 struct Neighbor {
     hardware_address: EthernetAddress,
     expires_at: Instant,
 }
 
-#[derive(Debug, Default)]
-enum ArpState {
-    #[default]
-    Discovered,
-    Discovering {
-        target: Ipv4Address,
-        tries: u32,
-        silent_until: Instant,
-    },
+/// An ongoing ARP or neighbor discovery resolution
+#[derive(Debug)]
+struct Discovery {
+    tries: u32,
+    silent_until: Instant,
 }
 
 type PacketBuffer = smoltcp::storage::PacketBuffer<'static, IpAddress>;
 
 const EMPTY_MAC: EthernetAddress = EthernetAddress([0; 6]);
 
+/// Ethertype of 802.1Q tagged frames, the tag is followed by the ethertype of the payload
+const VLAN_ETHERTYPE: u16 = 0x8100;
+/// Ethertype of the outer tag of 802.1ad (QinQ) double tagged frames
+const QINQ_ETHERTYPE: u16 = 0x88a8;
+const VLAN_TAG_LEN: usize = 4;
+
+/// Parses the 802.1Q tag at the start of the payload of a tagged frame, returning the VLAN id and
+/// the ethertype of the rest of the payload
+fn parse_vlan_tag(payload: &[u8]) -> Option<(u16, EthernetProtocol)> {
+    let tag = payload.get(..VLAN_TAG_LEN)?;
+    let vlan_id = u16::from_be_bytes([tag[0], tag[1]]) & 0x0fff;
+    let ethertype = EthernetProtocol::from(u16::from_be_bytes([tag[2], tag[3]]));
+    Some((vlan_id, ethertype))
+}
+
+fn ethertype_of(addr: &IpAddress) -> EthernetProtocol {
+    match addr {
+        IpAddress::Ipv4(_) => EthernetProtocol::Ipv4,
+        IpAddress::Ipv6(_) => EthernetProtocol::Ipv6,
+    }
+}
+
+/// Ethernet address an IPv6 multicast address is sent to (RFC 2464 § 7)
+fn ipv6_multicast_mac(addr: Ipv6Address) -> EthernetAddress {
+    let octets = addr.octets();
+    EthernetAddress([0x33, 0x33, octets[12], octets[13], octets[14], octets[15]])
+}
+
+/// Solicited-node multicast address of `addr` (RFC 4291 § 2.7.1)
+fn solicited_node(addr: Ipv6Address) -> Ipv6Address {
+    let octets = addr.octets();
+    Ipv6Address::new(
+        0xff02,
+        0,
+        0,
+        0,
+        0,
+        1,
+        0xff00 | u16::from(octets[13]),
+        u16::from_be_bytes([octets[14], octets[15]]),
+    )
+}
+
 pub struct EthernetLink {
     name: Rc<str>,
     neighbor_cache: BTreeMap<IpAddress, Neighbor>,
-    arp_state: ArpState,
+    /// When expired neighbors are next removed from the cache
+    next_neighbor_sweep: Instant,
+    discovering: BTreeMap<IpAddress, Discovery>,
     waiting_packets: PacketBuffer,
+    /// Empty queue the size of `waiting_packets`, which it is swapped with when packets are
+    /// taken out of it
+    spare_waiting_packets: Option<PacketBuffer>,
+    /// Number of waiting packets sent once their neighbor was found
+    flushed_packets: u64,
+    stats: LinkStats,
+    reassembler: Reassembler,
+    /// Largest IP packet sent in a single frame, see `set_mtu`
+    mtu: usize,
+    /// VLAN the link is a member of, see `set_vlan_id`
+    vlan_id: Option<u16>,
+    /// Accept IPv4 frames addressed to other hardware addresses
+    promiscuous: bool,
     input_buffer: Vec<u8>,
     output_buffer: Vec<u8>,
     network_file: File,
     hardware_address: Option<EthernetAddress>,
-    ip_address: Option<Ipv4Cidr>,
+    ipv4_address: Option<Ipv4Cidr>,
+    ipv6_address: Option<Ipv6Cidr>,
 }
 
 impl EthernetLink {
     // TODO: Review these constants
     const MAX_WAITING_PACKET_COUNT: usize = 10;
-    const MTU: usize = 1500;
-    const WAITING_PACKET_BUFFER_SIZE: usize = Self::MTU * Self::MAX_WAITING_PACKET_COUNT;
+    const DEFAULT_MTU: usize = 1500;
+    /// The smallest MTU IPv4 has to work with (RFC 791)
+    const MIN_MTU: usize = 68;
+    /// Enough for jumbo frames
+    const MAX_MTU: usize = 9000;
 
     const NEIGHBOR_LIVE_TIME: Duration = Duration::from_secs(60);
+    const NEIGHBOR_SWEEP_INTERVAL: Duration = Duration::from_secs(10);
+    const NEIGHBOR_CACHE_CAPACITY: usize = 256;
     const ARP_SILENCE_TIME: Duration = Duration::from_secs(1);
+    const ARP_MAX_TRIES: u32 = 3;
 
     pub fn new(name: &str, network_file: File) -> Self {
-        let waiting_packets = PacketBuffer::new(
-            vec![PacketMetadata::EMPTY; Self::MAX_WAITING_PACKET_COUNT],
-            vec![0u8; Self::WAITING_PACKET_BUFFER_SIZE],
-        );
-
         Self {
             name: name.into(),
             network_file,
-            waiting_packets,
+            waiting_packets: Self::new_waiting_packets(Self::DEFAULT_MTU),
+            spare_waiting_packets: None,
+            flushed_packets: 0,
+            stats: LinkStats::default(),
+            reassembler: Reassembler::default(),
+            mtu: Self::DEFAULT_MTU,
+            vlan_id: None,
+            promiscuous: false,
             hardware_address: None,
-            ip_address: None,
-            input_buffer: vec![0u8; Self::MTU],
-            output_buffer: Vec::with_capacity(Self::MTU),
-            arp_state: Default::default(),
+            ipv4_address: None,
+            ipv6_address: None,
+            input_buffer: vec![0u8; 14 + Self::DEFAULT_MTU],
+            output_buffer: Vec::with_capacity(Self::DEFAULT_MTU),
+            discovering: Default::default(),
             neighbor_cache: Default::default(),
+            next_neighbor_sweep: Instant::ZERO,
+        }
+    }
+
+    fn new_waiting_packets(mtu: usize) -> PacketBuffer {
+        PacketBuffer::new(
+            vec![PacketMetadata::EMPTY; Self::MAX_WAITING_PACKET_COUNT],
+            vec![0u8; mtu * Self::MAX_WAITING_PACKET_COUNT],
+        )
+    }
+
+    /// An ethernet header, possibly with a VLAN tag, followed by up to MTU bytes of payload
+    fn max_frame_len(&self) -> usize {
+        14 + VLAN_TAG_LEN + self.mtu
+    }
+
+    /// Makes the link a member of the given VLAN. Outbound frames are then tagged with its id and
+    /// only inbound frames tagged with it are accepted. Without a VLAN id frames are sent
+    /// untagged, and tagged frames of any VLAN are accepted along with the untagged ones.
+    pub fn set_vlan_id(&mut self, vlan_id: Option<u16>) {
+        // 0 means no VLAN and 4095 is reserved
+        if let Some(id) = vlan_id.filter(|id| !(1..4095).contains(id)) {
+            error!("Invalid VLAN id {} for link {}", id, self.name);
+            return;
         }
+        self.vlan_id = vlan_id;
     }
 
     fn send_to<F>(&mut self, dst: EthernetAddress, size: usize, f: F, proto: EthernetProtocol)
@@ -81,24 +174,51 @@ impl EthernetLink {
             return;
         };
 
+        // The network driver would truncate anything bigger
+        if size > self.mtu {
+            error!(
+                "Dropped outbound frame on {} ({} bytes of payload exceed the MTU)",
+                self.name, size
+            );
+            self.stats.dropped_too_big += 1;
+            return;
+        }
+
         let repr = EthernetRepr {
             src_addr: hardware_address,
             dst_addr: dst,
-            ethertype: proto,
+            ethertype: match self.vlan_id {
+                Some(_) => EthernetProtocol::Unknown(VLAN_ETHERTYPE),
+                None => proto,
+            },
+        };
+        let tag_len = if self.vlan_id.is_some() {
+            VLAN_TAG_LEN
+        } else {
+            0
         };
 
         self.output_buffer.clear();
-        self.output_buffer.resize(repr.buffer_len() + size, 0);
+        self.output_buffer
+            .resize(repr.buffer_len() + tag_len + size, 0);
         let mut frame = EthernetFrame::new_unchecked(&mut self.output_buffer);
         repr.emit(&mut frame);
 
-        f(frame.payload_mut());
+        let payload = frame.payload_mut();
+        if let Some(vlan_id) = self.vlan_id {
+            payload[..2].copy_from_slice(&vlan_id.to_be_bytes());
+            payload[2..4].copy_from_slice(&u16::from(proto).to_be_bytes());
+        }
+        f(&mut payload[tag_len..]);
 
         if let Err(_) = self.network_file.write_all(&self.output_buffer) {
             error!(
                 "Dropped outboud packet on {} (failed to write to network file)",
                 self.name
             )
+        } else {
+            self.stats.tx_packets += 1;
+            self.stats.tx_bytes += self.output_buffer.len() as u64;
         }
     }
 
@@ -107,12 +227,13 @@ impl EthernetLink {
             return;
         };
 
-        let Some(ip_addr) = self.ip_address else {
+        let Some(ip_addr) = self.ipv4_address else {
             return;
         };
 
         let Ok(repr) = ArpPacket::new_checked(packet).and_then(|packet| ArpRepr::parse(&packet))
         else {
+            self.stats.malformed_frames += 1;
             debug!("Dropped incomming arp packet on {} (Malformed)", self.name);
             return;
         };
@@ -145,16 +266,24 @@ impl EthernetLink {
                     return;
                 }
 
+                if source_protocol_addr == ip_addr.address() {
+                    if source_hardware_addr != hardware_address {
+                        warn!(
+                            "Address conflict on {}: {} is also used by {}",
+                            self.name, source_protocol_addr, source_hardware_addr
+                        );
+                    }
+                    return;
+                }
+
                 if ip_addr.address() != target_protocol_addr {
                     return;
                 }
 
-                self.neighbor_cache.insert(
+                self.add_neighbor(
                     IpAddress::Ipv4(source_protocol_addr),
-                    Neighbor {
-                        hardware_address: source_hardware_addr,
-                        expires_at: now + Self::NEIGHBOR_LIVE_TIME,
-                    },
+                    source_hardware_addr,
+                    now,
                 );
 
                 if let ArpOperation::Request = operation {
@@ -173,76 +302,212 @@ impl EthernetLink {
                         EthernetProtocol::Arp,
                     );
                 }
-                self.check_waiting_packets(source_protocol_addr, source_hardware_addr, now);
+                self.check_waiting_packets(
+                    IpAddress::Ipv4(source_protocol_addr),
+                    source_hardware_addr,
+                );
             }
             _ => {}
         }
     }
 
-    fn check_waiting_packets(&mut self, ip: Ipv4Address, mac: EthernetAddress, now: Instant) {
-        let mut waiting_packets =
-            std::mem::replace(&mut self.waiting_packets, PacketBuffer::new(vec![], vec![]));
-        loop {
-            match waiting_packets.peek() {
-                Ok((IpAddress::Ipv4(dst), _)) if dst == &ip => {}
-                Ok((IpAddress::Ipv4(dst), _)) => {
-                    self.arp_state = ArpState::Discovering {
-                        target: *dst,
-                        tries: 0,
-                        silent_until: Instant::ZERO,
-                    };
-                    self.send_arp(now);
-                    break;
-                }
-                Err(_) => {
-                    self.arp_state = ArpState::Discovered;
-                    break;
+    /// Handles ICMPv6 neighbor solicitations and advertisements, which play the role of ARP
+    /// for IPv6. Returns false if the packet is anything else and should be passed up.
+    fn process_ndisc(&mut self, packet: &[u8], now: Instant) -> bool {
+        let Ok(ip_packet) = Ipv6Packet::new_checked(packet) else {
+            return false;
+        };
+        if ip_packet.next_header() != IpProtocol::Icmpv6 {
+            return false;
+        }
+        let Ok(icmp_packet) = Icmpv6Packet::new_checked(ip_packet.payload()) else {
+            return false;
+        };
+        match icmp_packet.msg_type() {
+            Icmpv6Message::NeighborSolicit | Icmpv6Message::NeighborAdvert => {}
+            _ => return false,
+        }
+
+        let Some(hardware_address) = self.hardware_address else {
+            return true;
+        };
+
+        let Some(ip_addr) = self.ipv6_address else {
+            return true;
+        };
+
+        let src_addr = ip_packet.src_addr();
+        let Ok(Icmpv6Repr::Ndisc(repr)) = Icmpv6Repr::parse(
+            &src_addr,
+            &ip_packet.dst_addr(),
+            &icmp_packet,
+            &ChecksumCapabilities::default(),
+        ) else {
+            self.stats.malformed_frames += 1;
+            debug!(
+                "Dropped incomming ndisc packet on {} (Malformed)",
+                self.name
+            );
+            return true;
+        };
+
+        // Neighbor discovery packets must not have been forwarded by a router
+        if ip_packet.hop_limit() != 255 {
+            return true;
+        }
+
+        let (neighbor_addr, lladdr) = match repr {
+            NdiscRepr::NeighborSolicit {
+                target_addr,
+                lladdr,
+            } => {
+                if target_addr != ip_addr.address() || src_addr.is_unspecified() {
+                    // Not for us, or duplicate address detection which we don't take part in
+                    return true;
                 }
+                (src_addr, lladdr)
             }
+            NdiscRepr::NeighborAdvert {
+                target_addr,
+                lladdr,
+                ..
+            } => (target_addr, lladdr),
+            _ => return true,
+        };
 
-            let (_, packet) = waiting_packets.dequeue().unwrap();
-            self.send_to(
-                mac,
-                packet.len(),
-                |buf| buf.copy_from_slice(packet),
-                EthernetProtocol::Ipv4,
-            );
+        let Some(neighbor_mac) = lladdr
+            .filter(|lladdr| lladdr.len() == 6)
+            .map(|lladdr| EthernetAddress::from_bytes(lladdr.as_bytes()))
+            .filter(|mac| mac.is_unicast())
+        else {
+            return true;
+        };
+
+        self.add_neighbor(IpAddress::Ipv6(neighbor_addr), neighbor_mac, now);
+
+        if let NdiscRepr::NeighborSolicit { .. } = repr {
+            let response = NdiscRepr::NeighborAdvert {
+                flags: NdiscNeighborFlags::SOLICITED | NdiscNeighborFlags::OVERRIDE,
+                target_addr: ip_addr.address(),
+                lladdr: Some(RawHardwareAddress::from_bytes(hardware_address.as_bytes())),
+            };
+            self.send_ndisc(ip_addr.address(), neighbor_addr, neighbor_mac, response);
         }
+        self.check_waiting_packets(IpAddress::Ipv6(neighbor_addr), neighbor_mac);
 
-        self.waiting_packets = waiting_packets;
+        true
     }
 
-    fn drop_waiting_packets(&mut self, ip: Ipv4Address, now: Instant) {
-        loop {
-            match self.waiting_packets.peek() {
-                Ok((IpAddress::Ipv4(dst), _)) if dst == &ip => {}
-                Ok((IpAddress::Ipv4(dst), _)) => {
-                    self.arp_state = ArpState::Discovering {
-                        target: *dst,
-                        tries: 0,
-                        silent_until: Instant::ZERO,
-                    };
+    /// Whether frames sent to `dst` are meant for this link
+    fn is_for_us(&self, dst: EthernetAddress, hardware_address: EthernetAddress) -> bool {
+        // We let EMPTY_MAC pass because somehow this is the mac used when net=redir is used
+        if dst.is_broadcast() || dst == EMPTY_MAC || dst == hardware_address {
+            return true;
+        }
 
-                    self.send_arp(now);
+        // Neighbor solicitations are sent to the solicited-node multicast group of the address
+        match self.ipv6_address {
+            Some(cidr) => {
+                dst == ipv6_multicast_mac(IPV6_LINK_LOCAL_ALL_NODES)
+                    || dst == ipv6_multicast_mac(solicited_node(cidr.address()))
+            }
+            None => false,
+        }
+    }
 
-                    return;
-                }
-                Err(_) => {
-                    self.arp_state = ArpState::Discovered;
-                    return;
-                }
+    fn send_ndisc(
+        &mut self,
+        src_addr: Ipv6Address,
+        dst_addr: Ipv6Address,
+        dst_mac: EthernetAddress,
+        repr: NdiscRepr,
+    ) {
+        let icmp_repr = Icmpv6Repr::Ndisc(repr);
+        let ip_repr = Ipv6Repr {
+            src_addr,
+            dst_addr,
+            next_header: IpProtocol::Icmpv6,
+            payload_len: icmp_repr.buffer_len(),
+            hop_limit: 255,
+        };
+
+        self.send_to(
+            dst_mac,
+            ip_repr.buffer_len() + icmp_repr.buffer_len(),
+            |buf| {
+                let mut packet = Ipv6Packet::new_unchecked(buf);
+                ip_repr.emit(&mut packet);
+                icmp_repr.emit(
+                    &src_addr,
+                    &dst_addr,
+                    &mut Icmpv6Packet::new_unchecked(packet.payload_mut()),
+                    &ChecksumCapabilities::default(),
+                );
+            },
+            EthernetProtocol::Ipv6,
+        );
+    }
+
+    /// Removes the waiting packets for `ip` from the queue and calls `f` on each of them, keeping
+    /// the packets waiting for other neighbors in order.
+    fn take_waiting_packets<F>(&mut self, ip: IpAddress, mut f: F) -> u64
+    where
+        F: FnMut(&mut Self, &[u8]),
+    {
+        let spare = self
+            .spare_waiting_packets
+            .take()
+            .unwrap_or_else(|| Self::new_waiting_packets(self.mtu));
+        let mut waiting_packets = std::mem::replace(&mut self.waiting_packets, spare);
+        let mut count = 0;
+        while let Ok((dst, packet)) = waiting_packets.dequeue() {
+            if dst == ip {
+                f(self, packet);
+                count += 1;
+            } else if let Ok(buf) = self.waiting_packets.enqueue(packet.len(), dst) {
+                buf.copy_from_slice(packet);
             }
+        }
+        self.spare_waiting_packets = Some(waiting_packets);
+        count
+    }
 
-            let _ = self.waiting_packets.dequeue();
-            debug!(
-                "Dropped packet on {} because neighbor was not found",
-                self.name
+    /// Sends the packets waiting for the neighbor `ip`, whose hardware address was just learned,
+    /// whether or not we were looking for it.
+    fn check_waiting_packets(&mut self, ip: IpAddress, mac: EthernetAddress) {
+        self.discovering.remove(&ip);
+
+        let flushed = self.take_waiting_packets(ip, |link, packet| {
+            link.send_to(
+                mac,
+                packet.len(),
+                |buf| buf.copy_from_slice(packet),
+                ethertype_of(&ip),
             )
+        });
+        if flushed > 0 {
+            self.flushed_packets += flushed;
+            debug!(
+                "Flushed {} waiting packets to {} on {} ({} flushed, {} dropped so far)",
+                flushed, ip, self.name, self.flushed_packets, self.stats.dropped_no_neighbor
+            );
+        }
+    }
+
+    fn drop_waiting_packets(&mut self, ip: IpAddress) {
+        let dropped = self.take_waiting_packets(ip, |_, _| {});
+        if dropped > 0 {
+            self.stats.dropped_no_neighbor += dropped;
+            debug!(
+                "Dropped {} packets on {} because neighbor {} was not found ({} flushed, {} dropped so far)",
+                dropped, self.name, ip, self.flushed_packets, self.stats.dropped_no_neighbor
+            );
         }
     }
 
     fn handle_missing_neighbor(&mut self, next_hop: IpAddress, packet: &[u8], now: Instant) {
         let Ok(buf) = self.waiting_packets.enqueue(packet.len(), next_hop) else {
+            self.stats.dropped_no_neighbor += 1;
             warn!(
                 "Dropped packet on {} because waiting queue was full",
                 self.name
@@ -251,63 +516,193 @@ impl EthernetLink {
         };
         buf.copy_from_slice(packet);
 
-        let IpAddress::Ipv4(next_hop) = next_hop;
-        if let ArpState::Discovered = self.arp_state {
-            self.arp_state = ArpState::Discovering {
-                target: next_hop,
+        self.start_discovery(next_hop, now);
+    }
+
+    fn start_discovery(&mut self, target: IpAddress, now: Instant) {
+        if let Entry::Vacant(e) = self.discovering.entry(target) {
+            e.insert(Discovery {
                 tries: 0,
                 silent_until: Instant::ZERO,
-            };
-
-            self.send_arp(now)
+            });
+            self.send_discovery(target, now);
         }
     }
 
+    /// Retries all ongoing resolutions which are due
     fn send_arp(&mut self, now: Instant) {
+        let targets: Vec<IpAddress> = self.discovering.keys().copied().collect();
+        for target in targets {
+            self.send_discovery(target, now);
+        }
+    }
+
+    /// Caches the hardware address of `ip`, evicting the neighbor closest to expiring if the cache
+    /// is full
+    fn add_neighbor(&mut self, ip: IpAddress, hardware_address: EthernetAddress, now: Instant) {
+        self.neighbor_cache.insert(
+            ip,
+            Neighbor {
+                hardware_address,
+                expires_at: now + Self::NEIGHBOR_LIVE_TIME,
+            },
+        );
+
+        if self.neighbor_cache.len() > Self::NEIGHBOR_CACHE_CAPACITY {
+            let oldest = self
+                .neighbor_cache
+                .iter()
+                .min_by_key(|(_, neighbor)| neighbor.expires_at)
+                .map(|(&ip, _)| ip);
+            if let Some(oldest) = oldest {
+                self.neighbor_cache.remove(&oldest);
+            }
+        }
+    }
+
+    /// Removes the expired neighbors every NEIGHBOR_SWEEP_INTERVAL, so that the ones which aren't
+    /// contacted anymore don't stay around forever. Neighbors which are being refreshed are kept
+    /// until their discovery fails.
+    fn sweep_neighbors(&mut self, now: Instant) {
+        if now < self.next_neighbor_sweep {
+            return;
+        }
+        self.next_neighbor_sweep = now + Self::NEIGHBOR_SWEEP_INTERVAL;
+
+        self.neighbor_cache
+            .retain(|ip, neighbor| neighbor.expires_at >= now || self.discovering.contains_key(ip));
+    }
+
+    fn send_discovery(&mut self, target: IpAddress, now: Instant) {
+        let Some(discovery) = self.discovering.get_mut(&target) else {
+            return;
+        };
+
+        if discovery.silent_until > now {
+            return;
+        }
+
+        if discovery.tries >= Self::ARP_MAX_TRIES {
+            self.discovering.remove(&target);
+            self.neighbor_cache.remove(&target);
+            self.drop_waiting_packets(target);
+            return;
+        }
+
+        discovery.tries += 1;
+        discovery.silent_until = now + Self::ARP_SILENCE_TIME;
+
+        match target {
+            IpAddress::Ipv4(target) => self.send_arp_request(target),
+            IpAddress::Ipv6(target) => self.send_neighbor_solicit(target),
+        }
+    }
+
+    /// Sends an ARP probe (RFC 5227) for a newly assigned address, which a host already using it
+    /// answers and `process_arp` warns about, then a gratuitous ARP so that switches and
+    /// neighbors learn our hardware address right away.
+    fn announce_ipv4_address(&mut self, addr: Ipv4Address) {
+        let Some(hardware_address) = self.hardware_address else {
+            return;
+        };
+
+        let probe = ArpRepr::EthernetIpv4 {
+            operation: ArpOperation::Request,
+            source_hardware_addr: hardware_address,
+            source_protocol_addr: Ipv4Address::UNSPECIFIED,
+            target_hardware_addr: EMPTY_MAC,
+            target_protocol_addr: addr,
+        };
+        let announcement = ArpRepr::EthernetIpv4 {
+            operation: ArpOperation::Request,
+            source_hardware_addr: hardware_address,
+            source_protocol_addr: addr,
+            target_hardware_addr: EMPTY_MAC,
+            target_protocol_addr: addr,
+        };
+
+        for arp_repr in [probe, announcement] {
+            self.send_to(
+                EthernetAddress::BROADCAST,
+                arp_repr.buffer_len(),
+                |buf| arp_repr.emit(&mut ArpPacket::new_unchecked(buf)),
+                EthernetProtocol::Arp,
+            );
+        }
+    }
+
+    fn send_arp_request(&mut self, target: Ipv4Address) {
         let Some(hardware_address) = self.hardware_address else {
             return;
         };
 
-        let Some(ip_address) = self.ip_address else {
+        let Some(ip_address) = self.ipv4_address else {
             return;
         };
 
-        match self.arp_state {
-            ArpState::Discovered => {}
-            ArpState::Discovering { silent_until, .. } if silent_until > now => {}
-            ArpState::Discovering { target, tries, .. } if tries >= 3 => {
-                self.drop_waiting_packets(target, now)
-            }
-            ArpState::Discovering {
-                target,
-                ref mut tries,
-                ref mut silent_until,
-            } => {
-                let arp_repr = ArpRepr::EthernetIpv4 {
-                    operation: ArpOperation::Request,
-                    source_hardware_addr: hardware_address,
-                    source_protocol_addr: ip_address.address(),
-                    target_hardware_addr: EthernetAddress::BROADCAST,
-                    target_protocol_addr: target,
-                };
+        let arp_repr = ArpRepr::EthernetIpv4 {
+            operation: ArpOperation::Request,
+            source_hardware_addr: hardware_address,
+            source_protocol_addr: ip_address.address(),
+            target_hardware_addr: EthernetAddress::BROADCAST,
+            target_protocol_addr: target,
+        };
 
-                *tries += 1;
-                *silent_until = now + Self::ARP_SILENCE_TIME;
+        self.stats.arp_requests_sent += 1;
+        self.send_to(
+            EthernetAddress::BROADCAST,
+            arp_repr.buffer_len(),
+            |buf| arp_repr.emit(&mut ArpPacket::new_unchecked(buf)),
+            EthernetProtocol::Arp,
+        );
+    }
 
-                self.send_to(
-                    EthernetAddress::BROADCAST,
-                    arp_repr.buffer_len(),
-                    |buf| arp_repr.emit(&mut ArpPacket::new_unchecked(buf)),
-                    EthernetProtocol::Arp,
-                );
-            }
-        }
+    fn send_neighbor_solicit(&mut self, target: Ipv6Address) {
+        let Some(hardware_address) = self.hardware_address else {
+            return;
+        };
+
+        let Some(ip_address) = self.ipv6_address else {
+            return;
+        };
+
+        let repr = NdiscRepr::NeighborSolicit {
+            target_addr: target,
+            lladdr: Some(RawHardwareAddress::from_bytes(hardware_address.as_bytes())),
+        };
+
+        let dst_addr = solicited_node(target);
+        self.stats.arp_requests_sent += 1;
+        self.send_ndisc(
+            ip_address.address(),
+            dst_addr,
+            ipv6_multicast_mac(dst_addr),
+            repr,
+        );
     }
 }
 
 impl LinkDevice for EthernetLink {
     fn send(&mut self, next_hop: IpAddress, packet: &[u8], now: Instant) {
-        let local_broadcast = match self.ip_address.and_then(|cidr| cidr.broadcast()) {
+        self.sweep_neighbors(now);
+
+        if packet.len() > self.mtu {
+            // IPv6 packets are only fragmented by their source
+            let fragmented = matches!(next_hop, IpAddress::Ipv4(_))
+                && fragmentation::fragment(packet, self.mtu, |fragment| {
+                    self.send(next_hop, fragment, now)
+                });
+            if !fragmented {
+                warn!(
+                    "Dropped outbound packet on {} (larger than MTU and can't be fragmented)",
+                    self.name
+                );
+                self.stats.dropped_too_big += 1;
+            }
+            return;
+        }
+
+        let local_broadcast = match self.ipv4_address.and_then(|cidr| cidr.broadcast()) {
             Some(addr) => IpAddress::Ipv4(addr) == next_hop,
             None => false,
         };
@@ -322,21 +717,34 @@ impl LinkDevice for EthernetLink {
             return;
         }
 
-        match self.neighbor_cache.entry(next_hop) {
-            Entry::Vacant(_) => self.handle_missing_neighbor(next_hop, packet, now),
-            Entry::Occupied(e) => {
-                if e.get().expires_at < now {
-                    e.remove();
-                    self.handle_missing_neighbor(next_hop, packet, now)
-                } else {
-                    let mac = e.get().hardware_address;
-                    self.send_to(
-                        mac,
-                        packet.len(),
-                        |buf| buf.copy_from_slice(packet),
-                        EthernetProtocol::Ipv4,
-                    )
+        if let IpAddress::Ipv6(addr) = next_hop {
+            if addr.is_multicast() {
+                self.send_to(
+                    ipv6_multicast_mac(addr),
+                    packet.len(),
+                    |buf| buf.copy_from_slice(packet),
+                    EthernetProtocol::Ipv6,
+                );
+                return;
+            }
+        }
+
+        match self.neighbor_cache.get(&next_hop) {
+            None => self.handle_missing_neighbor(next_hop, packet, now),
+            Some(neighbor) => {
+                let mac = neighbor.hardware_address;
+                if neighbor.expires_at < now {
+                    // Keep using an expired entry while it's being refreshed, so that traffic to
+                    // busy neighbors like the gateway doesn't stall every NEIGHBOR_LIVE_TIME.
+                    // The entry is only removed if the neighbor doesn't answer anymore.
+                    self.start_discovery(next_hop, now);
                 }
+                self.send_to(
+                    mac,
+                    packet.len(),
+                    |buf| buf.copy_from_slice(packet),
+                    ethertype_of(&next_hop),
+                )
             }
         }
     }
@@ -346,39 +754,113 @@ impl LinkDevice for EthernetLink {
             return None;
         };
 
+        self.sweep_neighbors(now);
+
         let mut input_buffer = std::mem::replace(&mut self.input_buffer, Vec::new());
+        input_buffer.resize(self.max_frame_len(), 0);
         loop {
-            if let Err(e) = self.network_file.read(&mut input_buffer) {
-                if e.kind() != ErrorKind::WouldBlock {
-                    error!("Failed to read ethernet device on link {}", self.name);
-                } else {
-                    // No packet to read but we check if we have arp to send
+            // Only the first `frame_len` bytes belong to this frame, the rest of the buffer holds
+            // whatever previous frames left there.
+            let frame_len = match self.network_file.read(&mut input_buffer) {
+                Ok(0) => {
                     self.send_arp(now);
+                    self.input_buffer = input_buffer;
+                    return None;
                 }
-                self.input_buffer = input_buffer;
-                return None;
-            }
-            let packet = EthernetFrame::new_unchecked(&input_buffer[..]);
+                Ok(frame_len) => frame_len,
+                Err(e) => {
+                    if e.kind() != ErrorKind::WouldBlock {
+                        error!("Failed to read ethernet device on link {}", self.name);
+                    } else {
+                        // No packet to read but we check if we have arp to send
+                        self.send_arp(now);
+                    }
+                    self.input_buffer = input_buffer;
+                    return None;
+                }
+            };
+            self.stats.rx_packets += 1;
+            self.stats.rx_bytes += frame_len as u64;
+
+            let Ok(packet) = EthernetFrame::new_checked(&input_buffer[..frame_len]) else {
+                self.stats.malformed_frames += 1;
+                debug!("Dropped incomming frame on {} (Malformed)", self.name);
+                continue;
+            };
             let Ok(repr) = EthernetRepr::parse(&packet) else {
+                self.stats.malformed_frames += 1;
                 debug!("Dropped incomming frame on {} (Malformed)", self.name);
                 continue;
             };
 
-            // We let EMPTY_MAC pass because somehow this is the mac used when net=redir is used
-            if !repr.dst_addr.is_broadcast()
-                && repr.dst_addr != EMPTY_MAC
-                && repr.dst_addr != hardware_address
-            {
+            let for_us = self.is_for_us(repr.dst_addr, hardware_address);
+            if !for_us && !self.promiscuous {
                 // Drop packets which are not for us
                 continue;
             }
 
-            match repr.ethertype {
+            let header_len = frame_len - packet.payload().len();
+            let (ethertype, payload_start) = match repr.ethertype {
+                EthernetProtocol::Unknown(VLAN_ETHERTYPE) => {
+                    let Some((vlan_id, ethertype)) = parse_vlan_tag(packet.payload()) else {
+                        self.stats.malformed_frames += 1;
+                        debug!("Dropped incomming frame on {} (Malformed)", self.name);
+                        continue;
+                    };
+                    if let EthernetProtocol::Unknown(VLAN_ETHERTYPE | QINQ_ETHERTYPE) = ethertype {
+                        warn!(
+                            "Dropped incomming frame on {} (Double tagged VLAN frames are not supported)",
+                            self.name
+                        );
+                        continue;
+                    }
+                    if self.vlan_id.is_some_and(|id| id != vlan_id) {
+                        // Drop packets of other VLANs
+                        continue;
+                    }
+                    (ethertype, header_len + VLAN_TAG_LEN)
+                }
+                EthernetProtocol::Unknown(QINQ_ETHERTYPE) => {
+                    warn!(
+                        "Dropped incomming frame on {} (Double tagged VLAN frames are not supported)",
+                        self.name
+                    );
+                    continue;
+                }
+                // Untagged frames don't belong to our VLAN
+                _ if self.vlan_id.is_some() => continue,
+                ethertype => (ethertype, header_len),
+            };
+            if !for_us {
+                // Neighbor discovery traffic of other hosts must not end up in our neighbor cache
+                if ethertype != EthernetProtocol::Ipv4 {
+                    continue;
+                }
+                debug!(
+                    "Accepted frame for {} on {} (promiscuous mode)",
+                    repr.dst_addr, self.name
+                );
+            }
+            let payload = &input_buffer[payload_start..frame_len];
+
+            match ethertype {
                 EthernetProtocol::Ipv4 => {
-                    self.input_buffer = input_buffer;
-                    return Some(EthernetFrame::new_unchecked(&self.input_buffer[..]).payload());
+                    if !fragmentation::is_fragment(payload) {
+                        self.input_buffer = input_buffer;
+                        return Some(&self.input_buffer[payload_start..frame_len]);
+                    }
+                    if self.reassembler.process(payload, now) {
+                        self.input_buffer = input_buffer;
+                        return Some(self.reassembler.datagram());
+                    }
+                }
+                EthernetProtocol::Ipv6 => {
+                    if !self.process_ndisc(payload, now) {
+                        self.input_buffer = input_buffer;
+                        return Some(&self.input_buffer[payload_start..frame_len]);
+                    }
                 }
-                EthernetProtocol::Arp => self.process_arp(packet.payload(), now),
+                EthernetProtocol::Arp => self.process_arp(payload, now),
                 _ => continue,
             }
         }
@@ -397,16 +879,360 @@ impl LinkDevice for EthernetLink {
         self.hardware_address
     }
 
+    fn stats(&self) -> LinkStats {
+        self.stats.clone()
+    }
+
     fn set_mac_address(&mut self, addr: EthernetAddress) {
         self.hardware_address = Some(addr)
     }
 
-    fn ip_address(&self) -> Option<IpCidr> {
-        Some(IpCidr::Ipv4(self.ip_address?))
+    fn mtu(&self) -> Option<usize> {
+        Some(self.mtu)
+    }
+
+    fn set_mtu(&mut self, mtu: usize) {
+        let mtu = mtu.clamp(Self::MIN_MTU, Self::MAX_MTU);
+        if mtu == self.mtu {
+            return;
+        }
+        self.mtu = mtu;
+
+        self.input_buffer.resize(self.max_frame_len(), 0);
+        self.input_buffer.shrink_to_fit();
+
+        // Packets which no longer fit in a frame would be dropped when sent anyway
+        self.spare_waiting_packets = None;
+        let mut waiting_packets =
+            std::mem::replace(&mut self.waiting_packets, Self::new_waiting_packets(mtu));
+        while let Ok((dst, packet)) = waiting_packets.dequeue() {
+            if packet.len() > mtu {
+                self.stats.dropped_too_big += 1;
+            } else if let Ok(buf) = self.waiting_packets.enqueue(packet.len(), dst) {
+                buf.copy_from_slice(packet);
+            }
+        }
+    }
+
+    fn set_promiscuous(&mut self, on: bool) {
+        self.promiscuous = on;
+    }
+
+    fn ip_addresses(&self) -> Vec<IpCidr> {
+        let ipv4 = self.ipv4_address.map(IpCidr::Ipv4);
+        let ipv6 = self.ipv6_address.map(IpCidr::Ipv6);
+        ipv4.into_iter().chain(ipv6).collect()
     }
 
     fn set_ip_address(&mut self, addr: IpCidr) {
-        let IpCidr::Ipv4(addr) = addr;
-        self.ip_address = Some(addr);
+        match addr {
+            IpCidr::Ipv4(addr) => {
+                self.ipv4_address = Some(addr);
+                self.announce_ipv4_address(addr.address());
+            }
+            IpCidr::Ipv6(addr) => self.ipv6_address = Some(addr),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::net::UnixDatagram;
+
+    use smoltcp::wire::{Ipv4Packet, Ipv4Repr};
+
+    use super::*;
+
+    /// A broadcast frame carrying an IPv4 packet with `payload_len` bytes of `fill`
+    fn ipv4_frame(payload_len: usize, fill: u8) -> Vec<u8> {
+        let eth_repr = EthernetRepr {
+            src_addr: EthernetAddress([0x52, 0x54, 0, 0x12, 0x34, 0x56]),
+            dst_addr: EthernetAddress::BROADCAST,
+            ethertype: EthernetProtocol::Ipv4,
+        };
+        let ip_repr = Ipv4Repr {
+            src_addr: Ipv4Address::new(10, 0, 2, 2),
+            dst_addr: Ipv4Address::BROADCAST,
+            next_header: IpProtocol::Udp,
+            payload_len,
+            hop_limit: 64,
+        };
+
+        let mut buf = vec![0u8; eth_repr.buffer_len() + ip_repr.buffer_len() + payload_len];
+        let mut frame = EthernetFrame::new_unchecked(&mut buf[..]);
+        eth_repr.emit(&mut frame);
+        let mut packet = Ipv4Packet::new_unchecked(frame.payload_mut());
+        ip_repr.emit(&mut packet, &ChecksumCapabilities::default());
+        packet.payload_mut().fill(fill);
+        buf
+    }
+
+    #[test]
+    fn recv_returns_only_the_current_frame() {
+        let (device, link_end) = UnixDatagram::pair().unwrap();
+        link_end.set_nonblocking(true).unwrap();
+        let mut link = EthernetLink::new("eth0", File::from(std::os::fd::OwnedFd::from(link_end)));
+        link.set_mac_address(EthernetAddress([0x52, 0x54, 0, 0xab, 0xcd, 0xef]));
+
+        let short = ipv4_frame(8, 0xaa);
+        let full = ipv4_frame(EthernetLink::DEFAULT_MTU - 20, 0xbb);
+        for frame in [&short, &full, &short] {
+            device.send(frame).unwrap();
+        }
+
+        let now = Instant::ZERO;
+        assert_eq!(link.recv(now), Some(&short[14..]));
+        assert_eq!(link.recv(now), Some(&full[14..]));
+        assert_eq!(link.recv(now), Some(&short[14..]));
+        assert_eq!(link.recv(now), None);
+    }
+
+    /// Send an IPv4 packet with a 2000 byte payload to the broadcast address, returning the
+    /// frames written to the device
+    fn send_jumbo_packet(
+        link: &mut EthernetLink,
+        device: &UnixDatagram,
+        dont_frag: bool,
+    ) -> Vec<Vec<u8>> {
+        let mut packet = ipv4_frame(2000, 0xcc)[14..].to_vec();
+        let mut ip_packet = Ipv4Packet::new_unchecked(&mut packet[..]);
+        ip_packet.set_dont_frag(dont_frag);
+        ip_packet.fill_checksum();
+
+        link.send(
+            IpAddress::Ipv4(Ipv4Address::BROADCAST),
+            &packet,
+            Instant::ZERO,
+        );
+
+        let mut frames = vec![];
+        let mut buf = [0u8; 4096];
+        while let Ok(len) = device.recv(&mut buf) {
+            frames.push(buf[..len].to_vec());
+        }
+        frames
+    }
+
+    #[test]
+    fn send_fragments_or_rejects_packets_over_the_mtu() {
+        let (device, link_end) = UnixDatagram::pair().unwrap();
+        device.set_nonblocking(true).unwrap();
+        let mut link = EthernetLink::new("eth0", File::from(std::os::fd::OwnedFd::from(link_end)));
+        link.set_mac_address(EthernetAddress([0x52, 0x54, 0, 0xab, 0xcd, 0xef]));
+
+        let frames = send_jumbo_packet(&mut link, &device, false);
+        assert_eq!(frames.len(), 2);
+        let mut payload_len = 0;
+        for frame in &frames {
+            assert!(frame.len() <= link.max_frame_len());
+            let fragment = Ipv4Packet::new_checked(&frame[14..]).unwrap();
+            assert!(fragment.verify_checksum());
+            payload_len += fragment.payload().len();
+        }
+        assert_eq!(payload_len, 2000);
+        assert_eq!(link.stats().dropped_too_big, 0);
+
+        assert!(send_jumbo_packet(&mut link, &device, true).is_empty());
+        assert_eq!(link.stats().dropped_too_big, 1);
+    }
+
+    #[test]
+    fn neighbor_cache_is_bounded_and_swept() {
+        let (_device, link_end) = UnixDatagram::pair().unwrap();
+        let mut link = EthernetLink::new("eth0", File::from(std::os::fd::OwnedFd::from(link_end)));
+        let mac = EthernetAddress([0x52, 0x54, 0, 0x12, 0x34, 0x56]);
+        let neighbor = |i: usize| IpAddress::Ipv4(Ipv4Address::from_bits(0x0a00_0000 + i as u32));
+
+        let count = EthernetLink::NEIGHBOR_CACHE_CAPACITY + 10;
+        for i in 0..count {
+            link.add_neighbor(neighbor(i), mac, Instant::from_millis(i as i64));
+        }
+        assert_eq!(
+            link.neighbor_cache.len(),
+            EthernetLink::NEIGHBOR_CACHE_CAPACITY
+        );
+        // The neighbors added first were evicted
+        assert!(!link.neighbor_cache.contains_key(&neighbor(9)));
+        assert!(link.neighbor_cache.contains_key(&neighbor(10)));
+
+        // Neighbors are only swept once they expired, unless they are being refreshed
+        let now = Instant::from_millis(count as i64) + EthernetLink::NEIGHBOR_LIVE_TIME;
+        link.discovering.insert(
+            neighbor(10),
+            Discovery {
+                tries: 1,
+                silent_until: now,
+            },
+        );
+        link.sweep_neighbors(now);
+        assert_eq!(
+            link.neighbor_cache.keys().copied().collect::<Vec<_>>(),
+            [neighbor(10)]
+        );
+    }
+
+    /// Inserts an 802.1Q tag for `vlan_id` into an untagged frame
+    fn tag_frame(frame: &[u8], vlan_id: u16, tpid: u16) -> Vec<u8> {
+        let mut tagged = frame[..12].to_vec();
+        tagged.extend_from_slice(&tpid.to_be_bytes());
+        tagged.extend_from_slice(&vlan_id.to_be_bytes());
+        tagged.extend_from_slice(&frame[12..]);
+        tagged
+    }
+
+    #[test]
+    fn vlan_tagged_frames() {
+        let (device, link_end) = UnixDatagram::pair().unwrap();
+        device.set_nonblocking(true).unwrap();
+        link_end.set_nonblocking(true).unwrap();
+        let mut link = EthernetLink::new("eth0", File::from(std::os::fd::OwnedFd::from(link_end)));
+        link.set_mac_address(EthernetAddress([0x52, 0x54, 0, 0xab, 0xcd, 0xef]));
+        let now = Instant::ZERO;
+
+        let untagged = ipv4_frame(8, 0xaa);
+        let tagged = tag_frame(&untagged, 42, VLAN_ETHERTYPE);
+        let other_vlan = tag_frame(&untagged, 43, VLAN_ETHERTYPE);
+        let double_tagged = tag_frame(&tagged, 7, QINQ_ETHERTYPE);
+        let double_8021q = tag_frame(&tagged, 7, VLAN_ETHERTYPE);
+
+        // Without a VLAN id tagged frames are accepted along with untagged ones
+        for frame in [&untagged, &tagged, &double_tagged, &double_8021q] {
+            device.send(frame).unwrap();
+        }
+        assert_eq!(link.recv(now), Some(&untagged[14..]));
+        assert_eq!(link.recv(now), Some(&untagged[14..]));
+        assert_eq!(link.recv(now), None);
+
+        link.set_vlan_id(Some(42));
+        for frame in [&untagged, &other_vlan, &tagged] {
+            device.send(frame).unwrap();
+        }
+        assert_eq!(link.recv(now), Some(&untagged[14..]));
+        assert_eq!(link.recv(now), None);
+
+        let packet = &untagged[14..];
+        link.send(IpAddress::Ipv4(Ipv4Address::BROADCAST), packet, now);
+        let mut buf = [0u8; 2048];
+        let len = device.recv(&mut buf).unwrap();
+        // Everything but the source address matches the tagged frame
+        assert_eq!(&buf[12..len], &tagged[12..]);
+    }
+
+    #[test]
+    fn promiscuous_mode_accepts_ipv4_for_other_hosts() {
+        let (device, link_end) = UnixDatagram::pair().unwrap();
+        link_end.set_nonblocking(true).unwrap();
+        let mut link = EthernetLink::new("eth0", File::from(std::os::fd::OwnedFd::from(link_end)));
+        link.set_mac_address(EthernetAddress([0x52, 0x54, 0, 0xab, 0xcd, 0xef]));
+        let now = Instant::ZERO;
+
+        let other_host = EthernetAddress([0x52, 0x54, 0, 0x11, 0x22, 0x33]);
+        let mut ipv4 = ipv4_frame(8, 0xaa);
+        EthernetFrame::new_unchecked(&mut ipv4[..]).set_dst_addr(other_host);
+
+        let arp_repr = ArpRepr::EthernetIpv4 {
+            operation: ArpOperation::Reply,
+            source_hardware_addr: EthernetAddress([0x52, 0x54, 0, 0x12, 0x34, 0x56]),
+            source_protocol_addr: Ipv4Address::new(10, 0, 2, 2),
+            target_hardware_addr: other_host,
+            target_protocol_addr: Ipv4Address::new(10, 0, 2, 3),
+        };
+        let mut arp = vec![0u8; 14 + arp_repr.buffer_len()];
+        let mut frame = EthernetFrame::new_unchecked(&mut arp[..]);
+        EthernetRepr {
+            src_addr: EthernetAddress([0x52, 0x54, 0, 0x12, 0x34, 0x56]),
+            dst_addr: other_host,
+            ethertype: EthernetProtocol::Arp,
+        }
+        .emit(&mut frame);
+        arp_repr.emit(&mut ArpPacket::new_unchecked(frame.payload_mut()));
+
+        device.send(&ipv4).unwrap();
+        assert_eq!(link.recv(now), None);
+
+        link.set_promiscuous(true);
+        for frame in [&arp, &ipv4] {
+            device.send(frame).unwrap();
+        }
+        assert_eq!(link.recv(now), Some(&ipv4[14..]));
+        assert!(link.neighbor_cache.is_empty());
+    }
+
+    #[test]
+    fn neighbor_solicitation_to_solicited_node_group_is_answered() {
+        let (device, link_end) = UnixDatagram::pair().unwrap();
+        device.set_nonblocking(true).unwrap();
+        link_end.set_nonblocking(true).unwrap();
+        let mut link = EthernetLink::new("eth0", File::from(std::os::fd::OwnedFd::from(link_end)));
+        link.set_mac_address(EthernetAddress([0x52, 0x54, 0, 0xab, 0xcd, 0xef]));
+        let our_addr = Ipv6Address::new(0xfe80, 0, 0, 0, 0, 0, 0, 1);
+        link.set_ip_address(IpCidr::Ipv6(Ipv6Cidr::new(our_addr, 64)));
+
+        let neighbor_mac = EthernetAddress([0x52, 0x54, 0, 0x12, 0x34, 0x56]);
+        let neighbor_addr = Ipv6Address::new(0xfe80, 0, 0, 0, 0, 0, 0, 2);
+        let group = solicited_node(our_addr);
+        let icmp_repr = Icmpv6Repr::Ndisc(NdiscRepr::NeighborSolicit {
+            target_addr: our_addr,
+            lladdr: Some(RawHardwareAddress::from_bytes(neighbor_mac.as_bytes())),
+        });
+        let ip_repr = Ipv6Repr {
+            src_addr: neighbor_addr,
+            dst_addr: group,
+            next_header: IpProtocol::Icmpv6,
+            payload_len: icmp_repr.buffer_len(),
+            hop_limit: 255,
+        };
+        let eth_repr = EthernetRepr {
+            src_addr: neighbor_mac,
+            dst_addr: ipv6_multicast_mac(group),
+            ethertype: EthernetProtocol::Ipv6,
+        };
+
+        let mut frame =
+            vec![0u8; eth_repr.buffer_len() + ip_repr.buffer_len() + icmp_repr.buffer_len()];
+        let mut eth_frame = EthernetFrame::new_unchecked(&mut frame[..]);
+        eth_repr.emit(&mut eth_frame);
+        let mut ip_packet = Ipv6Packet::new_unchecked(eth_frame.payload_mut());
+        ip_repr.emit(&mut ip_packet);
+        icmp_repr.emit(
+            &neighbor_addr,
+            &group,
+            &mut Icmpv6Packet::new_unchecked(ip_packet.payload_mut()),
+            &ChecksumCapabilities::default(),
+        );
+
+        device.send(&frame).unwrap();
+        assert_eq!(link.recv(Instant::ZERO), None);
+        assert!(link
+            .neighbor_cache
+            .contains_key(&IpAddress::Ipv6(neighbor_addr)));
+
+        // The advertisement went straight back to the neighbor
+        let mut buf = [0u8; 2048];
+        let len = device.recv(&mut buf).unwrap();
+        let reply = EthernetFrame::new_checked(&buf[..len]).unwrap();
+        assert_eq!(reply.dst_addr(), neighbor_mac);
+        assert_eq!(reply.ethertype(), EthernetProtocol::Ipv6);
+    }
+
+    #[test]
+    fn set_mtu_allows_jumbo_frames() {
+        let (device, link_end) = UnixDatagram::pair().unwrap();
+        device.set_nonblocking(true).unwrap();
+        link_end.set_nonblocking(true).unwrap();
+        let mut link = EthernetLink::new("eth0", File::from(std::os::fd::OwnedFd::from(link_end)));
+        link.set_mac_address(EthernetAddress([0x52, 0x54, 0, 0xab, 0xcd, 0xef]));
+
+        link.set_mtu(100_000);
+        assert_eq!(link.mtu, EthernetLink::MAX_MTU);
+
+        let frames = send_jumbo_packet(&mut link, &device, true);
+        assert_eq!(frames.len(), 1);
+        assert_eq!(frames[0].len(), 14 + 20 + 2000);
+
+        // The input buffer grew along with the MTU
+        let jumbo = ipv4_frame(8000, 0xdd);
+        device.send(&jumbo).unwrap();
+        assert_eq!(link.recv(Instant::ZERO), Some(&jumbo[14..]));
     }
 }
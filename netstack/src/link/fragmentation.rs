@@ -0,0 +1,343 @@
+use smoltcp::time::{Duration, Instant};
+use smoltcp::wire::{IpProtocol, Ipv4Address, Ipv4Packet};
+
+/// Returns whether the given IPv4 packet is a fragment of a bigger datagram
+pub fn is_fragment(packet: &[u8]) -> bool {
+    match Ipv4Packet::new_checked(packet) {
+        Ok(packet) => packet.more_frags() || packet.frag_offset() != 0,
+        Err(_) => false,
+    }
+}
+
+/// Split an IPv4 packet into fragments of at most `mtu` bytes and call `f` on each of them.
+/// Returns false if the packet is malformed or can't be fragmented (DF flag set).
+pub fn fragment<F>(packet: &[u8], mtu: usize, mut f: F) -> bool
+where
+    F: FnMut(&[u8]),
+{
+    let Ok(packet) = Ipv4Packet::new_checked(packet) else {
+        return false;
+    };
+
+    if packet.dont_frag() {
+        return false;
+    }
+
+    let header_len = usize::from(packet.header_len());
+    // Fragment offsets are expressed in units of 8 bytes
+    let max_chunk_len = mtu.saturating_sub(header_len) & !7;
+    if max_chunk_len == 0 {
+        return false;
+    }
+
+    let header = &packet.as_ref()[..header_len];
+    let payload = packet.payload();
+    let base_offset = packet.frag_offset();
+    let more_frags = packet.more_frags();
+
+    let mut buffer = Vec::with_capacity(header_len + max_chunk_len);
+    for (i, chunk) in payload.chunks(max_chunk_len).enumerate() {
+        let offset = i * max_chunk_len;
+        let is_last = offset + chunk.len() == payload.len();
+
+        buffer.clear();
+        buffer.extend_from_slice(header);
+        buffer.extend_from_slice(chunk);
+
+        let mut fragment = Ipv4Packet::new_unchecked(&mut buffer[..]);
+        fragment.set_total_len((header_len + chunk.len()) as u16);
+        fragment.set_frag_offset(base_offset + offset as u16);
+        fragment.set_more_frags(more_frags || !is_last);
+        fragment.fill_checksum();
+
+        f(&buffer);
+    }
+
+    true
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct FragmentKey {
+    src: Ipv4Address,
+    dst: Ipv4Address,
+    ident: u16,
+    protocol: IpProtocol,
+}
+
+struct Reassembly {
+    key: FragmentKey,
+    header: Vec<u8>,
+    data: Vec<u8>,
+    /// Sorted and merged list of the received byte ranges of the payload
+    received: Vec<(usize, usize)>,
+    total_len: Option<usize>,
+    expires_at: Instant,
+}
+
+impl Reassembly {
+    fn add_range(&mut self, start: usize, end: usize) {
+        self.received.push((start, end));
+        self.received.sort_unstable();
+
+        let mut merged: Vec<(usize, usize)> = Vec::with_capacity(self.received.len());
+        for &(start, end) in self.received.iter() {
+            match merged.last_mut() {
+                Some(last) if start <= last.1 => last.1 = last.1.max(end),
+                _ => merged.push((start, end)),
+            }
+        }
+        self.received = merged;
+    }
+
+    fn is_complete(&self) -> bool {
+        match (self.total_len, self.received.as_slice()) {
+            (Some(total_len), [(0, end)]) => *end == total_len,
+            _ => false,
+        }
+    }
+}
+
+/// Bounded reassembly buffer for incoming IPv4 fragments
+#[derive(Default)]
+pub struct Reassembler {
+    pending: Vec<Reassembly>,
+    datagram: Vec<u8>,
+}
+
+impl Reassembler {
+    const MAX_PENDING_DATAGRAMS: usize = 4;
+    const MAX_DATAGRAM_SIZE: usize = u16::MAX as usize;
+    const REASSEMBLY_TIMEOUT: Duration = Duration::from_secs(10);
+
+    /// Feed an incoming fragment to the reassembler.
+    /// Returns true once a full datagram is available through `datagram`.
+    pub fn process(&mut self, packet: &[u8], now: Instant) -> bool {
+        self.pending.retain(|reassembly| {
+            if reassembly.expires_at < now {
+                debug!(
+                    "Dropped incomplete datagram from {} (reassembly timed out)",
+                    reassembly.key.src
+                );
+                false
+            } else {
+                true
+            }
+        });
+
+        let Ok(packet) = Ipv4Packet::new_checked(packet) else {
+            return false;
+        };
+
+        let key = FragmentKey {
+            src: packet.src_addr(),
+            dst: packet.dst_addr(),
+            ident: packet.ident(),
+            protocol: packet.next_header(),
+        };
+
+        let header_len = usize::from(packet.header_len());
+        let start = usize::from(packet.frag_offset());
+        let end = start + packet.payload().len();
+
+        let index = match self.pending.iter().position(|r| r.key == key) {
+            Some(index) => index,
+            None => {
+                if self.pending.len() >= Self::MAX_PENDING_DATAGRAMS {
+                    let oldest = self
+                        .pending
+                        .iter()
+                        .enumerate()
+                        .min_by_key(|(_, r)| r.expires_at)
+                        .map(|(i, _)| i)
+                        .expect("pending can't be empty");
+                    let dropped = self.pending.swap_remove(oldest);
+                    debug!(
+                        "Dropped incomplete datagram from {} (reassembly buffer full)",
+                        dropped.key.src
+                    );
+                }
+                self.pending.push(Reassembly {
+                    key,
+                    header: Vec::new(),
+                    data: Vec::new(),
+                    received: Vec::new(),
+                    total_len: None,
+                    expires_at: now + Self::REASSEMBLY_TIMEOUT,
+                });
+                self.pending.len() - 1
+            }
+        };
+
+        let reassembly = &mut self.pending[index];
+
+        if header_len + end > Self::MAX_DATAGRAM_SIZE {
+            debug!(
+                "Dropped incoming datagram from {} (reassembled size too big)",
+                key.src
+            );
+            self.pending.swap_remove(index);
+            return false;
+        }
+
+        if start == 0 {
+            reassembly.header.clear();
+            reassembly
+                .header
+                .extend_from_slice(&packet.as_ref()[..header_len]);
+        }
+
+        if !packet.more_frags() {
+            reassembly.total_len = Some(end);
+        }
+
+        if reassembly.data.len() < end {
+            reassembly.data.resize(end, 0);
+        }
+        reassembly.data[start..end].copy_from_slice(packet.payload());
+        reassembly.add_range(start, end);
+
+        if !reassembly.is_complete() || reassembly.header.is_empty() {
+            return false;
+        }
+
+        let reassembly = self.pending.swap_remove(index);
+        let total_len = reassembly.total_len.expect("reassembly is complete");
+
+        self.datagram.clear();
+        self.datagram.extend_from_slice(&reassembly.header);
+        self.datagram
+            .extend_from_slice(&reassembly.data[..total_len]);
+
+        let mut datagram = Ipv4Packet::new_unchecked(&mut self.datagram[..]);
+        datagram.set_total_len((reassembly.header.len() + total_len) as u16);
+        datagram.set_frag_offset(0);
+        datagram.set_more_frags(false);
+        datagram.fill_checksum();
+
+        true
+    }
+
+    /// Returns the last datagram fully reassembled by `process`
+    pub fn datagram(&self) -> &[u8] {
+        &self.datagram
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use smoltcp::phy::ChecksumCapabilities;
+    use smoltcp::wire::Ipv4Repr;
+
+    use super::*;
+
+    /// An IPv4 packet carrying `payload` at `offset` of datagram `ident`
+    fn packet(ident: u16, offset: usize, more_frags: bool, payload: &[u8]) -> Vec<u8> {
+        let repr = Ipv4Repr {
+            src_addr: Ipv4Address::new(10, 0, 2, 2),
+            dst_addr: Ipv4Address::new(10, 0, 2, 15),
+            next_header: IpProtocol::Udp,
+            payload_len: payload.len(),
+            hop_limit: 64,
+        };
+
+        let mut buf = vec![0u8; repr.buffer_len() + payload.len()];
+        let mut packet = Ipv4Packet::new_unchecked(&mut buf[..]);
+        repr.emit(&mut packet, &ChecksumCapabilities::default());
+        packet.set_ident(ident);
+        packet.set_dont_frag(false);
+        packet.set_more_frags(more_frags);
+        packet.set_frag_offset(offset as u16);
+        packet.payload_mut().copy_from_slice(payload);
+        packet.fill_checksum();
+        buf
+    }
+
+    fn payload(len: usize) -> Vec<u8> {
+        (0..len).map(|i| i as u8).collect()
+    }
+
+    #[test]
+    fn fragment_offsets_and_more_frags() {
+        let data = payload(100);
+        let mut fragments = Vec::new();
+        // 20 bytes of header leave room for 44 bytes, rounded down to 40
+        assert!(fragment(&packet(1, 0, false, &data), 64, |f| {
+            fragments.push(f.to_vec())
+        }));
+
+        let layout: Vec<_> = fragments
+            .iter()
+            .map(|f| {
+                let f = Ipv4Packet::new_checked(&f[..]).unwrap();
+                assert!(f.verify_checksum());
+                assert_eq!(usize::from(f.total_len()), 20 + f.payload().len());
+                (f.frag_offset(), f.payload().len(), f.more_frags())
+            })
+            .collect();
+        assert_eq!(layout, [(0, 40, true), (40, 40, true), (80, 20, false)]);
+
+        let mut reassembler = Reassembler::default();
+        let now = Instant::from_secs(0);
+        assert!(!reassembler.process(&fragments[2], now));
+        assert!(!reassembler.process(&fragments[0], now));
+        assert!(reassembler.process(&fragments[1], now));
+        assert_eq!(reassembler.datagram(), packet(1, 0, false, &data));
+    }
+
+    #[test]
+    fn fragment_refuses_dont_frag() {
+        let mut buf = packet(1, 0, false, &payload(100));
+        Ipv4Packet::new_unchecked(&mut buf[..]).set_dont_frag(true);
+        assert!(!fragment(&buf, 64, |_| panic!("fragmented a DF packet")));
+    }
+
+    #[test]
+    fn reassemble_overlapping_fragments() {
+        let data = payload(100);
+        let mut reassembler = Reassembler::default();
+        let now = Instant::from_secs(0);
+        assert!(!reassembler.process(&packet(1, 40, false, &data[40..]), now));
+        assert!(!reassembler.process(&packet(1, 0, true, &data[..32]), now));
+        assert!(reassembler.process(&packet(1, 16, true, &data[16..56]), now));
+        assert_eq!(reassembler.datagram(), packet(1, 0, false, &data));
+    }
+
+    #[test]
+    fn reassembly_times_out() {
+        let data = payload(100);
+        let mut reassembler = Reassembler::default();
+        assert!(!reassembler.process(&packet(1, 0, true, &data[..48]), Instant::from_secs(0)));
+        assert!(!reassembler.process(&packet(1, 48, false, &data[48..]), Instant::from_secs(11)));
+        // Only the late fragment is left waiting for the rest of its datagram
+        assert_eq!(reassembler.pending.len(), 1);
+        assert_eq!(reassembler.pending[0].received, [(48, 100)]);
+    }
+
+    #[test]
+    fn reassembly_drops_oversized_datagrams() {
+        let mut reassembler = Reassembler::default();
+        let now = Instant::from_secs(0);
+        assert!(!reassembler.process(&packet(1, 0, true, &payload(48)), now));
+        assert!(!reassembler.process(&packet(1, 65528, false, &payload(16)), now));
+        assert!(reassembler.pending.is_empty());
+    }
+
+    #[test]
+    fn reassembly_evicts_the_oldest_datagram() {
+        let data = payload(100);
+        let mut reassembler = Reassembler::default();
+        for ident in 0..=Reassembler::MAX_PENDING_DATAGRAMS as u16 {
+            let now = Instant::from_secs(i64::from(ident));
+            assert!(!reassembler.process(&packet(ident, 0, true, &data[..48]), now));
+        }
+        assert_eq!(
+            reassembler.pending.len(),
+            Reassembler::MAX_PENDING_DATAGRAMS
+        );
+
+        let now = Instant::from_secs(5);
+        assert!(reassembler.process(&packet(1, 48, false, &data[48..]), now));
+        assert_eq!(reassembler.datagram(), packet(1, 0, false, &data));
+        assert!(!reassembler.process(&packet(0, 48, false, &data[48..]), now));
+    }
+}
@@ -53,8 +53,16 @@ impl LinkDevice for LoopbackDevice {
 
     fn set_mac_address(&mut self, _addr: smoltcp::wire::EthernetAddress) {}
 
-    fn ip_address(&self) -> Option<smoltcp::wire::IpCidr> {
-        Some("127.0.0.1/8".parse().unwrap())
+    fn mtu(&self) -> Option<usize> {
+        None
+    }
+
+    fn set_mtu(&mut self, _mtu: usize) {}
+
+    fn set_promiscuous(&mut self, _on: bool) {}
+
+    fn ip_addresses(&self) -> Vec<smoltcp::wire::IpCidr> {
+        vec!["127.0.0.1/8".parse().unwrap()]
     }
 
     fn set_ip_address(&mut self, _addr: smoltcp::wire::IpCidr) {
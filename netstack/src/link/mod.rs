@@ -1,4 +1,5 @@
 pub mod ethernet;
+pub mod fragmentation;
 pub mod loopback;
 
 use std::rc::Rc;
@@ -24,13 +25,47 @@ pub trait LinkDevice {
     /// Returns wether this device have packets pending
     fn can_recv(&self) -> bool;
 
+    /// Returns the packet counters of the device. Devices which don't count packets report zeros.
+    fn stats(&self) -> LinkStats {
+        LinkStats::default()
+    }
+
     fn mac_address(&self) -> Option<EthernetAddress>;
     fn set_mac_address(&mut self, addr: EthernetAddress);
 
-    fn ip_address(&self) -> Option<IpCidr>;
+    /// Returns the largest IP packet the device sends in one piece, or None if it has no limit
+    fn mtu(&self) -> Option<usize>;
+    /// Sets the largest IP packet the device sends in one piece, e.g. to the MTU advertised by
+    /// the network driver. Bigger IPv4 packets are fragmented, bigger IPv6 packets dropped.
+    fn set_mtu(&mut self, mtu: usize);
+
+    /// Makes the device pass on IPv4 packets regardless of their destination hardware address,
+    /// e.g. for packet capture. Off by default.
+    fn set_promiscuous(&mut self, on: bool);
+
+    /// Returns the addresses of the device, at most one per IP version
+    fn ip_addresses(&self) -> Vec<IpCidr>;
+    /// Sets the address of the device for the IP version of `addr`
     fn set_ip_address(&mut self, addr: IpCidr);
 }
 
+/// Packet counters of a link device, for diagnostics
+#[derive(Debug, Clone, Default)]
+pub struct LinkStats {
+    pub rx_packets: u64,
+    pub tx_packets: u64,
+    pub rx_bytes: u64,
+    pub tx_bytes: u64,
+    /// ARP requests and IPv6 neighbor solicitations sent to resolve neighbors
+    pub arp_requests_sent: u64,
+    /// Packets dropped because their neighbor couldn't be resolved or the waiting queue was full
+    pub dropped_no_neighbor: u64,
+    /// Packets dropped because they exceed the MTU and can't be fragmented, the equivalent of
+    /// `EMSGSIZE` for traffic that has no sender to report it to
+    pub dropped_too_big: u64,
+    pub malformed_frames: u64,
+}
+
 #[derive(Default)]
 pub struct DeviceList {
     inner: Vec<Box<dyn LinkDevice>>,
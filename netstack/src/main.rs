@@ -61,6 +61,10 @@ fn run(daemon: daemon::Daemon) -> Result<()> {
     let hardware_addr = std::fs::read(format!("/scheme/{adapter}/mac"))
         .map(|mac_address| EthernetAddress::from_bytes(&mac_address))
         .context("failed to get mac address from network adapter")?;
+    // Drivers which don't advertise an MTU get the ethernet default
+    let mtu = std::fs::read_to_string(format!("/scheme/{adapter}/mtu"))
+        .ok()
+        .and_then(|mtu| mtu.trim().parse().ok());
 
     trace!("opening ip scheme socket");
     let ip_fd = Socket::nonblock("ip")
@@ -148,6 +152,7 @@ fn run(daemon: daemon::Daemon) -> Result<()> {
     let mut smolnetd = Smolnetd::new(
         network_fd,
         hardware_addr,
+        mtu,
         ip_fd,
         udp_fd,
         tcp_fd,
@@ -4,7 +4,7 @@ use std::rc::Rc;
 use smoltcp::phy::{Device, DeviceCapabilities, Medium};
 use smoltcp::storage::PacketMetadata;
 use smoltcp::time::Instant;
-use smoltcp::wire::IpAddress;
+use smoltcp::wire::{IpAddress, IpVersion, Ipv6Packet};
 
 use self::route_table::RouteTable;
 use crate::link::DeviceList;
@@ -39,8 +39,19 @@ impl Router {
         }
     }
 
+    /// MTU used when no link limits the packet size
     pub const MTU: usize = 1486;
 
+    /// The largest IP packet every link can send in one piece
+    fn mtu(&self) -> usize {
+        self.devices
+            .borrow()
+            .iter()
+            .filter_map(|dev| dev.mtu())
+            .min()
+            .unwrap_or(Router::MTU)
+    }
+
     pub fn can_recv(&self) -> bool {
         let mut can_recv = false;
         for dev in self.devices.borrow().iter() {
@@ -64,16 +75,26 @@ impl Router {
                     break;
                 };
 
-                self.rx_buffer
-                    .enqueue(buf.len(), ())
-                    .expect("We checked if it was full")
-                    .copy_from_slice(buf);
+                // Reassembled datagrams can be bigger than the MTU and might
+                // not fit in the remaining space even if the buffer isn't full
+                match self.rx_buffer.enqueue(buf.len(), ()) {
+                    Ok(dst) => dst.copy_from_slice(buf),
+                    Err(_) => {
+                        warn!("Dropped packet of {} bytes (rx buffer full)", buf.len());
+                        break;
+                    }
+                }
             }
         }
     }
 
     pub fn dispatch(&mut self, now: Instant) {
         while let Ok(((), packet)) = self.tx_buffer.dequeue() {
+            if let Ok(IpVersion::Ipv6) = IpVersion::of_packet(packet) {
+                Self::dispatch_ipv6(&self.devices, &self.route_table, packet, now);
+                continue;
+            }
+
             if let Ok(mut packet) = smoltcp::wire::Ipv4Packet::new_checked(packet) {
                 let dst_addr = IpAddress::Ipv4(packet.dst_addr());
                 if packet.dst_addr().is_broadcast() {
@@ -100,10 +121,11 @@ impl Router {
                         continue;
                     };
 
-                    let IpAddress::Ipv4(src) = rule.src;
-                    if src != packet.src_addr() {
-                        packet.set_src_addr(src);
-                        packet.fill_checksum()
+                    if let IpAddress::Ipv4(src) = rule.src {
+                        if src != packet.src_addr() {
+                            packet.set_src_addr(src);
+                            packet.fill_checksum()
+                        }
                     }
 
                     dev.send(next_hop, packet.into_inner(), now);
@@ -111,6 +133,43 @@ impl Router {
             }
         }
     }
+
+    fn dispatch_ipv6(
+        devices: &RefCell<DeviceList>,
+        route_table: &RefCell<RouteTable>,
+        packet: &[u8],
+        now: Instant,
+    ) {
+        let Ok(ip_packet) = Ipv6Packet::new_checked(packet) else {
+            return;
+        };
+
+        // Unlike IPv4 the source address is left as is, since it's covered by the checksum of
+        // the transport layer and smoltcp already picked one from the interface addresses.
+        let dst_addr = IpAddress::Ipv6(ip_packet.dst_addr());
+        if ip_packet.dst_addr().is_multicast() {
+            for dev in devices.borrow_mut().iter_mut() {
+                dev.send(dst_addr, packet, now)
+            }
+            return;
+        }
+
+        let route_table = route_table.borrow();
+        let Some(rule) = route_table.lookup_rule(&dst_addr) else {
+            warn!("No route found for destination: {}", dst_addr);
+            return;
+        };
+
+        let next_hop = rule.via.unwrap_or(dst_addr);
+
+        let mut devices = devices.borrow_mut();
+        let Some(dev) = devices.get_mut(&rule.dev) else {
+            warn!("Device {} not found", rule.dev);
+            return;
+        };
+
+        dev.send(next_hop, packet, now);
+    }
 }
 
 impl Device for Router {
@@ -149,7 +208,7 @@ impl Device for Router {
     fn capabilities(&self) -> smoltcp::phy::DeviceCapabilities {
         let mut caps = DeviceCapabilities::default();
         caps.medium = Medium::Ip;
-        caps.max_transmission_unit = Router::MTU;
+        caps.max_transmission_unit = self.mtu();
         caps.max_burst_size = Some(Smolnetd::SOCKET_BUFFER_SIZE);
         caps
     }
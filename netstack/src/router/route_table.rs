@@ -1,7 +1,21 @@
 use std::fmt::Display;
 use std::rc::Rc;
 
-use smoltcp::wire::{IpAddress, IpCidr};
+use smoltcp::wire::{IpAddress, IpCidr, Ipv6Address, Ipv6Cidr};
+
+/// Returns the network part of `cidr`, e.g. 10.0.2.0/24 for 10.0.2.15/24
+pub fn network_cidr(cidr: IpCidr) -> IpCidr {
+    match cidr {
+        IpCidr::Ipv4(cidr) => IpCidr::Ipv4(cidr.network()),
+        IpCidr::Ipv6(cidr) => {
+            let mask = u128::MAX
+                .checked_shl(128 - u32::from(cidr.prefix_len()))
+                .unwrap_or(0);
+            let network = Ipv6Address::from(u128::from(cidr.address()) & mask);
+            IpCidr::Ipv6(Ipv6Cidr::new(network, cidr.prefix_len()))
+        }
+    }
+}
 
 #[derive(Debug)]
 pub struct Rule {
@@ -94,6 +94,7 @@ impl Smolnetd {
     pub fn new(
         network_file: Fd,
         hardware_addr: EthernetAddress,
+        mtu: Option<usize>,
         ip_file: Socket,
         udp_file: Socket,
         tcp_file: Socket,
@@ -140,6 +141,15 @@ impl Smolnetd {
             File::from_raw_fd(network_file.into_raw() as RawFd)
         });
         eth0.set_mac_address(hardware_addr);
+        if let Some(mtu) = mtu {
+            eth0.set_mtu(mtu);
+        }
+        if let Ok(vlan_id) = getcfg("vlan_id") {
+            match vlan_id.parse() {
+                Ok(vlan_id) => eth0.set_vlan_id(Some(vlan_id)),
+                Err(_) => error!("Can't parse the 'vlan_id' cfg: {vlan_id}"),
+            }
+        }
         let eth0_name = Rc::clone(eth0.name());
 
         devices.borrow_mut().push(loopback);
@@ -20,7 +20,7 @@ use syscall::{Error as SyscallError, EventFlags as SyscallEventFlags, Result as
 
 use crate::error::{Error, Result};
 use crate::link::DeviceList;
-use crate::router::route_table::{RouteTable, Rule};
+use crate::router::route_table::{network_cidr, RouteTable, Rule};
 
 use self::nodes::*;
 use self::notifier::*;
@@ -28,19 +28,24 @@ use super::{post_fevent, Interface};
 
 const WRITE_BUFFER_MAX_SIZE: usize = 0xffff;
 
-fn gateway_cidr() -> IpCidr {
-    // TODO: const fn
-    IpCidr::new(IpAddress::v4(0, 0, 0, 0), 0)
+/// The default route of the IP version of the gateway `via`
+fn gateway_cidr(via: &IpAddress) -> IpCidr {
+    match via {
+        IpAddress::Ipv4(_) => IpCidr::new(IpAddress::v4(0, 0, 0, 0), 0),
+        IpAddress::Ipv6(_) => IpCidr::new(IpAddress::v6(0, 0, 0, 0, 0, 0, 0, 0), 0),
+    }
 }
 
 fn parse_route(value: &str, route_table: &RouteTable) -> SyscallResult<Rule> {
     let mut parts = value.split_whitespace();
     let cidr_str = parts.next().ok_or(SyscallError::new(syscall::EINVAL))?;
-    let cidr = match cidr_str {
-        "default" => gateway_cidr(),
-        cidr_str => cidr_str
-            .parse()
-            .map_err(|_| SyscallError::new(syscall::EINVAL))?,
+    let cidr: Option<IpCidr> = match cidr_str {
+        "default" => None,
+        cidr_str => Some(
+            cidr_str
+                .parse()
+                .map_err(|_| SyscallError::new(syscall::EINVAL))?,
+        ),
     };
 
     let via: IpAddress = match parts.next().ok_or(SyscallError::new(syscall::EINVAL))? {
@@ -56,6 +61,11 @@ fn parse_route(value: &str, route_table: &RouteTable) -> SyscallResult<Rule> {
         return Err(SyscallError::new(syscall::EINVAL));
     }
 
+    let cidr = cidr.unwrap_or_else(|| gateway_cidr(&via));
+    if cidr.address().version() != via.version() {
+        return Err(SyscallError::new(syscall::EINVAL));
+    }
+
     let rule = route_table
         .lookup_rule(&via)
         .ok_or(SyscallError::new(syscall::EINVAL))?;
@@ -190,15 +200,41 @@ fn mk_root_node(
                         Ok(())
                     }
                 },
+                "stats" => {
+                    ro [devices]
+                    || {
+                        match devices.borrow().get("eth0") {
+                            Some(dev) => {
+                                let stats = dev.stats();
+                                format!(
+                                    "rx_packets {}\nrx_bytes {}\ntx_packets {}\ntx_bytes {}\n\
+                                     arp_requests_sent {}\ndropped_no_neighbor {}\n\
+                                     dropped_too_big {}\nmalformed_frames {}\n",
+                                    stats.rx_packets,
+                                    stats.rx_bytes,
+                                    stats.tx_packets,
+                                    stats.tx_bytes,
+                                    stats.arp_requests_sent,
+                                    stats.dropped_no_neighbor,
+                                    stats.dropped_too_big,
+                                    stats.malformed_frames,
+                                )
+                            }
+                            None => "Device not found\n".into(),
+                        }
+                    }
+                },
                 "addr" => {
                     "list" => {
                         ro [devices]
                         || {
                             let res = match devices.borrow().get("eth0") {
                                 Some(dev) => {
-                                    match dev.ip_address() {
-                                        Some(addr) => format!("{addr}\n"),
-                                        None => "Not configured\n".into(),
+                                    let addrs = dev.ip_addresses();
+                                    if addrs.is_empty() {
+                                        "Not configured\n".into()
+                                    } else {
+                                        addrs.iter().map(|addr| format!("{addr}\n")).collect()
                                     }
                                 }
                                 None => "Device not found\n".into(),
@@ -222,16 +258,17 @@ fn mk_root_node(
                             }
                         }
                         |cur_value| {
-                            // TODO: Multiple IPs
+                            // TODO: Multiple IPs per IP version
                             if let Some(cidr) = cur_value.take() {
                                 if let Some(dev) = devices.borrow_mut().get_mut("eth0") {
 
                                     let mut route_table = route_table.borrow_mut();
-                                    if let Some(old_addr) = dev.ip_address() {
-                                        let IpCidr::Ipv4(old_v4_cidr) = old_addr;
-                                        let old_network = IpCidr::Ipv4(old_v4_cidr.network());
-
-                                        route_table.remove_rule(old_network);
+                                    let old_addr = dev
+                                        .ip_addresses()
+                                        .into_iter()
+                                        .find(|addr| addr.address().version() == cidr.address().version());
+                                    if let Some(old_addr) = old_addr {
+                                        route_table.remove_rule(network_cidr(old_addr));
                                         route_table.change_src(old_addr.address(), cidr.address());
                                         iface.borrow_mut().update_ip_addrs(|addrs| addrs.retain(|addr| *addr != old_addr))
                                     }
@@ -249,9 +286,7 @@ fn mk_root_node(
                                         let _ = addrs.insert(0, cidr);
                                     });
 
-                                    let IpCidr::Ipv4(v4_cidr) = cidr;
-                                    let network_cidr = IpCidr::Ipv4(v4_cidr.network());
-                                    route_table.insert_rule(Rule::new(network_cidr, None, dev.name().clone(), cidr.address()))
+                                    route_table.insert_rule(Rule::new(network_cidr(cidr), None, dev.name().clone(), cidr.address()))
                                 }
                                 notifier.borrow_mut().schedule_notify("ifaces/eth0/addr/list");
                                 notifier.borrow_mut().schedule_notify("route/list");
@@ -1,43 +1,50 @@
 use std::env;
-use std::fs;
-use std::time::Instant;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::time::{Duration, Instant};
 
-fn main() {
-    let args: Vec<String> = env::args().collect();
-    if args.len() < 2 {
-        eprintln!("Usage: readdir-bench <dir> [--stat]");
-        return;
-    }
+/// Default block size of the read/write benchmark.
+const DEFAULT_BLOCK_SIZE: usize = 64 * 1024;
 
-    let dir = &args[1];
-    let do_stat = args.get(2).map(|s| s == "--stat").unwrap_or(false);
-
-    println!("Testing directory: {}", dir);
-    println!("Mode: {}", if do_stat { "readdir + stat" } else { "readdir only" });
+/// Timings of one scan of the directory.
+struct Run {
+    total_time: Duration,
+    /// Time taken to get each entry from the directory iterator.
+    entry_times: Vec<Duration>,
+    /// Time taken by each successful `stat`.
+    stat_times: Vec<Duration>,
+}
 
+fn scan(dir: &str, do_stat: bool) -> Option<Run> {
     let start = Instant::now();
 
-    let entries = match fs::read_dir(dir) {
+    let mut entries = match fs::read_dir(dir) {
         Ok(e) => e,
         Err(e) => {
             eprintln!("Error: {}", e);
-            return;
+            return None;
         }
     };
 
     let readdir_time = start.elapsed();
     println!("read_dir() took: {:?}", readdir_time);
 
-    let mut count = 0;
-    let mut stat_count = 0;
+    let mut entry_times = Vec::new();
+    let mut stat_times = Vec::new();
     let iter_start = Instant::now();
 
-    for entry in entries {
+    loop {
+        let entry_start = Instant::now();
+        let Some(entry) = entries.next() else {
+            break;
+        };
         if let Ok(entry) = entry {
-            count += 1;
+            entry_times.push(entry_start.elapsed());
             if do_stat {
+                let stat_start = Instant::now();
                 if let Ok(_meta) = entry.metadata() {
-                    stat_count += 1;
+                    stat_times.push(stat_start.elapsed());
                 }
             }
         }
@@ -45,15 +52,223 @@ fn main() {
 
     let iter_time = iter_start.elapsed();
     let total_time = start.elapsed();
+    let count = entry_times.len();
+    let stat_count = stat_times.len();
 
     println!("Iteration took: {:?}", iter_time);
     println!("Total entries: {}", count);
     if do_stat {
         println!("Stat succeeded: {}", stat_count);
-        println!("Time per stat: {:?}", iter_time / stat_count as u32);
+        if stat_count > 0 {
+            println!("Time per stat: {:?}", iter_time / stat_count as u32);
+        }
     }
     println!("Total time: {:?}", total_time);
     if count > 0 {
         println!("Time per entry: {:?}", total_time / count as u32);
     }
+
+    Some(Run {
+        total_time,
+        entry_times,
+        stat_times,
+    })
+}
+
+/// Nearest-rank percentile of already sorted durations.
+fn percentile(sorted: &[Duration], p: usize) -> Duration {
+    let rank = (sorted.len() * p).div_ceil(100).max(1);
+    sorted[rank - 1]
+}
+
+fn print_percentiles(name: &str, mut times: Vec<Duration>) {
+    if times.is_empty() {
+        return;
+    }
+    times.sort();
+    println!(
+        "{}: p50 {:?}, p90 {:?}, p99 {:?}, max {:?}",
+        name,
+        percentile(&times, 50),
+        percentile(&times, 90),
+        percentile(&times, 99),
+        times[times.len() - 1],
+    );
+}
+
+/// Parses a byte count, with an optional `K`, `M` or `G` suffix.
+fn parse_size(s: &str) -> Option<usize> {
+    let (digits, unit) = match s.as_bytes().last()? {
+        b'K' | b'k' => (&s[..s.len() - 1], 1 << 10),
+        b'M' | b'm' => (&s[..s.len() - 1], 1 << 20),
+        b'G' | b'g' => (&s[..s.len() - 1], 1 << 30),
+        _ => (s, 1),
+    };
+    digits.parse::<usize>().ok()?.checked_mul(unit)
+}
+
+fn print_throughput(phase: &str, bytes: usize, time: Duration) {
+    let mb_per_sec = bytes as f64 / (1024.0 * 1024.0) / time.as_secs_f64();
+    println!(
+        "{}: {} bytes in {:?} ({:.2} MB/s)",
+        phase, bytes, time, mb_per_sec
+    );
+}
+
+/// Writes `size` bytes to `path` in blocks of `block_size`, fsyncs and reads them back.
+fn rw_phases(path: &Path, size: usize, block_size: usize) -> Result<(), (&'static str, io::Error)> {
+    let block = (0..block_size).map(|i| i as u8).collect::<Vec<u8>>();
+
+    let start = Instant::now();
+    let mut file = File::create(path).map_err(|e| ("create", e))?;
+    let mut written = 0;
+    while written < size {
+        let len = block_size.min(size - written);
+        file.write_all(&block[..len]).map_err(|e| ("write", e))?;
+        written += len;
+    }
+    print_throughput("Write", written, start.elapsed());
+
+    let start = Instant::now();
+    file.sync_all().map_err(|e| ("fsync", e))?;
+    println!("Fsync took: {:?}", start.elapsed());
+    drop(file);
+
+    let start = Instant::now();
+    let mut file = File::open(path).map_err(|e| ("open", e))?;
+    let mut buffer = vec![0; block_size];
+    let mut read = 0;
+    loop {
+        match file.read(&mut buffer).map_err(|e| ("read", e))? {
+            0 => break,
+            n => read += n,
+        }
+    }
+    print_throughput("Read", read, start.elapsed());
+    if read != size {
+        return Err((
+            "read",
+            io::Error::other(format!("read {} bytes back instead of {}", read, size)),
+        ));
+    }
+
+    Ok(())
+}
+
+fn rw_bench(dir: &str, size: usize, block_size: usize) {
+    let path = Path::new(dir).join(format!(".readdir-bench-{}.tmp", std::process::id()));
+    println!("Testing file: {}", path.display());
+    println!(
+        "Mode: read/write {} bytes in blocks of {} bytes",
+        size, block_size
+    );
+
+    let start = Instant::now();
+    let result = rw_phases(&path, size, block_size);
+    let total_time = start.elapsed();
+
+    if let Err(e) = fs::remove_file(&path) {
+        if e.kind() != io::ErrorKind::NotFound {
+            eprintln!("Error removing {}: {}", path.display(), e);
+        }
+    }
+
+    match result {
+        Ok(()) => println!("Total time: {:?}", total_time),
+        Err((phase, e)) => eprintln!("Error during {}: {}", phase, e),
+    }
+}
+
+fn main() {
+    let mut dir = None;
+    let mut do_stat = false;
+    let mut iterations = 1;
+    let mut rw_size = None;
+    let mut block_size = DEFAULT_BLOCK_SIZE;
+
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--stat" => do_stat = true,
+            "--iterations" => match args.next().and_then(|n| n.parse().ok()) {
+                Some(n) if n > 0 => iterations = n,
+                _ => {
+                    eprintln!("--iterations needs a positive number");
+                    return;
+                }
+            },
+            "--rw" => match args.next().as_deref().and_then(parse_size) {
+                Some(size) => rw_size = Some(size),
+                None => {
+                    eprintln!("--rw needs a size");
+                    return;
+                }
+            },
+            "--block" => match args.next().as_deref().and_then(parse_size) {
+                Some(size) if size > 0 => block_size = size,
+                _ => {
+                    eprintln!("--block needs a positive size");
+                    return;
+                }
+            },
+            _ if dir.is_none() => dir = Some(arg),
+            _ => {
+                eprintln!("Unexpected argument: {}", arg);
+                return;
+            }
+        }
+    }
+    let Some(dir) = dir else {
+        eprintln!("Usage: readdir-bench <dir> [--stat] [--iterations N]");
+        eprintln!("       readdir-bench <dir> --rw <size> [--block <size>]");
+        return;
+    };
+
+    if let Some(size) = rw_size {
+        rw_bench(&dir, size, block_size);
+        return;
+    }
+
+    println!("Testing directory: {}", dir);
+    println!("Mode: {}", if do_stat { "readdir + stat" } else { "readdir only" });
+
+    let mut runs = Vec::new();
+    for i in 0..iterations {
+        if iterations > 1 {
+            println!("\nIteration {}/{}:", i + 1, iterations);
+        }
+        match scan(&dir, do_stat) {
+            Some(run) => runs.push(run),
+            None => return,
+        }
+    }
+
+    println!();
+    let total_times = runs.iter().map(|run| run.total_time).collect::<Vec<_>>();
+    let mut entry_times = Vec::new();
+    let mut stat_times = Vec::new();
+    for run in runs {
+        entry_times.extend(run.entry_times);
+        stat_times.extend(run.stat_times);
+    }
+    print_percentiles("Per entry", entry_times);
+    print_percentiles("Per stat", stat_times);
+
+    if total_times.len() > 1 {
+        let secs = total_times
+            .iter()
+            .map(Duration::as_secs_f64)
+            .collect::<Vec<_>>();
+        let mean = secs.iter().sum::<f64>() / secs.len() as f64;
+        let variance =
+            secs.iter().map(|s| (s - mean).powi(2)).sum::<f64>() / (secs.len() - 1) as f64;
+        println!(
+            "Total time over {} runs: mean {:?}, stddev {:?}, min {:?}, max {:?}",
+            secs.len(),
+            Duration::from_secs_f64(mean),
+            Duration::from_secs_f64(variance.sqrt()),
+            total_times.iter().min().unwrap(),
+            total_times.iter().max().unwrap(),
+        );
+    }
 }
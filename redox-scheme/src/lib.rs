@@ -10,7 +10,7 @@ use core::str;
 use core::task::Poll;
 
 use libredox::flag;
-use syscall::error::{Error, Result, EINTR, EWOULDBLOCK};
+use syscall::error::{Error, Result, EAGAIN, EINTR, EINVAL, EOPNOTSUPP, EWOULDBLOCK};
 use syscall::flag::{
     CallFlags, FmoveFdFlags, FobtainFdFlags, RecvFdFlags, SchemeSocketCall, SendFdFlags,
 };
@@ -28,6 +28,50 @@ pub struct CallerCtx {
     pub id: Id,
 }
 
+/// Permissions checked by [`CallerCtx::can_access`], combined with `|`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct AccessMode(u16);
+
+impl AccessMode {
+    pub const READ: Self = Self(0o4);
+    pub const WRITE: Self = Self(0o2);
+    pub const EXEC: Self = Self(0o1);
+}
+
+impl core::ops::BitOr for AccessMode {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self {
+        Self(self.0 | rhs.0)
+    }
+}
+
+impl CallerCtx {
+    pub fn is_root(&self) -> bool {
+        self.uid == 0
+    }
+
+    /// Whether the caller may access a file owned by `file_uid` and `file_gid` with permission
+    /// bits `mode` in the way described by `want`.
+    ///
+    /// Only the owner, group or other bits apply, in that order, like on Unix. Root may read
+    /// and write anything, but may only execute files with at least one execute bit set.
+    pub fn can_access(&self, file_uid: u32, file_gid: u32, mode: u16, want: AccessMode) -> bool {
+        if self.is_root() {
+            return want.0 & AccessMode::EXEC.0 == 0 || mode & 0o111 != 0;
+        }
+
+        let perm = if self.uid == file_uid {
+            mode >> 6
+        } else if self.gid == file_gid {
+            mode >> 3
+        } else {
+            mode
+        } & 0o7;
+        perm & want.0 == want.0
+    }
+}
+
 pub enum OpenResult {
     ThisScheme { number: usize, flags: NewFdFlags },
     OtherScheme { fd: usize },
@@ -37,7 +81,7 @@ pub enum OpenResult {
 
 use core::mem::{size_of, MaybeUninit};
 
-use self::scheme::IntoTag;
+use self::scheme::{IntoTag, SchemeSync};
 
 #[repr(transparent)]
 #[derive(Debug, Default)]
@@ -48,6 +92,14 @@ pub struct Request {
 #[derive(Clone, Copy, Debug, Eq, Ord, Hash, PartialEq, PartialOrd)]
 pub struct Id(u32);
 
+impl Id {
+    /// Make up the id of a request, for testing schemes without a kernel sending requests.
+    #[doc(hidden)]
+    pub fn from_raw(raw: u32) -> Self {
+        Id(raw)
+    }
+}
+
 #[derive(Debug, Eq, Ord, Hash, PartialEq, PartialOrd)]
 pub struct Tag(Id);
 
@@ -100,6 +152,16 @@ impl CallRequest {
     pub fn request_id(&self) -> Id {
         Id(self.inner.sqe.tag)
     }
+    /// Raw opcode of the underlying SQE, including opcodes unknown to [`Opcode`].
+    #[inline]
+    pub fn raw_opcode(&self) -> u8 {
+        self.inner.sqe.opcode
+    }
+    /// Raw arguments of the underlying SQE, for handling opcodes not decoded by `Op`.
+    #[inline]
+    pub fn raw_args(&self) -> &[u64; 6] {
+        &self.inner.sqe.args
+    }
 }
 
 impl SendFdRequest {
@@ -127,13 +189,25 @@ impl SendFdRequest {
         self.inner.sqe.args[3] as usize
     }
 
+    /// Obtain the file descriptors sent by the caller into `dst_fds`.
+    ///
+    /// The kernel installs the descriptors at the lowest free slots and writes their numbers
+    /// into `dst_fds`. Choosing the slots with [`FobtainFdFlags::MANUAL_FD`] isn't supported by
+    /// the kernel, and fails with `EOPNOTSUPP`.
+    ///
+    /// Fails with `EINVAL` if `dst_fds` is too short to hold [`Self::num_fds`] descriptors.
     pub fn obtain_fd(
         &self,
         socket: &Socket,
         flags: FobtainFdFlags,
         dst_fds: &mut [usize],
     ) -> Result<()> {
-        assert!(!flags.contains(FobtainFdFlags::MANUAL_FD));
+        if flags.contains(FobtainFdFlags::MANUAL_FD) {
+            return Err(Error::new(EOPNOTSUPP));
+        }
+        if dst_fds.len() < self.num_fds() {
+            return Err(Error::new(EINVAL));
+        }
 
         let request_id = self.request_id().0;
         let metadata: [u64; 2] = [SchemeSocketCall::ObtainFd as u64, request_id as u64];
@@ -180,7 +254,14 @@ impl RecvFdRequest {
         self.inner.sqe.args[2] as usize
     }
 
+    /// Move `fds` to the caller.
+    ///
+    /// Fails with `EINVAL` if `fds` holds more than the [`Self::num_fds`] descriptors requested.
     pub fn move_fd(&self, socket: &Socket, flags: FmoveFdFlags, fds: &[usize]) -> Result<()> {
+        if fds.len() > self.num_fds() {
+            return Err(Error::new(EINVAL));
+        }
+
         let metadata: [u64; 2] = [SchemeSocketCall::MoveFd as u64, self.request_id().0 as u64];
 
         let fds_bytes: &[u8] = unsafe {
@@ -209,6 +290,16 @@ impl Request {
     pub fn context_id(&self) -> usize {
         self.sqe.caller as usize
     }
+    /// Returns the opcode of this request without consuming it, or `None` if it is unknown.
+    #[inline]
+    pub fn peek_opcode(&self) -> Option<Opcode> {
+        Opcode::try_from_raw(self.sqe.opcode)
+    }
+    /// Returns the caller of this request without consuming it.
+    #[inline]
+    pub fn peek_caller(&self) -> usize {
+        self.sqe.caller as usize
+    }
     pub fn kind(self) -> RequestKind {
         match Opcode::try_from_raw(self.sqe.opcode) {
             Some(Opcode::Cancel) => RequestKind::Cancellation(CancellationRequest {
@@ -267,6 +358,45 @@ impl Socket {
         }
         Ok(())
     }
+    /// Read requests directly into the back of a ring buffer, in FIFO order.
+    ///
+    /// At most `max` requests are read, using a single syscall. If the free space at the back of
+    /// `ring` wraps around the end of its buffer, only the contiguous part before the wrap point
+    /// is filled, and the next call continues from the start of the buffer. Callers wanting a
+    /// bounded queue should pass `ring.capacity() - ring.len()` (or less) as `max`, so that the
+    /// ring never reallocates.
+    ///
+    /// Returns the number of requests appended to `ring`.
+    pub fn read_requests_ring(
+        &self,
+        ring: &mut VecDeque<Request>,
+        max: usize,
+        behavior: SignalBehavior,
+    ) -> Result<usize> {
+        if max == 0 {
+            return Ok(0);
+        }
+
+        let old_len = ring.len();
+        ring.resize_with(old_len + max, Request::default);
+
+        let (front, back) = ring.as_mut_slices();
+        let tail = if old_len >= front.len() {
+            &mut back[old_len - front.len()..]
+        } else {
+            &mut front[old_len..]
+        };
+
+        // SAFETY: Request is plain data, so viewing initialized requests as MaybeUninit is sound
+        // as long as only valid requests are written back, which read_requests guarantees.
+        let tail = unsafe { &mut *(tail as *mut [Request] as *mut [MaybeUninit<Request>]) };
+
+        let res = read_requests(self.inner.raw(), tail, behavior);
+        let num_read = *res.as_ref().unwrap_or(&0);
+        ring.truncate(old_len + num_read);
+
+        res
+    }
     pub fn next_request(&self, behavior: SignalBehavior) -> Result<Option<Request>> {
         let mut buf = MaybeUninit::uninit();
         Ok(
@@ -277,7 +407,45 @@ impl Socket {
             },
         )
     }
+    /// Serve requests with `scheme` until the socket is closed.
+    ///
+    /// Calls are dispatched with [`CallRequest::handle_sync`], and `OnClose`, `Cancellation`,
+    /// `SendFd` and `RecvFd` requests are forwarded to the corresponding [`SchemeSync`] methods.
+    /// Returns `Ok(())` once [`Socket::next_request`] yields `None`, and fails with `EAGAIN` if
+    /// the kernel didn't accept a response, which would leave its caller waiting forever.
+    pub fn serve_sync(&self, scheme: &mut impl SchemeSync, behavior: SignalBehavior) -> Result<()> {
+        while let Some(request) = self.next_request(behavior)? {
+            let response = match request.kind() {
+                RequestKind::Call(call) => call.handle_sync(scheme),
+                RequestKind::OnClose { id } => {
+                    scheme.on_close(id);
+                    continue;
+                }
+                RequestKind::Cancellation(req) => {
+                    scheme.on_cancel(req.id);
+                    continue;
+                }
+                RequestKind::SendFd(sendfd_request) => {
+                    let result = scheme.on_sendfd(&sendfd_request);
+                    Response::new(result, sendfd_request)
+                }
+                RequestKind::RecvFd(recvfd_request) => {
+                    let result = scheme.on_recvfd(&recvfd_request);
+                    Response::open_dup_like(result, recvfd_request)
+                }
+                _ => continue,
+            };
+            if !self.write_response(response, behavior)? {
+                return Err(Error::new(EAGAIN));
+            }
+        }
+        Ok(())
+    }
     // TODO: trait ResponseBuf?
+    /// Write the responses in `buf` using a single syscall, removing the ones that were written.
+    ///
+    /// As [`Response`] is `Copy`, nothing stops a copy of a written response from being written
+    /// again. New code should use [`Socket::write_owned_responses`] instead.
     pub fn write_responses(
         &self,
         buf: &mut VecDeque<Response>,
@@ -292,6 +460,64 @@ impl Socket {
 
         Ok(())
     }
+    /// Write the responses in `buf` using a single syscall, removing the ones that were written.
+    ///
+    /// Like [`Socket::write_responses`], but as [`OwnedResponse`] isn't `Copy`, the written
+    /// responses are gone and can't be sent a second time.
+    pub fn write_owned_responses(
+        &self,
+        buf: &mut VecDeque<OwnedResponse>,
+        behavior: SignalBehavior,
+    ) -> Result<()> {
+        let (slice, _) = buf.as_slices();
+        // SAFETY: `OwnedResponse` is a transparent wrapper around `Response`
+        let slice =
+            unsafe { core::slice::from_raw_parts(slice.as_ptr().cast::<Response>(), slice.len()) };
+
+        // NOTE: error only allowed to occur if nothing was written
+        // SAFETY: each `OwnedResponse` is unique and the written ones are removed from `buf`
+        let n = unsafe { write_responses(self.inner.raw(), slice, behavior)? };
+        assert!(buf.len() >= n);
+        buf.drain(..n);
+
+        Ok(())
+    }
+    /// Write all responses yielded by `responses` using a single syscall.
+    ///
+    /// Returns the number of responses the kernel accepted, along with the remaining responses
+    /// that were not written, in order. Only the accepted responses are forgotten, so the
+    /// remainder can be retried later (e.g. with [`Socket::write_responses`]) without sending any
+    /// response twice. `EINTR`, `EWOULDBLOCK` and `EAGAIN` are reported as nothing written.
+    pub fn write_response_iter<I>(
+        &self,
+        responses: I,
+        behavior: SignalBehavior,
+    ) -> Result<(usize, VecDeque<Response>)>
+    where
+        I: IntoIterator<Item = Response>,
+    {
+        let mut buf: VecDeque<Response> = responses.into_iter().collect();
+        let total = buf.len();
+
+        match self.write_responses(&mut buf, behavior) {
+            Ok(()) | Err(Error { errno: EINTR | EWOULDBLOCK | EAGAIN }) => {}
+            Err(err) => return Err(err),
+        }
+
+        Ok((total - buf.len(), buf))
+    }
+    /// Post one fevent per `(id, flags)` pair, using a single syscall.
+    ///
+    /// Returns the number of events enqueued, so that the caller can retry the remaining tail.
+    pub fn post_fevents(&self, events: &[(usize, usize)], behavior: SignalBehavior) -> Result<usize> {
+        let responses: Vec<Response> = events
+            .iter()
+            .map(|&(id, flags)| Response::post_fevent(id, flags))
+            .collect();
+
+        // SAFETY: the responses were just created and are only written once
+        unsafe { write_responses(self.inner.raw(), &responses, behavior) }
+    }
     pub fn write_response(&self, resp: Response, behavior: SignalBehavior) -> Result<bool> {
         Ok(unsafe { write_responses(self.inner.raw(), &[resp], behavior)? } > 0)
     }
@@ -300,10 +526,43 @@ impl Socket {
     }
 }
 
+#[cfg(feature = "std")]
+impl Socket {
+    /// Asynchronously wait for the next request.
+    ///
+    /// The socket must have been created with [`Socket::nonblock`]. The returned future is
+    /// cancellation-safe, see [`wrappers::NextRequest`].
+    pub fn next_request_async(
+        &self,
+    ) -> impl core::future::Future<Output = Result<Option<Request>>> + '_ {
+        wrappers::NextRequest::new(self)
+    }
+}
+
+/// A response to a scheme request.
+///
+/// Every response must only be written once, but being `Copy`, this type can't enforce that.
+/// It is kept for writing responses in bulk through [`write_responses`]; prefer
+/// [`OwnedResponse`] otherwise.
 #[repr(transparent)]
 #[derive(Clone, Copy, Default)]
 pub struct Response(Cqe);
 
+impl core::fmt::Debug for Response {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let opcode = self.0.flags & 0b111;
+        let mut s = f.debug_struct("Response");
+        match CqeOpcode::try_from_raw(opcode) {
+            Some(opcode) => s.field("opcode", &opcode),
+            None => s.field("opcode", &opcode),
+        };
+        s.field("tag", &self.0.tag)
+            .field("result", &self.0.result)
+            .field("extra_raw", &self.0.extra_raw)
+            .finish()
+    }
+}
+
 impl Response {
     #[inline]
     pub fn err(err: i32, req: impl IntoTag) -> Self {
@@ -375,9 +634,80 @@ impl Response {
     }
 }
 
+/// A [`Response`] that can only be written once.
+///
+/// This isn't `Copy`, so [`Socket::write_owned_responses`] consumes the responses it writes and
+/// writing one twice is a compile error rather than a double free.
+#[repr(transparent)]
+#[derive(Debug)]
+pub struct OwnedResponse(Response);
+
+impl OwnedResponse {
+    #[inline]
+    pub fn err(err: i32, req: impl IntoTag) -> Self {
+        Self(Response::err(err, req))
+    }
+    #[inline]
+    pub fn ok(status: usize, req: impl IntoTag) -> Self {
+        Self(Response::ok(status, req))
+    }
+
+    pub fn new(status: Result<usize>, req: impl IntoTag) -> Self {
+        Self(Response::new(status, req))
+    }
+    pub fn open_dup_like(res: Result<OpenResult>, req: impl IntoTag) -> Self {
+        Self(Response::open_dup_like(res, req))
+    }
+    pub fn return_external_fd(fd: usize, req: impl IntoTag) -> Self {
+        Self(Response::return_external_fd(fd, req))
+    }
+    pub fn return_external_multiple_fds(num_fds: usize, req: impl IntoTag) -> Self {
+        Self(Response::return_external_multiple_fds(num_fds, req))
+    }
+    pub fn with_extra(self, extra: [u8; 3]) -> Self {
+        Self(self.0.with_extra(extra))
+    }
+    pub fn post_fevent(id: usize, flags: usize) -> Self {
+        Self(Response::post_fevent(id, flags))
+    }
+
+    /// Give up the write-once guarantee, e.g. to pass the response to [`write_responses`].
+    pub fn into_inner(self) -> Response {
+        self.0
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
 pub enum SignalBehavior {
     Interrupt,
     Restart,
+    /// Restart at most the given number of times, then return `EINTR`.
+    RestartWithLimit(u32),
+}
+
+/// Run `call` until it returns something other than `EINTR`, or `behavior` says to give up.
+#[inline]
+fn retry_on_eintr<T>(
+    behavior: SignalBehavior,
+    mut call: impl FnMut() -> libredox::error::Result<T>,
+) -> Result<T> {
+    let mut restarts = 0;
+    loop {
+        match call() {
+            Ok(value) => return Ok(value),
+            Err(error) if error.errno() == EINTR => match behavior {
+                SignalBehavior::Restart => continue,
+                SignalBehavior::RestartWithLimit(limit) if restarts < limit => {
+                    restarts += 1;
+                    continue;
+                }
+                SignalBehavior::RestartWithLimit(_) | SignalBehavior::Interrupt => {
+                    return Err(error.into())
+                }
+            },
+            Err(err) => return Err(err.into()),
+        }
+    }
 }
 
 /// Read requests into a possibly uninitialized buffer.
@@ -389,18 +719,11 @@ pub fn read_requests(
 ) -> Result<usize> {
     let len = buf.len().checked_mul(size_of::<Request>()).unwrap();
 
-    let bytes_read = loop {
-        match libredox::call::read(socket, unsafe {
+    let bytes_read = retry_on_eintr(behavior, || {
+        libredox::call::read(socket, unsafe {
             core::slice::from_raw_parts_mut(buf.as_mut_ptr().cast(), len)
-        }) {
-            Ok(n) => break n,
-            Err(error) if error.errno() == EINTR => match behavior {
-                SignalBehavior::Restart => continue,
-                SignalBehavior::Interrupt => return Err(error.into()),
-            },
-            Err(err) => return Err(err.into()),
-        }
-    };
+        })
+    })?;
 
     debug_assert_eq!(bytes_read % size_of::<Request>(), 0);
 
@@ -411,7 +734,8 @@ pub fn read_requests(
 //
 // SAFETY
 //
-// Every Response can only be written once, otherwise double frees can occur.
+// Every Response can only be written once, otherwise double frees can occur. Use
+// `Socket::write_owned_responses` to have that checked by the compiler instead.
 #[inline]
 pub unsafe fn write_responses(
     socket: usize,
@@ -425,16 +749,113 @@ pub unsafe fn write_responses(
         )
     };
 
-    let bytes_written = loop {
-        match libredox::call::write(socket, bytes) {
-            Ok(n) => break n,
-            Err(error) if error.errno() == EINTR => match behavior {
-                SignalBehavior::Restart => continue,
-                SignalBehavior::Interrupt => return Err(error.into()),
-            },
-            Err(err) => return Err(err.into()),
-        }
-    };
+    let bytes_written = retry_on_eintr(behavior, || libredox::call::write(socket, bytes))?;
     debug_assert_eq!(bytes_written % size_of::<Response>(), 0);
     Ok(bytes_written / size_of::<Response>())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use syscall::error::EIO;
+
+    fn caller(uid: u32, gid: u32) -> CallerCtx {
+        CallerCtx {
+            pid: 1,
+            uid,
+            gid,
+            id: Id(0),
+        }
+    }
+
+    #[test]
+    fn can_access_truth_table() {
+        use AccessMode as A;
+
+        let owner = caller(1000, 100);
+        let group = caller(1001, 100);
+        let other = caller(1002, 200);
+        let root = caller(0, 0);
+        let rw = A::READ | A::WRITE;
+
+        // (mode, want, owner, group, other, root)
+        let table = [
+            (0o640, A::READ, true, true, false, true),
+            (0o640, A::WRITE, true, false, false, true),
+            (0o640, rw, true, false, false, true),
+            (0o640, A::EXEC, false, false, false, false),
+            (0o604, A::READ, true, false, true, true),
+            (0o046, rw, false, false, true, true),
+            (0o070, rw, false, true, false, true),
+            (0o755, A::EXEC, true, true, true, true),
+            (0o001, A::EXEC, false, false, true, true),
+            (0o000, rw, false, false, false, true),
+        ];
+        for (mode, want, o, g, x, r) in table {
+            let results =
+                [&owner, &group, &other, &root].map(|ctx| ctx.can_access(1000, 100, mode, want));
+            assert_eq!(results, [o, g, x, r], "mode {:o}, want {:?}", mode, want);
+        }
+
+        assert!(root.is_root());
+        assert!(!owner.is_root());
+    }
+
+    #[test]
+    fn owned_response_matches_response() {
+        let owned = OwnedResponse::new(Err(Error::new(EIO)), Tag(Id(7))).with_extra([1, 2, 3]);
+        let response = Response::new(Err(Error::new(EIO)), Tag(Id(7))).with_extra([1, 2, 3]);
+
+        assert_eq!(size_of::<OwnedResponse>(), size_of::<Response>());
+        assert_eq!(
+            format!("{:?}", owned.into_inner()),
+            format!("{:?}", response)
+        );
+    }
+
+    // Mock syscall failing with EINTR the first `interrupts` times it is called
+    fn interrupted(interrupts: u32, calls: &mut u32) -> libredox::error::Result<usize> {
+        *calls += 1;
+        if *calls <= interrupts {
+            Err(libredox::error::Error::new(EINTR))
+        } else {
+            Ok(42)
+        }
+    }
+
+    #[test]
+    fn restart_with_limit_gives_up() {
+        let mut calls = 0;
+        let res = retry_on_eintr(SignalBehavior::RestartWithLimit(3), || {
+            interrupted(10, &mut calls)
+        });
+        assert_eq!(res, Err(Error::new(EINTR)));
+        assert_eq!(calls, 4);
+    }
+
+    #[test]
+    fn restart_with_limit_succeeds() {
+        let mut calls = 0;
+        let res = retry_on_eintr(SignalBehavior::RestartWithLimit(3), || {
+            interrupted(3, &mut calls)
+        });
+        assert_eq!(res, Ok(42));
+        assert_eq!(calls, 4);
+    }
+
+    #[test]
+    fn restart_and_interrupt_unchanged() {
+        let mut calls = 0;
+        let res = retry_on_eintr(SignalBehavior::Restart, || interrupted(100, &mut calls));
+        assert_eq!((res, calls), (Ok(42), 101));
+
+        let mut calls = 0;
+        let res = retry_on_eintr(SignalBehavior::Interrupt, || interrupted(1, &mut calls));
+        assert_eq!((res, calls), (Err(Error::new(EINTR)), 1));
+
+        let res = retry_on_eintr(SignalBehavior::RestartWithLimit(5), || {
+            Err::<usize, _>(libredox::error::Error::new(EIO))
+        });
+        assert_eq!(res, Err(Error::new(EIO)));
+    }
+}
@@ -1,7 +1,8 @@
 #![allow(async_fn_in_trait)]
 
 use core::fmt::{self, Debug};
-use core::mem::size_of;
+use core::mem::{align_of, size_of, size_of_val};
+use core::time::Duration;
 use syscall::dirent::DirentBuf;
 use syscall::schemev2::{Opcode, Sqe};
 use syscall::{error::*, flag::*, Stat, StatVfs, TimeSpec};
@@ -30,6 +31,27 @@ impl<Flags: Debug> Debug for OpPathLike<Flags> {
     }
 }
 
+impl OpPathLike<usize> {
+    pub fn wants_create(&self) -> bool {
+        self.flags & O_CREAT != 0
+    }
+    pub fn wants_excl(&self) -> bool {
+        self.flags & O_EXCL != 0
+    }
+    pub fn wants_trunc(&self) -> bool {
+        self.flags & O_TRUNC != 0
+    }
+    pub fn wants_append(&self) -> bool {
+        self.flags & O_APPEND != 0
+    }
+    pub fn wants_directory(&self) -> bool {
+        self.flags & O_DIRECTORY != 0
+    }
+    pub fn wants_stat(&self) -> bool {
+        self.flags & O_STAT != 0
+    }
+}
+
 pub struct OpFdPathLike<Flags> {
     pub fd: usize,
     pub fcntl_flags: u32,
@@ -45,6 +67,27 @@ impl<F> OpFdPathLike<F> {
     }
 }
 
+impl OpFdPathLike<usize> {
+    pub fn wants_create(&self) -> bool {
+        self.inner.wants_create()
+    }
+    pub fn wants_excl(&self) -> bool {
+        self.inner.wants_excl()
+    }
+    pub fn wants_trunc(&self) -> bool {
+        self.inner.wants_trunc()
+    }
+    pub fn wants_append(&self) -> bool {
+        self.inner.wants_append()
+    }
+    pub fn wants_directory(&self) -> bool {
+        self.inner.wants_directory()
+    }
+    pub fn wants_stat(&self) -> bool {
+        self.inner.wants_stat()
+    }
+}
+
 impl<Flags: Debug> Debug for OpFdPathLike<Flags> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.debug_struct("OpFdPathLike")
@@ -56,6 +99,24 @@ impl<Flags: Debug> Debug for OpFdPathLike<Flags> {
     }
 }
 
+/// Types that can be safely reinterpreted from any sequence of bytes of the right size.
+///
+/// # Safety
+///
+/// Implementors must be `#[repr(C)]` or `#[repr(transparent)]` (or primitive), contain no
+/// padding, and every bit pattern must be a valid value.
+pub unsafe trait FromBytes {}
+
+macro_rules! from_bytes_impls {
+    [$($ty:ty,)*] => {
+        $(
+        unsafe impl FromBytes for $ty {}
+        )*
+    }
+}
+from_bytes_impls![u8, u16, u32, u64, usize, i8, i16, i32, i64, isize,];
+unsafe impl<T: FromBytes, const N: usize> FromBytes for [T; N] {}
+
 pub struct OpCall {
     req: Tag,
     pub fd: usize,
@@ -73,6 +134,28 @@ impl OpCall {
     pub fn metadata(&self) -> &[u64] {
         &self.metadata
     }
+    /// Reinterpret the metadata words as a caller-defined `T`, if its size matches exactly.
+    pub fn metadata_as<T: FromBytes>(&self) -> Option<&T> {
+        cast_bytes::<T>(self.metadata.as_ptr().cast(), size_of_val(&self.metadata))
+            // SAFETY: the size and alignment were checked, and T is valid for any bytes
+            .map(|ptr| unsafe { &*ptr })
+    }
+    /// Reinterpret the payload as a caller-defined `T`, if its size matches exactly.
+    pub fn payload_as_mut<T: FromBytes>(&mut self) -> Option<&mut T> {
+        let payload = self.payload();
+        let len = payload.len();
+        // The pointer must come from the mutable borrow to be written through
+        cast_bytes::<T>(payload.as_mut_ptr(), len)
+            // SAFETY: borrows &mut self.req, see above for the cast
+            .map(|ptr| unsafe { &mut *ptr.cast_mut() })
+    }
+}
+
+fn cast_bytes<T: FromBytes>(ptr: *const u8, len: usize) -> Option<*const T> {
+    if len != size_of::<T>() || ptr.align_offset(align_of::<T>()) != 0 {
+        return None;
+    }
+    Some(ptr.cast())
 }
 impl Debug for OpCall {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
@@ -125,6 +208,11 @@ impl OpGetdents {
         let sz = self.header_size;
         DirentBuf::new(self.raw_buf(), sz)
     }
+    /// Total size of the caller's buffer in bytes. This doesn't shrink as entries are written
+    /// to [`Self::buf`], use [`DirentBuf`] to keep track of the space left.
+    pub fn capacity(&mut self) -> usize {
+        self.raw_buf().len()
+    }
 }
 #[derive(Debug)]
 pub struct OpRead {
@@ -132,6 +220,8 @@ pub struct OpRead {
     pub fd: usize,
     pub offset: u64,
     pub flags: u32,
+    /// How long the caller is willing to wait, see [`decode_timeout`].
+    pub timeout: Option<Duration>,
     buf: *mut [u8],
 }
 impl OpRead {
@@ -146,6 +236,8 @@ pub struct OpWrite {
     pub fd: usize,
     pub offset: u64,
     pub flags: u32,
+    /// How long the caller is willing to wait, see [`decode_timeout`].
+    pub timeout: Option<Duration>,
     buf: *const [u8],
 }
 impl OpWrite {
@@ -155,6 +247,55 @@ impl OpWrite {
     }
 }
 
+/// Decode the timeout of a read or write from its raw flags argument (`args[4]` of the SQE).
+///
+/// Only the lower 32 bits of that argument hold the flags. The upper 32 bits hold the number of
+/// milliseconds the caller is willing to wait for the operation, e.g. from `SO_RCVTIMEO`, with
+/// zero meaning no timeout. The deadline is relative to when the request was received: a scheme
+/// that can't complete the operation by then should fail it with `EAGAIN`.
+pub fn decode_timeout(raw_flags: u64) -> Option<Duration> {
+    match raw_flags >> 32 {
+        0 => None,
+        ms => Some(Duration::from_millis(ms)),
+    }
+}
+
+/// An `fcntl` command with its argument, as decoded by [`decode_fcntl`].
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum FcntlCmd {
+    /// `F_DUPFD` or `F_DUPFD_CLOEXEC`, duplicating to the lowest fd not below `min_fd`.
+    DupFd { min_fd: usize, cloexec: bool },
+    /// `F_GETFD`
+    GetFd,
+    /// `F_SETFD`, with the new file descriptor flags.
+    SetFd(usize),
+    /// `F_GETFL`
+    GetFl,
+    /// `F_SETFL`, with the new file status flags (e.g. `O_NONBLOCK`).
+    SetFl(usize),
+    /// Any other command, with its raw `cmd` and `arg`.
+    Unknown(usize, usize),
+}
+
+/// Classify the raw `cmd` and `arg` of an [`Op::Fcntl`].
+pub fn decode_fcntl(cmd: usize, arg: usize) -> FcntlCmd {
+    match cmd {
+        F_DUPFD => FcntlCmd::DupFd {
+            min_fd: arg,
+            cloexec: false,
+        },
+        F_DUPFD_CLOEXEC => FcntlCmd::DupFd {
+            min_fd: arg,
+            cloexec: true,
+        },
+        F_GETFD => FcntlCmd::GetFd,
+        F_SETFD => FcntlCmd::SetFd(arg),
+        F_GETFL => FcntlCmd::GetFl,
+        F_SETFL => FcntlCmd::SetFl(arg),
+        _ => FcntlCmd::Unknown(cmd, arg),
+    }
+}
+
 #[non_exhaustive]
 #[derive(Debug)]
 pub enum Op {
@@ -296,6 +437,7 @@ impl Op {
                 buf: slice::from_raw_parts_mut(b as *mut u8, c),
                 offset: args[3],
                 flags: args[4] as u32,
+                timeout: decode_timeout(args[4]),
             }),
             Opcode::Write => Op::Write(OpWrite {
                 req,
@@ -303,6 +445,7 @@ impl Op {
                 buf: slice::from_raw_parts(b as *const u8, c),
                 offset: args[3],
                 flags: args[4] as u32,
+                timeout: decode_timeout(args[4]),
             }),
 
             // TODO: 64-bit offset on 32-bit platforms
@@ -417,6 +560,37 @@ impl Op {
         };
         flags as usize & O_NONBLOCK != 0
     }
+    /// Name of the operation, mainly useful for logging.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Op::Open(_) => "open",
+            Op::OpenAt(_) => "openat",
+            Op::Rmdir(_) => "rmdir",
+            Op::Unlink(_) => "unlink",
+            Op::UnlinkAt(_) => "unlinkat",
+            Op::Dup(_) => "dup",
+            Op::Read(_) => "read",
+            Op::Write(_) => "write",
+            Op::Fsize { .. } => "fsize",
+            Op::Fchmod { .. } => "fchmod",
+            Op::Fchown { .. } => "fchown",
+            Op::Fcntl { .. } => "fcntl",
+            Op::Fevent { .. } => "fevent",
+            Op::Flink(_) => "flink",
+            Op::Fpath(_) => "fpath",
+            Op::Frename(_) => "frename",
+            Op::Fstat(_) => "fstat",
+            Op::FstatVfs(_) => "fstatvfs",
+            Op::Fsync { .. } => "fsync",
+            Op::Ftruncate { .. } => "ftruncate",
+            Op::Futimens(_) => "futimens",
+            Op::MmapPrep { .. } => "mmap_prep",
+            Op::Munmap { .. } => "munmap",
+            Op::Call(_) => "call",
+            Op::Getdents(_) => "getdents",
+            Op::Recvfd(_) => "recvfd",
+        }
+    }
     pub fn file_id(&self) -> Option<usize> {
         Some(match self {
             Op::Open(_) | Op::Rmdir(_) | Op::Unlink(_) => return None,
@@ -478,7 +652,10 @@ impl CallRequest {
 
         let op = match self.op() {
             Ok(op) => op,
-            Err(this) => return Response::new(Err(Error::new(ENOSYS)), this),
+            Err(this) => {
+                s.on_unsupported("unknown", &caller);
+                return Response::new(Err(Error::new(ENOSYS)), this);
+            }
         };
         op.handle_sync(caller, s)
     }
@@ -534,6 +711,19 @@ impl Op {
         caller: &CallerCtx,
         s: &mut impl SchemeSync,
     ) -> SchemeResponse {
+        let res = self.dispatch_sync(caller, s);
+        if let SchemeResponse::Regular(Err(Error {
+            errno: EOPNOTSUPP | ENOSYS,
+        }))
+        | SchemeResponse::Opened(Err(Error {
+            errno: EOPNOTSUPP | ENOSYS,
+        })) = res
+        {
+            s.on_unsupported(self.name(), caller);
+        }
+        res
+    }
+    fn dispatch_sync(&mut self, caller: &CallerCtx, s: &mut impl SchemeSync) -> SchemeResponse {
         match *self {
             Op::Open(ref req) => {
                 let res = s.open(req.path(), req.flags, &caller);
@@ -1057,6 +1247,11 @@ pub trait SchemeSync {
         Err(Error::new(EOPNOTSUPP))
     }
 
+    /// Fill `buf` with the entries following `opaque_offset`.
+    ///
+    /// Each entry carries the `next_opaque_id` the caller resumes from, so when
+    /// [`DirentBuf::entry`] fails because the buffer is full, the entries written so far should
+    /// be returned as is. Returning an empty buffer signals the end of the directory.
     fn getdents<'buf>(
         &mut self,
         id: usize,
@@ -1090,12 +1285,21 @@ pub trait SchemeSync {
 
     fn on_close(&mut self, id: usize) {}
 
+    /// Called when the caller cancelled the request with the given id, so that any work still
+    /// in progress on its behalf can be aborted.
+    fn on_cancel(&mut self, id: Id) {}
+
     fn on_sendfd(&mut self, sendfd_request: &SendFdRequest) -> Result<usize> {
         Err(Error::new(EOPNOTSUPP))
     }
     fn on_recvfd(&mut self, recvfd_request: &RecvFdRequest) -> Result<OpenResult> {
         Err(Error::new(EOPNOTSUPP))
     }
+
+    /// Called whenever an operation fails with `EOPNOTSUPP` or `ENOSYS`, which is what the
+    /// default implementations return. `op_name` is the name of the operation, as returned by
+    /// [`Op::name`]. Does nothing by default.
+    fn on_unsupported(&mut self, op_name: &str, ctx: &CallerCtx) {}
 }
 pub trait IntoTag {
     fn into_tag(self) -> Tag;
@@ -1244,3 +1448,111 @@ impl IntoTag for Op {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn op_call(payload: &mut [u8], metadata: [u64; 3]) -> OpCall {
+        OpCall {
+            req: Tag(Id(0)),
+            fd: 0,
+            payload,
+            metadata,
+        }
+    }
+
+    #[repr(C)]
+    struct Metadata {
+        kind: u64,
+        offset: u32,
+        len: u32,
+        flags: u64,
+    }
+    unsafe impl FromBytes for Metadata {}
+
+    #[repr(C)]
+    struct TooSmall {
+        kind: u64,
+    }
+    unsafe impl FromBytes for TooSmall {}
+
+    #[test]
+    fn metadata_as_matching_size() {
+        let mut payload = [0_u8; 0];
+        let call = op_call(&mut payload, [1, 2 | (3 << 32), 4]);
+        let metadata = call.metadata_as::<Metadata>().unwrap();
+
+        assert_eq!(metadata.kind, 1);
+        assert_eq!(metadata.flags, 4);
+        if cfg!(target_endian = "little") {
+            assert_eq!((metadata.offset, metadata.len), (2, 3));
+        }
+        assert_eq!(call.metadata_as::<[u64; 3]>(), Some(&[1, 2 | (3 << 32), 4]));
+    }
+
+    #[test]
+    fn metadata_as_mismatched_size() {
+        let mut payload = [0_u8; 0];
+        let call = op_call(&mut payload, [1, 2, 3]);
+
+        assert!(call.metadata_as::<TooSmall>().is_none());
+        assert!(call.metadata_as::<[u64; 4]>().is_none());
+    }
+
+    #[test]
+    fn payload_as_mut() {
+        let mut payload = [0_u64; 1];
+        let bytes = unsafe { core::slice::from_raw_parts_mut(payload.as_mut_ptr().cast(), 8) };
+        let mut call = op_call(bytes, [0; 3]);
+
+        *call.payload_as_mut::<u64>().unwrap() = 42;
+        assert!(call.payload_as_mut::<u32>().is_none());
+        assert_eq!(payload[0], 42);
+    }
+
+    #[test]
+    fn decode_fcntl_commands() {
+        assert_eq!(decode_fcntl(F_GETFL, 0), FcntlCmd::GetFl);
+        assert_eq!(
+            decode_fcntl(F_SETFL, O_RDWR | O_NONBLOCK),
+            FcntlCmd::SetFl(O_RDWR | O_NONBLOCK)
+        );
+        assert_eq!(decode_fcntl(F_SETFD, O_CLOEXEC), FcntlCmd::SetFd(O_CLOEXEC));
+        assert_eq!(
+            decode_fcntl(F_DUPFD_CLOEXEC, 3),
+            FcntlCmd::DupFd {
+                min_fd: 3,
+                cloexec: true
+            }
+        );
+        assert_eq!(decode_fcntl(1234, 5), FcntlCmd::Unknown(1234, 5));
+    }
+
+    #[test]
+    fn read_timeout_from_sqe() {
+        let mut buf = [0_u8; 16];
+        let (ptr, len) = (buf.as_mut_ptr() as u64, buf.len() as u64);
+        let sqe = |flags: u64| Sqe {
+            opcode: Opcode::Read as u8,
+            sqe_flags: Default::default(),
+            _rsvd: 0,
+            tag: 1,
+            args: [3, ptr, len, 0, flags, 0],
+            caller: 0,
+        };
+
+        let flags = O_NONBLOCK as u64 | (250 << 32);
+        let Some(Op::Read(op)) = (unsafe { Op::from_sqe_unchecked(&sqe(flags)) }) else {
+            panic!("not decoded as a read");
+        };
+        assert_eq!(op.flags, O_NONBLOCK as u32);
+        assert_eq!(op.timeout, Some(Duration::from_millis(250)));
+
+        let Some(Op::Read(op)) = (unsafe { Op::from_sqe_unchecked(&sqe(O_NONBLOCK as u64)) })
+        else {
+            panic!("not decoded as a read");
+        };
+        assert_eq!(op.timeout, None);
+    }
+}
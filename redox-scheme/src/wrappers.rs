@@ -1,10 +1,17 @@
+use core::future::Future;
 use core::ops::{Deref, DerefMut};
+use core::pin::Pin;
+use core::task::{Context, Poll, Waker};
 use std::collections::{HashMap, VecDeque};
 use std::ops::ControlFlow;
+use std::sync::{Mutex, OnceLock};
 
 use libredox::error::Error as LError;
+use libredox::flag;
 
+use syscall::data::Event;
 use syscall::error::{self as errno, Error, ECANCELED, EIO, EOPNOTSUPP};
+use syscall::flag::EventFlags;
 use syscall::Result;
 
 use crate::scheme::{Op, SchemeResponse, SchemeSync};
@@ -61,6 +68,7 @@ impl<'sock> ReadinessBased<'sock> {
                         self.responses_to_write
                             .push_back(Response::err(ECANCELED, op));
                     }
+                    acquire_scheme().on_cancel(req.id);
                     continue;
                 }
                 RequestKind::OnClose { id } => {
@@ -193,3 +201,185 @@ impl<'sock> ReadinessBased<'sock> {
         }
     }
 }
+
+/// Run a scheme daemon serving `name` until its socket is closed.
+///
+/// The socket is created before `build` is called, so that `build` can e.g. enter the null
+/// namespace. `daemon` is then signaled ready, and requests are served with
+/// [`Socket::serve_sync`]. The process exits once the socket reaches EOF.
+#[cfg(feature = "daemon")]
+pub fn run_scheme<S: SchemeSync>(
+    name: &str,
+    build: impl FnOnce() -> S,
+    daemon: daemon::Daemon,
+) -> ! {
+    let socket = Socket::create(name)
+        .unwrap_or_else(|err| panic!("failed to create scheme socket {name}: {err}"));
+
+    let mut scheme = build();
+
+    daemon.ready();
+
+    match socket.serve_sync(&mut scheme, SignalBehavior::Restart) {
+        Ok(()) => std::process::exit(0),
+        Err(err) => {
+            eprintln!("scheme {name}: failed to serve requests: {err}");
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Future returned by [`Socket::next_request_async`].
+///
+/// No request is ever buffered inside the future: a request is only read from the socket in
+/// `poll`, and immediately returned. Dropping it before completion therefore never loses one.
+pub struct NextRequest<'sock> {
+    socket: &'sock Socket,
+    registered: bool,
+}
+
+/// Event queue shared by all pending [`NextRequest`] futures of the process, and the thread
+/// waking them when their socket becomes readable.
+struct Reactor {
+    queue: libredox::Fd,
+    sockets: Mutex<HashMap<usize, Registration>>,
+}
+
+/// Futures waiting on one socket, which stays subscribed as long as any of them exists.
+#[derive(Default)]
+struct Registration {
+    futures: usize,
+    wakers: Vec<Waker>,
+}
+
+impl Reactor {
+    fn get() -> Result<&'static Reactor> {
+        static REACTOR: OnceLock<Reactor> = OnceLock::new();
+
+        if let Some(reactor) = REACTOR.get() {
+            return Ok(reactor);
+        }
+        let queue = libredox::Fd::open("/scheme/event", flag::O_CREAT | flag::O_CLOEXEC, 0)?;
+        let mut created = false;
+        let reactor = REACTOR.get_or_init(|| {
+            created = true;
+            Reactor {
+                queue,
+                sockets: Mutex::new(HashMap::new()),
+            }
+        });
+        if created {
+            std::thread::spawn(move || reactor.run());
+        }
+        Ok(reactor)
+    }
+
+    fn run(&self) {
+        loop {
+            let mut event = Event::default();
+            match self.queue.read(&mut event) {
+                Ok(_) => {}
+                Err(err) if err.errno() == errno::EINTR => continue,
+                Err(err) => panic!("redox-scheme: failed to read event queue: {err}"),
+            }
+            let wakers = match self.sockets.lock().unwrap().get_mut(&event.data) {
+                Some(registration) => core::mem::take(&mut registration.wakers),
+                None => continue,
+            };
+            for waker in wakers {
+                waker.wake();
+            }
+        }
+    }
+
+    fn subscribe(&self, fd: usize, flags: EventFlags) -> Result<()> {
+        self.queue.write(&Event {
+            id: fd,
+            flags,
+            data: fd,
+        })?;
+        Ok(())
+    }
+
+    fn register(&self, fd: usize) -> Result<()> {
+        let mut sockets = self.sockets.lock().unwrap();
+        let registration = sockets.entry(fd).or_default();
+        if registration.futures == 0 {
+            if let Err(err) = self.subscribe(fd, EventFlags::EVENT_READ) {
+                sockets.remove(&fd);
+                return Err(err);
+            }
+        }
+        registration.futures += 1;
+        Ok(())
+    }
+
+    fn unregister(&self, fd: usize) {
+        let mut sockets = self.sockets.lock().unwrap();
+        let Some(registration) = sockets.get_mut(&fd) else {
+            return;
+        };
+        registration.futures -= 1;
+        if registration.futures == 0 {
+            sockets.remove(&fd);
+            let _ = self.subscribe(fd, EventFlags::empty());
+        }
+    }
+
+    fn wake_on_event(&self, fd: usize, waker: &Waker) {
+        let mut sockets = self.sockets.lock().unwrap();
+        let wakers = &mut sockets.get_mut(&fd).unwrap().wakers;
+        if !wakers.iter().any(|w| w.will_wake(waker)) {
+            wakers.push(waker.clone());
+        }
+    }
+}
+
+impl<'sock> NextRequest<'sock> {
+    pub(crate) fn new(socket: &'sock Socket) -> Self {
+        Self {
+            socket,
+            registered: false,
+        }
+    }
+}
+
+impl Future for NextRequest<'_> {
+    type Output = Result<Option<Request>>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        let fd = this.socket.inner().raw();
+        let reactor = match Reactor::get() {
+            Ok(reactor) => reactor,
+            Err(err) => return Poll::Ready(Err(err)),
+        };
+
+        // Subscribe and store the waker before reading, so that a request arriving in between
+        // still wakes the task.
+        if !this.registered {
+            if let Err(err) = reactor.register(fd) {
+                return Poll::Ready(Err(err));
+            }
+            this.registered = true;
+        }
+        reactor.wake_on_event(fd, cx.waker());
+
+        match this.socket.next_request(SignalBehavior::Restart) {
+            Err(Error {
+                errno: errno::EWOULDBLOCK | errno::EAGAIN,
+            }) => Poll::Pending,
+            res => Poll::Ready(res),
+        }
+    }
+}
+
+impl Drop for NextRequest<'_> {
+    fn drop(&mut self) {
+        if self.registered {
+            if let Ok(reactor) = Reactor::get() {
+                reactor.unregister(self.socket.inner().raw());
+            }
+        }
+    }
+}
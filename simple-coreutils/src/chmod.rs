@@ -1,12 +1,20 @@
 use std::env;
 use std::fs;
 use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
 
 fn main() {
-    let args: Vec<String> = env::args().collect();
+    let mut args: Vec<String> = env::args().collect();
+
+    // Options come before the mode, which may itself start with '-' (e.g. -x)
+    let mut recursive = false;
+    while args.len() > 1 && (args[1] == "-R" || args[1] == "--recursive") {
+        recursive = true;
+        args.remove(1);
+    }
 
     if args.len() < 3 {
-        eprintln!("usage: chmod MODE FILE...");
+        eprintln!("usage: chmod [-R] MODE FILE...");
         std::process::exit(1);
     }
 
@@ -22,9 +30,12 @@ fn main() {
     let mut failed = false;
 
     for path in &args[2..] {
-        if let Err(e) = set_permissions(path, mode) {
-            eprintln!("chmod: {}: {}", path, e);
+        let path = Path::new(path);
+        if let Err(e) = set_permissions(path, &mode) {
+            eprintln!("chmod: {}: {}", path.display(), e);
             failed = true;
+        } else if recursive && path.is_dir() {
+            chmod_recursive(path, &mode, &mut failed);
         }
     }
 
@@ -33,46 +44,224 @@ fn main() {
     }
 }
 
-fn parse_mode(s: &str) -> Option<u32> {
-    // Try octal first (e.g., 755, 0644)
-    if let Ok(mode) = u32::from_str_radix(s.trim_start_matches('0'), 8) {
-        if mode <= 0o7777 {
-            return Some(mode);
+/// Apply `mode` to everything below the directory `dir`, without following symlinks
+fn chmod_recursive(dir: &Path, mode: &Mode, failed: &mut bool) {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) => {
+            eprintln!("chmod: {}: {}", dir.display(), e);
+            *failed = true;
+            return;
+        }
+    };
+
+    for entry in entries {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(e) => {
+                eprintln!("chmod: {}: {}", dir.display(), e);
+                *failed = true;
+                continue;
+            }
+        };
+        let path = entry.path();
+
+        // The mode of a symlink is not used, and following it could leave the tree
+        let file_type = match entry.file_type() {
+            Ok(file_type) if file_type.is_symlink() => continue,
+            Ok(file_type) => file_type,
+            Err(e) => {
+                eprintln!("chmod: {}: {}", path.display(), e);
+                *failed = true;
+                continue;
+            }
+        };
+
+        if let Err(e) = set_permissions(&path, mode) {
+            eprintln!("chmod: {}: {}", path.display(), e);
+            *failed = true;
+        } else if file_type.is_dir() {
+            chmod_recursive(&path, mode, failed);
         }
     }
+}
+
+/// A parsed MODE argument
+#[derive(Debug)]
+enum Mode {
+    /// Octal mode, replacing the current one
+    Octal(u32),
+    /// Comma separated `[ugoa]*[+-=][rwxXst]*` clauses, applied to the current mode
+    Symbolic(Vec<Clause>),
+}
+
+#[derive(Debug)]
+struct Clause {
+    /// Bits of the users the clause applies to; all of them when no user is given
+    who: u32,
+    /// Operators with their permission letters, e.g. `u+r-w` has two
+    ops: Vec<(char, String)>,
+}
+
+const WHO_USER: u32 = 0o4700;
+const WHO_GROUP: u32 = 0o2070;
+const WHO_OTHER: u32 = 0o1007;
+const WHO_ALL: u32 = WHO_USER | WHO_GROUP | WHO_OTHER;
 
-    // Simple symbolic mode support (e.g., +x, a+x, u+rwx)
-    // For now, just handle common cases
+fn parse_mode(s: &str) -> Option<Mode> {
     let s = s.trim();
 
-    if s == "+x" || s == "a+x" {
-        return Some(0o111); // Will be OR'd with existing
+    // Octal (e.g., 755, 0644)
+    if !s.is_empty() && s.bytes().all(|b| (b'0'..=b'7').contains(&b)) {
+        return match u32::from_str_radix(s, 8) {
+            Ok(mode) if mode <= 0o7777 => Some(Mode::Octal(mode)),
+            _ => None,
+        };
     }
-    if s == "-x" || s == "a-x" {
-        return Some(0o7666); // Will be AND'd
-    }
-    if s == "+r" || s == "a+r" {
-        return Some(0o444);
-    }
-    if s == "+w" || s == "a+w" {
-        return Some(0o222);
+
+    let mut clauses = Vec::new();
+    for clause in s.split(',') {
+        let mut chars = clause.chars().peekable();
+
+        let mut who = 0;
+        while let Some(&c) = chars.peek() {
+            who |= match c {
+                'u' => WHO_USER,
+                'g' => WHO_GROUP,
+                'o' => WHO_OTHER,
+                'a' => WHO_ALL,
+                _ => break,
+            };
+            chars.next();
+        }
+
+        let mut ops = Vec::new();
+        while let Some(op) = chars.next() {
+            if !matches!(op, '+' | '-' | '=') {
+                return None;
+            }
+            let mut perms = String::new();
+            while let Some(&c) = chars.peek() {
+                if !matches!(c, 'r' | 'w' | 'x' | 'X' | 's' | 't') {
+                    break;
+                }
+                perms.push(c);
+                chars.next();
+            }
+            ops.push((op, perms));
+        }
+
+        if ops.is_empty() {
+            return None;
+        }
+
+        // The umask isn't applied, no user means everyone
+        clauses.push(Clause { who: if who == 0 { WHO_ALL } else { who }, ops });
     }
 
-    None
+    Some(Mode::Symbolic(clauses))
 }
 
-fn set_permissions(path: &str, mode: u32) -> std::io::Result<()> {
-    let metadata = fs::metadata(path)?;
-    let current_mode = metadata.permissions().mode();
+impl Mode {
+    /// Compute the new mode of a file from its `current` mode
+    fn apply(&self, current: u32, is_dir: bool) -> u32 {
+        let clauses = match self {
+            Mode::Octal(mode) => return *mode,
+            Mode::Symbolic(clauses) => clauses,
+        };
+
+        let mut mode = current & 0o7777;
+        for clause in clauses {
+            for (op, perms) in &clause.ops {
+                let mut bits = 0;
+                for c in perms.chars() {
+                    bits |= match c {
+                        'r' => 0o444,
+                        'w' => 0o222,
+                        'x' => 0o111,
+                        // Execute only for directories and files already executable by someone
+                        'X' if is_dir || mode & 0o111 != 0 => 0o111,
+                        's' => 0o6000,
+                        't' => 0o1000,
+                        _ => 0,
+                    };
+                }
+                let bits = bits & clause.who;
 
-    // For simple symbolic modes, we'd need to combine
-    // For now, just set absolute mode for octal
-    let new_mode = if mode <= 0o7777 {
+                mode = match op {
+                    '+' => mode | bits,
+                    '-' => mode & !bits,
+                    _ => (mode & !clause.who) | bits,
+                };
+            }
+        }
         mode
-    } else {
-        current_mode & mode // For -x type operations
-    };
+    }
+}
+
+fn set_permissions(path: &Path, mode: &Mode) -> std::io::Result<()> {
+    let metadata = fs::metadata(path)?;
+    let new_mode = mode.apply(metadata.permissions().mode(), metadata.is_dir());
 
     let permissions = fs::Permissions::from_mode(new_mode);
     fs::set_permissions(path, permissions)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn apply(mode: &str, current: u32, is_dir: bool) -> u32 {
+        parse_mode(mode).unwrap().apply(current, is_dir)
+    }
+
+    #[test]
+    fn octal() {
+        assert_eq!(apply("755", 0o644, false), 0o755);
+        assert_eq!(apply("0", 0o644, false), 0);
+        assert!(parse_mode("10000").is_none());
+        assert!(parse_mode("8").is_none());
+    }
+
+    #[test]
+    fn add() {
+        assert_eq!(apply("u+x", 0o644, false), 0o744);
+        assert_eq!(apply("+x", 0o644, false), 0o755);
+        assert_eq!(apply("g+s", 0o755, true), 0o2755);
+    }
+
+    #[test]
+    fn remove() {
+        assert_eq!(apply("go-w", 0o666, false), 0o644);
+        assert_eq!(apply("a-x", 0o755, false), 0o644);
+    }
+
+    #[test]
+    fn set() {
+        assert_eq!(apply("a=rx", 0o644, false), 0o555);
+        assert_eq!(apply("o=", 0o757, false), 0o750);
+        assert_eq!(apply("u=rwx,go=r", 0o600, false), 0o744);
+    }
+
+    #[test]
+    fn conditional_execute() {
+        assert_eq!(apply("a+X", 0o644, false), 0o644);
+        assert_eq!(apply("a+X", 0o744, false), 0o755);
+        assert_eq!(apply("a+X", 0o644, true), 0o755);
+    }
+
+    #[test]
+    fn several_operators() {
+        assert_eq!(apply("u+x-w", 0o644, false), 0o544);
+        assert_eq!(apply("u+x,g-r", 0o644, false), 0o704);
+    }
+
+    #[test]
+    fn invalid() {
+        assert!(parse_mode("").is_none());
+        assert!(parse_mode("u").is_none());
+        assert!(parse_mode("z+x").is_none());
+        assert!(parse_mode("u+q").is_none());
+        assert!(parse_mode("u+x,").is_none());
+    }
+}
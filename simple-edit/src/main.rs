@@ -1,9 +1,89 @@
 // Simple line editor for Redox OS
 // Commands: p (print), a (append), i N (insert at line N), d N (delete line N), w (write), q (quit)
+// s/pat/repl/[g] (substitute), /pat (search)
 
 use std::env;
 use std::fs::{File, OpenOptions};
-use std::io::{self, BufRead, BufReader, Write, stdin, stdout};
+use std::io::{self, Read, Write, stdin, stdout};
+
+use regex::Regex;
+
+/// Split `N,Ms/...` into its line address and the command after it.
+fn split_address(input: &str) -> (&str, &str) {
+    let end = input.find(|c: char| !c.is_ascii_digit() && c != ',').unwrap_or(input.len());
+    input.split_at(end)
+}
+
+/// Parse a line address (`N` or `N,M`, 1-based) into a 0-based inclusive range.
+/// An empty address means the current line.
+fn parse_range(address: &str, current: usize, len: usize) -> Option<(usize, usize)> {
+    let (start, end) = if address.is_empty() {
+        (current, current)
+    } else if let Some((start, end)) = address.split_once(',') {
+        (start.parse().ok()?, end.parse().ok()?)
+    } else {
+        let n = address.parse().ok()?;
+        (n, n)
+    };
+
+    if start == 0 || start > end || end > len {
+        return None;
+    }
+    Some((start - 1, end - 1))
+}
+
+/// Parse the `/pat/repl/flags` part of a substitution. Any punctuation can be the delimiter,
+/// and a backslash escapes it inside the pattern or replacement.
+fn parse_substitution(s: &str) -> Option<(String, String, bool)> {
+    let mut chars = s.chars();
+    let delim = chars.next().filter(|c| c.is_ascii_punctuation() && *c != '\\')?;
+
+    let mut fields = vec![String::new()];
+    let mut escaped = false;
+    for c in chars {
+        if escaped {
+            if c != delim {
+                fields.last_mut()?.push('\\');
+            }
+            fields.last_mut()?.push(c);
+            escaped = false;
+        } else if c == '\\' {
+            escaped = true;
+        } else if c == delim {
+            fields.push(String::new());
+        } else {
+            fields.last_mut()?.push(c);
+        }
+    }
+
+    // The closing delimiter is optional when there are no flags
+    let flags = match fields.len() {
+        2 => String::new(),
+        3 => fields.pop()?,
+        _ => return None,
+    };
+    let global = match flags.as_str() {
+        "" => false,
+        "g" => true,
+        _ => return None,
+    };
+
+    let repl = fields.pop()?;
+    let pat = fields.pop()?;
+    Some((pat, repl, global))
+}
+
+/// Write `lines` separated by `line_ending`. The last line only gets one when `final_newline`
+/// is set, so that a file is written back the way it was loaded.
+fn write_lines(filename: &str, lines: &[String], line_ending: &str, final_newline: bool) -> io::Result<()> {
+    let mut content = lines.join(line_ending);
+    if final_newline && !lines.is_empty() {
+        content.push_str(line_ending);
+    }
+
+    let mut file = File::create(filename)?;
+    file.write_all(content.as_bytes())
+}
 
 fn main() {
     let args: Vec<String> = env::args().collect();
@@ -15,20 +95,44 @@ fn main() {
     let filename = &args[1];
     let mut lines: Vec<String> = Vec::new();
     let mut modified = false;
+    // Kept from the loaded file, new files get LF and a final newline
+    let mut line_ending = "\n";
+    let mut final_newline = true;
 
     // Try to read existing file
-    if let Ok(file) = File::open(filename) {
-        let reader = BufReader::new(file);
-        for line in reader.lines() {
-            if let Ok(l) = line {
-                lines.push(l);
+    if let Ok(mut file) = File::open(filename) {
+        let mut bytes = Vec::new();
+        let _ = file.read_to_end(&mut bytes);
+        let content = String::from_utf8_lossy(&bytes);
+
+        // The first line decides, a '\r' elsewhere in an LF file is content
+        let crlf = content.find('\n').is_some_and(|i| content[..i].ends_with('\r'));
+        if crlf {
+            line_ending = "\r\n";
+        }
+        final_newline = content.is_empty() || content.ends_with('\n');
+
+        if !content.is_empty() {
+            let body = content.strip_suffix('\n').unwrap_or(&content);
+            for line in body.split('\n') {
+                let line = if crlf { line.strip_suffix('\r').unwrap_or(line) } else { line };
+                lines.push(line.to_string());
             }
         }
-        println!("Loaded {} lines from {}", lines.len(), filename);
+        println!(
+            "Loaded {} lines from {} ({} line endings{})",
+            lines.len(),
+            filename,
+            if crlf { "CRLF" } else { "LF" },
+            if final_newline { "" } else { ", no final newline" }
+        );
     } else {
         println!("New file: {}", filename);
     }
 
+    // Line used by commands without an address, 1-based (0 when the buffer is empty)
+    let mut current = lines.len();
+
     println!("Commands: p[rint], a[ppend], i N [insert], d N [delete], w[rite], q[uit], h[elp]");
 
     let stdin = stdin();
@@ -46,10 +150,98 @@ fn main() {
             continue;
         }
 
+        // /pat - print the lines matching pat
+        if let Some(pattern) = input.strip_prefix('/') {
+            let pattern = pattern.strip_suffix('/').unwrap_or(pattern);
+            match Regex::new(pattern) {
+                Ok(re) => {
+                    let mut found = 0;
+                    for (i, line) in lines.iter().enumerate() {
+                        if re.is_match(line) {
+                            println!("{:4}: {}", i + 1, line);
+                            found += 1;
+                        }
+                    }
+                    if found == 0 {
+                        println!("No match");
+                    }
+                }
+                Err(e) => println!("Invalid regex: {}", e),
+            }
+            continue;
+        }
+
+        // [N[,M]]s/pat/repl/[g] - substitute on the current line or the given lines
+        let (address, command) = split_address(input);
+        if let Some(rest) = command.strip_prefix('s').filter(|rest| rest.starts_with(|c: char| c.is_ascii_punctuation())) {
+            let Some((pattern, replacement, global)) = parse_substitution(rest) else {
+                println!("Usage: [N[,M]]s/pattern/replacement/[g]");
+                continue;
+            };
+            let re = match Regex::new(&pattern) {
+                Ok(re) => re,
+                Err(e) => {
+                    println!("Invalid regex: {}", e);
+                    continue;
+                }
+            };
+            let Some((start, end)) = parse_range(address, current, lines.len()) else {
+                println!("Invalid range");
+                continue;
+            };
+
+            let mut count = 0;
+            for (i, line) in lines.iter_mut().enumerate().take(end + 1).skip(start) {
+                let matches = if global { re.find_iter(line).count() } else { re.is_match(line) as usize };
+                if matches == 0 {
+                    continue;
+                }
+                *line = if global {
+                    re.replace_all(line, replacement.as_str()).into_owned()
+                } else {
+                    re.replace(line, replacement.as_str()).into_owned()
+                };
+                count += matches;
+                current = i + 1;
+            }
+
+            if count == 0 {
+                println!("No match");
+            } else {
+                modified = true;
+                println!("{} substitution{}", count, if count == 1 { "" } else { "s" });
+            }
+            continue;
+        }
+
+        // N - print line N and make it the current line
+        if input.bytes().all(|b| b.is_ascii_digit()) {
+            match parse_range(input, current, lines.len()) {
+                Some((n, _)) => {
+                    println!("{:4}: {}", n + 1, lines[n]);
+                    current = n + 1;
+                }
+                None => println!("Invalid range"),
+            }
+            continue;
+        }
+
         let parts: Vec<&str> = input.split_whitespace().collect();
         let cmd = parts[0];
 
         match cmd {
+            "p" | "print" if parts.len() > 1 => {
+                // p N or p N,M
+                match parse_range(&parts[1..].concat(), current, lines.len()) {
+                    Some((start, end)) => {
+                        for (i, line) in lines.iter().enumerate().take(end + 1).skip(start) {
+                            println!("{:4}: {}", i + 1, line);
+                        }
+                        current = end + 1;
+                    }
+                    None => println!("Invalid range"),
+                }
+            }
             "p" | "print" => {
                 if lines.is_empty() {
                     println!("(empty)");
@@ -73,6 +265,7 @@ fn main() {
                     lines.push(line);
                     modified = true;
                 }
+                current = lines.len();
                 println!("Now {} lines", lines.len());
             }
             "i" | "insert" => {
@@ -91,6 +284,7 @@ fn main() {
                         let line = line.trim_end_matches('\n').to_string();
                         lines.insert(n - 1, line);
                         modified = true;
+                        current = n;
                         println!("Inserted at line {}", n);
                     }
                 }
@@ -107,6 +301,7 @@ fn main() {
                     }
                     lines.remove(n - 1);
                     modified = true;
+                    current = n.min(lines.len());
                     println!("Deleted line {}", n);
                 }
             }
@@ -127,19 +322,14 @@ fn main() {
                         let line = line.trim_end_matches('\n').to_string();
                         lines[n - 1] = line;
                         modified = true;
+                        current = n;
                         println!("Updated line {}", n);
                     }
                 }
             }
             "w" | "write" => {
-                match File::create(filename) {
-                    Ok(mut file) => {
-                        for line in &lines {
-                            if writeln!(file, "{}", line).is_err() {
-                                println!("Error writing to file");
-                                continue;
-                            }
-                        }
+                match write_lines(filename, &lines, line_ending, final_newline) {
+                    Ok(()) => {
                         println!("Wrote {} lines to {}", lines.len(), filename);
                         modified = false;
                     }
@@ -155,13 +345,8 @@ fn main() {
             }
             "q!" => break,
             "wq" => {
-                match File::create(filename) {
-                    Ok(mut file) => {
-                        for line in &lines {
-                            let _ = writeln!(file, "{}", line);
-                        }
-                        println!("Wrote {} lines", lines.len());
-                    }
+                match write_lines(filename, &lines, line_ending, final_newline) {
+                    Ok(()) => println!("Wrote {} lines", lines.len()),
                     Err(e) => println!("Cannot write: {}", e),
                 }
                 break;
@@ -169,10 +354,14 @@ fn main() {
             "h" | "help" => {
                 println!("Commands:");
                 println!("  p        - print all lines");
+                println!("  p N[,M]  - print line N, or lines N to M");
+                println!("  N        - print line N and make it the current line");
                 println!("  a        - append lines");
                 println!("  i N      - insert before line N");
                 println!("  e N      - edit line N");
                 println!("  d N      - delete line N");
+                println!("  [N[,M]]s/re/repl/[g] - substitute on the current line or lines N-M");
+                println!("  /re      - print the lines matching re");
                 println!("  w        - write file");
                 println!("  q        - quit (warns if unsaved)");
                 println!("  q!       - quit without saving");
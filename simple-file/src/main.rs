@@ -1,19 +1,28 @@
 // POSIX-compatible file type detection for Redox OS
 // Uses infer crate for magic number detection
+use flate2::read::GzDecoder;
 use std::env;
 use std::fs::{self, File};
-use std::io::{BufRead, BufReader, Read};
+use std::io::{self, stdin, BufRead, BufReader, Read, Seek, SeekFrom};
 use std::os::unix::fs::FileTypeExt;
 use std::path::Path;
 
 const VERSION: &str = "1.0.0";
 
+/// How much of a file is read for detection, enough to reach the ISO 9660
+/// primary volume descriptor at 0x8000.
+const HEADER_LEN: u64 = 0x8800;
+
+/// How much of a compressed file is decompressed for `-z`.
+const UNCOMPRESS_LEN: u64 = 8192;
+
 struct Options {
     brief: bool,
     mime_type: bool,
     mime_encoding: bool,
     follow_symlinks: bool,
     no_pad: bool,
+    uncompress: bool,
 }
 
 impl Default for Options {
@@ -24,6 +33,7 @@ impl Default for Options {
             mime_encoding: false,
             follow_symlinks: true,
             no_pad: false,
+            uncompress: false,
         }
     }
 }
@@ -89,14 +99,44 @@ fn detect_file_type(path: &Path, opts: &Options) -> String {
         Err(e) => return format!("cannot open `{}' ({})", path.display(), e),
     };
 
-    let mut buffer = [0u8; 8192];
-    let bytes_read = match file.read(&mut buffer) {
-        Ok(n) => n,
-        Err(e) => return format!("cannot read `{}' ({})", path.display(), e),
-    };
+    let mut buffer = Vec::new();
+    if let Err(e) = file.by_ref().take(HEADER_LEN).read_to_end(&mut buffer) {
+        return format!("cannot read `{}' ({})", path.display(), e);
+    }
+
+    let mut desc = detect_content_type(&buffer, path);
+    if buffer.starts_with(b"\x7fELF") {
+        // The program and section headers can be anywhere in the file.
+        if let Some(details) = detect_elf_details(&buffer, &mut file) {
+            desc.push_str(&details);
+        }
+    }
+    if opts.uncompress {
+        if let Some(inner) = detect_compressed_content(&buffer, path) {
+            return format!("{} ({})", desc, inner);
+        }
+    }
+    desc
+}
+
+/// Describes the data inside a gzip stream from a bounded prefix of it.
+/// xz and bzip2 have no decoder here and are only described from the outside.
+fn detect_compressed_content(buf: &[u8], path: &Path) -> Option<String> {
+    if !buf.starts_with(&[0x1f, 0x8b]) {
+        return None;
+    }
+
+    // A truncated or corrupt stream still leaves whatever was decoded before
+    // the error, which is usually enough to recognize the contents.
+    let mut inner = Vec::new();
+    let _ = GzDecoder::new(buf).take(UNCOMPRESS_LEN).read_to_end(&mut inner);
+    if inner.is_empty() {
+        return None;
+    }
 
-    let buf = &buffer[..bytes_read];
-    detect_content_type(buf, path)
+    // Text detection by extension should look at `foo.tar`, not `foo.tar.gz`.
+    let inner_path = Path::new(path.file_stem().unwrap_or(path.as_os_str()));
+    Some(detect_content_type(&inner, inner_path))
 }
 
 fn detect_content_type(buf: &[u8], path: &Path) -> String {
@@ -105,6 +145,10 @@ fn detect_content_type(buf: &[u8], path: &Path) -> String {
         return detect_elf_type(buf);
     }
 
+    if let Some((desc, _)) = detect_magic(buf) {
+        return desc;
+    }
+
     // Use infer for other binary formats
     if let Some(kind) = infer::get(buf) {
         return format_infer_type(kind);
@@ -118,6 +162,97 @@ fn detect_content_type(buf: &[u8], path: &Path) -> String {
     }
 }
 
+/// Formats that infer doesn't know or doesn't describe as precisely as
+/// `file` does. Returns the description and the MIME type.
+fn detect_magic(buf: &[u8]) -> Option<(String, &'static str)> {
+    if buf.starts_with(b"SQLite format 3\0") {
+        return Some(("SQLite 3.x database".to_string(), "application/vnd.sqlite3"));
+    }
+    if buf.starts_with(b"OggS") {
+        return Some(detect_ogg_type(buf));
+    }
+    if buf.starts_with(b"!<arch>\n") {
+        return Some(detect_ar_type(buf));
+    }
+    if let Some(desc) = detect_cpio_type(buf) {
+        return Some((desc.to_string(), "application/x-cpio"));
+    }
+    if buf.len() >= 0x8006 && &buf[0x8001..0x8006] == b"CD001" {
+        return Some((detect_iso9660_type(buf), "application/x-iso9660-image"));
+    }
+    None
+}
+
+fn detect_ogg_type(buf: &[u8]) -> (String, &'static str) {
+    // The first packet follows the 27 byte page header and its segment table.
+    let packet = buf
+        .get(26)
+        .and_then(|&segments| buf.get(27 + segments as usize..))
+        .unwrap_or(&[]);
+
+    if packet.starts_with(b"OpusHead") {
+        ("Ogg data, Opus audio".to_string(), "audio/ogg")
+    } else if packet.starts_with(b"\x01vorbis") {
+        ("Ogg data, Vorbis audio".to_string(), "audio/ogg")
+    } else if packet.starts_with(b"\x80theora") {
+        ("Ogg data, Theora video".to_string(), "video/ogg")
+    } else if packet.starts_with(b"\x7fFLAC") {
+        ("Ogg data, FLAC audio".to_string(), "audio/ogg")
+    } else {
+        ("Ogg data".to_string(), "application/ogg")
+    }
+}
+
+fn detect_ar_type(buf: &[u8]) -> (String, &'static str) {
+    // A Debian package is an ar archive whose first member is "debian-binary",
+    // holding the format version.
+    let header = buf.get(8..68).unwrap_or(&[]);
+    if header.starts_with(b"debian-binary") {
+        let size = String::from_utf8_lossy(&header[48..58])
+            .trim()
+            .parse::<usize>()
+            .unwrap_or(0);
+        let version = buf.get(68..68 + size).map(String::from_utf8_lossy);
+        let desc = match version {
+            Some(version) => format!("Debian binary package (format {})", version.trim()),
+            None => "Debian binary package".to_string(),
+        };
+        return (desc, "application/vnd.debian.binary-package");
+    }
+    ("current ar archive".to_string(), "application/x-archive")
+}
+
+fn detect_cpio_type(buf: &[u8]) -> Option<&'static str> {
+    if buf.starts_with(b"070701") {
+        Some("ASCII cpio archive (SVR4 with no CRC)")
+    } else if buf.starts_with(b"070702") {
+        Some("ASCII cpio archive (SVR4 with CRC)")
+    } else if buf.starts_with(b"070707") {
+        Some("ASCII cpio archive (pre-SVR4 or odc)")
+    } else if buf.starts_with(&[0xc7, 0x71]) {
+        Some("cpio archive")
+    } else if buf.starts_with(&[0x71, 0xc7]) {
+        Some("byte-swapped cpio archive")
+    } else {
+        None
+    }
+}
+
+fn detect_iso9660_type(buf: &[u8]) -> String {
+    let mut desc = "ISO 9660 CD-ROM filesystem data".to_string();
+    // The volume identifier of the primary volume descriptor.
+    if buf[0x8000] == 1 {
+        if let Some(label) = buf.get(0x8028..0x8048) {
+            let label = String::from_utf8_lossy(label);
+            let label = label.trim_end_matches([' ', '\0']);
+            if !label.is_empty() {
+                desc.push_str(&format!(" '{}'", label));
+            }
+        }
+    }
+    desc
+}
+
 fn detect_elf_type(buf: &[u8]) -> String {
     if buf.len() < 20 {
         return "ELF".to_string();
@@ -181,6 +316,135 @@ fn detect_elf_type(buf: &[u8]) -> String {
     desc
 }
 
+/// Reads integers of an ELF file in its class and byte order.
+struct ElfReader<'a, R> {
+    file: &'a mut R,
+    is_64: bool,
+    little_endian: bool,
+}
+
+impl<R: Read + Seek> ElfReader<'_, R> {
+    /// Reads `len` bytes at `offset`, failing if they aren't all within the
+    /// file so that bogus header fields can't cause huge allocations.
+    fn read_at(&mut self, offset: u64, len: usize) -> Option<Vec<u8>> {
+        let file_len = self.file.seek(SeekFrom::End(0)).ok()?;
+        if offset.checked_add(len as u64)? > file_len {
+            return None;
+        }
+        let mut bytes = vec![0u8; len];
+        self.file.seek(SeekFrom::Start(offset)).ok()?;
+        self.file.read_exact(&mut bytes).ok()?;
+        Some(bytes)
+    }
+
+    fn u16(&self, bytes: &[u8], offset: usize) -> u16 {
+        let b = [bytes[offset], bytes[offset + 1]];
+        if self.little_endian {
+            u16::from_le_bytes(b)
+        } else {
+            u16::from_be_bytes(b)
+        }
+    }
+
+    fn u32(&self, bytes: &[u8], offset: usize) -> u32 {
+        let b = bytes[offset..offset + 4].try_into().unwrap();
+        if self.little_endian {
+            u32::from_le_bytes(b)
+        } else {
+            u32::from_be_bytes(b)
+        }
+    }
+
+    /// Reads an address or offset sized field, which is 32 bits wide in
+    /// 32-bit files.
+    fn word(&self, bytes: &[u8], offset: usize) -> u64 {
+        if self.is_64 {
+            let b = bytes[offset..offset + 8].try_into().unwrap();
+            if self.little_endian {
+                u64::from_le_bytes(b)
+            } else {
+                u64::from_be_bytes(b)
+            }
+        } else {
+            u64::from(self.u32(bytes, offset))
+        }
+    }
+}
+
+/// Describes how an ELF file is linked and whether it is stripped, from its
+/// program and section headers.
+fn detect_elf_details<R: Read + Seek>(header: &[u8], file: &mut R) -> Option<String> {
+    const PT_DYNAMIC: u32 = 2;
+    const PT_INTERP: u32 = 3;
+    const SHT_SYMTAB: u32 = 2;
+
+    let is_64 = match header.get(4)? {
+        1 => false,
+        2 => true,
+        _ => return None,
+    };
+    let elf = &mut ElfReader {
+        file,
+        is_64,
+        little_endian: *header.get(5)? == 1,
+    };
+    let header = header.get(..if is_64 { 64 } else { 52 })?;
+
+    // Offsets of e_phoff, e_shoff and e_phentsize differ between classes.
+    let (phoff, shoff, sizes) = if is_64 {
+        (elf.word(header, 32), elf.word(header, 40), 54)
+    } else {
+        (elf.word(header, 28), elf.word(header, 32), 42)
+    };
+    let phentsize = usize::from(elf.u16(header, sizes));
+    let phnum = usize::from(elf.u16(header, sizes + 2));
+    let shentsize = usize::from(elf.u16(header, sizes + 4));
+    let shnum = usize::from(elf.u16(header, sizes + 6));
+    // Entries must be large enough to hold the fields read from them below.
+    let (min_phentsize, min_shentsize) = if is_64 { (56, 64) } else { (32, 40) };
+
+    let mut details = String::new();
+
+    if phnum > 0 && phentsize >= min_phentsize {
+        let phdrs = elf.read_at(phoff, phnum * phentsize)?;
+        let mut interp = None;
+        let mut dynamic = false;
+        for phdr in phdrs.chunks_exact(phentsize) {
+            // p_offset and p_filesz come right after p_type (and p_flags on 64-bit).
+            let (offset, filesz) = if is_64 {
+                (elf.word(phdr, 8), elf.word(phdr, 32))
+            } else {
+                (elf.word(phdr, 4), elf.word(phdr, 16))
+            };
+            match elf.u32(phdr, 0) {
+                PT_INTERP => interp = elf.read_at(offset, filesz.min(4096) as usize),
+                PT_DYNAMIC => dynamic = true,
+                _ => {}
+            }
+        }
+
+        match interp {
+            Some(path) => {
+                let path = String::from_utf8_lossy(&path);
+                details.push_str(", dynamically linked, interpreter ");
+                details.push_str(path.trim_end_matches('\0'));
+            }
+            None if dynamic => details.push_str(", dynamically linked"),
+            None => details.push_str(", statically linked"),
+        }
+    }
+
+    if shnum > 0 && shentsize >= min_shentsize {
+        let shdrs = elf.read_at(shoff, shnum * shentsize)?;
+        let symtab = shdrs
+            .chunks_exact(shentsize)
+            .any(|shdr| elf.u32(shdr, 4) == SHT_SYMTAB);
+        details.push_str(if symtab { ", not stripped" } else { ", stripped" });
+    }
+
+    Some(details)
+}
+
 fn format_infer_type(kind: infer::Type) -> String {
     match kind.mime_type() {
         "application/gzip" => "gzip compressed data".to_string(),
@@ -354,24 +618,27 @@ fn get_mime_type(path: &Path, opts: &Options) -> String {
         return "inode/x-empty".to_string();
     }
 
-    let mut file = match File::open(path) {
+    let file = match File::open(path) {
         Ok(f) => f,
         Err(_) => return "application/octet-stream".to_string(),
     };
 
-    let mut buffer = [0u8; 8192];
-    let bytes_read = match file.read(&mut buffer) {
-        Ok(n) => n,
-        Err(_) => return "application/octet-stream".to_string(),
-    };
+    let mut buffer = Vec::new();
+    if file.take(HEADER_LEN).read_to_end(&mut buffer).is_err() {
+        return "application/octet-stream".to_string();
+    }
 
-    let buf = &buffer[..bytes_read];
+    let buf = &buffer[..];
 
     // Check ELF
     if buf.len() >= 4 && &buf[0..4] == b"\x7fELF" {
         return "application/x-executable".to_string();
     }
 
+    if let Some((_, mime)) = detect_magic(buf) {
+        return mime.to_string();
+    }
+
     if let Some(kind) = infer::get(buf) {
         return kind.mime_type().to_string();
     }
@@ -387,8 +654,30 @@ fn get_mime_type(path: &Path, opts: &Options) -> String {
     }
 }
 
+/// Reads file names one per line, or separated by NUL bytes. NUL separated
+/// names are taken verbatim since they may begin or end with spaces.
+fn read_names<R: BufRead>(reader: R, nul_separated: bool) -> io::Result<Vec<String>> {
+    let mut names = Vec::new();
+    if nul_separated {
+        for name in reader.split(0) {
+            let name = name?;
+            if !name.is_empty() {
+                names.push(String::from_utf8_lossy(&name).into_owned());
+            }
+        }
+    } else {
+        for line in reader.lines().map_while(Result::ok) {
+            let trimmed = line.trim();
+            if !trimmed.is_empty() {
+                names.push(trimmed.to_string());
+            }
+        }
+    }
+    Ok(names)
+}
+
 fn print_usage() {
-    eprintln!("Usage: file [-bchiLNv] [-f namefile] [file ...]");
+    eprintln!("Usage: file [-0bchiLNvz] [-f namefile] [file ...]");
     eprintln!("       file -v | --version");
     eprintln!("       file -h | --help");
 }
@@ -399,11 +688,13 @@ fn print_help() {
     println!("Options:");
     println!("  -b, --brief         Do not prepend filenames to output lines");
     println!("  -c, --checking      (ignored, for compatibility)");
-    println!("  -f, --files-from F  Read filenames from file F");
+    println!("  -f, --files-from F  Read filenames from file F (- for stdin)");
+    println!("  -0, --print0        Filenames read with -f are NUL separated");
     println!("  -h, --no-dereference  Don't follow symlinks (default: follow)");
     println!("  -i, --mime          Output MIME type strings");
     println!("  -L, --dereference   Follow symlinks (default)");
     println!("  -N, --no-pad        Don't pad output");
+    println!("  -z, --uncompress    Look inside gzip compressed files");
     println!("      --mime-type     Output MIME type only");
     println!("      --mime-encoding Output MIME encoding only");
     println!("  -v, --version       Display version and exit");
@@ -415,6 +706,7 @@ fn main() {
     let mut opts = Options::default();
     let mut files: Vec<String> = Vec::new();
     let mut files_from: Option<String> = None;
+    let mut nul_separated = false;
     let mut i = 1;
 
     while i < args.len() {
@@ -431,6 +723,8 @@ fn main() {
             "--mime-encoding" => opts.mime_encoding = true,
             "-L" | "--dereference" => opts.follow_symlinks = true,
             "-N" | "--no-pad" => opts.no_pad = true,
+            "-z" | "--uncompress" => opts.uncompress = true,
+            "-0" | "--print0" => nul_separated = true,
             "-v" | "--version" => {
                 println!("file-{} (simple-file for Redox OS)", VERSION);
                 println!("Using infer crate for magic detection");
@@ -461,6 +755,8 @@ fn main() {
                         }
                         'L' => opts.follow_symlinks = true,
                         'N' => opts.no_pad = true,
+                        'z' => opts.uncompress = true,
+                        '0' => nul_separated = true,
                         'v' => {
                             println!("file-{}", VERSION);
                             return;
@@ -478,18 +774,15 @@ fn main() {
         i += 1;
     }
 
-    // Read files from -f option
+    // Read files from -f option, "-" being stdin
     if let Some(ref namefile) = files_from {
-        match File::open(namefile) {
-            Ok(f) => {
-                let reader = BufReader::new(f);
-                for line in reader.lines().map_while(Result::ok) {
-                    let trimmed = line.trim();
-                    if !trimmed.is_empty() {
-                        files.push(trimmed.to_string());
-                    }
-                }
-            }
+        let result = if namefile == "-" {
+            read_names(stdin().lock(), nul_separated)
+        } else {
+            File::open(namefile).and_then(|f| read_names(BufReader::new(f), nul_separated))
+        };
+        match result {
+            Ok(names) => files.extend(names),
             Err(e) => {
                 eprintln!("file: cannot open `{}' ({})", namefile, e);
                 std::process::exit(1);
@@ -526,3 +819,126 @@ fn main() {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn detect(buf: &[u8]) -> String {
+        detect_content_type(buf, Path::new("sample"))
+    }
+
+    #[test]
+    fn sqlite() {
+        let mut buf = b"SQLite format 3\0".to_vec();
+        buf.extend_from_slice(&[0x10, 0x00, 0x01, 0x01]);
+        assert_eq!(detect(&buf), "SQLite 3.x database");
+    }
+
+    #[test]
+    fn ogg_codecs() {
+        let mut page = b"OggS\0\x02".to_vec();
+        page.resize(26, 0);
+        page.extend_from_slice(&[1, 19]);
+
+        let mut opus = page.clone();
+        opus.extend_from_slice(b"OpusHead\x01\x02");
+        assert_eq!(detect(&opus), "Ogg data, Opus audio");
+
+        let mut vorbis = page;
+        vorbis.extend_from_slice(b"\x01vorbis\0\0\0\0\x02");
+        assert_eq!(detect(&vorbis), "Ogg data, Vorbis audio");
+    }
+
+    #[test]
+    fn ar_and_deb() {
+        let mut deb = b"!<arch>\n".to_vec();
+        deb.extend_from_slice(b"debian-binary   1342943816  0     0     100644  4         `\n");
+        deb.extend_from_slice(b"2.0\n");
+        assert_eq!(detect(&deb), "Debian binary package (format 2.0)");
+
+        let mut ar = b"!<arch>\n".to_vec();
+        ar.extend_from_slice(b"foo.o/          0           0     0     644     8         `\n");
+        ar.extend_from_slice(&[0x7f, b'E', b'L', b'F', 2, 1, 1, 0]);
+        assert_eq!(detect(&ar), "current ar archive");
+    }
+
+    #[test]
+    fn cpio() {
+        assert_eq!(detect(b"07070100000000000081a4"), "ASCII cpio archive (SVR4 with no CRC)");
+        assert_eq!(detect(&[0xc7, 0x71, 0x00, 0x01, 0xff, 0x00]), "cpio archive");
+    }
+
+    #[test]
+    fn iso9660() {
+        let mut buf = vec![0u8; 0x8800];
+        buf[0x8000] = 1;
+        buf[0x8001..0x8006].copy_from_slice(b"CD001");
+        buf[0x8028..0x8048].copy_from_slice(b"REDOX                           ");
+        assert_eq!(detect(&buf), "ISO 9660 CD-ROM filesystem data 'REDOX'");
+    }
+
+    #[test]
+    fn elf_32_msb_dynamic() {
+        let interp = b"/lib/ld.so.1\0";
+        let mut elf = b"\x7fELF\x01\x02\x01".to_vec();
+        elf.resize(16, 0);
+        // e_type, e_machine, e_version, e_entry, e_phoff, e_shoff, e_flags
+        for field in [2u16, 20] {
+            elf.extend_from_slice(&field.to_be_bytes());
+        }
+        let shoff = 52 + 32 + interp.len() as u32;
+        for field in [1u32, 0, 52, shoff, 0] {
+            elf.extend_from_slice(&field.to_be_bytes());
+        }
+        // e_ehsize, e_phentsize, e_phnum, e_shentsize, e_shnum, e_shstrndx
+        for field in [52u16, 32, 1, 40, 2, 0] {
+            elf.extend_from_slice(&field.to_be_bytes());
+        }
+        // PT_INTERP
+        for field in [3u32, 84, 0, 0, interp.len() as u32, interp.len() as u32, 4, 1] {
+            elf.extend_from_slice(&field.to_be_bytes());
+        }
+        elf.extend_from_slice(interp);
+        // A null section and a SHT_SYMTAB one.
+        elf.resize(elf.len() + 44, 0);
+        elf.extend_from_slice(&2u32.to_be_bytes());
+        elf.resize(elf.len() + 32, 0);
+
+        let mut desc = detect(&elf);
+        desc.push_str(&detect_elf_details(&elf, &mut std::io::Cursor::new(&elf)).unwrap());
+        assert_eq!(
+            desc,
+            "ELF 32-bit MSB executable, PowerPC, dynamically linked, interpreter /lib/ld.so.1, not stripped"
+        );
+    }
+
+    #[test]
+    fn elf_bogus_header_fields() {
+        let mut elf = b"\x7fELF\x02\x01\x01".to_vec();
+        elf.resize(16, 0);
+        // e_type, e_machine, e_version, e_entry, e_phoff, e_shoff, e_flags
+        for field in [2u16, 62] {
+            elf.extend_from_slice(&field.to_le_bytes());
+        }
+        elf.extend_from_slice(&1u32.to_le_bytes());
+        for field in [0u64, 64, 64] {
+            elf.extend_from_slice(&field.to_le_bytes());
+        }
+        elf.extend_from_slice(&0u32.to_le_bytes());
+        // Entries too small to hold a header, and a count larger than the file.
+        for field in [64u16, 8, 1, 8, 0xffff, 0] {
+            elf.extend_from_slice(&field.to_le_bytes());
+        }
+        elf.resize(elf.len() + 64, 0);
+        let details = detect_elf_details(&elf, &mut std::io::Cursor::new(&elf));
+        assert_eq!(details.as_deref(), Some(""));
+
+        // Large enough entries, but more of them than fit in the file.
+        for (offset, field) in [(54, 56u16), (56, 0xffff), (58, 64)] {
+            elf[offset..offset + 2].copy_from_slice(&field.to_le_bytes());
+        }
+        let details = detect_elf_details(&elf, &mut std::io::Cursor::new(&elf));
+        assert_eq!(details, None);
+    }
+}
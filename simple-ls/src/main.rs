@@ -1,8 +1,15 @@
 // Simple ls implementation for Redox OS
+use std::collections::HashMap;
 use std::env;
 use std::fs;
+use std::io::IsTerminal;
 use std::os::unix::fs::MetadataExt;
 use std::path::Path;
+use std::thread;
+
+// Directories with more entries than this are stat'ed in parallel
+const PARALLEL_STAT_THRESHOLD: usize = 64;
+const STAT_THREADS: usize = 4;
 
 fn format_time(secs: i64) -> String {
     if secs == 0 {
@@ -61,25 +68,183 @@ fn is_leap_year(year: i64) -> bool {
     (year % 4 == 0 && year % 100 != 0) || (year % 400 == 0)
 }
 
+// Size column of the long listing, e.g. 1.2K or 34M with -h
+fn format_size(size: u64, human: bool) -> String {
+    const UNITS: [&str; 6] = ["K", "M", "G", "T", "P", "E"];
+
+    if !human || size < 1024 {
+        return size.to_string();
+    }
+
+    let mut value = size as f64;
+    let mut unit = "";
+    for u in UNITS {
+        value /= 1024.0;
+        unit = u;
+        if value < 1024.0 {
+            break;
+        }
+    }
+
+    if value < 10.0 {
+        format!("{:.1}{}", value, unit)
+    } else {
+        format!("{:.0}{}", value, unit)
+    }
+}
+
+// How names are laid out without -l
+#[derive(Clone, Copy, PartialEq)]
+enum Layout {
+    // Columns filled top to bottom (-C, default on a terminal)
+    Down,
+    // Columns filled left to right (-x)
+    Across,
+    // One name per line (-1, default when not a terminal)
+    Single,
+}
+
+fn terminal_width() -> usize {
+    let mut size: libc::winsize = unsafe { std::mem::zeroed() };
+    let ret = unsafe { libc::ioctl(libc::STDOUT_FILENO, libc::TIOCGWINSZ, &mut size) };
+    if ret == 0 && size.ws_col > 0 {
+        size.ws_col as usize
+    } else {
+        80
+    }
+}
+
+fn print_grid(names: &[&str], layout: Layout, width: usize) {
+    if names.is_empty() {
+        return;
+    }
+    if layout == Layout::Single {
+        for name in names {
+            println!("{}", name);
+        }
+        return;
+    }
+
+    // Every column is as wide as the longest name, plus two spaces
+    let column_width = names.iter().map(|name| name.chars().count()).max().unwrap_or(0) + 2;
+    let columns = ((width + 2) / column_width).clamp(1, names.len());
+    let rows = names.len().div_ceil(columns);
+
+    for row in 0..rows {
+        let mut line = String::new();
+        for column in 0..columns {
+            let index = match layout {
+                Layout::Across => row * columns + column,
+                _ => column * rows + row,
+            };
+            let Some(name) = names.get(index) else {
+                continue;
+            };
+            line.push_str(&format!("{:<width$}", name, width = column_width));
+        }
+        println!("{}", line.trim_end());
+    }
+}
+
+// Reads the id -> name table of /etc/passwd or /etc/group. Redox separates fields with ';'
+// and has no password field in passwd, so the id isn't at the same index in both formats.
+fn read_id_names(path: &str, colon_index: usize, semicolon_index: usize) -> HashMap<u32, String> {
+    let mut names = HashMap::new();
+
+    if let Ok(content) = fs::read_to_string(path) {
+        for line in content.lines() {
+            let (fields, index): (Vec<&str>, usize) = if line.contains(':') {
+                (line.split(':').collect(), colon_index)
+            } else {
+                (line.split(';').collect(), semicolon_index)
+            };
+            if let Some(id) = fields.get(index).and_then(|id| id.trim().parse().ok()) {
+                names.entry(id).or_insert_with(|| fields[0].to_string());
+            }
+        }
+    }
+
+    names
+}
+
+// Owner and group names, looked up once rather than per entry
+struct Owners {
+    users: HashMap<u32, String>,
+    groups: HashMap<u32, String>,
+}
+
+impl Owners {
+    fn load() -> Self {
+        Owners {
+            users: read_id_names("/etc/passwd", 2, 1),
+            groups: read_id_names("/etc/group", 2, 2),
+        }
+    }
+
+    // Used by -n, every id is printed as a number
+    fn numeric() -> Self {
+        Owners {
+            users: HashMap::new(),
+            groups: HashMap::new(),
+        }
+    }
+
+    fn user(&self, uid: u32) -> String {
+        self.users.get(&uid).cloned().unwrap_or_else(|| uid.to_string())
+    }
+
+    fn group(&self, gid: u32) -> String {
+        self.groups.get(&gid).cloned().unwrap_or_else(|| gid.to_string())
+    }
+}
+
+struct Options {
+    layout: Layout,
+    show_long: bool,
+    show_all: bool,
+    sort_by_time: bool,
+    reverse_order: bool,
+    recursive: bool,
+    human_sizes: bool,
+    numeric_ids: bool,
+    owners: Owners,
+}
+
 fn main() {
     let args: Vec<String> = env::args().collect();
 
     // Parse flags
-    let mut show_long = false;
-    let mut show_all = false;
-    let mut sort_by_time = false;
-    let mut reverse_order = false;
+    let mut options = Options {
+        layout: if std::io::stdout().is_terminal() { Layout::Down } else { Layout::Single },
+        show_long: false,
+        show_all: false,
+        sort_by_time: false,
+        reverse_order: false,
+        recursive: false,
+        human_sizes: false,
+        numeric_ids: false,
+        owners: Owners::numeric(),
+    };
     let mut paths: Vec<&str> = Vec::new();
 
     for arg in &args[1..] {
         if arg.starts_with('-') {
             for c in arg.chars().skip(1) {
                 match c {
-                    'l' => show_long = true,
-                    'a' => show_all = true,
-                    't' => sort_by_time = true,
-                    'r' => reverse_order = true,
-                    '1' => {} // One entry per line (implied with -l)
+                    'l' => options.show_long = true,
+                    'a' => options.show_all = true,
+                    't' => options.sort_by_time = true,
+                    'r' => options.reverse_order = true,
+                    'R' => options.recursive = true,
+                    'h' => options.human_sizes = true,
+                    'n' => {
+                        // Like -l, with numeric owners
+                        options.show_long = true;
+                        options.numeric_ids = true;
+                    }
+                    '1' => options.layout = Layout::Single, // Implied with -l
+                    'C' => options.layout = Layout::Down,
+                    'x' => options.layout = Layout::Across,
                     _ => {}
                 }
             }
@@ -92,8 +257,12 @@ fn main() {
         paths.push(".");
     }
 
+    if options.show_long && !options.numeric_ids {
+        options.owners = Owners::load();
+    }
+
     for path in paths {
-        list_path(path, show_long, show_all, sort_by_time, reverse_order);
+        list_path(path, &options);
     }
 }
 
@@ -102,22 +271,78 @@ struct EntryInfo {
     is_dir: bool,
     is_symlink: bool,
     mode: u32,
+    uid: u32,
+    gid: u32,
     size: u64,
+    // Allocated 512-byte blocks
+    blocks: u64,
     mtime: i64,
     link_target: Option<String>,
 }
 
-fn list_path(path: &str, show_long: bool, show_all: bool, sort_by_time: bool, reverse_order: bool) {
+fn entry_info(entry: &fs::DirEntry, name: String) -> EntryInfo {
+    let (is_dir, is_symlink, mode, uid, gid, size, blocks, mtime, link_target) =
+        if let Ok(meta) = entry.metadata() {
+            let symlink_meta = fs::symlink_metadata(entry.path()).ok();
+            let is_symlink = symlink_meta.map(|m| m.file_type().is_symlink()).unwrap_or(false);
+            let link_target = if is_symlink {
+                fs::read_link(entry.path()).ok().map(|p| p.display().to_string())
+            } else {
+                None
+            };
+            (meta.is_dir(), is_symlink, meta.mode(), meta.uid(), meta.gid(), meta.len(), meta.blocks(), meta.mtime(), link_target)
+        } else {
+            (false, false, 0, 0, 0, 0, 0, 0, None)
+        };
+
+    EntryInfo {
+        name,
+        is_dir,
+        is_symlink,
+        mode,
+        uid,
+        gid,
+        size,
+        blocks,
+        mtime,
+        link_target,
+    }
+}
+
+// Every stat is a round trip on filesystems like 9p, so large directories are stat'ed on a few
+// threads. The entries keep their order.
+fn stat_entries(entries: Vec<(fs::DirEntry, String)>) -> Vec<EntryInfo> {
+    if entries.len() <= PARALLEL_STAT_THRESHOLD {
+        return entries.into_iter().map(|(entry, name)| entry_info(&entry, name)).collect();
+    }
+
+    let chunk_size = entries.len().div_ceil(STAT_THREADS);
+    thread::scope(|scope| {
+        let workers: Vec<_> = entries
+            .chunks(chunk_size)
+            .map(|chunk| {
+                scope.spawn(move || {
+                    chunk.iter().map(|(entry, name)| entry_info(entry, name.clone())).collect::<Vec<_>>()
+                })
+            })
+            .collect();
+        workers.into_iter().flat_map(|worker| worker.join().unwrap()).collect()
+    })
+}
+
+fn list_path(path: &str, options: &Options) {
     let p = Path::new(path);
 
     // Handle single file
     if p.is_file() {
         if let Ok(meta) = fs::metadata(p) {
-            if show_long {
+            if options.show_long {
                 let mode = meta.mode();
-                let size = meta.len();
+                let user = options.owners.user(meta.uid());
+                let group = options.owners.group(meta.gid());
+                let size = format_size(meta.len(), options.human_sizes);
                 let mtime = format_time(meta.mtime());
-                println!("-{:o} {:>8} {} {}", mode & 0o777, size, mtime, path);
+                println!("-{:o} {:<8} {:<8} {:>8} {} {}", mode & 0o777, user, group, size, mtime, path);
             } else {
                 println!("{}", path);
             }
@@ -128,7 +353,7 @@ fn list_path(path: &str, show_long: bool, show_all: bool, sort_by_time: bool, re
     // Handle symlink pointing to file
     if p.is_symlink() {
         if let Ok(target) = fs::read_link(p) {
-            if show_long {
+            if options.show_long {
                 println!("l          {} -> {}", path, target.display());
             } else {
                 println!("{}", path);
@@ -137,48 +362,32 @@ fn list_path(path: &str, show_long: bool, show_all: bool, sort_by_time: bool, re
         return;
     }
 
+    if options.recursive {
+        println!("{}:", path);
+    }
+
     // Handle directory - collect entries first for sorting
     match fs::read_dir(path) {
         Ok(entries) => {
-            let mut entry_list: Vec<EntryInfo> = Vec::new();
+            let mut dir_entries = Vec::new();
 
             for entry in entries {
                 if let Ok(entry) = entry {
                     let name = entry.file_name();
                     let name_str = name.to_string_lossy().to_string();
 
-                    if !show_all && name_str.starts_with('.') {
+                    if !options.show_all && name_str.starts_with('.') {
                         continue;
                     }
 
-                    let (is_dir, is_symlink, mode, size, mtime, link_target) =
-                        if let Ok(meta) = entry.metadata() {
-                            let symlink_meta = fs::symlink_metadata(entry.path()).ok();
-                            let is_symlink = symlink_meta.map(|m| m.file_type().is_symlink()).unwrap_or(false);
-                            let link_target = if is_symlink {
-                                fs::read_link(entry.path()).ok().map(|p| p.display().to_string())
-                            } else {
-                                None
-                            };
-                            (meta.is_dir(), is_symlink, meta.mode(), meta.len(), meta.mtime(), link_target)
-                        } else {
-                            (false, false, 0, 0, 0, None)
-                        };
-
-                    entry_list.push(EntryInfo {
-                        name: name_str,
-                        is_dir,
-                        is_symlink,
-                        mode,
-                        size,
-                        mtime,
-                        link_target,
-                    });
+                    dir_entries.push((entry, name_str));
                 }
             }
 
+            let mut entry_list = stat_entries(dir_entries);
+
             // Sort entries
-            if sort_by_time {
+            if options.sort_by_time {
                 // Sort by time, newest first (descending mtime)
                 // Larger mtime values should come first
                 entry_list.sort_by(|a, b| {
@@ -191,13 +400,19 @@ fn list_path(path: &str, show_long: bool, show_all: bool, sort_by_time: bool, re
                 entry_list.sort_by(|a, b| a.name.cmp(&b.name)); // Alphabetical
             }
 
-            if reverse_order {
+            if options.reverse_order {
                 entry_list.reverse();
             }
 
+            // Like GNU ls, the total is counted in 1K blocks
+            if options.show_long {
+                let blocks: u64 = entry_list.iter().map(|entry| entry.blocks).sum();
+                println!("total {}", blocks.div_ceil(2));
+            }
+
             // Display entries
             for entry in &entry_list {
-                if show_long {
+                if options.show_long {
                     let file_type = if entry.is_symlink {
                         "l"
                     } else if entry.is_dir {
@@ -206,17 +421,29 @@ fn list_path(path: &str, show_long: bool, show_all: bool, sort_by_time: bool, re
                         "-"
                     };
                     let mtime_str = format_time(entry.mtime);
+                    let size_str = format_size(entry.size, options.human_sizes);
+                    let user = options.owners.user(entry.uid);
+                    let group = options.owners.group(entry.gid);
                     if let Some(ref target) = entry.link_target {
-                        println!("{}{:o} {:>8} {} {} -> {}", file_type, entry.mode & 0o777, entry.size, mtime_str, entry.name, target);
+                        println!("{}{:o} {:<8} {:<8} {:>8} {} {} -> {}", file_type, entry.mode & 0o777, user, group, size_str, mtime_str, entry.name, target);
                     } else {
-                        println!("{}{:o} {:>8} {} {}", file_type, entry.mode & 0o777, entry.size, mtime_str, entry.name);
+                        println!("{}{:o} {:<8} {:<8} {:>8} {} {}", file_type, entry.mode & 0o777, user, group, size_str, mtime_str, entry.name);
                     }
-                } else {
-                    print!("{}  ", entry.name);
                 }
             }
-            if !show_long {
-                println!();
+            if !options.show_long {
+                let names: Vec<&str> = entry_list.iter().map(|entry| entry.name.as_str()).collect();
+                print_grid(&names, options.layout, terminal_width());
+            }
+
+            // Depth first, in listing order; directory symlinks aren't followed to avoid loops
+            if options.recursive {
+                for entry in &entry_list {
+                    if entry.is_dir && !entry.is_symlink && entry.name != "." && entry.name != ".." {
+                        println!();
+                        list_path(&p.join(&entry.name).to_string_lossy(), options);
+                    }
+                }
             }
         }
         Err(e) => {
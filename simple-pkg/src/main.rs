@@ -4,16 +4,20 @@
 use std::env;
 use std::fs::{self, File};
 use std::io::Read;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::process;
 use std::sync::Arc;
 
+use sha2::{Digest, Sha256};
 use ureq::{Agent, tls::{TlsConfig, TlsProvider, RootCerts}};
 
 // HTTPS package server
 const PKG_SERVER: &str = "https://static.redox-os.org/pkg/aarch64-unknown-redox";
 const PKG_DIR: &str = "/pkg";
 const LOCAL_PKG: &str = "/scheme/9p.hostshare/packages";
+// Paths extracted by an install, one per line with the size of regular files after a tab,
+// used by `pkg remove` and `pkg verify`
+const MANIFEST: &str = ".manifest";
 
 fn create_agent() -> Agent {
     let crypto = Arc::new(rustls_rustcrypto::provider());
@@ -39,7 +43,9 @@ fn print_usage() {
     eprintln!("  list              List installed packages");
     eprintln!("  available         List packages in {}", LOCAL_PKG);
     eprintln!("  install <name>    Install package (from local or remote)");
-    eprintln!("  install-local <path>  Install from local .tar.gz file");
+    eprintln!("  install-local <path>  Install from local .tar.gz file or directory");
+    eprintln!("  remove <name>     Remove an installed package");
+    eprintln!("  verify <name>     Check installed files against the package manifest");
     eprintln!("  search <query>    Search remote packages");
     eprintln!("  fetch <url>       Fetch and extract a package from URL");
     eprintln!();
@@ -66,11 +72,22 @@ fn fetch_url(url: &str) -> Result<Vec<u8>, String> {
 /// Parse repo.toml format: name = "hash"
 fn parse_repo(content: &str) -> Vec<(String, String)> {
     let mut packages = Vec::new();
+    let mut section = None;
 
     for line in content.lines() {
         let line = line.trim();
-        // Skip empty lines, comments, and section headers
-        if line.is_empty() || line.starts_with('#') || line.starts_with('[') {
+        // Skip empty lines and comments
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        if line.starts_with('[') {
+            section = Some(line.trim_matches(|c| c == '[' || c == ']').trim().to_string());
+            continue;
+        }
+
+        // Per-package sections like [name] hold fields, not packages
+        if section.as_deref().is_some_and(|s| s != "packages") {
             continue;
         }
 
@@ -87,6 +104,58 @@ fn parse_repo(content: &str) -> Vec<(String, String)> {
     packages
 }
 
+/// Find the `sha256 = "..."` field in the `[name]` section of repo.toml
+fn find_package_sha256(content: &str, name: &str) -> Option<String> {
+    let mut in_section = false;
+
+    for line in content.lines() {
+        let line = line.trim();
+        if line.starts_with('[') {
+            in_section = line.trim_matches(|c| c == '[' || c == ']').trim() == name;
+            continue;
+        }
+        if !in_section {
+            continue;
+        }
+
+        if let Some((key, value)) = line.split_once('=') {
+            if key.trim() == "sha256" {
+                let value = value.trim().trim_matches('"').trim_matches('\'');
+                if !value.is_empty() {
+                    return Some(value.to_string());
+                }
+            }
+        }
+    }
+
+    None
+}
+
+fn sha256_hex(data: &[u8]) -> String {
+    Sha256::digest(data)
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// Check downloaded bytes against the checksum from repo.toml, exits on mismatch
+fn verify_checksum(name: &str, data: &[u8], expected: Option<&str>) {
+    let Some(expected) = expected else {
+        eprintln!("Warning: no checksum listed for {}, installing unverified", name);
+        return;
+    };
+
+    let actual = sha256_hex(data);
+    if !actual.eq_ignore_ascii_case(expected) {
+        eprintln!("Checksum mismatch for {}, refusing to install", name);
+        eprintln!("  expected: {}", expected);
+        eprintln!("  actual:   {}", actual);
+        process::exit(1);
+    }
+
+    eprintln!("Checksum OK (sha256 {})", actual);
+}
+
 fn list_installed() {
     let pkg_dir = Path::new(PKG_DIR);
 
@@ -143,8 +212,9 @@ fn install_local(path: &str) {
         process::exit(1);
     }
 
-    let name = Path::new(&path)
-        .file_stem()
+    // Directories are named as they are, archives without their extension
+    let source = Path::new(&path);
+    let name = if source.is_dir() { source.file_name() } else { source.file_stem() }
         .and_then(|s| s.to_str())
         .unwrap_or("package")
         .trim_end_matches(".tar");
@@ -154,8 +224,14 @@ fn install_local(path: &str) {
 
     eprintln!("Installing {} from {}...", name, path);
 
-    // Detect format by extension
-    if path.ends_with(".pkgar") {
+    if source.is_dir() {
+        // Already unpacked package tree
+        match install_dir(source, &dest_dir) {
+            Ok(count) => eprintln!("Successfully installed {} ({} files)", name, count),
+            Err(e) => eprintln!("Error copying: {}", e),
+        }
+    } else if path.ends_with(".pkgar") {
+        // Detect format by extension
         // Read pkgar file and extract
         match fs::read(&path) {
             Ok(data) => {
@@ -175,6 +251,61 @@ fn install_local(path: &str) {
     }
 }
 
+/// Copy an unpacked package tree into `dest`, returning the number of copied entries
+fn install_dir(source: &Path, dest: &str) -> Result<usize, String> {
+    let mut paths = Vec::new();
+    copy_tree(source, Path::new(dest), &mut paths)?;
+    write_manifest(dest, &paths)?;
+    Ok(paths.len())
+}
+
+/// Recursively copy `source` into `dest`, keeping file modes and recreating symlinks
+fn copy_tree(source: &Path, dest: &Path, paths: &mut Vec<PathBuf>) -> Result<(), String> {
+    let entries = fs::read_dir(source)
+        .map_err(|e| format!("Cannot read {}: {}", source.display(), e))?;
+
+    for entry in entries {
+        let entry = entry.map_err(|e| format!("Cannot read {}: {}", source.display(), e))?;
+        let from = entry.path();
+        let to = dest.join(entry.file_name());
+
+        // file_type() doesn't follow symlinks
+        let file_type = entry.file_type()
+            .map_err(|e| format!("Cannot stat {}: {}", from.display(), e))?;
+
+        if file_type.is_symlink() {
+            let target = fs::read_link(&from)
+                .map_err(|e| format!("Cannot read link {}: {}", from.display(), e))?;
+            fs::remove_file(&to).ok();
+
+            #[cfg(unix)]
+            {
+                std::os::unix::fs::symlink(&target, &to)
+                    .map_err(|e| format!("Cannot create link {}: {}", to.display(), e))?;
+                paths.push(to);
+            }
+            #[cfg(not(unix))]
+            eprintln!("Warning: skipping symlink {} -> {}", from.display(), target.display());
+        } else if file_type.is_dir() {
+            fs::create_dir_all(&to)
+                .map_err(|e| format!("Cannot create {}: {}", to.display(), e))?;
+            if let Ok(meta) = entry.metadata() {
+                fs::set_permissions(&to, meta.permissions()).ok();
+            }
+            // Directories are listed before their contents
+            paths.push(to.clone());
+            copy_tree(&from, &to, paths)?;
+        } else {
+            // fs::copy also copies the permission bits
+            fs::copy(&from, &to)
+                .map_err(|e| format!("Cannot copy {}: {}", from.display(), e))?;
+            paths.push(to);
+        }
+    }
+
+    Ok(())
+}
+
 fn search_packages(query: &str) {
     let repo_url = format!("{}/repo.toml", PKG_SERVER);
 
@@ -203,7 +334,7 @@ fn search_packages(query: &str) {
 fn install_package(name: &str) {
     let repo_url = format!("{}/repo.toml", PKG_SERVER);
 
-    let _hash = match fetch_url(&repo_url) {
+    let sha256 = match fetch_url(&repo_url) {
         Ok(data) => {
             let content = String::from_utf8_lossy(&data);
             let packages = parse_repo(&content);
@@ -211,6 +342,7 @@ fn install_package(name: &str) {
                 eprintln!("Package '{}' not found in repository", name);
                 process::exit(1);
             }
+            find_package_sha256(&content, name)
         }
         Err(e) => {
             eprintln!("Error fetching repo: {}", e);
@@ -220,10 +352,10 @@ fn install_package(name: &str) {
 
     // Redox packages are .pkgar format, directly named
     let pkg_url = format!("{}/{}.pkgar", PKG_SERVER, name);
-    fetch_and_install_pkgar(&pkg_url, name);
+    fetch_and_install_pkgar(&pkg_url, name, sha256.as_deref());
 }
 
-fn fetch_and_install_pkgar(url: &str, name: &str) {
+fn fetch_and_install_pkgar(url: &str, name: &str, sha256: Option<&str>) {
     eprintln!("Downloading {} from {}", name, url);
 
     let data = match fetch_url(url) {
@@ -235,6 +367,7 @@ fn fetch_and_install_pkgar(url: &str, name: &str) {
     };
 
     eprintln!("Downloaded {} bytes", data.len());
+    verify_checksum(name, &data, sha256);
 
     let dest_dir = format!("{}/{}", PKG_DIR, name);
     fs::create_dir_all(&dest_dir).ok();
@@ -279,6 +412,7 @@ fn extract_pkgar(data: &[u8], dest: &str) -> Result<usize, String> {
 
     // Parse entries and extract files
     let mut extracted = 0;
+    let mut paths = Vec::new();
     for i in 0..count {
         let entry_offset = entries_start + i * ENTRY_SIZE;
         let entry = &data[entry_offset..entry_offset + ENTRY_SIZE];
@@ -311,6 +445,7 @@ fn extract_pkgar(data: &[u8], dest: &str) -> Result<usize, String> {
 
         if is_dir {
             fs::create_dir_all(&full_path).ok();
+            paths.push(PathBuf::from(&full_path));
         } else {
             // Create parent directories
             if let Some(parent) = Path::new(&full_path).parent() {
@@ -334,14 +469,17 @@ fn extract_pkgar(data: &[u8], dest: &str) -> Result<usize, String> {
                 }
 
                 extracted += 1;
+                paths.push(PathBuf::from(&full_path));
             }
         }
     }
 
+    write_manifest(dest, &paths)?;
+
     Ok(extracted)
 }
 
-fn fetch_and_install(url: &str, name: &str) {
+fn fetch_and_install(url: &str, name: &str, sha256: Option<&str>) {
     eprintln!("Installing {} from {}", name, url);
 
     let data = match fetch_url(url) {
@@ -353,6 +491,7 @@ fn fetch_and_install(url: &str, name: &str) {
     };
 
     eprintln!("Downloaded {} bytes", data.len());
+    verify_checksum(name, &data, sha256);
 
     let tmp_path = format!("/tmp/{}.tar.gz", name);
     if let Err(e) = fs::write(&tmp_path, &data) {
@@ -378,26 +517,209 @@ fn fetch_and_install(url: &str, name: &str) {
 }
 
 fn extract_tar_gz(archive_path: &str, dest: &str) -> Result<(), String> {
-    use std::io::BufReader;
+    use std::io::{BufRead, BufReader};
     use flate2::read::GzDecoder;
 
+    const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
     let file = File::open(archive_path)
         .map_err(|e| format!("Cannot open archive: {}", e))?;
 
-    let reader = BufReader::new(file);
+    let mut reader = BufReader::new(file);
 
-    if archive_path.ends_with(".gz") {
+    // Detect compression from the content, the file name can't be trusted
+    let header = reader.fill_buf()
+        .map_err(|e| format!("Cannot read archive: {}", e))?;
+    let gzipped = header.starts_with(&GZIP_MAGIC);
+
+    let paths = if gzipped {
         let decoder = GzDecoder::new(reader);
-        let mut archive = tar::Archive::new(decoder);
-        archive.unpack(dest)
-            .map_err(|e| format!("Extraction failed: {}", e))?;
+        unpack_tar(tar::Archive::new(decoder), dest)?
     } else {
-        let mut archive = tar::Archive::new(reader);
-        archive.unpack(dest)
+        unpack_tar(tar::Archive::new(reader), dest)?
+    };
+
+    write_manifest(dest, &paths)
+}
+
+/// Unpack every entry of `archive` into `dest`, returning the extracted paths
+fn unpack_tar<R: Read>(mut archive: tar::Archive<R>, dest: &str) -> Result<Vec<PathBuf>, String> {
+    fs::create_dir_all(dest)
+        .map_err(|e| format!("Extraction failed: {}", e))?;
+
+    let entries = archive.entries()
+        .map_err(|e| format!("Extraction failed: {}", e))?;
+
+    let mut paths = Vec::new();
+    for entry in entries {
+        let mut entry = entry.map_err(|e| format!("Extraction failed: {}", e))?;
+        let path = entry.path()
+            .map_err(|e| format!("Extraction failed: {}", e))?
+            .into_owned();
+
+        // Entries escaping `dest` are skipped by unpack_in
+        let unpacked = entry.unpack_in(dest)
             .map_err(|e| format!("Extraction failed: {}", e))?;
+        // The archive root (".") is the package directory itself
+        let path = path.strip_prefix(".").unwrap_or(&path);
+        if unpacked && !path.as_os_str().is_empty() {
+            paths.push(Path::new(dest).join(path));
+        }
     }
 
-    Ok(())
+    Ok(paths)
+}
+
+struct ManifestEntry {
+    path: PathBuf,
+    // Size of a regular file when it was installed
+    size: Option<u64>,
+}
+
+fn write_manifest(dest: &str, paths: &[PathBuf]) -> Result<(), String> {
+    let mut manifest = String::new();
+    for path in paths {
+        manifest.push_str(&path.to_string_lossy());
+        if let Ok(meta) = fs::symlink_metadata(path) {
+            if meta.is_file() {
+                manifest.push_str(&format!("\t{}", meta.len()));
+            }
+        }
+        manifest.push('\n');
+    }
+
+    fs::write(Path::new(dest).join(MANIFEST), manifest)
+        .map_err(|e| format!("Cannot write manifest: {}", e))
+}
+
+fn read_manifest(pkg_dir: &Path) -> Option<Vec<ManifestEntry>> {
+    let content = fs::read_to_string(pkg_dir.join(MANIFEST)).ok()?;
+    let entries = content.lines()
+        .filter(|l| !l.is_empty())
+        .map(|line| match line.rsplit_once('\t') {
+            Some((path, size)) => ManifestEntry { path: PathBuf::from(path), size: size.parse().ok() },
+            None => ManifestEntry { path: PathBuf::from(line), size: None },
+        })
+        .collect();
+    Some(entries)
+}
+
+/// Directory of an installed package, exits if `name` isn't one
+fn installed_package_dir(name: &str) -> PathBuf {
+    if name.is_empty() || name.contains('/') || name == "." || name == ".." {
+        eprintln!("Invalid package name: {}", name);
+        process::exit(1);
+    }
+
+    let pkg_dir = Path::new(PKG_DIR).join(name);
+    if !pkg_dir.is_dir() {
+        eprintln!("Package '{}' is not installed", name);
+        process::exit(1);
+    }
+
+    pkg_dir
+}
+
+/// Remove the directories left empty below `dir`, and `dir` itself if it ends up empty
+fn remove_empty_dirs(dir: &Path) {
+    if let Ok(entries) = fs::read_dir(dir) {
+        for entry in entries.flatten() {
+            if entry.file_type().is_ok_and(|t| t.is_dir()) {
+                remove_empty_dirs(&entry.path());
+            }
+        }
+    }
+    fs::remove_dir(dir).ok();
+}
+
+fn remove_package(name: &str) {
+    let pkg_dir = installed_package_dir(name);
+
+    let paths: Vec<PathBuf> = match read_manifest(&pkg_dir) {
+        Some(entries) => entries.into_iter().map(|e| e.path).collect(),
+        None => {
+            eprintln!("No manifest for '{}' ({}), refusing to remove", name, pkg_dir.join(MANIFEST).display());
+            process::exit(1);
+        }
+    };
+
+    // Files listed by other packages' manifests are kept
+    let mut others = Vec::new();
+    if let Ok(entries) = fs::read_dir(PKG_DIR) {
+        for entry in entries.flatten() {
+            if entry.file_name() != name {
+                others.extend(read_manifest(&entry.path()).unwrap_or_default().into_iter().map(|e| e.path));
+            }
+        }
+    }
+
+    eprintln!("Removing {}...", name);
+
+    // Reverse extraction order, so files go before their directories
+    let mut removed = 0;
+    for path in paths.iter().rev() {
+        if others.contains(path) {
+            eprintln!("Warning: {} is also owned by another package, skipping", path.display());
+            continue;
+        }
+
+        let result = match fs::symlink_metadata(path) {
+            Ok(meta) if meta.is_dir() => fs::remove_dir(path),
+            Ok(_) => fs::remove_file(path),
+            Err(_) => continue,
+        };
+        match result {
+            Ok(()) => removed += 1,
+            Err(e) if path.is_dir() => eprintln!("Warning: keeping directory {}: {}", path.display(), e),
+            Err(e) => eprintln!("Warning: failed to remove {}: {}", path.display(), e),
+        }
+    }
+
+    fs::remove_file(pkg_dir.join(MANIFEST)).ok();
+    remove_empty_dirs(&pkg_dir);
+
+    if pkg_dir.exists() {
+        eprintln!("Removed {} ({} files), some files were left in {}", name, removed, pkg_dir.display());
+    } else {
+        eprintln!("Removed {} ({} files)", name, removed);
+    }
+}
+
+fn verify_package(name: &str) {
+    let pkg_dir = installed_package_dir(name);
+
+    let entries = match read_manifest(&pkg_dir) {
+        Some(entries) => entries,
+        None => {
+            eprintln!("No manifest for '{}': it was installed before manifest support, reinstall it to verify", name);
+            process::exit(1);
+        }
+    };
+
+    let mut missing = 0;
+    let mut changed = 0;
+    for entry in &entries {
+        match fs::symlink_metadata(&entry.path) {
+            Ok(meta) => {
+                if let Some(size) = entry.size {
+                    if !meta.is_file() || meta.len() != size {
+                        println!("changed: {} (size {}, expected {})", entry.path.display(), meta.len(), size);
+                        changed += 1;
+                    }
+                }
+            }
+            Err(_) => {
+                println!("missing: {}", entry.path.display());
+                missing += 1;
+            }
+        }
+    }
+
+    if missing + changed > 0 {
+        println!("{}: {} files checked, {} missing, {} changed", name, entries.len(), missing, changed);
+        process::exit(1);
+    }
+    println!("{}: {} files OK", name, entries.len());
 }
 
 fn show_info(name: &str) {
@@ -476,6 +798,20 @@ fn main() {
             }
             install_local(&args[2]);
         }
+        "remove" | "rm" => {
+            if args.len() < 3 {
+                eprintln!("Usage: pkg remove <package>");
+                process::exit(1);
+            }
+            remove_package(&args[2]);
+        }
+        "verify" => {
+            if args.len() < 3 {
+                eprintln!("Usage: pkg verify <package>");
+                process::exit(1);
+            }
+            verify_package(&args[2]);
+        }
         "info" | "show" => {
             if args.len() < 3 {
                 eprintln!("Usage: pkg info <package>");
@@ -488,7 +824,7 @@ fn main() {
                 eprintln!("Usage: pkg fetch <url>");
                 process::exit(1);
             }
-            fetch_and_install(&args[2], "fetched");
+            fetch_and_install(&args[2], "fetched", None);
         }
         "update" | "up" => update_repo(),
         "-h" | "--help" | "help" => print_usage(),
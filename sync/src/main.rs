@@ -1,27 +1,60 @@
 //! sync - flush filesystem buffers to disk
 //!
 //! In Redox's microkernel architecture, each filesystem handles its own caching.
-//! This tool syncs files by opening and fsyncing key paths.
+//! This tool syncs files by opening and fsyncing key paths. Without paths, every filesystem is
+//! synced through the root of its scheme.
+//!
+//! Usage: sync [-r] [PATH...]
+//!
+//! With `-r`, directories are synced recursively, which is the reliable way to flush a whole
+//! subtree. Symlinks to directories are not followed.
 
 use std::env;
+use std::mem::MaybeUninit;
 use std::fs::{self, File, OpenOptions};
 use std::os::unix::io::AsRawFd;
 use std::path::Path;
 
+fn fsync_file(f: &File) -> bool {
+    unsafe { libc::fsync(f.as_raw_fd()) == 0 }
+}
+
 fn fsync_path(path: &Path) -> bool {
     if let Ok(f) = OpenOptions::new().read(true).open(path) {
-        unsafe { libc::fsync(f.as_raw_fd()) == 0 }
+        fsync_file(&f)
     } else {
         false
     }
 }
 
-fn sync_dir(path: &Path) {
+/// Syncs every filesystem, by fsyncing the root of each scheme that supports `fstatvfs`.
+///
+/// Returns `false` if the schemes couldn't be listed.
+fn sync_schemes() -> bool {
+    let Ok(entries) = fs::read_dir("/scheme") else {
+        return false;
+    };
+    for entry in entries.flatten() {
+        let Ok(f) = OpenOptions::new().read(true).open(entry.path()) else {
+            continue;
+        };
+        let mut stat = MaybeUninit::<libc::statvfs>::uninit();
+        if unsafe { libc::fstatvfs(f.as_raw_fd(), stat.as_mut_ptr()) } == 0 {
+            fsync_file(&f);
+        }
+    }
+    true
+}
+
+fn sync_dir(path: &Path, recursive: bool) {
     if let Ok(entries) = fs::read_dir(path) {
         for entry in entries.flatten() {
             let p = entry.path();
             if p.is_file() {
                 fsync_path(&p);
+            } else if recursive && entry.file_type().is_ok_and(|t| t.is_dir()) {
+                // The entry's own file type, so that symlinks can't cause loops
+                sync_dir(&p, recursive);
             }
         }
     }
@@ -29,14 +62,20 @@ fn sync_dir(path: &Path) {
 }
 
 fn main() {
-    let args: Vec<String> = env::args().skip(1).collect();
+    let mut args: Vec<String> = env::args().skip(1).collect();
+    let recursive = args.iter().any(|arg| arg == "-r");
+    args.retain(|arg| arg != "-r");
 
     if args.is_empty() {
+        if sync_schemes() {
+            return;
+        }
+
         // Sync common locations
         for path in &["/", "/root", "/home", "/tmp"] {
             let p = Path::new(path);
             if p.exists() {
-                sync_dir(p);
+                sync_dir(p, recursive);
             }
         }
     } else {
@@ -44,7 +83,7 @@ fn main() {
         for arg in &args {
             let p = Path::new(arg);
             if p.is_dir() {
-                sync_dir(p);
+                sync_dir(p, recursive);
             } else {
                 fsync_path(p);
             }